@@ -0,0 +1,80 @@
+use crate::db::file_name::table_file_name;
+use crate::db::table_cache::TableCache;
+use crate::db::version_edit::FileMetaData;
+use crate::obj::options::Options;
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::iterator::Iter;
+use crate::table::table_builder::TableBuilder;
+use crate::util::env::Env;
+use crate::util::writable_file::{StdWritableFile, WritableFile};
+use std::sync::{Arc, Mutex};
+
+/// 把 iter 中的所有数据写成编号为 meta.number 的 table 文件，并填充 meta 的其余字段。
+/// iter 为空时不生成文件，meta.file_size 为 0。
+pub(crate) fn build_table<E>(
+    dbname: &String,
+    env: &E,
+    options: Arc<Options<E>>,
+    table_cache: &TableCache<E>,
+    iter: &mut dyn Iter,
+    meta: &mut FileMetaData,
+) -> Status
+where
+    E: Env + 'static,
+{
+    let mut s = Status::ok();
+    meta.file_size = 0;
+    iter.seek_to_first();
+
+    let fname = table_file_name(dbname, meta.number);
+    if iter.valid() {
+        let file: Arc<Mutex<dyn WritableFile>> =
+            match env.new_writable_file::<StdWritableFile, _>(&fname) {
+                Ok(file) => Arc::new(Mutex::new(file)),
+                Err(e) => return e,
+            };
+
+        let mut builder = TableBuilder::new(options, file.clone());
+        meta.smallest.decode_from(&iter.key());
+        let mut key = Slice::new_empty();
+        while iter.valid() {
+            key = iter.key();
+            builder.add(&key, &iter.value());
+            iter.next();
+        }
+        if !key.is_empty() {
+            meta.largest.decode_from(&key);
+        }
+
+        // 写完并 sync 后再关闭文件
+        s = builder.finish();
+        if s.is_ok() {
+            meta.file_size = builder.file_size();
+            debug_assert!(meta.file_size > 0);
+        }
+        drop(builder);
+        if s.is_ok() {
+            s = file.lock().unwrap().sync();
+        }
+        drop(file);
+
+        if s.is_ok() {
+            // 确认生成的文件可以正常打开
+            if let Err(e) = table_cache.find_table(meta.number, meta.file_size) {
+                s = e;
+            }
+        }
+    }
+
+    // 检查迭代器的错误
+    let iter_status = iter.status();
+    if !iter_status.is_ok() {
+        s = iter_status;
+    }
+
+    if !s.is_ok() || meta.file_size == 0 {
+        env.remove_file(&fname);
+    }
+    s
+}
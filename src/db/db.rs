@@ -1,32 +1,59 @@
+use crate::db::builder::build_table;
+use crate::db::db_iter::DBIter;
+use crate::db::file_name::{
+    current_file_name, descriptor_file_name, lock_file_name, log_file_name, parse_file_name,
+    set_current_file, table_file_name, FileType,
+};
+use crate::db::internal_filter_policy::InternalFilterPolicy;
+use crate::db::internal_key::{InternalKey, LookupKey};
+use crate::db::internal_key_comparator::{
+    parse_internal_key, InternalKeyComparator, ParsedInternalKey, ValueType,
+    K_L0_SLOWDOWN_WRITES_TRIGGER, K_L0_STOP_WRITES_TRIGGER, K_MAX_SEQUENCE_NUMBER, K_NUM_LEVELS,
+    K_VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::log_reader::{Reader, Reporter};
+use crate::db::log_writer::LogWriter;
 use crate::db::mem_table::MemTable;
+use crate::db::snapshot::{Snapshot, SnapshotList};
 use crate::db::table_cache::TableCache;
+use crate::db::version_edit::{FileMetaData, VersionEdit};
+use crate::db::version_set::{Compaction, GetStats, Version, VersionSet};
+use crate::db::write_batch::WriteBatch;
 use crate::db::write_options::WriteOptions;
-use crate::obj::options::{Options, ReadOptions};
+use crate::obj::options::{Options, ReadOptions, WALRecoveryMode};
 use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
 use crate::table::iterator::Iter;
+use crate::table::merger::new_merging_iterator;
+use crate::table::table_builder::TableBuilder;
+use crate::util::bytewise_comparator_impl::byte_wise_comparator;
+use crate::util::cache::new_cache;
 use crate::util::comparator::Comparator;
 use crate::util::env::{Env, FileLock};
 use crate::util::filter_policy::FilterPolicy;
-use crate::util::writable_file::WritableFile;
-use std::num::NonZeroUsize;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use crate::util::sequential_file::{SequentialFile, StdSequentialFile};
+use crate::util::writable_file::{StdWritableFile, WritableFile};
 use ahash::HashSet;
-use crate::db::internal_key::InternalKey;
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use tracing::{info, warn};
 
-struct Range {
+/// user key 在 [start, limit) 中的范围
+pub struct Range {
     start: Slice,
     limit: Slice,
 }
 
 impl Range {
-    fn new(start: Slice, limit: Slice) -> Self {
+    pub fn new(start: Slice, limit: Slice) -> Self {
         Range { start, limit }
     }
 }
 
-trait DB<E>
+pub trait DB<E>
 where
     E: Env,
 {
@@ -36,21 +63,44 @@ where
 
     fn put(&self, options: &WriteOptions, key: &Slice, value: &Slice) -> Status;
     fn delete(&self, options: &WriteOptions, key: &Slice) -> Status;
+    /// 原子地应用 batch 中的所有修改
+    fn write(&self, options: &WriteOptions, updates: WriteBatch) -> Status;
 
+    /// options.snapshot 不为空时读该快照时刻的数据
     fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Slice, Status>;
 
-    fn new_iterator(&self, options: &ReadOptions) -> Arc<dyn Iter>;
+    /// 返回的迭代器按 user key 遍历数据库，只包含最新的可见版本
+    fn new_iterator(&self, options: &ReadOptions) -> Box<dyn Iter>;
 
+    /// 返回当前状态的快照，不再使用时需要调用 release_snapshot
+    fn get_snapshot(&self) -> Arc<Snapshot>;
+    fn release_snapshot(&self, snapshot: Arc<Snapshot>);
 
+    /// 把属性的值写入 value，不认识的属性返回 false。支持的属性：
+    /// - "leveldb.num-files-at-level<N>"：第 N 层的文件个数
+    /// - "leveldb.stats"：每层的文件个数、大小和 compaction 统计，格式与 C++ 版本相同
+    /// - "leveldb.write-stall-stats"：写入因 level-0 文件过多或 memtable 写满被延迟和阻塞的次数和时间
+    /// - "leveldb.sstables"：每层的文件及其 key 范围
+    /// - "leveldb.approximate-memory-usage"：memtable 和 block cache 占用的内存
+    /// - "leveldb.block-cache-usage"：block cache 中所有 block 解压后的总大小
+    /// - "leveldb.block-cache-pinned-usage"：其中正在被迭代器引用、不能淘汰的部分
+    /// - "leveldb.estimate-num-keys"：key 个数的估计，包括旧版本和删除标记。
+    ///   每个 table 第一次被统计时读取它的第一个 data block，之后使用缓存在 table 中的结果
+    /// - "leveldb.filter-stats"：每个 table 的 filter 省去读取的次数和误判的次数
     fn get_property(&self, property: &Slice, value: &mut String) -> bool;
 
-    fn get_approximate_sizes(&self, range: &Range, n: i64, sizes: &mut u64);
+    /// 每个范围内的数据在文件中大致占用的字节数，数据压缩后可能比写入的数据小得多。
+    /// include_memtable 为 true 时还会加上 memtable 中还没有写入文件的数据
+    fn approximate_sizes(&self, ranges: &[Range], include_memtable: bool) -> Vec<u64>;
 
-    fn compact_range(&self, begin: &Slice, end: &Slice);
+    /// 把 [begin, end] 范围内的数据 compaction 到底层，丢弃删除的和被覆盖的数据，完成后才返回。
+    /// begin 为 None 表示从第一个 key 开始，end 为 None 表示到最后一个 key
+    fn compact_range(&self, begin: Option<&Slice>, end: Option<&Slice>);
 }
 
 const K_NUM_NON_TABLE_CACHE_FILES: usize = 10;
-struct DBImpl<E>
+
+pub struct DBImpl<E>
 where
     E: Env,
 {
@@ -61,100 +111,3050 @@ where
     table_cache_: Arc<TableCache<E>>,
     db_lock: Option<Arc<FileLock>>,
     shutting_down: AtomicBool,
+    mutex_: Mutex<DBState<E>>,
+    // 后台任务完成时通知，与 mutex_ 配合使用
+    background_work_finished_signal_: Condvar,
+    // 与 imm_.is_some() 相同，compaction 时不加锁检查
+    has_imm_: AtomicBool,
+    // 迭代器通过它调度读采样触发的 compaction，不会让 db 一直存活
+    weak_self_: Weak<DBImpl<E>>,
+}
+
+// 由 DBImpl::mutex_ 保护的状态
+struct DBState<E>
+where
+    E: Env,
+{
     mem_: Option<Arc<MemTable>>,
     imm_: Option<Arc<MemTable>>,
-    logfile_: Option<Arc<dyn WritableFile>>,
+    logfile_: Option<Arc<Mutex<dyn WritableFile>>>,
     logfile_number_: u64,
+    log_: Option<LogWriter>,
+    versions_: VersionSet<E>,
+    // 等待写入的 writer，队首的 writer 负责把后面的写入合并提交
+    writers_: VecDeque<Arc<Writer>>,
+    // 后台任务或同步日志失败后，拒绝后续的写入
+    bg_error_: Status,
+    // 每个 DBIter 的采样随机数种子
+    seed_: u32,
+    snapshots_: SnapshotList,
+    // 正在生成的 table 文件，不能被当作过期文件删除
+    pending_outputs_: HashSet<u64>,
+    background_compaction_scheduled_: bool,
+    // 正在等待或执行的手动 compaction
+    manual_compaction_: Option<Arc<ManualCompaction>>,
+    stats_: [CompactionStats; K_NUM_LEVELS],
+    write_stall_stats_: WriteStallStats,
 }
 
-fn table_cache_size(max_open_files: usize) -> usize {
-    // Reserve ten files or so for other uses and give the rest to TableCache.
-    max_open_files - K_NUM_NON_TABLE_CACHE_FILES
+// 一次等待提交的写入，done 和 status 只在持有 DBImpl::mutex_ 时修改
+struct Writer {
+    batch: Option<WriteBatch>,
+    sync: bool,
+    done: AtomicBool,
+    status: Mutex<Status>,
+    cv: Condvar,
 }
 
+impl Writer {
+    fn new(batch: Option<WriteBatch>, sync: bool) -> Self {
+        Writer {
+            batch,
+            sync,
+            done: AtomicBool::new(false),
+            status: Mutex::new(Status::ok()),
+            cv: Condvar::new(),
+        }
+    }
+}
 
+// 每层 compaction 的累计统计，写入该层的 compaction 记在该层上
+#[derive(Debug, Clone, Copy, Default)]
+struct CompactionStats {
+    micros: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+}
 
+impl CompactionStats {
+    fn add(&mut self, other: &CompactionStats) {
+        self.micros += other.micros;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
 
+// 写入因为 compaction 跟不上而被延迟或阻塞的次数和时间
+#[derive(Debug, Default)]
+struct WriteStallStats {
+    // level-0 文件过多，每次写入延迟 1ms
+    level0_slowdown_count: u64,
+    level0_slowdown_micros: u64,
+    // memtable 已满，上一个 memtable 还在写入 level-0
+    memtable_wait_count: u64,
+    memtable_wait_micros: u64,
+    // level-0 文件数达到上限
+    level0_stop_count: u64,
+    level0_stop_micros: u64,
+}
 
+// 一次手动 compaction 的请求，done 和 begin 只在持有 DBImpl::mutex_ 时修改
+struct ManualCompaction {
+    level: usize,
+    done: AtomicBool,
+    // None 表示无边界。后台每次只完成一部分，begin 随之向后移动
+    begin: Mutex<Option<InternalKey>>,
+    end: Option<InternalKey>,
+}
 
+// compaction 生成的一个输出文件
+struct CompactionOutput {
+    number: u64,
+    file_size: u64,
+    smallest: InternalKey,
+    largest: InternalKey,
+}
 
+// 一次 compaction 的执行状态
+struct CompactionState<E>
+where
+    E: Env,
+{
+    compaction: Compaction<E>,
+    // 比它小的序列号没有快照会访问，同一个 user key 在它之前的旧版本可以丢弃
+    smallest_snapshot: u64,
+    outputs: Vec<CompactionOutput>,
+    // 正在生成的输出文件
+    outfile: Option<Arc<Mutex<dyn WritableFile>>>,
+    builder: Option<TableBuilder<E>>,
+    total_bytes: u64,
+}
 
+impl<E> CompactionState<E>
+where
+    E: Env,
+{
+    fn new(compaction: Compaction<E>) -> Self {
+        CompactionState {
+            compaction,
+            smallest_snapshot: 0,
+            outputs: Vec::new(),
+            outfile: None,
+            builder: None,
+            total_bytes: 0,
+        }
+    }
+}
+
+// 交给后台线程的 DBImpl 指针。DBImpl 在 drop 时会等待后台任务结束，所以后台任务执行期间指针一直有效
+struct DBPtr<E: Env>(*const DBImpl<E>);
+
+unsafe impl<E: Env> Send for DBPtr<E> {}
 
+fn table_cache_size(max_open_files: usize) -> usize {
+    // Reserve ten files or so for other uses and give the rest to TableCache.
+    max_open_files - K_NUM_NON_TABLE_CACHE_FILES
+}
 
+// internal key 的比较器目前固定按字节序比较 user key，其他比较器写出的数据顺序会是错的
+pub(crate) fn check_comparator<E: Env>(options: &Options<E>) -> Status {
+    let expected = byte_wise_comparator().name();
+    if options.comparator.name() != expected {
+        return Status::invalid_argument(
+            options.comparator.name(),
+            Some(&format!("only {} is supported", expected)),
+        );
+    }
+    Status::ok()
+}
 
+// 把用户配置限制在合理范围内，并改用 internal key 的比较器和 filter policy
+pub(crate) fn sanitize_options<E: Env>(src: &Options<E>) -> Options<E> {
+    let mut result = src.clone();
+    result.comparator = Arc::new(InternalKeyComparator::new());
+    result.filter_policy = src
+        .filter_policy
+        .clone()
+        .map(|policy| Arc::new(InternalFilterPolicy::new(policy)) as Arc<dyn FilterPolicy>);
+    result.max_open_files = result
+        .max_open_files
+        .clamp(64 + K_NUM_NON_TABLE_CACHE_FILES as u64, 50000);
+    result.write_buffer_size = result.write_buffer_size.clamp(64 << 10, 1 << 30);
+    result.max_file_size = result.max_file_size.clamp(1 << 20, 1 << 30);
+    result.block_size = result.block_size.clamp(1 << 10, 4 << 20);
+    if result.block_cache.is_none() {
+        result.block_cache = NonZeroUsize::new(result.block_cache_capacity)
+            .map(|capacity| new_cache(result.cache_type, capacity));
+    }
+    result
+}
 
+// 是否因此让恢复失败由 Reader 按 WALRecoveryMode 决定，这里只打印日志
+pub(crate) struct LogReporter {
+    pub(crate) fname: String,
+}
+
+impl Reporter for LogReporter {
+    fn corruption(&mut self, bytes: usize, status: &Status) {
+        warn!("{}: dropping {} bytes; {}", self.fname, bytes, status);
+    }
+}
 
 impl<E> DBImpl<E>
 where
     E: Env + 'static,
 {
-    fn new(options: Arc<Options<E>>, dbname: String) -> DBImpl<E> {
+    fn new(raw_options: Arc<Options<E>>, dbname: String) -> DBImpl<E> {
+        let options = Arc::new(sanitize_options(&raw_options));
+        let table_cache = Arc::new(TableCache::new(
+            dbname.clone(),
+            options.clone(),
+            NonZeroUsize::try_from(table_cache_size(options.max_open_files as usize)).unwrap(),
+        ));
         DBImpl {
             internal_comparator_: options.comparator.clone(),
             internal_filter_policy_: options.filter_policy.clone(),
             options_: options.clone(),
             dbname_: dbname.clone(),
-            table_cache_: Arc::new(TableCache::new(
-                dbname.clone(),
-                options.clone(),
-                NonZeroUsize::try_from(table_cache_size(options.max_file_size)).unwrap(),
-            )),
             db_lock: None,
             shutting_down: Default::default(),
-            mem_: None,
-            imm_: None,
-            logfile_: None,
-            logfile_number_: 0,
+            mutex_: Mutex::new(DBState {
+                mem_: None,
+                imm_: None,
+                logfile_: None,
+                logfile_number_: 0,
+                log_: None,
+                versions_: VersionSet::new(dbname, options, table_cache.clone()),
+                writers_: VecDeque::new(),
+                bg_error_: Status::ok(),
+                seed_: 0,
+                snapshots_: SnapshotList::new(),
+                pending_outputs_: HashSet::default(),
+                background_compaction_scheduled_: false,
+                manual_compaction_: None,
+                stats_: [CompactionStats::default(); K_NUM_LEVELS],
+                write_stall_stats_: WriteStallStats::default(),
+            }),
+            background_work_finished_signal_: Condvar::new(),
+            has_imm_: AtomicBool::new(false),
+            weak_self_: Weak::new(),
+            table_cache_: table_cache,
+        }
+    }
+
+    // 创建只包含初始状态的 MANIFEST-000001，并让 CURRENT 指向它
+    fn new_db(&self) -> Status {
+        let mut new_db = VersionEdit::new();
+        new_db.set_comparator_name(
+            InternalKeyComparator::new()
+                .user_comparator()
+                .name()
+                .to_string(),
+        );
+        new_db.set_log_number_(0);
+        new_db.set_next_file_number_(2);
+        new_db.set_last_sequence_(0);
+
+        let manifest = descriptor_file_name(&self.dbname_, 1);
+        let env = self.options_.env.as_ref();
+        let file: Arc<Mutex<dyn WritableFile>> =
+            match env.new_writable_file::<StdWritableFile, _>(&manifest) {
+                Ok(file) => Arc::new(Mutex::new(file)),
+                Err(e) => return e,
+            };
+        let mut s = {
+            let mut log = LogWriter::new(file.clone());
+            let mut record = BytesMut::new();
+            new_db.encode_to(&mut record);
+            log.add_record(&Slice::new_from_ptr(&record))
+        };
+        if s.is_ok() {
+            s = file.lock().unwrap().sync();
+        }
+        drop(file);
+        if s.is_ok() {
+            s = set_current_file(env, &self.dbname_, 1);
+        } else {
+            env.remove_file(&manifest);
         }
+        s
+    }
+
+    // 获取 LOCK 文件，防止其他进程同时打开数据库
+    fn lock_db(&mut self) -> Status {
+        let env = self.options_.env.as_ref();
+        // 目录可能已经存在，忽略错误
+        env.create_dir(&self.dbname_);
+        debug_assert!(self.db_lock.is_none());
+        match env.lock_file(lock_file_name(&self.dbname_)) {
+            Ok(lock) => {
+                self.db_lock = Some(Arc::new(lock));
+                Status::ok()
+            }
+            Err(e) => e,
+        }
+    }
+
+    // 恢复 VersionSet，然后重放所有比 MANIFEST 中记录的日志更新的 WAL，调用前需要先 lock_db
+    fn recover(
+        &self,
+        state: &mut DBState<E>,
+        edit: &mut VersionEdit,
+        save_manifest: &mut bool,
+    ) -> Status {
+        let env = self.options_.env.clone();
+        if !env.file_exists(current_file_name(&self.dbname_)) {
+            if self.options_.create_if_missing {
+                info!("Creating DB {} since it was missing.", self.dbname_);
+                let s = self.new_db();
+                if !s.is_ok() {
+                    return s;
+                }
+            } else {
+                return Status::invalid_argument(
+                    &self.dbname_,
+                    Some("does not exist (create_if_missing is false)"),
+                );
+            }
+        } else if self.options_.error_if_exists {
+            return Status::invalid_argument(
+                &self.dbname_,
+                Some("exists (error_if_exists is true)"),
+            );
+        }
+
+        let s = state.versions_.recover(save_manifest);
+        if !s.is_ok() {
+            return s;
+        }
+
+        // 比 MANIFEST 中记录的 log_number 更新的日志是在上次写 MANIFEST 之后产生的，
+        // prev_log_number 对应的日志是老版本中正在 compaction 的 memtable
+        let min_log = state.versions_.log_number();
+        let prev_log = state.versions_.prev_log_number();
+        let filenames = match env.get_children(&self.dbname_) {
+            Ok(filenames) => filenames,
+            Err(e) => return e,
+        };
+        let mut expected = HashSet::default();
+        state.versions_.add_live_files(&mut expected);
+        let mut logs = Vec::new();
+        for filename in filenames.iter() {
+            if let Some((number, file_type)) = parse_file_name(filename) {
+                expected.remove(&number);
+                if file_type == FileType::LogFile && (number >= min_log || number == prev_log) {
+                    logs.push(number);
+                }
+            }
+        }
+        if let Some(missing) = expected.iter().next() {
+            return Status::corruption(
+                &format!("{} missing files; e.g.", expected.len()),
+                Some(&table_file_name(&self.dbname_, *missing)),
+            );
+        }
+
+        // 按生成顺序重放
+        logs.sort();
+        let mut max_sequence = 0;
+        let mut stop_replay = false;
+        for (i, log) in logs.iter().enumerate() {
+            if stop_replay {
+                // PointInTimeRecovery 在前面的日志中遇到了损坏，之后的日志全部丢弃
+                let fname = log_file_name(&self.dbname_, *log);
+                let bytes = env.get_file_size(&fname).unwrap_or(0);
+                LogReporter { fname }.corruption(
+                    bytes as usize,
+                    &Status::corruption("previous log is corrupted", None),
+                );
+                state.versions_.mark_file_number_used(*log);
+                continue;
+            }
+            let s = self.recover_log_file(
+                state,
+                *log,
+                i == logs.len() - 1,
+                save_manifest,
+                edit,
+                &mut max_sequence,
+                &mut stop_replay,
+            );
+            if !s.is_ok() {
+                return s;
+            }
+            // 上一次打开时可能在分配了日志编号后还没来得及写 MANIFEST
+            state.versions_.mark_file_number_used(*log);
+        }
+
+        if state.versions_.last_sequence() < max_sequence {
+            state.versions_.set_last_sequence(max_sequence);
+        }
+        Status::ok()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn recover_log_file(
+        &self,
+        state: &mut DBState<E>,
+        log_number: u64,
+        last_log: bool,
+        save_manifest: &mut bool,
+        edit: &mut VersionEdit,
+        max_sequence: &mut u64,
+        stop_replay: &mut bool,
+    ) -> Status {
+        let env = self.options_.env.as_ref();
+        let fname = log_file_name(&self.dbname_, log_number);
+        let file: Arc<Mutex<dyn SequentialFile>> =
+            match env.new_sequential_file::<StdSequentialFile, _>(&fname) {
+                Ok(file) => Arc::new(Mutex::new(file)),
+                Err(e) => return e,
+            };
+
+        // paranoid_checks 时不允许跳过损坏的记录
+        let recovery_mode = match self.options_.wal_recovery_mode {
+            WALRecoveryMode::SkipAnyCorruptedRecords if self.options_.paranoid_checks => {
+                WALRecoveryMode::AbsoluteConsistency
+            }
+            mode => mode,
+        };
+        let mut reporter = LogReporter {
+            fname: fname.clone(),
+        };
+        info!("Recovering log #{}", log_number);
+
+        // 即使 paranoid_checks 为 false 也要做 checksum 校验，以免把损坏的数据当作有效数据
+        let mut reader = Reader::new(
+            file,
+            Some(Box::new(LogReporter {
+                fname: fname.clone(),
+            })),
+            true,
+            0,
+            recovery_mode,
+        );
+        let mut scratch = BytesMut::new();
+        let mut record = Slice::new_empty();
+        let mut batch = WriteBatch::new();
+        let mut mem: Option<Arc<MemTable>> = None;
+        let mut compactions = 0;
+        let mut status = Status::ok();
+        // 记录本身完好但内容损坏：太短或者 batch 格式错误
+        let mut bad_record = false;
+        while reader.read_record(&mut record, &mut scratch) && status.is_ok() {
+            let reason = if record.size() < 12 {
+                Status::corruption("log record too small", None)
+            } else {
+                batch.set_contents(&record);
+                batch.validate()
+            };
+            if !reason.is_ok() {
+                bad_record = true;
+                match recovery_mode {
+                    WALRecoveryMode::PointInTimeRecovery => {
+                        // 丢弃这条记录及之后的全部内容
+                        let file_size = env.get_file_size(&fname).unwrap_or(0);
+                        let offset = reader.last_record_offset();
+                        reporter.corruption(file_size.saturating_sub(offset) as usize, &reason);
+                        *stop_replay = true;
+                        break;
+                    }
+                    WALRecoveryMode::SkipAnyCorruptedRecords => {
+                        reporter.corruption(record.size(), &reason);
+                    }
+                    _ => {
+                        reporter.corruption(record.size(), &reason);
+                        status = reason;
+                    }
+                }
+                continue;
+            }
+
+            let mem_ref = mem.get_or_insert_with(|| Arc::new(MemTable::new()));
+            status = batch.insert_into(mem_ref);
+            if !status.is_ok() {
+                break;
+            }
+            let last_seq = batch.sequence() + batch.count() as u64 - 1;
+            if last_seq > *max_sequence {
+                *max_sequence = last_seq;
+            }
+
+            if mem_ref.approximate_memory_usage() > self.options_.write_buffer_size {
+                compactions += 1;
+                *save_manifest = true;
+                status = self.write_level0_table(state, mem_ref, edit, None);
+                mem = None;
+                if !status.is_ok() {
+                    // 出错时直接返回，以免写入新的 MANIFEST 后丢失数据
+                    break;
+                }
+            }
+            if status.is_ok() {
+                status = reader.status();
+            }
+        }
+        if status.is_ok() {
+            status = reader.status();
+        }
+        if reader.dropped_bytes() > 0 {
+            bad_record = true;
+            if recovery_mode == WALRecoveryMode::PointInTimeRecovery {
+                // 之后的日志接不上了，不再重放
+                *stop_replay = true;
+            }
+        }
+        drop(reader);
+
+        // 最后一个日志没有触发过 flush 时可以继续追加写入；
+        // 有损坏的日志不能复用，否则新追加的记录会跟在损坏的内容之后
+        if status.is_ok() && self.options_.reuse_logs && last_log && compactions == 0 && !bad_record
+        {
+            debug_assert!(state.logfile_.is_none());
+            debug_assert!(state.log_.is_none());
+            debug_assert!(state.mem_.is_none());
+            if let Ok(lfile_size) = env.get_file_size(&fname) {
+                if let Ok(file) = env.new_appendable_file::<StdWritableFile, _>(&fname) {
+                    info!("Reusing old log {}", fname);
+                    let file: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(file));
+                    state.log_ = Some(LogWriter::new_dest_length(file.clone(), lfile_size));
+                    state.logfile_ = Some(file);
+                    state.logfile_number_ = log_number;
+                    // 没有数据时也要创建 memtable，调用方据此判断日志已被复用
+                    state.mem_ = Some(mem.take().unwrap_or_else(|| Arc::new(MemTable::new())));
+                }
+            }
+        }
+
+        if let Some(mem) = mem {
+            // mem 没有被复用，写成 level-0 文件
+            if status.is_ok() {
+                *save_manifest = true;
+                status = self.write_level0_table(state, &mem, edit, None);
+            }
+        }
+        status
+    }
+
+    // 把 mem 写成 table 文件并记录到 edit 中，base 不为空时可以把文件直接放到更深的层
+    fn write_level0_table(
+        &self,
+        state: &mut DBState<E>,
+        mem: &Arc<MemTable>,
+        edit: &mut VersionEdit,
+        base: Option<&Version<E>>,
+    ) -> Status {
+        let start_micros = self.options_.env.now_micros();
+        let mut meta = FileMetaData::new();
+        meta.number = state.versions_.new_file_number();
+        let s = self.build_level0_table(mem, &mut meta);
+        if s.is_ok() {
+            let stats = CompactionStats {
+                micros: self.options_.env.now_micros() - start_micros,
+                bytes_read: 0,
+                bytes_written: meta.file_size,
+            };
+            let level = Self::add_level0_table(edit, meta, base);
+            state.stats_[level].add(&stats);
+        }
+        s
+    }
+
+    // 把 mem 写成编号为 meta.number 的 table 文件，不访问 DBState，可以在不持有锁时调用
+    fn build_level0_table(&self, mem: &Arc<MemTable>, meta: &mut FileMetaData) -> Status {
+        let mut iter = mem.new_iterator();
+        info!("Level-0 table #{}: started", meta.number);
+
+        let s = build_table(
+            &self.dbname_,
+            self.options_.env.as_ref(),
+            self.options_.clone(),
+            &self.table_cache_,
+            &mut iter,
+            meta,
+        );
+        info!(
+            "Level-0 table #{}: {} bytes {}",
+            meta.number, meta.file_size, s
+        );
+        s
+    }
+
+    // 返回文件放入的层
+    fn add_level0_table(
+        edit: &mut VersionEdit,
+        meta: FileMetaData,
+        base: Option<&Version<E>>,
+    ) -> usize {
+        let mut level = 0;
+        // file_size 为 0 说明文件已被删除，不需要加入 version
+        if meta.file_size > 0 {
+            if let Some(base) = base {
+                level = base.pick_level_for_memtable_output(
+                    &meta.smallest.user_key(),
+                    &meta.largest.user_key(),
+                );
+            }
+            edit.add_file(
+                level as i32,
+                meta.number,
+                meta.file_size,
+                meta.smallest,
+                meta.largest,
+            );
+        }
+        level
     }
 }
 
-impl<E> DB<E> for DBImpl<E>
+impl<E> DBImpl<E>
+where
+    E: Env + 'static,
+{
+    // compaction 需要保留对这个序列号可见的所有版本
+    fn smallest_snapshot(state: &DBState<E>) -> u64 {
+        match state.snapshots_.oldest() {
+            Some(snapshot) => snapshot.sequence_number(),
+            None => state.versions_.last_sequence(),
+        }
+    }
+
+    // 合并 mem_、imm_ 和当前版本所有文件的迭代器，同时返回当前的最新序列号、
+    // 采样种子和迭代器对应的版本
+    fn new_internal_iterator(
+        &self,
+        options: &ReadOptions,
+    ) -> (Box<dyn Iter>, u64, u32, Arc<Version<E>>) {
+        let mut state = self.mutex_.lock().unwrap();
+        let latest_snapshot = state.versions_.last_sequence();
+        let mut list: Vec<Box<dyn Iter>> = Vec::new();
+        if let Some(mem) = &state.mem_ {
+            list.push(Box::new(mem.new_iterator()));
+        }
+        if let Some(imm) = &state.imm_ {
+            list.push(Box::new(imm.new_iterator()));
+        }
+        let current = state.versions_.current();
+        current.add_iterators(options, &mut list);
+        state.seed_ = state.seed_.wrapping_add(1);
+        let seed = state.seed_;
+        drop(state);
+
+        let internal_iter = new_merging_iterator(self.internal_comparator_.clone(), list);
+        (internal_iter, latest_snapshot, seed, current)
+    }
+
+    // 写入排队，由队首的 writer 把队列中的写入合并成一组提交。
+    // updates 为 None 时只等待前面的写入完成
+    fn write_impl(&self, options: &WriteOptions, updates: Option<WriteBatch>) -> Status {
+        let w = Arc::new(Writer::new(updates, options.sync));
+        let mut state = self.mutex_.lock().unwrap();
+        state.writers_.push_back(w.clone());
+        while !w.done.load(Ordering::Relaxed) && !Arc::ptr_eq(state.writers_.front().unwrap(), &w) {
+            state = w.cv.wait(state).unwrap();
+        }
+        if w.done.load(Ordering::Relaxed) {
+            return w.status.lock().unwrap().clone();
+        }
+
+        // batch 为 None 时强制切换 memtable
+        let (mut state, mut status) = self.make_room_for_write(state, w.batch.is_none());
+        let mut last_writer = w.clone();
+        if status.is_ok() && w.batch.is_some() {
+            let (mut write_batch, last) = self.build_batch_group(&state);
+            last_writer = last;
+            let mut last_sequence = state.versions_.last_sequence();
+            write_batch.set_sequence(last_sequence + 1);
+            last_sequence += write_batch.count() as u64;
+
+            // 写日志和 memtable 时释放锁，只有队首的 writer 会访问 log_ 和 mem_，
+            // 后续的 writer 可以在这期间继续排队
+            let mut log = state.log_.take().unwrap();
+            let logfile = state.logfile_.clone().unwrap();
+            let mem = state.mem_.clone().unwrap();
+            drop(state);
+            status = log.add_record(&write_batch.contents());
+            let mut sync_error = false;
+            if status.is_ok() && options.sync {
+                status = logfile.lock().unwrap().sync();
+                if !status.is_ok() {
+                    sync_error = true;
+                }
+            }
+            if status.is_ok() {
+                status = write_batch.insert_into(&mem);
+            }
+            state = self.mutex_.lock().unwrap();
+            state.log_ = Some(log);
+            if sync_error {
+                // 日志的状态不确定，之后的写入都失败，避免重启后出现部分写入
+                self.record_background_error(&mut state, &status);
+            }
+            state.versions_.set_last_sequence(last_sequence);
+        }
+
+        loop {
+            let ready = state.writers_.pop_front().unwrap();
+            if !Arc::ptr_eq(&ready, &w) {
+                *ready.status.lock().unwrap() = status.clone();
+                ready.done.store(true, Ordering::Relaxed);
+                ready.cv.notify_one();
+            }
+            if Arc::ptr_eq(&ready, &last_writer) {
+                break;
+            }
+        }
+
+        // 唤醒新的队首
+        if let Some(front) = state.writers_.front() {
+            front.cv.notify_one();
+        }
+        status
+    }
+
+    // 把队首开始的若干个 writer 合并成一个 batch，返回合并后的 batch 和最后一个被合并的 writer
+    fn build_batch_group(&self, state: &DBState<E>) -> (WriteBatch, Arc<Writer>) {
+        let first = state.writers_.front().unwrap();
+        let mut result = first.batch.clone().unwrap();
+        let mut size = result.approximate_size();
+
+        // 限制一组的大小，但小的写入不应被合并得太大，以免拖慢它的延迟
+        let mut max_size = 1 << 20;
+        if size <= (128 << 10) {
+            max_size = size + (128 << 10);
+        }
+
+        let mut last_writer = first.clone();
+        for w in state.writers_.iter().skip(1) {
+            if w.sync && !first.sync {
+                // 不把需要 sync 的写入合并到不 sync 的组中
+                break;
+            }
+            match w.batch.as_ref() {
+                Some(batch) => {
+                    size += batch.approximate_size();
+                    if size > max_size {
+                        break;
+                    }
+                    result.append(batch);
+                }
+                None => break,
+            }
+            last_writer = w.clone();
+        }
+        (result, last_writer)
+    }
+
+    fn record_background_error(&self, state: &mut DBState<E>, s: &Status) {
+        if state.bg_error_.is_ok() {
+            state.bg_error_ = s.clone();
+            self.background_work_finished_signal_.notify_all();
+        }
+    }
+
+    // 保证 mem_ 有空间写入，必要时把 mem_ 切换为 imm_ 并换一个新的日志。
+    // 只由队首的 writer 调用，force 为 true 时即使 mem_ 还有空间也切换
+    fn make_room_for_write<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DBState<E>>,
+        mut force: bool,
+    ) -> (MutexGuard<'a, DBState<E>>, Status) {
+        let env = self.options_.env.as_ref();
+        let mut allow_delay = !force;
+        loop {
+            if !state.bg_error_.is_ok() {
+                // 返回后台任务的错误
+                let s = state.bg_error_.clone();
+                return (state, s);
+            } else if allow_delay
+                && state.versions_.num_level_files(0) >= K_L0_SLOWDOWN_WRITES_TRIGGER
+            {
+                // 快到达 level-0 的上限了，与其等到上限时让一次写入等待几秒，
+                // 不如让每次写入延迟 1ms，把 CPU 让给 compaction。每次写入最多延迟一次
+                drop(state);
+                let start_micros = env.now_micros();
+                env.sleep_for_microseconds(1000);
+                let micros = env.now_micros() - start_micros;
+                allow_delay = false;
+                state = self.mutex_.lock().unwrap();
+                state.write_stall_stats_.level0_slowdown_count += 1;
+                state.write_stall_stats_.level0_slowdown_micros += micros;
+            } else if !force
+                && state.mem_.as_ref().unwrap().approximate_memory_usage()
+                    <= self.options_.write_buffer_size
+            {
+                // 当前的 memtable 还有空间
+                break;
+            } else if state.imm_.is_some() {
+                // 上一个 memtable 还在 compaction，等待它完成
+                info!("Current memtable full; waiting...");
+                let start_micros = env.now_micros();
+                state = self.background_work_finished_signal_.wait(state).unwrap();
+                state.write_stall_stats_.memtable_wait_count += 1;
+                state.write_stall_stats_.memtable_wait_micros += env.now_micros() - start_micros;
+            } else if state.versions_.num_level_files(0) >= K_L0_STOP_WRITES_TRIGGER {
+                // level-0 的文件太多了，等待 compaction
+                info!("Too many L0 files; waiting...");
+                let start_micros = env.now_micros();
+                state = self.background_work_finished_signal_.wait(state).unwrap();
+                state.write_stall_stats_.level0_stop_count += 1;
+                state.write_stall_stats_.level0_stop_micros += env.now_micros() - start_micros;
+            } else {
+                // 切换到新的 memtable 和日志，并触发旧 memtable 的 compaction
+                let new_log_number = state.versions_.new_file_number();
+                let lfile: Arc<Mutex<dyn WritableFile>> = match self
+                    .options_
+                    .env
+                    .new_writable_file::<StdWritableFile, _>(log_file_name(
+                        &self.dbname_,
+                        new_log_number,
+                    )) {
+                    Ok(lfile) => Arc::new(Mutex::new(lfile)),
+                    Err(e) => {
+                        // 避免在一个紧密的循环中不停地分配文件编号
+                        state.versions_.reuse_file_number(new_log_number);
+                        return (state, e);
+                    }
+                };
+                if let Some(old) = state.logfile_.take() {
+                    let s = old.lock().unwrap().flush();
+                    if !s.is_ok() {
+                        // 可能丢失了写入，和 sync 失败一样处理
+                        self.record_background_error(&mut state, &s);
+                    }
+                }
+                state.log_ = Some(LogWriter::new(lfile.clone()));
+                state.logfile_ = Some(lfile);
+                state.logfile_number_ = new_log_number;
+                state.imm_ = state.mem_.take();
+                self.has_imm_.store(true, Ordering::Release);
+                state.mem_ = Some(Arc::new(MemTable::new()));
+                // 不再强制切换
+                force = false;
+                self.maybe_schedule_compaction(&mut state);
+            }
+        }
+        (state, Status::ok())
+    }
+
+    fn maybe_schedule_compaction(&self, state: &mut DBState<E>) {
+        if state.background_compaction_scheduled_ {
+            // 已经调度过了
+        } else if self.shutting_down.load(Ordering::Acquire) {
+            // 数据库正在关闭，不再调度新的任务
+        } else if !state.bg_error_.is_ok() {
+            // 出错后不再修改数据库
+        } else if state.imm_.is_none()
+            && state.manual_compaction_.is_none()
+            && !state.versions_.needs_compaction()
+        {
+            // 没有需要做的工作
+        } else {
+            state.background_compaction_scheduled_ = true;
+            let db = DBPtr(self as *const DBImpl<E>);
+            self.options_.env.schedule(move || {
+                let db = db;
+                unsafe { (*db.0).background_call() }
+            });
+        }
+    }
+
+    /// 读路径（查找或迭代器的读采样）发现有文件需要 compaction 时调用
+    pub(crate) fn schedule_read_compaction(&self) {
+        let mut state = self.mutex_.lock().unwrap();
+        self.maybe_schedule_compaction(&mut state);
+    }
+
+    fn background_call(&self) {
+        let mut state = self.mutex_.lock().unwrap();
+        debug_assert!(state.background_compaction_scheduled_);
+        if self.shutting_down.load(Ordering::Acquire) {
+            // 数据库正在关闭，不再做后台工作
+        } else if !state.bg_error_.is_ok() {
+            // 出错后不再做后台工作
+        } else {
+            state = self.background_compaction(state);
+        }
+        state.background_compaction_scheduled_ = false;
+
+        // 上一次 compaction 可能在某一层产生了过多的文件，需要再次调度
+        self.maybe_schedule_compaction(&mut state);
+        self.background_work_finished_signal_.notify_all();
+    }
+
+    fn background_compaction<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DBState<E>>,
+    ) -> MutexGuard<'a, DBState<E>> {
+        if state.imm_.is_some() {
+            return self.compact_mem_table(state);
+        }
+
+        let manual = state.manual_compaction_.clone();
+        // 手动 compaction 这一次处理到的位置
+        let mut manual_end = None;
+        let c = if let Some(m) = manual.as_ref() {
+            let begin = m.begin.lock().unwrap().clone();
+            let c = state
+                .versions_
+                .compact_range(m.level, begin.as_ref(), m.end.as_ref());
+            m.done.store(c.is_none(), Ordering::Release);
+            if let Some(c) = c.as_ref() {
+                manual_end = Some(c.input(0, c.num_input_files(0) - 1).largest.clone());
+            }
+            info!(
+                "Manual compaction at level-{} from {} .. {}; will stop at {}",
+                m.level,
+                begin.map_or("(begin)".to_string(), |k| k.debug_string()),
+                m.end
+                    .as_ref()
+                    .map_or("(end)".to_string(), |k| k.debug_string()),
+                manual_end
+                    .as_ref()
+                    .map_or("(end)".to_string(), |k| k.debug_string())
+            );
+            c
+        } else {
+            state.versions_.pick_compaction()
+        };
+
+        let mut status = Status::ok();
+        match c {
+            // 没有需要做的工作
+            None => {}
+            // 手动 compaction 需要合并数据，不能只移动文件
+            Some(c) if manual.is_none() && c.is_trivial_move() => {
+                status = self.move_file_to_next_level(&mut state, c);
+            }
+            Some(c) => {
+                let mut compact = CompactionState::new(c);
+                (state, status) = self.do_compaction_work(state, &mut compact);
+                if !status.is_ok() {
+                    self.record_background_error(&mut state, &status);
+                }
+                self.cleanup_compaction(&mut state, compact);
+                state = self.remove_obsolete_files(state);
+            }
+        }
+
+        if let Some(m) = manual {
+            if !status.is_ok() {
+                m.done.store(true, Ordering::Release);
+            }
+            if !m.done.load(Ordering::Acquire) {
+                // 只完成了一部分范围，下一次从这次结束的位置继续
+                *m.begin.lock().unwrap() = manual_end;
+            }
+            state.manual_compaction_ = None;
+        }
+        state
+    }
+
+    // 把唯一的输入文件直接移动到下一层
+    fn move_file_to_next_level(&self, state: &mut DBState<E>, mut c: Compaction<E>) -> Status {
+        let level = c.level();
+        let f = c.input(0, 0).clone();
+        c.edit().remove_file(level as i32, f.number);
+        c.edit().add_file(
+            level as i32 + 1,
+            f.number,
+            f.file_size,
+            f.smallest.clone(),
+            f.largest.clone(),
+        );
+        let s = state.versions_.log_and_apply(c.edit());
+        if !s.is_ok() {
+            self.record_background_error(state, &s);
+        }
+        info!(
+            "Moved #{} to level-{} {} bytes {}: {}",
+            f.number,
+            level + 1,
+            f.file_size,
+            s,
+            state.versions_.current().debug_string()
+        );
+        s
+    }
+
+    fn cleanup_compaction(&self, state: &mut DBState<E>, mut compact: CompactionState<E>) {
+        if let Some(mut builder) = compact.builder.take() {
+            // compaction 中途失败时丢弃正在生成的文件
+            builder.abandon();
+        } else {
+            debug_assert!(compact.outfile.is_none());
+        }
+        for out in compact.outputs.iter() {
+            state.pending_outputs_.remove(&out.number);
+        }
+    }
+
+    fn open_compaction_output_file(&self, compact: &mut CompactionState<E>) -> Status {
+        debug_assert!(compact.builder.is_none());
+        let file_number = {
+            let mut state = self.mutex_.lock().unwrap();
+            let file_number = state.versions_.new_file_number();
+            state.pending_outputs_.insert(file_number);
+            file_number
+        };
+        compact.outputs.push(CompactionOutput {
+            number: file_number,
+            file_size: 0,
+            smallest: InternalKey::default(),
+            largest: InternalKey::default(),
+        });
+
+        let fname = table_file_name(&self.dbname_, file_number);
+        match self
+            .options_
+            .env
+            .new_writable_file::<StdWritableFile, _>(&fname)
+        {
+            Ok(file) => {
+                let file: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(file));
+                compact.builder = Some(TableBuilder::new(self.options_.clone(), file.clone()));
+                compact.outfile = Some(file);
+                Status::ok()
+            }
+            Err(e) => e,
+        }
+    }
+
+    fn finish_compaction_output_file(
+        &self,
+        compact: &mut CompactionState<E>,
+        input: &dyn Iter,
+    ) -> Status {
+        let mut builder = compact.builder.take().unwrap();
+        let output_number = compact.outputs.last().unwrap().number;
+        debug_assert!(output_number != 0);
+
+        let current_entries = builder.num_entries();
+        let mut s = input.status();
+        if s.is_ok() {
+            s = builder.finish();
+        } else {
+            builder.abandon();
+        }
+        let current_bytes = builder.file_size();
+        compact.outputs.last_mut().unwrap().file_size = current_bytes;
+        compact.total_bytes += current_bytes;
+        drop(builder);
+
+        let outfile = compact.outfile.take().unwrap();
+        if s.is_ok() {
+            s = outfile.lock().unwrap().sync();
+        }
+        drop(outfile);
+
+        if s.is_ok() && current_entries > 0 {
+            // 确认生成的文件可以正常使用
+            let iter =
+                self.table_cache_
+                    .new_iterator(&ReadOptions::new(), output_number, current_bytes);
+            s = iter.status();
+            if s.is_ok() {
+                info!(
+                    "Generated table #{}@{}: {} keys, {} bytes",
+                    output_number,
+                    compact.compaction.level(),
+                    current_entries,
+                    current_bytes
+                );
+            }
+        }
+        s
+    }
+
+    fn install_compaction_results(
+        &self,
+        state: &mut DBState<E>,
+        compact: &mut CompactionState<E>,
+    ) -> Status {
+        let c = &mut compact.compaction;
+        info!(
+            "Compacted {}@{} + {}@{} files => {} bytes",
+            c.num_input_files(0),
+            c.level(),
+            c.num_input_files(1),
+            c.level() + 1,
+            compact.total_bytes
+        );
+
+        // 删除所有输入文件，加入输出文件
+        c.add_input_deletions();
+        let level = c.level() as i32;
+        for out in compact.outputs.iter() {
+            c.edit().add_file(
+                level + 1,
+                out.number,
+                out.file_size,
+                out.smallest.clone(),
+                out.largest.clone(),
+            );
+        }
+        state.versions_.log_and_apply(c.edit())
+    }
+
+    // 合并 compaction 的输入，丢弃不再可见的旧版本和删除标记，写入 level+1 层。读写文件时释放锁
+    fn do_compaction_work<'a>(
+        &'a self,
+        state: MutexGuard<'a, DBState<E>>,
+        compact: &mut CompactionState<E>,
+    ) -> (MutexGuard<'a, DBState<E>>, Status) {
+        let level = compact.compaction.level();
+        info!(
+            "Compacting {}@{} + {}@{} files",
+            compact.compaction.num_input_files(0),
+            level,
+            compact.compaction.num_input_files(1),
+            level + 1
+        );
+        debug_assert!(state.versions_.num_level_files(level) > 0);
+        debug_assert!(compact.builder.is_none());
+        debug_assert!(compact.outfile.is_none());
+        let start_micros = self.options_.env.now_micros();
+        // 中途 compaction imm_ 的时间，不计入这次 compaction
+        let mut imm_micros = 0;
+        compact.smallest_snapshot = Self::smallest_snapshot(&state);
+
+        let mut input = state.versions_.make_input_iterator(&compact.compaction);
+        drop(state);
+
+        let icmp = InternalKeyComparator::new();
+        let ucmp = icmp.user_comparator();
+        input.seek_to_first();
+        let mut status = Status::ok();
+        let mut current_user_key = BytesMut::new();
+        let mut has_current_user_key = false;
+        let mut last_sequence_for_key = K_MAX_SEQUENCE_NUMBER;
+        while input.valid() && !self.shutting_down.load(Ordering::Acquire) {
+            // 优先 compaction imm_，避免写入被阻塞
+            if self.has_imm_.load(Ordering::Relaxed) {
+                let imm_start = self.options_.env.now_micros();
+                let state = self.mutex_.lock().unwrap();
+                if state.imm_.is_some() {
+                    let _state = self.compact_mem_table(state);
+                    // 唤醒在 make_room_for_write 中等待的写入
+                    self.background_work_finished_signal_.notify_all();
+                }
+                imm_micros += self.options_.env.now_micros() - imm_start;
+            }
+
+            let key = input.key();
+            if compact.builder.is_some() && compact.compaction.should_stop_before(&key) {
+                status = self.finish_compaction_output_file(compact, input.as_ref());
+                if !status.is_ok() {
+                    break;
+                }
+            }
+
+            // 判断是否丢弃这个 entry
+            let mut drop_entry = false;
+            let mut ikey = ParsedInternalKey {
+                user_key: Slice::new_empty(),
+                sequence: 0,
+                value_type: ValueType::KTypeValue,
+            };
+            if !parse_internal_key(&key, &mut ikey) {
+                // 不丢弃损坏的 key
+                current_user_key.clear();
+                has_current_user_key = false;
+                last_sequence_for_key = K_MAX_SEQUENCE_NUMBER;
+            } else {
+                if !has_current_user_key
+                    || ucmp.compare(&ikey.user_key, &Slice::new_from_ptr(&current_user_key))
+                        != std::cmp::Ordering::Equal
+                {
+                    // 这个 user key 第一次出现
+                    current_user_key.clear();
+                    current_user_key.extend_from_slice(ikey.user_key.data());
+                    has_current_user_key = true;
+                    last_sequence_for_key = K_MAX_SEQUENCE_NUMBER;
+                }
+
+                if last_sequence_for_key <= compact.smallest_snapshot {
+                    // 已经有一个更新且对所有快照可见的版本，这个版本被它覆盖了
+                    drop_entry = true;
+                } else if ikey.value_type == ValueType::KTypeDeletion
+                    && ikey.sequence <= compact.smallest_snapshot
+                    && compact.compaction.is_base_level_for_key(&ikey.user_key)
+                {
+                    // 对于这个 user key：
+                    // (1) 更深的层中没有数据
+                    // (2) 更浅的层中的数据序列号更大
+                    // (3) 本层中序列号更小的数据会被上面的规则丢弃
+                    // 所以这个删除标记已经没有用了
+                    drop_entry = true;
+                }
+                last_sequence_for_key = ikey.sequence;
+            }
+
+            if !drop_entry {
+                if compact.builder.is_none() {
+                    status = self.open_compaction_output_file(compact);
+                    if !status.is_ok() {
+                        break;
+                    }
+                }
+                let builder = compact.builder.as_mut().unwrap();
+                let output = compact.outputs.last_mut().unwrap();
+                if builder.num_entries() == 0 {
+                    output.smallest.decode_from(&key);
+                }
+                output.largest.decode_from(&key);
+                builder.add(&key, &input.value());
+
+                // 文件足够大时结束这个输出文件
+                if builder.file_size() >= compact.compaction.max_output_file_size() {
+                    status = self.finish_compaction_output_file(compact, input.as_ref());
+                    if !status.is_ok() {
+                        break;
+                    }
+                }
+            }
+
+            input.next();
+        }
+
+        if status.is_ok() && self.shutting_down.load(Ordering::Acquire) {
+            status = Status::io_error("Deleting DB during compaction", None);
+        }
+        if status.is_ok() && compact.builder.is_some() {
+            status = self.finish_compaction_output_file(compact, input.as_ref());
+        }
+        if status.is_ok() {
+            status = input.status();
+        }
+        drop(input);
+
+        let mut stats = CompactionStats {
+            micros: self.options_.env.now_micros() - start_micros - imm_micros,
+            bytes_read: 0,
+            bytes_written: compact.outputs.iter().map(|out| out.file_size).sum(),
+        };
+        for which in 0..2 {
+            for i in 0..compact.compaction.num_input_files(which) {
+                stats.bytes_read += compact.compaction.input(which, i).file_size;
+            }
+        }
+
+        let mut state = self.mutex_.lock().unwrap();
+        state.stats_[level + 1].add(&stats);
+        if status.is_ok() {
+            status = self.install_compaction_results(&mut state, compact);
+        }
+        info!("compacted to: {}", state.versions_.current().debug_string());
+        (state, status)
+    }
+
+    // 把 imm_ 写成 level-0 文件，写文件时释放锁
+    fn compact_mem_table<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DBState<E>>,
+    ) -> MutexGuard<'a, DBState<E>> {
+        let imm = state.imm_.clone().unwrap();
+        let base = state.versions_.current();
+        let mut meta = FileMetaData::new();
+        meta.number = state.versions_.new_file_number();
+        state.pending_outputs_.insert(meta.number);
+        drop(state);
+        let start_micros = self.options_.env.now_micros();
+        let mut s = self.build_level0_table(&imm, &mut meta);
+        let stats = CompactionStats {
+            micros: self.options_.env.now_micros() - start_micros,
+            bytes_read: 0,
+            bytes_written: meta.file_size,
+        };
+        state = self.mutex_.lock().unwrap();
+        state.pending_outputs_.remove(&meta.number);
+
+        if s.is_ok() && self.shutting_down.load(Ordering::Acquire) {
+            s = Status::io_error("Deleting DB during memtable compaction", None);
+        }
+
+        // 用新的 level-0 文件替换 imm_
+        if s.is_ok() {
+            let mut edit = VersionEdit::new();
+            let level = Self::add_level0_table(&mut edit, meta, Some(&base));
+            state.stats_[level].add(&stats);
+            // 更早的日志已经不再需要
+            edit.set_prev_log_number_(0);
+            edit.set_log_number_(state.logfile_number_);
+            s = state.versions_.log_and_apply(&mut edit);
+        }
+
+        if s.is_ok() {
+            state.imm_ = None;
+            self.has_imm_.store(false, Ordering::Release);
+            state = self.remove_obsolete_files(state);
+        } else {
+            self.record_background_error(&mut state, &s);
+        }
+        state
+    }
+
+    // 删除不再需要的文件：已经写入 table 的日志、旧的 MANIFEST、不被任何版本引用的 table。
+    // 删除文件时释放锁
+    fn remove_obsolete_files<'a>(
+        &'a self,
+        state: MutexGuard<'a, DBState<E>>,
+    ) -> MutexGuard<'a, DBState<E>> {
+        if !state.bg_error_.is_ok() {
+            // 出错后无法确定新版本是否已经生效，不删除任何文件
+            return state;
+        }
+
+        // 正在生成的文件和所有仍被引用的版本中的文件
+        let mut live = state.pending_outputs_.clone();
+        state.versions_.add_live_files(&mut live);
+
+        let env = self.options_.env.as_ref();
+        let filenames = match env.get_children(&self.dbname_) {
+            Ok(filenames) => filenames,
+            // 忽略错误，下次再删
+            Err(_) => return state,
+        };
+        let log_number = state.versions_.log_number();
+        let prev_log_number = state.versions_.prev_log_number();
+        let manifest_file_number = state.versions_.manifest_file_number();
+        let mut files_to_delete = Vec::new();
+        for filename in filenames {
+            if let Some((number, file_type)) = parse_file_name(&filename) {
+                let keep = match file_type {
+                    FileType::LogFile => number >= log_number || number == prev_log_number,
+                    // 保留当前的 MANIFEST，以及可能是刚刚创建的更新的 MANIFEST
+                    FileType::DescriptorFile => number >= manifest_file_number,
+                    FileType::TableFile => live.contains(&number),
+                    // 正在写入的临时文件记录在 pending_outputs_ 中
+                    FileType::TempFile => live.contains(&number),
+                    FileType::CurrentFile | FileType::DBLockFile | FileType::InfoLogFile => true,
+                };
+                if !keep {
+                    if file_type == FileType::TableFile {
+                        self.table_cache_.evict(number);
+                    }
+                    info!("Delete type={:?} #{}", file_type, number);
+                    files_to_delete.push(filename);
+                }
+            }
+        }
+
+        // 这些文件不会再被访问，删除时不需要持有锁
+        drop(state);
+        for filename in files_to_delete {
+            env.remove_file(format!("{}/{}", self.dbname_, filename));
+        }
+        self.mutex_.lock().unwrap()
+    }
+
+    // 切换 memtable 并等待它被写成 table 文件
+    pub(crate) fn test_compact_mem_table(&self) -> Status {
+        // 空的 batch 会进入写队列，等前面的写入完成后切换 memtable
+        let s = self.write_impl(&WriteOptions::default(), None);
+        if !s.is_ok() {
+            return s;
+        }
+        let mut state = self.mutex_.lock().unwrap();
+        while state.imm_.is_some() && state.bg_error_.is_ok() {
+            state = self.background_work_finished_signal_.wait(state).unwrap();
+        }
+        if state.imm_.is_some() {
+            state.bg_error_.clone()
+        } else {
+            Status::ok()
+        }
+    }
+
+    // 把 level 层中与 [begin, end] 重叠的文件 compaction 到 level+1 层，完成后返回
+    pub(crate) fn test_compact_range(
+        &self,
+        level: usize,
+        begin: Option<&Slice>,
+        end: Option<&Slice>,
+    ) {
+        debug_assert!(level + 1 < K_NUM_LEVELS);
+        let manual = Arc::new(ManualCompaction {
+            level,
+            done: AtomicBool::new(false),
+            begin: Mutex::new(begin.map(|k| {
+                InternalKey::new(k.clone(), K_MAX_SEQUENCE_NUMBER, K_VALUE_TYPE_FOR_SEEK)
+            })),
+            end: end.map(|k| InternalKey::new(k.clone(), 0, ValueType::KTypeDeletion)),
+        });
+
+        let mut state = self.mutex_.lock().unwrap();
+        while !manual.done.load(Ordering::Acquire)
+            && !self.shutting_down.load(Ordering::Acquire)
+            && state.bg_error_.is_ok()
+        {
+            if state.manual_compaction_.is_none() {
+                // 空闲，提交这次手动 compaction
+                state.manual_compaction_ = Some(manual.clone());
+                self.maybe_schedule_compaction(&mut state);
+            } else {
+                // 正在执行这次或其它的 compaction
+                state = self.background_work_finished_signal_.wait(state).unwrap();
+            }
+        }
+        if state
+            .manual_compaction_
+            .as_ref()
+            .is_some_and(|m| Arc::ptr_eq(m, &manual))
+        {
+            // 出错或关闭时取消还没执行的手动 compaction
+            state.manual_compaction_ = None;
+        }
+    }
+}
+
+impl<E> Drop for DBImpl<E>
 where
     E: Env,
+{
+    fn drop(&mut self) {
+        // 等待后台任务结束
+        self.shutting_down.store(true, Ordering::Release);
+        let mut state = self.mutex_.lock().unwrap();
+        while state.background_compaction_scheduled_ {
+            state = self.background_work_finished_signal_.wait(state).unwrap();
+        }
+        drop(state);
+
+        if let Some(lock) = self.db_lock.take() {
+            self.options_.env.unlock_file(&lock);
+        }
+    }
+}
+
+impl<E> DB<E> for DBImpl<E>
+where
+    E: Env + 'static,
 {
     fn open(options: Arc<Options<E>>, name: String) -> Result<Arc<Self>, Status>
     where
         Self: Sized,
     {
-        let db = Arc::new(DBImpl::new(options, name));
-        let save_manifest = false;
+        let s = check_comparator(&options);
+        if !s.is_ok() {
+            return Err(s);
+        }
+        let mut db = DBImpl::new(options, name);
+        let s = db.lock_db();
+        if !s.is_ok() {
+            return Err(s);
+        }
 
+        let mut state = db.mutex_.lock().unwrap();
+        let mut edit = VersionEdit::new();
+        // recover 决定是否需要写一个新的 MANIFEST
+        let mut save_manifest = false;
+        let mut s = db.recover(&mut state, &mut edit, &mut save_manifest);
+        if s.is_ok() && state.mem_.is_none() {
+            // 创建新的日志和对应的 memtable
+            let new_log_number = state.versions_.new_file_number();
+            match db
+                .options_
+                .env
+                .new_writable_file::<StdWritableFile, _>(log_file_name(&db.dbname_, new_log_number))
+            {
+                Ok(lfile) => {
+                    let lfile: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(lfile));
+                    edit.set_log_number_(new_log_number);
+                    state.log_ = Some(LogWriter::new(lfile.clone()));
+                    state.logfile_ = Some(lfile);
+                    state.logfile_number_ = new_log_number;
+                    state.mem_ = Some(Arc::new(MemTable::new()));
+                }
+                Err(e) => s = e,
+            }
+        }
+        if s.is_ok() && save_manifest {
+            // 旧的日志已经不再需要
+            edit.set_prev_log_number_(0);
+            edit.set_log_number_(state.logfile_number_);
+            s = state.versions_.log_and_apply(&mut edit);
+        }
+        if s.is_ok() {
+            state = db.remove_obsolete_files(state);
+        }
+        drop(state);
+        if !s.is_ok() {
+            return Err(s);
+        }
+        // 后台任务持有 db 的地址，只能在 db 放进 Arc 之后调度
+        let db = Arc::new_cyclic(|weak_self| {
+            db.weak_self_ = weak_self.clone();
+            db
+        });
+        let mut state = db.mutex_.lock().unwrap();
+        db.maybe_schedule_compaction(&mut state);
+        drop(state);
+        Ok(db)
+    }
 
+    fn put(&self, options: &WriteOptions, key: &Slice, value: &Slice) -> Status {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(options, batch)
+    }
 
+    fn delete(&self, options: &WriteOptions, key: &Slice) -> Status {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(options, batch)
+    }
 
+    fn write(&self, options: &WriteOptions, updates: WriteBatch) -> Status {
+        self.write_impl(options, Some(updates))
+    }
 
+    fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Slice, Status> {
+        // 查找本身不持有锁，结束后把 seek 统计记到当前版本上
+        let state = self.mutex_.lock().unwrap();
+        let sequence = match &options.snapshot {
+            Some(snapshot) => snapshot.sequence_number(),
+            None => state.versions_.last_sequence(),
+        };
+        let mem = state.mem_.clone();
+        let imm = state.imm_.clone();
+        let current = state.versions_.current();
+        drop(state);
 
+        let lkey = LookupKey::new(key, sequence);
+        if let Some(result) = mem.as_ref().and_then(|mem| mem.get(&lkey)) {
+            return result;
+        }
+        if let Some(result) = imm.as_ref().and_then(|imm| imm.get(&lkey)) {
+            return result;
+        }
 
+        let mut stats = GetStats::new();
+        let ikey = InternalKey::new(key.clone(), sequence, K_VALUE_TYPE_FOR_SEEK);
+        let result = current.get(options, &ikey, &mut stats);
+        if current.update_stats(&stats) {
+            // 有文件的 allowed_seeks 耗尽，需要 compaction
+            self.schedule_read_compaction();
+        }
+        result
     }
 
-    fn put(&self, options: &WriteOptions, key: &Slice, value: &Slice) -> Status {
-        todo!()
+    fn new_iterator(&self, options: &ReadOptions) -> Box<dyn Iter> {
+        let (iter, latest_snapshot, seed, version) = self.new_internal_iterator(options);
+        let sequence = match &options.snapshot {
+            Some(snapshot) => snapshot.sequence_number(),
+            None => latest_snapshot,
+        };
+        Box::new(DBIter::new(
+            self.weak_self_.clone(),
+            version,
+            iter,
+            sequence,
+            seed,
+        ))
     }
 
-    fn delete(&self, options: &WriteOptions, key: &Slice) -> Status {
-        todo!()
+    fn get_snapshot(&self) -> Arc<Snapshot> {
+        let mut state = self.mutex_.lock().unwrap();
+        let sequence = state.versions_.last_sequence();
+        state.snapshots_.new_snapshot(sequence)
     }
 
-    fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Slice, Status> {
-        todo!()
+    fn release_snapshot(&self, snapshot: Arc<Snapshot>) {
+        let mut state = self.mutex_.lock().unwrap();
+        state.snapshots_.delete(&snapshot);
     }
 
-    fn new_iterator(&self, options: &ReadOptions) -> Arc<dyn Iter> {
-        todo!()
+    fn get_property(&self, property: &Slice, value: &mut String) -> bool {
+        value.clear();
+        let property = property.to_string();
+        let input = match property.strip_prefix("leveldb.") {
+            Some(input) => input,
+            None => return false,
+        };
+
+        let state = self.mutex_.lock().unwrap();
+        if let Some(level) = input.strip_prefix("num-files-at-level") {
+            return match level.parse::<usize>() {
+                Ok(level) if level < K_NUM_LEVELS => {
+                    *value = state.versions_.num_level_files(level).to_string();
+                    true
+                }
+                _ => false,
+            };
+        }
+        match input {
+            "stats" => {
+                value.push_str(
+                    "                               Compactions\n\
+                     Level  Files Size(MB) Time(sec) Read(MB) Write(MB)\n\
+                     --------------------------------------------------\n",
+                );
+                for level in 0..K_NUM_LEVELS {
+                    let files = state.versions_.num_level_files(level);
+                    let stats = &state.stats_[level];
+                    if stats.micros > 0 || files > 0 {
+                        value.push_str(&format!(
+                            "{:3} {:8} {:8.0} {:9.0} {:8.0} {:9.0}\n",
+                            level,
+                            files,
+                            state.versions_.num_level_bytes(level) as f64 / 1048576.0,
+                            stats.micros as f64 / 1e6,
+                            stats.bytes_read as f64 / 1048576.0,
+                            stats.bytes_written as f64 / 1048576.0
+                        ));
+                    }
+                }
+                true
+            }
+            "write-stall-stats" => {
+                let stall = &state.write_stall_stats_;
+                value.push_str(&format!(
+                    "Write stalls: level0 slowdown {} ({:.3} sec), memtable full {} ({:.3} sec), \
+                     level0 stop {} ({:.3} sec)\n",
+                    stall.level0_slowdown_count,
+                    stall.level0_slowdown_micros as f64 / 1e6,
+                    stall.memtable_wait_count,
+                    stall.memtable_wait_micros as f64 / 1e6,
+                    stall.level0_stop_count,
+                    stall.level0_stop_micros as f64 / 1e6
+                ));
+                true
+            }
+            "sstables" => {
+                *value = state.versions_.current().debug_string();
+                true
+            }
+            "approximate-memory-usage" => {
+                let mut total_usage = self
+                    .options_
+                    .block_cache
+                    .as_ref()
+                    .map_or(0, |cache| cache.usage());
+                if let Some(mem) = state.mem_.as_ref() {
+                    total_usage += mem.approximate_memory_usage();
+                }
+                if let Some(imm) = state.imm_.as_ref() {
+                    total_usage += imm.approximate_memory_usage();
+                }
+                *value = total_usage.to_string();
+                true
+            }
+            "block-cache-usage" | "block-cache-pinned-usage" => {
+                let cache = match self.options_.block_cache {
+                    Some(ref cache) => cache,
+                    None => return false,
+                };
+                let usage = if input == "block-cache-usage" {
+                    cache.usage()
+                } else {
+                    cache.pinned_usage()
+                };
+                *value = usage.to_string();
+                true
+            }
+            "estimate-num-keys" => {
+                // 第一次估计 table 中的个数需要打开 table 并读文件，不持有锁
+                let mem = state.mem_.clone();
+                let imm = state.imm_.clone();
+                let current = state.versions_.current();
+                drop(state);
+                let mut num_keys = current.approximate_num_entries();
+                for mem in mem.iter().chain(imm.iter()) {
+                    num_keys += mem.num_entries() as u64;
+                }
+                *value = num_keys.to_string();
+                true
+            }
+            "filter-stats" => {
+                // 需要打开 table，不持有锁
+                let current = state.versions_.current();
+                drop(state);
+                let (mut total_useful, mut total_false_positive) = (0, 0);
+                for (level, files) in current.files.iter().enumerate() {
+                    for f in files.iter() {
+                        let (useful, false_positive) =
+                            self.table_cache_.filter_stats(f.number, f.file_size);
+                        value.push_str(&format!(
+                            "level {} #{}: useful {} false-positive {}\n",
+                            level, f.number, useful, false_positive
+                        ));
+                        total_useful += useful;
+                        total_false_positive += false_positive;
+                    }
+                }
+                value.push_str(&format!(
+                    "total: useful {} false-positive {}\n",
+                    total_useful, total_false_positive
+                ));
+                true
+            }
+            _ => false,
+        }
     }
 
+    fn approximate_sizes(&self, ranges: &[Range], include_memtable: bool) -> Vec<u64> {
+        let state = self.mutex_.lock().unwrap();
+        let current = state.versions_.current();
+        let mems: Vec<Arc<MemTable>> = if include_memtable {
+            state
+                .mem_
+                .iter()
+                .chain(state.imm_.iter())
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        drop(state);
 
+        ranges
+            .iter()
+            .map(|range| {
+                let k1 = InternalKey::new(
+                    range.start.clone(),
+                    K_MAX_SEQUENCE_NUMBER,
+                    K_VALUE_TYPE_FOR_SEEK,
+                );
+                let k2 = InternalKey::new(
+                    range.limit.clone(),
+                    K_MAX_SEQUENCE_NUMBER,
+                    K_VALUE_TYPE_FOR_SEEK,
+                );
+                let start = current.approximate_offset_of(&k1);
+                let limit = current.approximate_offset_of(&k2);
+                let mut size = limit.saturating_sub(start);
+                for mem in mems.iter() {
+                    size += mem.approximate_range_size(&range.start, &range.limit);
+                }
+                size
+            })
+            .collect()
+    }
 
-    fn get_property(&self, property: &Slice, value: &mut String) -> bool {
-        todo!()
+    fn compact_range(&self, begin: Option<&Slice>, end: Option<&Slice>) {
+        // level-0 总是参与，更深的层只需要处理到最后一个有重叠文件的层
+        let mut max_level_with_files = 1;
+        {
+            let state = self.mutex_.lock().unwrap();
+            let base = state.versions_.current();
+            for level in 1..K_NUM_LEVELS {
+                if base.overlap_in_level(level, begin, end) {
+                    max_level_with_files = level;
+                }
+            }
+        }
+        // 先把 memtable 写成文件。出错时后台错误会让下面的 compaction 直接返回
+        let _ = self.test_compact_mem_table();
+        for level in 0..max_level_with_files {
+            self.test_compact_range(level, begin, end);
+        }
+    }
+}
+
+/// 删除数据库的所有文件，目录中不属于数据库的文件会保留。数据库正在被使用时返回错误
+pub fn destroy_db<E: Env>(dbname: &str, options: Arc<Options<E>>) -> Status {
+    let env = options.env.as_ref();
+    let dbname = dbname.to_string();
+    let filenames = match env.get_children(&dbname) {
+        Ok(filenames) => filenames,
+        // 目录可能不存在，忽略错误
+        Err(_) => return Status::ok(),
+    };
+
+    let lockname = lock_file_name(&dbname);
+    let lock = match env.lock_file(&lockname) {
+        Ok(lock) => lock,
+        Err(e) => return e,
+    };
+    let mut result = Status::ok();
+    for filename in filenames.iter() {
+        match parse_file_name(filename) {
+            // 锁文件最后删除
+            Some((_, FileType::DBLockFile)) | None => {}
+            Some(_) => {
+                let del = env.remove_file(format!("{}/{}", dbname, filename));
+                if result.is_ok() && !del.is_ok() {
+                    result = del;
+                }
+            }
+        }
+    }
+    // 数据库已经删除，忽略这些错误
+    env.unlock_file(&lock);
+    env.remove_file(&lockname);
+    // 目录中还有其它文件时删除失败
+    env.remove_dir(&dbname);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_test_util::{test_db_name, test_options};
+    use crate::db::internal_key_comparator::K_L0_COMPACTION_TRIGGER;
+    use crate::db::log_format::K_HEADER_SIZE;
+    use crate::db::repair::repair_db;
+    use crate::obj::options::{CacheType, CompressionType};
+    use crate::util::env::StdEnv;
+    use crate::util::new_bloom_filter_policy;
+    use crate::util::random::Random;
+    use crate::util::test_util::random_string;
+
+    // 向 db 当前使用的日志中追加一个 batch，模拟写入后进程退出
+    fn append_to_log(db: &DBImpl<StdEnv>, batch: &WriteBatch) {
+        let mut state = db.mutex_.lock().unwrap();
+        let log = state.log_.as_mut().unwrap();
+        assert!(log.add_record(&batch.contents()).is_ok());
+        assert!(state
+            .logfile_
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .sync()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_open_create_and_reopen() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_open_create");
+        let options = Arc::new(test_options(env.clone()));
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        assert!(env.file_exists(current_file_name(&dbname)));
+        assert!(env.file_exists(lock_file_name(&dbname)));
+        drop(db);
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let state = db.mutex_.lock().unwrap();
+        assert!(state.mem_.is_some());
+        assert_eq!(0, state.versions_.last_sequence());
+        drop(state);
+        drop(db);
+    }
+
+    #[test]
+    fn test_open_missing_and_exists() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_open_missing");
+        let mut options = test_options(env.clone());
+        options.create_if_missing = false;
+        let s = DBImpl::open(Arc::new(options.clone()), dbname.clone())
+            .err()
+            .unwrap();
+        assert!(s.is_invalid_argument());
+
+        options.create_if_missing = true;
+        drop(DBImpl::open(Arc::new(options.clone()), dbname.clone()).unwrap());
+
+        options.error_if_exists = true;
+        let s = DBImpl::open(Arc::new(options), dbname.clone())
+            .err()
+            .unwrap();
+        assert!(s.is_invalid_argument());
+    }
+
+    // 按字节逆序比较 user key
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, a: &Slice, b: &Slice) -> std::cmp::Ordering {
+            byte_wise_comparator().compare(b, a)
+        }
+
+        fn name(&self) -> &'static str {
+            "test.ReverseComparator"
+        }
+
+        fn find_shortest_separator(&self, _start: &mut BytesMut, _limit: &Slice) {}
+
+        fn find_short_successor(&self, _key: &mut BytesMut) {}
+    }
+
+    #[test]
+    fn test_open_custom_comparator() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_open_custom_comparator");
+        let mut options = test_options(env.clone());
+        options.comparator = Arc::new(ReverseComparator);
+        let options = Arc::new(options);
+        // 不支持的比较器直接拒绝，不能按字节序写出数据
+        let s = DBImpl::open(options.clone(), dbname.clone()).err().unwrap();
+        assert!(s.is_invalid_argument(), "{}", s);
+        assert!(!env.file_exists(&dbname));
+        assert!(repair_db(&dbname, options).is_invalid_argument());
+    }
+
+    #[test]
+    fn test_open_locked() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_open_locked");
+        let options = Arc::new(test_options(env.clone()));
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        assert!(DBImpl::open(options.clone(), dbname.clone()).is_err());
+        drop(db);
+        assert!(DBImpl::open(options, dbname.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_recover_log_to_level0() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_recover_log");
+        let options = Arc::new(test_options(env.clone()));
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("foo"), &Slice::from("v1"));
+        batch.put(&Slice::from("bar"), &Slice::from("v2"));
+        batch.delete(&Slice::from("baz"));
+        batch.set_sequence(1);
+        append_to_log(&db, &batch);
+        drop(db);
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(3, state.versions_.last_sequence());
+            assert_eq!(1, state.versions_.num_level_files(0));
+            let files = &state.versions_.current().files[0];
+            assert_eq!("bar", files[0].smallest.user_key().to_string());
+            assert_eq!("foo", files[0].largest.user_key().to_string());
+        }
+        drop(db);
+
+        // 日志已经写成 table，再次打开不会重复生成
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!(3, state.versions_.last_sequence());
+        assert_eq!(1, state.versions_.num_level_files(0));
+        drop(state);
+        drop(db);
+    }
+
+    #[test]
+    fn test_recover_flushes_large_log() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_recover_large_log");
+        let mut options = test_options(env.clone());
+        options.write_buffer_size = 64 << 10;
+        let options = Arc::new(options);
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let value = "x".repeat(1000);
+        for i in 0..200u64 {
+            let mut batch = WriteBatch::new();
+            batch.put(
+                &Slice::new_from_string(format!("key{:06}", i)),
+                &Slice::new_from_string(value.clone()),
+            );
+            batch.set_sequence(i + 1);
+            append_to_log(&db, &batch);
+        }
+        drop(db);
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!(200, state.versions_.last_sequence());
+        assert!(state.versions_.num_level_files(0) > 1);
+        drop(state);
+        drop(db);
+    }
+
+    // 在一个日志中依次写入 a、b、c，在下一个日志中写入 d，返回第一个日志的文件名。
+    // bad_batch 为 true 时 b 所在 batch 的记录个数是错的，但日志记录的 checksum 正确
+    fn write_logs_for_recovery(
+        options: &Arc<Options<StdEnv>>,
+        dbname: &str,
+        bad_batch: bool,
+    ) -> String {
+        let db = DBImpl::open(options.clone(), dbname.to_string()).unwrap();
+        let logfile_number = db.mutex_.lock().unwrap().logfile_number_;
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::from(*key), &Slice::from("v"));
+            batch.set_sequence(i as u64 + 1);
+            if bad_batch && *key == "b" {
+                batch.set_count(2);
+            }
+            append_to_log(&db, &batch);
+        }
+        drop(db);
+
+        let file = options
+            .env
+            .new_writable_file::<StdWritableFile, _>(log_file_name(
+                &dbname.to_string(),
+                logfile_number + 1,
+            ))
+            .unwrap();
+        let file: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(file));
+        let mut log = LogWriter::new(file.clone());
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("d"), &Slice::from("v"));
+        batch.set_sequence(4);
+        assert!(log.add_record(&batch.contents()).is_ok());
+        assert!(file.lock().unwrap().sync().is_ok());
+        log_file_name(&dbname.to_string(), logfile_number)
+    }
+
+    // 按 mode 打开数据库，返回 a、b、c、d 中能读到的 key
+    fn recover_with_mode(
+        options: &Arc<Options<StdEnv>>,
+        dbname: &str,
+        mode: WALRecoveryMode,
+    ) -> Result<String, Status> {
+        let mut options = options.as_ref().clone();
+        options.wal_recovery_mode = mode;
+        let db = DBImpl::open(Arc::new(options), dbname.to_string())?;
+        let read_options = ReadOptions::new();
+        Ok(["a", "b", "c", "d"]
+            .into_iter()
+            .filter(|k| db.get(&read_options, &Slice::from(*k)).is_ok())
+            .collect())
+    }
+
+    #[test]
+    fn test_wal_recovery_mode() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_wal_recovery_mode");
+        let options = Arc::new(test_options(env.clone()));
+        let modes = [
+            WALRecoveryMode::TolerateCorruptedTailRecords,
+            WALRecoveryMode::AbsoluteConsistency,
+            WALRecoveryMode::PointInTimeRecovery,
+            WALRecoveryMode::SkipAnyCorruptedRecords,
+        ];
+
+        // 第一个日志的最后一条记录没有写完
+        for mode in modes {
+            let _ = std::fs::remove_dir_all(&dbname);
+            let log = write_logs_for_recovery(&options, &dbname, false);
+            let len = std::fs::metadata(&log).unwrap().len();
+            let file = std::fs::OpenOptions::new().write(true).open(&log).unwrap();
+            file.set_len(len - 3).unwrap();
+            drop(file);
+            match (mode, recover_with_mode(&options, &dbname, mode)) {
+                (WALRecoveryMode::AbsoluteConsistency, r) => {
+                    assert!(r.unwrap_err().is_corruption())
+                }
+                // 丢弃了 c，后面的日志接不上
+                (WALRecoveryMode::PointInTimeRecovery, r) => assert_eq!("ab", r.unwrap()),
+                (_, r) => assert_eq!("abd", r.unwrap(), "{:?}", mode),
+            }
+        }
+
+        // 第一个日志中间的记录损坏，checksum 不匹配时丢弃 block 剩余的部分
+        for mode in modes {
+            let _ = std::fs::remove_dir_all(&dbname);
+            let log = write_logs_for_recovery(&options, &dbname, false);
+            let mut contents = std::fs::read(&log).unwrap();
+            let record_size = contents.len() / 3;
+            contents[record_size + K_HEADER_SIZE + 12] ^= 0xff;
+            std::fs::write(&log, contents).unwrap();
+            match (mode, recover_with_mode(&options, &dbname, mode)) {
+                (WALRecoveryMode::PointInTimeRecovery, r) => assert_eq!("a", r.unwrap()),
+                (WALRecoveryMode::SkipAnyCorruptedRecords, r) => assert_eq!("ad", r.unwrap()),
+                (_, r) => assert!(r.unwrap_err().is_corruption(), "{:?}", mode),
+            }
+            // 恢复成功后被丢弃的日志已经删除，再次打开结果不变
+            if let Ok(keys) = recover_with_mode(&options, &dbname, mode) {
+                assert_eq!(
+                    keys,
+                    recover_with_mode(&options, &dbname, WALRecoveryMode::AbsoluteConsistency)
+                        .unwrap()
+                );
+            }
+        }
+
+        // 记录的 checksum 正确但 batch 损坏，b 不能只写入一部分
+        for mode in modes {
+            let _ = std::fs::remove_dir_all(&dbname);
+            write_logs_for_recovery(&options, &dbname, true);
+            match (mode, recover_with_mode(&options, &dbname, mode)) {
+                (WALRecoveryMode::PointInTimeRecovery, r) => assert_eq!("a", r.unwrap()),
+                (WALRecoveryMode::SkipAnyCorruptedRecords, r) => assert_eq!("acd", r.unwrap()),
+                (_, r) => assert!(r.unwrap_err().is_corruption(), "{:?}", mode),
+            }
+        }
+    }
+
+    #[test]
+    fn test_put_delete_write() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_put_delete");
+        let options = Arc::new(test_options(env.clone()));
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        assert!(db
+            .put(&write_options, &Slice::from("foo"), &Slice::from("v1"))
+            .is_ok());
+        assert!(db.delete(&write_options, &Slice::from("bar")).is_ok());
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("a"), &Slice::from("va"));
+        batch.put(&Slice::from("b"), &Slice::from("vb"));
+        assert!(db.write(&WriteOptions { sync: true }, batch).is_ok());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(4, state.versions_.last_sequence());
+            let mem = state.mem_.as_ref().unwrap();
+            let get = |k: &'static str| mem.get(&LookupKey::new(&Slice::from(k), 4)).unwrap();
+            assert_eq!("v1", get("foo").unwrap().to_string());
+            assert_eq!("vb", get("b").unwrap().to_string());
+            assert!(get("bar").unwrap_err().is_not_found());
+        }
+        drop(db);
+
+        // 写入的数据在重新打开时从日志恢复
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!(4, state.versions_.last_sequence());
+        assert_eq!(1, state.versions_.num_level_files(0));
+        drop(state);
+        drop(db);
+    }
+
+    #[test]
+    fn test_get_across_mem_and_tables() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_get");
+        let options = Arc::new(test_options(env.clone()));
+        let write_options = WriteOptions::default();
+        let read_options = ReadOptions::new();
+        let get = |db: &DBImpl<StdEnv>, k: &'static str| {
+            db.get(&read_options, &Slice::from(k))
+                .map(|v| v.to_string())
+        };
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        assert!(get(&db, "a").unwrap_err().is_not_found());
+        assert!(db
+            .put(&write_options, &Slice::from("a"), &Slice::from("a1"))
+            .is_ok());
+        assert!(db
+            .put(&write_options, &Slice::from("z"), &Slice::from("z1"))
+            .is_ok());
+        assert_eq!("a1", get(&db, "a").unwrap());
+        drop(db);
+
+        // 每次重新打开都会把日志恢复成一个新的 level-0 文件
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        assert_eq!("z1", get(&db, "z").unwrap());
+        assert!(db
+            .put(&write_options, &Slice::from("a"), &Slice::from("a2"))
+            .is_ok());
+        assert!(db.delete(&write_options, &Slice::from("z")).is_ok());
+        assert_eq!("a2", get(&db, "a").unwrap());
+        assert!(get(&db, "z").unwrap_err().is_not_found());
+        drop(db);
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let current = db.mutex_.lock().unwrap().versions_.current();
+        assert_eq!(2, current.num_files(0));
+        assert_eq!("a2", get(&db, "a").unwrap());
+        assert!(get(&db, "z").unwrap_err().is_not_found());
+
+        // 两个文件都要读才能确认 m 不存在，第一个读过的文件被扣一次 seek
+        let newest = current.files[0]
+            .iter()
+            .max_by_key(|f| f.number)
+            .unwrap()
+            .clone();
+        let before = newest.allowed_seeks.load(Ordering::Relaxed);
+        assert!(get(&db, "m").unwrap_err().is_not_found());
+        assert_eq!(before - 1, newest.allowed_seeks.load(Ordering::Relaxed));
+        assert!(current.file_to_compact().is_none());
+        drop(current);
+        drop(db);
+    }
+
+    #[test]
+    fn test_seek_triggered_compaction() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_seek_compaction");
+        let db = DBImpl::open(Arc::new(test_options(env.clone())), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let read_options = ReadOptions::new();
+        // 两个 key 范围相同的文件
+        for value in ["1", "2"] {
+            for key in ["a", "z"] {
+                assert!(db
+                    .put(&write_options, &Slice::from(key), &Slice::from(value))
+                    .is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+        }
+        let num_files = |db: &DBImpl<StdEnv>| {
+            let state = db.mutex_.lock().unwrap();
+            (0..K_NUM_LEVELS)
+                .map(|level| state.versions_.num_level_files(level))
+                .sum::<usize>()
+        };
+        assert_eq!(2, num_files(&db));
+
+        // 每次查找不存在的 m 都要读两个文件，第一个文件的 allowed_seeks 最终耗尽
+        for _ in 0..200 {
+            assert!(db
+                .get(&read_options, &Slice::from("m"))
+                .unwrap_err()
+                .is_not_found());
+        }
+        wait_for_background_work(&db);
+        assert_eq!(1, num_files(&db));
+        assert_eq!(
+            "2",
+            db.get(&read_options, &Slice::from("a"))
+                .unwrap()
+                .to_string()
+        );
+        drop(db);
+    }
+
+    #[test]
+    fn test_read_sample_triggered_compaction() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_read_sample_compaction");
+        let db = DBImpl::open(Arc::new(test_options(env.clone())), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let value = Slice::new_from_vec(vec![b'v'; 6000]);
+        // 两个 key 范围相同的文件，每个约 600KB
+        for _ in 0..2 {
+            for i in 0..100 {
+                let key = Slice::new_from_string(format!("key{:03}", i));
+                assert!(db.put(&write_options, &key, &value).is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+        }
+        let num_files = |db: &DBImpl<StdEnv>| {
+            let state = db.mutex_.lock().unwrap();
+            (0..K_NUM_LEVELS)
+                .map(|level| state.versions_.num_level_files(level))
+                .sum::<usize>()
+        };
+        assert_eq!(2, num_files(&db));
+        // 让第一次采样就耗尽 allowed_seeks
+        let current = db.mutex_.lock().unwrap().versions_.current();
+        for files in current.files.iter() {
+            for f in files {
+                f.allowed_seeks.store(1, Ordering::Relaxed);
+            }
+        }
+        drop(current);
+
+        // 平均每读 1MB 采样一次，最多读 2MB 就会采样
+        for _ in 0..4 {
+            let mut iter = db.new_iterator(&ReadOptions::new());
+            iter.seek_to_first();
+            while iter.valid() {
+                iter.next();
+            }
+        }
+        wait_for_background_work(&db);
+        assert_eq!(1, num_files(&db));
+        drop(db);
+    }
+
+    fn iter_contents(iter: &mut dyn Iter, forward: bool) -> Vec<String> {
+        let mut result = vec![];
+        while iter.valid() {
+            result.push(format!(
+                "{}={}",
+                iter.key().to_string(),
+                iter.value().to_string()
+            ));
+            if forward {
+                iter.next();
+            } else {
+                iter.prev();
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_iterator_hides_deletions_and_old_versions() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_iterator");
+        let options = Arc::new(test_options(env.clone()));
+        let write_options = WriteOptions::default();
+        let put = |db: &DBImpl<StdEnv>, k: &'static str, v: &'static str| {
+            assert!(db
+                .put(&write_options, &Slice::from(k), &Slice::from(v))
+                .is_ok());
+        };
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        drop(iter);
+        put(&db, "a", "a1");
+        put(&db, "b", "b1");
+        put(&db, "c", "c1");
+        put(&db, "d", "d1");
+        drop(db);
+
+        // 一部分数据在 level-0 文件中，一部分在 memtable 中
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        put(&db, "b", "b2");
+        assert!(db.delete(&write_options, &Slice::from("c")).is_ok());
+        put(&db, "e", "e1");
+        assert!(db.delete(&write_options, &Slice::from("e")).is_ok());
+
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        // 迭代器创建之后的写入不可见
+        put(&db, "f", "f1");
+        iter.seek_to_first();
+        assert_eq!(
+            vec!["a=a1", "b=b2", "d=d1"],
+            iter_contents(iter.as_mut(), true)
+        );
+        iter.seek_to_last();
+        assert_eq!(
+            vec!["d=d1", "b=b2", "a=a1"],
+            iter_contents(iter.as_mut(), false)
+        );
+
+        iter.seek(&Slice::from("c"));
+        assert_eq!(
+            "d=d1",
+            format!("{}={}", iter.key().to_string(), iter.value().to_string())
+        );
+        iter.prev();
+        assert_eq!("b", iter.key().to_string());
+        iter.prev();
+        assert_eq!("a", iter.key().to_string());
+        iter.next();
+        assert_eq!(
+            "b=b2",
+            format!("{}={}", iter.key().to_string(), iter.value().to_string())
+        );
+        iter.next();
+        assert_eq!("d", iter.key().to_string());
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek(&Slice::from("z"));
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+        drop(iter);
+        drop(db);
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_snapshot");
+        let options = Arc::new(test_options(env.clone()));
+        let write_options = WriteOptions::default();
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let put = |k: &'static str, v: &'static str| {
+            assert!(db
+                .put(&write_options, &Slice::from(k), &Slice::from(v))
+                .is_ok());
+        };
+        let get = |snapshot: &Option<Arc<Snapshot>>, k: &'static str| {
+            let mut read_options = ReadOptions::new();
+            read_options.snapshot = snapshot.clone();
+            db.get(&read_options, &Slice::from(k))
+                .map(|v| v.to_string())
+        };
+
+        put("a", "a1");
+        put("b", "b1");
+        let s1 = Some(db.get_snapshot());
+        put("a", "a2");
+        assert!(db.delete(&write_options, &Slice::from("b")).is_ok());
+        put("c", "c1");
+        let s2 = Some(db.get_snapshot());
+        put("c", "c2");
+
+        assert_eq!("a1", get(&s1, "a").unwrap());
+        assert_eq!("b1", get(&s1, "b").unwrap());
+        assert!(get(&s1, "c").unwrap_err().is_not_found());
+        assert_eq!("a2", get(&s2, "a").unwrap());
+        assert!(get(&s2, "b").unwrap_err().is_not_found());
+        assert_eq!("c1", get(&s2, "c").unwrap());
+        assert_eq!("c2", get(&None, "c").unwrap());
+
+        let mut read_options = ReadOptions::new();
+        read_options.snapshot = s1.clone();
+        let mut iter = db.new_iterator(&read_options);
+        iter.seek_to_first();
+        assert_eq!(vec!["a=a1", "b=b1"], iter_contents(iter.as_mut(), true));
+        drop(iter);
+
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(2, DBImpl::smallest_snapshot(&state));
+        }
+        db.release_snapshot(s1.unwrap());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(5, DBImpl::smallest_snapshot(&state));
+        }
+        db.release_snapshot(s2.unwrap());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert!(state.snapshots_.is_empty());
+            assert_eq!(6, DBImpl::smallest_snapshot(&state));
+        }
+        drop(db);
+    }
+
+    fn count_files(env: &StdEnv, dbname: &str, file_type: FileType) -> usize {
+        env.get_children(dbname)
+            .unwrap()
+            .iter()
+            .filter(|f| parse_file_name(f).is_some_and(|(_, t)| t == file_type))
+            .count()
+    }
+
+    #[test]
+    fn test_minor_compaction() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_minor_compaction");
+        let mut options = test_options(env.clone());
+        options.write_buffer_size = 64 << 10;
+        let options = Arc::new(options);
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let value = Slice::new_from_vec(vec![b'v'; 1000]);
+
+        // 写满几个 memtable，每次切换都会生成新的日志和 level-0 文件
+        for i in 0..300 {
+            let key = Slice::new_from_string(format!("key{:06}", i));
+            assert!(db.put(&write_options, &key, &value).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert!(state.imm_.is_none());
+            let current = state.versions_.current();
+            let files: usize = (0..K_NUM_LEVELS).map(|l| current.num_files(l)).sum();
+            assert!(files >= 4, "{}", current.debug_string());
+            assert_eq!(state.logfile_number_, state.versions_.log_number());
+        }
+        // 已经 flush 的日志被删除
+        assert_eq!(1, count_files(&env, &dbname, FileType::LogFile));
+
+        let read_options = ReadOptions::new();
+        for i in (0..300).step_by(7) {
+            let key = Slice::new_from_string(format!("key{:06}", i));
+            assert_eq!(1000, db.get(&read_options, &key).unwrap().len());
+        }
+        let mut iter = db.new_iterator(&read_options);
+        iter.seek_to_first();
+        assert_eq!(300, iter_contents(iter.as_mut(), true).len());
+        drop(iter);
+        drop(db);
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let key = Slice::new_from_string(format!("key{:06}", 299));
+        assert_eq!(1000, db.get(&read_options, &key).unwrap().len());
+        drop(db);
+    }
+
+    // 等待已经调度的后台任务全部完成
+    fn wait_for_background_work(db: &DBImpl<StdEnv>) {
+        let mut state = db.mutex_.lock().unwrap();
+        while state.background_compaction_scheduled_ {
+            state = db.background_work_finished_signal_.wait(state).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_major_compaction() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_major_compaction");
+        let mut options = test_options(env.clone());
+        options.write_buffer_size = 64 << 10;
+        let db = DBImpl::open(Arc::new(options), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let read_options = ReadOptions::new();
+        const K_NUM_KEYS: usize = 100;
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+        let value = |round: u8| Slice::new_from_vec(vec![b'a' + round; 1000]);
+
+        let mut snapshot = None;
+        for round in 0..6 {
+            for i in 0..K_NUM_KEYS {
+                assert!(db.put(&write_options, &key(i), &value(round)).is_ok());
+            }
+            if round == 0 {
+                snapshot = Some(db.get_snapshot());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+        }
+        // 偶数 key 被删除
+        for i in (0..K_NUM_KEYS).step_by(2) {
+            assert!(db.delete(&write_options, &key(i)).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        wait_for_background_work(&db);
+
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert!(state.bg_error_.is_ok());
+            let current = state.versions_.current();
+            // level-0 的文件已经被合并到更深的层
+            assert!(
+                current.num_files(0) < K_L0_COMPACTION_TRIGGER,
+                "{}",
+                current.debug_string()
+            );
+            assert!(current.compaction_score < 1.0);
+            let deeper: usize = (1..K_NUM_LEVELS).map(|l| current.num_files(l)).sum();
+            assert!(deeper > 0);
+        }
+
+        for i in 0..K_NUM_KEYS {
+            let r = db.get(&read_options, &key(i));
+            if i % 2 == 0 {
+                assert!(r.unwrap_err().is_not_found());
+            } else {
+                assert_eq!(value(5).data(), r.unwrap().data());
+            }
+        }
+        let mut iter = db.new_iterator(&read_options);
+        iter.seek_to_first();
+        assert_eq!(K_NUM_KEYS / 2, iter_contents(iter.as_mut(), true).len());
+        drop(iter);
+
+        // 快照可见的旧版本在 compaction 后仍然保留
+        let mut snapshot_options = ReadOptions::new();
+        snapshot_options.snapshot = snapshot.clone();
+        for i in 0..K_NUM_KEYS {
+            let v = db.get(&snapshot_options, &key(i)).unwrap();
+            assert_eq!(value(0).data(), v.data());
+        }
+        db.release_snapshot(snapshot.unwrap());
+        drop(db);
+    }
+
+    // 每层的文件个数，去掉末尾为 0 的层，如 "1,0,2"
+    fn files_per_level(db: &DBImpl<StdEnv>) -> String {
+        let state = db.mutex_.lock().unwrap();
+        let current = state.versions_.current();
+        let counts: Vec<String> = (0..K_NUM_LEVELS)
+            .map(|l| current.num_files(l).to_string())
+            .collect();
+        let last = counts.iter().rposition(|c| c != "0").map_or(0, |l| l + 1);
+        counts[..last].join(",")
+    }
+
+    // 内部迭代器中 entry 的个数，包括旧版本和删除标记
+    fn count_internal_entries(db: &DBImpl<StdEnv>) -> usize {
+        let (mut iter, _, _, _) = db.new_internal_iterator(&ReadOptions::new());
+        let mut count = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        count
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_compact_range");
+        let db = DBImpl::open(Arc::new(test_options(env.clone())), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let read_options = ReadOptions::new();
+        const K_NUM_KEYS: usize = 100;
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+
+        // 新写入的文件与已有的文件重叠，依次落在 level-2、level-1、level-0
+        for i in 0..K_NUM_KEYS {
+            assert!(db.put(&write_options, &key(i), &Slice::from("v1")).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        for i in 0..K_NUM_KEYS {
+            assert!(db.put(&write_options, &key(i), &Slice::from("v2")).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        for i in (0..K_NUM_KEYS).step_by(2) {
+            assert!(db.delete(&write_options, &key(i)).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        assert_eq!("1,1,1", files_per_level(&db));
+        assert_eq!(K_NUM_KEYS * 2 + K_NUM_KEYS / 2, count_internal_entries(&db));
+
+        // 与任何文件都不重叠的范围不做任何事
+        db.compact_range(Some(&Slice::from("a")), Some(&Slice::from("b")));
+        assert_eq!("1,1,1", files_per_level(&db));
+        db.compact_range(Some(&Slice::from("z")), None);
+        assert_eq!("1,1,1", files_per_level(&db));
+
+        // 整个范围合并到最底层，旧版本和删除标记都被丢弃
+        db.compact_range(None, None);
+        assert_eq!("0,0,1", files_per_level(&db));
+        assert_eq!(K_NUM_KEYS / 2, count_internal_entries(&db));
+        for i in 0..K_NUM_KEYS {
+            let r = db.get(&read_options, &key(i));
+            if i % 2 == 0 {
+                assert!(r.unwrap_err().is_not_found());
+            } else {
+                assert_eq!("v2", r.unwrap().to_string());
+            }
+        }
+
+        // memtable 中的数据也会被写入并合并
+        assert!(db.delete(&write_options, &key(1)).is_ok());
+        db.compact_range(Some(&key(0)), Some(&key(1)));
+        assert_eq!("0,0,1", files_per_level(&db));
+        assert_eq!(K_NUM_KEYS / 2 - 1, count_internal_entries(&db));
+        assert!(db.get(&read_options, &key(1)).unwrap_err().is_not_found());
+        drop(db);
+    }
+
+    #[test]
+    fn test_remove_obsolete_files() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_remove_obsolete_files");
+        let options = Arc::new(test_options(env.clone()));
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+
+        for round in 0..3 {
+            for i in 0..100 {
+                let value = Slice::new_from_string(format!("v{}", round));
+                assert!(db.put(&write_options, &key(i), &value).is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+        }
+        assert_eq!("1,1,1", files_per_level(&db));
+        assert_eq!(3, count_files(&env, &dbname, FileType::TableFile));
+
+        // 迭代器引用的旧版本中的文件不能删除
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        db.compact_range(None, None);
+        assert_eq!("0,0,1", files_per_level(&db));
+        assert_eq!(4, count_files(&env, &dbname, FileType::TableFile));
+        iter.seek_to_first();
+        assert!(iter.valid());
+        assert_eq!("v2", iter.value().to_string());
+        drop(iter);
+
+        // 旧版本释放后，下一次 compaction 删除其中的文件
+        assert!(db.put(&write_options, &key(0), &Slice::from("v3")).is_ok());
+        db.compact_range(None, None);
+        assert_eq!("0,0,1", files_per_level(&db));
+        assert_eq!(1, count_files(&env, &dbname, FileType::TableFile));
+        assert_eq!(1, count_files(&env, &dbname, FileType::LogFile));
+        drop(db);
+
+        // 重新打开后只留下新的 MANIFEST 和日志
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        assert_eq!(1, count_files(&env, &dbname, FileType::DescriptorFile));
+        assert_eq!(1, count_files(&env, &dbname, FileType::LogFile));
+        assert_eq!(1, count_files(&env, &dbname, FileType::TableFile));
+        assert_eq!(
+            "v3",
+            db.get(&ReadOptions::new(), &key(0)).unwrap().to_string()
+        );
+        drop(db);
+    }
+
+    #[test]
+    fn test_filter_policy() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_filter_policy");
+        let mut options = test_options(env.clone());
+        options.filter_policy = Some(new_bloom_filter_policy(10));
+        let db = DBImpl::open(Arc::new(options), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let read_options = ReadOptions::new();
+        const K_NUM_KEYS: usize = 1000;
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+        for i in (0..K_NUM_KEYS * 2).step_by(2) {
+            assert!(db.put(&write_options, &key(i), &key(i)).is_ok());
+        }
+        // 被删除的 key 也在 filter 中
+        assert!(db.delete(&write_options, &key(0)).is_ok());
+        assert!(db.test_compact_mem_table().is_ok());
+
+        // filter 按 user key 生成，任何序列号的 internal key 都能命中
+        assert!(db.get(&read_options, &key(0)).unwrap_err().is_not_found());
+        for i in (2..K_NUM_KEYS * 2).step_by(2) {
+            assert_eq!(key(i), db.get(&read_options, &key(i)).unwrap());
+        }
+        for i in (1..K_NUM_KEYS * 2).step_by(2) {
+            assert!(db.get(&read_options, &key(i)).unwrap_err().is_not_found());
+        }
+
+        let mut stats = String::new();
+        assert!(db.get_property(&Slice::from("leveldb.filter-stats"), &mut stats));
+        let total = stats.lines().last().unwrap();
+        let counters: Vec<u64> = total
+            .split_whitespace()
+            .filter_map(|s| s.parse().ok())
+            .collect();
+        // 最后一个 key 超出了文件的范围，不会查找 table
+        assert_eq!(
+            K_NUM_KEYS as u64 - 1,
+            counters[0] + counters[1],
+            "{}",
+            stats
+        );
+        assert!(counters[1] < 50, "{}", stats);
+        drop(db);
+    }
+
+    #[test]
+    fn test_block_cache_capacity() {
+        for cache_type in [CacheType::Lru, CacheType::Clock, CacheType::Midpoint] {
+            let env = Arc::new(StdEnv::new());
+            let dbname = test_db_name(&env, &format!("db_block_cache_{:?}", cache_type));
+            let mut options = test_options(env.clone());
+            options.block_cache_capacity = 256 * 1024;
+            options.cache_type = cache_type;
+            let db = DBImpl::open(Arc::new(options), dbname.clone()).unwrap();
+            let write_options = WriteOptions::default();
+            let read_options = ReadOptions::new();
+            let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+            let value = Slice::new_from_vec(vec![b'v'; 1000]);
+            // 解压后约 1MB 的数据，远大于 block cache 的容量
+            for i in 0..1000 {
+                assert!(db.put(&write_options, &key(i), &value).is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+            for i in 0..1000 {
+                assert_eq!(value, db.get(&read_options, &key(i)).unwrap());
+            }
+            let mut iter = db.new_iterator(&read_options);
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            assert_eq!(1000, count);
+            drop(iter);
+
+            let property = |name: &'static str| {
+                let mut value = String::new();
+                assert!(db.get_property(&Slice::from(name), &mut value), "{}", name);
+                value.parse::<usize>().unwrap()
+            };
+            let usage = property("leveldb.block-cache-usage");
+            assert!(usage > 0, "{:?}", cache_type);
+            assert!(usage <= 256 * 1024, "{:?}: {}", cache_type, usage);
+            assert_eq!(0, property("leveldb.block-cache-pinned-usage"));
+            drop(db);
+        }
+    }
+
+    #[test]
+    fn test_get_property() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_get_property");
+        let db = DBImpl::open(Arc::new(test_options(env.clone())), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+        let value = Slice::new_from_vec(vec![b'v'; 100]);
+        let property = |name: &'static str| {
+            let mut value = String::new();
+            assert!(db.get_property(&Slice::from(name), &mut value), "{}", name);
+            value
+        };
+
+        let mut value_str = String::new();
+        assert!(!db.get_property(&Slice::from("leveldb.unknown"), &mut value_str));
+        assert!(!db.get_property(&Slice::from("rocksdb.stats"), &mut value_str));
+        assert!(!db.get_property(&Slice::from("leveldb.num-files-at-level7"), &mut value_str));
+        assert!(!db.get_property(&Slice::from("leveldb.num-files-at-levelx"), &mut value_str));
+        assert_eq!("0", property("leveldb.num-files-at-level0"));
+        assert_eq!("0", property("leveldb.estimate-num-keys"));
+
+        for i in 0..100 {
+            assert!(db.put(&write_options, &key(i), &value).is_ok());
+        }
+        assert!(db.test_compact_mem_table().is_ok());
+        for i in 100..110 {
+            assert!(db.put(&write_options, &key(i), &value).is_ok());
+        }
+
+        // 不与其它文件重叠的 memtable 文件直接放到 level-2
+        assert_eq!("0", property("leveldb.num-files-at-level0"));
+        assert_eq!("1", property("leveldb.num-files-at-level2"));
+        let stats = property("leveldb.stats");
+        assert!(stats.contains("Compactions"), "{}", stats);
+        assert!(stats.contains("\n  2        1 "), "{}", stats);
+        assert!(!stats.contains("\n  0 "), "{}", stats);
+        let sstables = property("leveldb.sstables");
+        assert!(sstables.contains("--- level 2 ---\n"), "{}", sstables);
+        assert!(sstables.contains("'key000000' @ 1 : 1"), "{}", sstables);
+        assert!(
+            property("leveldb.approximate-memory-usage")
+                .parse::<u64>()
+                .unwrap()
+                > 1000
+        );
+
+        // table 中的个数按第一个 data block 估计
+        let num_keys = property("leveldb.estimate-num-keys")
+            .parse::<u64>()
+            .unwrap();
+        assert!((90..=130).contains(&num_keys), "{}", num_keys);
+        // 第二次使用缓存在 table 中的结果
+        assert_eq!(num_keys.to_string(), property("leveldb.estimate-num-keys"));
+        drop(db);
+    }
+
+    #[test]
+    fn test_approximate_sizes() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_approximate_sizes");
+        let mut options = test_options(env.clone());
+        options.compression = CompressionType::None;
+        let db = DBImpl::open(Arc::new(options), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        const K_NUM_KEYS: usize = 80;
+        const K_VALUE_SIZE: u64 = 10000;
+        let key = |i: usize| Slice::new_from_string(format!("key{:06}", i));
+        let range = |start: usize, limit: usize| Range::new(key(start), key(limit));
+        let all = || Range::new(Slice::from(""), Slice::from("z"));
+
+        let mut rnd = Random::new(301);
+        for i in 0..K_NUM_KEYS {
+            let mut value = BytesMut::new();
+            random_string(&mut rnd, K_VALUE_SIZE as usize, &mut value);
+            assert!(db
+                .put(&write_options, &key(i), &Slice::new_from_mut(&value))
+                .is_ok());
+        }
+
+        // 数据还在 memtable 中
+        assert_eq!(vec![0], db.approximate_sizes(&[all()], false));
+        let sizes = db.approximate_sizes(&[all(), range(10, 20)], true);
+        assert!(sizes[0] >= K_NUM_KEYS as u64 * K_VALUE_SIZE, "{:?}", sizes);
+        assert!(
+            sizes[0] <= K_NUM_KEYS as u64 * (K_VALUE_SIZE + 100),
+            "{:?}",
+            sizes
+        );
+        assert!(sizes[1] >= 10 * K_VALUE_SIZE && sizes[1] <= 10 * (K_VALUE_SIZE + 100));
+
+        db.compact_range(None, None);
+        let ranges: Vec<Range> = (0..=K_NUM_KEYS)
+            .step_by(10)
+            .map(|i| Range::new(Slice::from(""), key(i)))
+            .collect();
+        let sizes = db.approximate_sizes(&ranges, true);
+        for (n, size) in sizes.iter().enumerate() {
+            let i = n as u64 * 10;
+            assert!(*size >= i * K_VALUE_SIZE, "{:?}", sizes);
+            assert!(*size <= i * (K_VALUE_SIZE + 100), "{:?}", sizes);
+        }
+        let sizes = db.approximate_sizes(&[range(10, 20), range(20, 10), all()], false);
+        assert!(sizes[0] >= 10 * K_VALUE_SIZE && sizes[0] <= 10 * (K_VALUE_SIZE + 100));
+        // limit 小于 start 的范围大小为 0
+        assert_eq!(0, sizes[1]);
+        assert!(sizes[2] >= K_NUM_KEYS as u64 * K_VALUE_SIZE);
+        drop(db);
+    }
+
+    #[test]
+    fn test_destroy_db() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_destroy");
+        let options = Arc::new(test_options(env.clone()));
+        // 不存在的数据库
+        assert!(destroy_db(&dbname, options.clone()).is_ok());
+
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        assert!(db
+            .put(
+                &WriteOptions::default(),
+                &Slice::from("k"),
+                &Slice::from("v")
+            )
+            .is_ok());
+        assert!(db.test_compact_mem_table().is_ok());
+        // 数据库正在使用
+        assert!(!destroy_db(&dbname, options.clone()).is_ok());
+        assert!(env.file_exists(current_file_name(&dbname)));
+        drop(db);
+
+        let other = format!("{}/other.txt", dbname);
+        std::fs::write(&other, "not a db file").unwrap();
+        assert!(destroy_db(&dbname, options.clone()).is_ok());
+        assert_eq!(
+            vec!["other.txt".to_string()],
+            env.get_children(&dbname).unwrap()
+        );
+
+        std::fs::remove_file(&other).unwrap();
+        assert!(destroy_db(&dbname, options).is_ok());
+        assert!(!env.file_exists(&dbname));
     }
 
-    fn get_approximate_sizes(&self, range: &Range, n: i64, sizes: &mut u64) {
-        todo!()
+    // 不经过后台线程直接生成 n 个 level-0 文件
+    fn add_level0_files(db: &DBImpl<StdEnv>, n: usize) {
+        let mut state = db.mutex_.lock().unwrap();
+        for _ in 0..n {
+            let sequence = state.versions_.last_sequence() + 1;
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::from("k"), &Slice::from("v"));
+            batch.set_sequence(sequence);
+            let mem = Arc::new(MemTable::new());
+            assert!(batch.insert_into(&mem).is_ok());
+            state.versions_.set_last_sequence(sequence);
+            let mut edit = VersionEdit::new();
+            assert!(db
+                .write_level0_table(&mut state, &mem, &mut edit, None)
+                .is_ok());
+            assert!(state.versions_.log_and_apply(&mut edit).is_ok());
+        }
     }
 
-    fn compact_range(&self, begin: &Slice, end: &Slice) {
-        todo!()
+    #[test]
+    fn test_compaction_after_reopen() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_compaction_after_reopen");
+        let options = Arc::new(test_options(env.clone()));
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+        // 暂停 compaction，关闭时 level-0 的文件数已经到达触发 compaction 的阈值
+        db.mutex_.lock().unwrap().background_compaction_scheduled_ = true;
+        add_level0_files(&db, K_L0_COMPACTION_TRIGGER);
+        db.mutex_.lock().unwrap().background_compaction_scheduled_ = false;
+        drop(db);
+
+        // 重新打开后不需要任何写入就会调度 compaction
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        wait_for_background_work(&db);
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!(0, state.versions_.num_level_files(0));
+        assert_eq!(1, state.versions_.num_level_files(1));
+        drop(state);
+        drop(db);
+    }
+
+    #[test]
+    fn test_write_stalls() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_write_stalls");
+        let mut options = test_options(env.clone());
+        options.write_buffer_size = 64 << 10;
+        let db = DBImpl::open(Arc::new(options), dbname.clone()).unwrap();
+        let write_options = WriteOptions::default();
+        // 假装后台任务正在运行，暂停所有 compaction
+        db.mutex_.lock().unwrap().background_compaction_scheduled_ = true;
+
+        // level-0 的文件数到达 slowdown 阈值后，每次写入延迟一次
+        add_level0_files(&db, K_L0_SLOWDOWN_WRITES_TRIGGER);
+        for i in 0..3 {
+            let key = Slice::new_from_string(format!("key{}", i));
+            assert!(db.put(&write_options, &key, &Slice::from("v")).is_ok());
+        }
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert_eq!(3, state.write_stall_stats_.level0_slowdown_count);
+            assert!(state.write_stall_stats_.level0_slowdown_micros >= 3000);
+            assert_eq!(0, state.write_stall_stats_.level0_stop_count);
+        }
+
+        // 到达 stop 阈值后，memtable 写满时阻塞写入，直到 compaction 减少了 level-0 的文件
+        add_level0_files(&db, K_L0_STOP_WRITES_TRIGGER - K_L0_SLOWDOWN_WRITES_TRIGGER);
+        let big_value = Slice::new_from_string("x".repeat(100 << 10));
+        assert!(db
+            .put(&write_options, &Slice::from("big"), &big_value)
+            .is_ok());
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let db = db.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let value = Slice::from("v");
+                assert!(db
+                    .put(&WriteOptions::default(), &Slice::from("blocked"), &value)
+                    .is_ok());
+                done.store(true, Ordering::Release);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!done.load(Ordering::Acquire));
+
+        {
+            let mut state = db.mutex_.lock().unwrap();
+            state.background_compaction_scheduled_ = false;
+            db.maybe_schedule_compaction(&mut state);
+        }
+        writer.join().unwrap();
+        assert!(done.load(Ordering::Acquire));
+        assert!(db.mutex_.lock().unwrap().versions_.num_level_files(0) < K_L0_STOP_WRITES_TRIGGER);
+        {
+            let state = db.mutex_.lock().unwrap();
+            let stall = &state.write_stall_stats_;
+            assert_eq!(5, stall.level0_slowdown_count);
+            assert!(stall.level0_stop_count >= 1);
+            assert!(stall.level0_stop_micros >= 50_000);
+        }
+        let mut stats = String::new();
+        assert!(db.get_property(&Slice::from("leveldb.stats"), &mut stats));
+        // leveldb.stats 与 C++ 版本的输出保持一致，不包含写入阻塞的统计
+        assert!(!stats.contains("Write stalls"), "{}", stats);
+        let mut stalls = String::new();
+        assert!(db.get_property(&Slice::from("leveldb.write-stall-stats"), &mut stalls));
+        assert!(stalls.contains("level0 slowdown 5 ("), "{}", stalls);
+        assert_eq!(
+            "v",
+            db.get(&ReadOptions::new(), &Slice::from("blocked"))
+                .unwrap()
+                .to_string()
+        );
+        drop(db);
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        const K_NUM_THREADS: u64 = 8;
+        const K_NUM_KEYS: u64 = 200;
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "db_concurrent_writes");
+        let options = Arc::new(test_options(env.clone()));
+        let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+
+        let handles: Vec<_> = (0..K_NUM_THREADS)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let write_options = WriteOptions { sync: t == 0 };
+                    for i in 0..K_NUM_KEYS {
+                        let key = Slice::new_from_string(format!("{}.{}", t, i));
+                        let value = Slice::new_from_string(format!("v{}", i));
+                        assert!(db.put(&write_options, &key, &value).is_ok());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        {
+            let state = db.mutex_.lock().unwrap();
+            assert!(state.writers_.is_empty());
+            assert_eq!(K_NUM_THREADS * K_NUM_KEYS, state.versions_.last_sequence());
+            let mem = state.mem_.as_ref().unwrap();
+            for t in 0..K_NUM_THREADS {
+                for i in 0..K_NUM_KEYS {
+                    let key = Slice::new_from_string(format!("{}.{}", t, i));
+                    let lkey = LookupKey::new(&key, K_MAX_SEQUENCE_NUMBER);
+                    let value = mem.get(&lkey).unwrap().unwrap();
+                    assert_eq!(format!("v{}", i), value.to_string());
+                }
+            }
+        }
+        drop(db);
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let state = db.mutex_.lock().unwrap();
+        assert_eq!(K_NUM_THREADS * K_NUM_KEYS, state.versions_.last_sequence());
+        drop(state);
+        drop(db);
     }
 }
@@ -0,0 +1,316 @@
+use crate::db::db::DBImpl;
+use crate::db::internal_key_comparator::{
+    append_internal_key, extract_user_key, parse_internal_key, InternalKeyComparator,
+    ParsedInternalKey, ValueType, K_VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::version_set::Version;
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::iterator::Iter;
+use crate::util::env::Env;
+use crate::util::random::Random;
+use bytes::{BufMut, BytesMut};
+use std::cmp::Ordering;
+use std::sync::{Arc, Weak};
+
+// 平均每读这么多字节采样一次，用于触发读引起的 compaction
+const K_READ_BYTES_PERIOD: usize = 1048576;
+
+// Forward 时内部迭代器指向当前 entry；
+// Reverse 时内部迭代器指向当前 user key 的所有 entry 之前，当前的 key 和 value 保存在 saved_key、saved_value 中
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// 把内部迭代器的 (internal key, value) 转换成用户可见的 (user key, value)：
+/// 同一个 user key 只返回 sequence 之前最新的版本，并跳过删除标记
+pub(crate) struct DBIter<E>
+where
+    E: Env,
+{
+    // 采样使文件需要 compaction 时由 db 调度，db 已经关闭时忽略
+    db: Weak<DBImpl<E>>,
+    // 采样时记录到创建迭代器时的版本上
+    version: Arc<Version<E>>,
+    icmp: InternalKeyComparator,
+    iter: Box<dyn Iter>,
+    sequence: u64,
+    status: Status,
+    saved_key: BytesMut,
+    saved_value: BytesMut,
+    direction: Direction,
+    valid: bool,
+    rnd: Random,
+    bytes_until_read_sampling: usize,
+}
+
+impl<E> DBIter<E>
+where
+    E: Env + 'static,
+{
+    pub(crate) fn new(
+        db: Weak<DBImpl<E>>,
+        version: Arc<Version<E>>,
+        iter: Box<dyn Iter>,
+        sequence: u64,
+        seed: u32,
+    ) -> Self {
+        let mut rnd = Random::new(seed);
+        let bytes_until_read_sampling = rnd.uniform(2 * K_READ_BYTES_PERIOD as u32) as usize;
+        DBIter {
+            db,
+            version,
+            icmp: InternalKeyComparator::new(),
+            iter,
+            sequence,
+            status: Status::ok(),
+            saved_key: BytesMut::new(),
+            saved_value: BytesMut::new(),
+            direction: Direction::Forward,
+            valid: false,
+            rnd,
+            bytes_until_read_sampling,
+        }
+    }
+
+    fn random_compaction_period(&mut self) -> usize {
+        self.rnd.uniform(2 * K_READ_BYTES_PERIOD as u32) as usize
+    }
+
+    fn parse_key(&mut self, ikey: &mut ParsedInternalKey) -> bool {
+        let k = self.iter.key();
+        let bytes_read = k.len() + self.iter.value().len();
+        while self.bytes_until_read_sampling < bytes_read {
+            self.bytes_until_read_sampling += self.random_compaction_period();
+            if self.version.record_read_sample(&k) {
+                if let Some(db) = self.db.upgrade() {
+                    db.schedule_read_compaction();
+                }
+            }
+        }
+        self.bytes_until_read_sampling -= bytes_read;
+
+        if !parse_internal_key(&k, ikey) {
+            self.status = Status::corruption("corrupted internal key in DBIter", None);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn save_key(key: &Slice, dst: &mut BytesMut) {
+        dst.clear();
+        dst.put_slice(key.data());
+    }
+
+    fn compare_saved_key(&self, user_key: &Slice) -> Ordering {
+        self.icmp
+            .user_comparator()
+            .compare(user_key, &Slice::new_from_mut(&self.saved_key))
+    }
+
+    fn new_parsed_key() -> ParsedInternalKey {
+        ParsedInternalKey {
+            user_key: Slice::new_empty(),
+            sequence: 0,
+            value_type: ValueType::KTypeValue,
+        }
+    }
+
+    // 向后找到第一个可见的 entry，skipping 为 true 时跳过所有 <= saved_key 的 user key
+    fn find_next_user_entry(&mut self, mut skipping: bool) {
+        debug_assert!(self.iter.valid());
+        debug_assert!(self.direction == Direction::Forward);
+        loop {
+            let mut ikey = Self::new_parsed_key();
+            if self.parse_key(&mut ikey) && ikey.sequence <= self.sequence {
+                match ikey.value_type {
+                    ValueType::KTypeDeletion => {
+                        // 跳过这个 user key 之后所有更旧的版本
+                        Self::save_key(&ikey.user_key, &mut self.saved_key);
+                        skipping = true;
+                    }
+                    ValueType::KTypeValue => {
+                        if !skipping || self.compare_saved_key(&ikey.user_key) == Ordering::Greater
+                        {
+                            self.valid = true;
+                            self.saved_key.clear();
+                            return;
+                        }
+                    }
+                }
+            }
+            self.iter.next();
+            if !self.iter.valid() {
+                break;
+            }
+        }
+        self.saved_key.clear();
+        self.valid = false;
+    }
+
+    // 向前找到上一个 user key 的最新可见版本，结果保存在 saved_key、saved_value 中
+    fn find_prev_user_entry(&mut self) {
+        debug_assert!(self.direction == Direction::Reverse);
+        let mut value_type = ValueType::KTypeDeletion;
+        if self.iter.valid() {
+            loop {
+                let mut ikey = Self::new_parsed_key();
+                if self.parse_key(&mut ikey) && ikey.sequence <= self.sequence {
+                    if value_type != ValueType::KTypeDeletion
+                        && self.compare_saved_key(&ikey.user_key) == Ordering::Less
+                    {
+                        // 已经越过了前一个 user key 的所有 entry
+                        break;
+                    }
+                    value_type = ikey.value_type;
+                    if value_type == ValueType::KTypeDeletion {
+                        self.saved_key.clear();
+                        self.saved_value.clear();
+                    } else {
+                        Self::save_key(&extract_user_key(&self.iter.key()), &mut self.saved_key);
+                        Self::save_key(&self.iter.value(), &mut self.saved_value);
+                    }
+                }
+                self.iter.prev();
+                if !self.iter.valid() {
+                    break;
+                }
+            }
+        }
+
+        if value_type == ValueType::KTypeDeletion {
+            // 已经到头了
+            self.valid = false;
+            self.saved_key.clear();
+            self.saved_value.clear();
+            self.direction = Direction::Forward;
+        } else {
+            self.valid = true;
+        }
+    }
+}
+
+impl<E> Iter for DBIter<E>
+where
+    E: Env + 'static,
+{
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        self.iter.seek_to_first();
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        self.direction = Direction::Reverse;
+        self.saved_value.clear();
+        self.iter.seek_to_last();
+        self.find_prev_user_entry();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        self.saved_key.clear();
+        append_internal_key(
+            &mut self.saved_key,
+            &ParsedInternalKey {
+                user_key: target.clone(),
+                sequence: self.sequence,
+                value_type: K_VALUE_TYPE_FOR_SEEK,
+            },
+        );
+        self.iter.seek(&Slice::new_from_mut(&self.saved_key));
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Reverse {
+            self.direction = Direction::Forward;
+            // 内部迭代器位于 key() 的所有 entry 之前，先移到这些 entry 上，
+            // 再按正常流程跳过它们，要跳过的 key 已经在 saved_key 中
+            if !self.iter.valid() {
+                self.iter.seek_to_first();
+            } else {
+                self.iter.next();
+            }
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+        } else {
+            Self::save_key(&extract_user_key(&self.iter.key()), &mut self.saved_key);
+            self.iter.next();
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+        }
+        self.find_next_user_entry(true);
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Forward {
+            // 内部迭代器指向当前 entry，向前移到第一个 user key 更小的 entry
+            debug_assert!(self.iter.valid());
+            Self::save_key(&extract_user_key(&self.iter.key()), &mut self.saved_key);
+            loop {
+                self.iter.prev();
+                if !self.iter.valid() {
+                    self.valid = false;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    return;
+                }
+                if self.compare_saved_key(&extract_user_key(&self.iter.key())) == Ordering::Less {
+                    break;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.find_prev_user_entry();
+    }
+
+    fn key(&self) -> Slice {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => extract_user_key(&self.iter.key()),
+            Direction::Reverse => Slice::new_from_array(&self.saved_key),
+        }
+    }
+
+    fn value(&self) -> Slice {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => self.iter.value(),
+            Direction::Reverse => Slice::new_from_array(&self.saved_value),
+        }
+    }
+
+    fn status(&self) -> Status {
+        if self.status.is_ok() {
+            self.iter.status()
+        } else {
+            self.status.clone()
+        }
+    }
+}
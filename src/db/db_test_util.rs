@@ -0,0 +1,50 @@
+use crate::obj::options::Options;
+use crate::util::env::{Env, StdEnv};
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+/// 测试用的数据库目录，创建时删除上次留下的文件，drop 时删除整个目录，
+/// 断言失败导致 panic 时也会清理
+pub(crate) struct TestDir {
+    name: String,
+}
+
+impl Deref for TestDir {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.name
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        self.name.as_ref()
+    }
+}
+
+impl Display for TestDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.name);
+    }
+}
+
+/// 在 env 的测试目录下为 name 创建一个空的数据库目录
+pub(crate) fn test_db_name(env: &StdEnv, name: &str) -> TestDir {
+    let name = format!("{}/{}", env.get_test_directory().unwrap(), name);
+    let _ = std::fs::remove_dir_all(&name);
+    TestDir { name }
+}
+
+pub(crate) fn test_options(env: Arc<StdEnv>) -> Options<StdEnv> {
+    let mut options = Options::new(env);
+    options.create_if_missing = true;
+    options
+}
@@ -1,3 +1,18 @@
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::util::env::{write_string_to_file_sync, Env};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    LogFile,
+    DBLockFile,
+    TableFile,
+    DescriptorFile,
+    CurrentFile,
+    TempFile,
+    InfoLogFile,
+}
+
 fn make_file_name(db_name: &String, number: u64, suffix: &str) -> String {
     let result = format!("{db_name}/{number:06}.{suffix}");
     result
@@ -13,8 +28,104 @@ pub fn sst_table_file_name(db_name: &String, number: u64) -> String {
     return make_file_name(db_name, number, "sst");
 }
 
+pub fn log_file_name(db_name: &String, number: u64) -> String {
+    debug_assert!(number > 0);
+    make_file_name(db_name, number, "log")
+}
+
+/// MANIFEST 文件名，形如 `dbname/MANIFEST-000005`
+pub fn descriptor_file_name(db_name: &String, number: u64) -> String {
+    debug_assert!(number > 0);
+    format!("{db_name}/MANIFEST-{number:06}")
+}
+
+/// CURRENT 文件中保存当前使用的 MANIFEST 文件名
+pub fn current_file_name(db_name: &String) -> String {
+    format!("{db_name}/CURRENT")
+}
+
+/// 防止多个进程同时打开同一个数据库的锁文件
+pub fn lock_file_name(db_name: &String) -> String {
+    format!("{db_name}/LOCK")
+}
+
+/// 数据库的信息日志
+pub fn info_log_file_name(db_name: &String) -> String {
+    format!("{db_name}/LOG")
+}
+
+/// 上一次打开数据库时的信息日志
+pub fn old_info_log_file_name(db_name: &String) -> String {
+    format!("{db_name}/LOG.old")
+}
+
+pub fn temp_file_name(db_name: &String, number: u64) -> String {
+    debug_assert!(number > 0);
+    make_file_name(db_name, number, "dbtmp")
+}
+
+/// 解析 db 目录下的文件名，返回文件编号和类型，不属于数据库的文件返回 None。
+///   dbname/CURRENT
+///   dbname/LOCK
+///   dbname/LOG
+///   dbname/LOG.old
+///   dbname/MANIFEST-[0-9]+
+///   dbname/[0-9]+.(log|sst|ldb|dbtmp)
+pub fn parse_file_name(filename: &str) -> Option<(u64, FileType)> {
+    match filename {
+        "CURRENT" => return Some((0, FileType::CurrentFile)),
+        "LOCK" => return Some((0, FileType::DBLockFile)),
+        "LOG" | "LOG.old" => return Some((0, FileType::InfoLogFile)),
+        _ => {}
+    }
+    if let Some(rest) = filename.strip_prefix("MANIFEST-") {
+        let number = parse_number(rest)?;
+        return Some((number, FileType::DescriptorFile));
+    }
+    let (number, suffix) = filename.split_once('.')?;
+    let number = parse_number(number)?;
+    let file_type = match suffix {
+        "log" => FileType::LogFile,
+        "sst" | "ldb" => FileType::TableFile,
+        "dbtmp" => FileType::TempFile,
+        _ => return None,
+    };
+    Some((number, file_type))
+}
+
+// 只接受十进制数字，与 "123abc"、"+1" 这类字符串区分开
+fn parse_number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// 先写临时文件再 rename，保证 CURRENT 的切换是原子的
+pub(crate) fn set_current_file<E: Env>(
+    env: &E,
+    db_name: &String,
+    descriptor_number: u64,
+) -> Status {
+    let manifest = descriptor_file_name(db_name, descriptor_number);
+    let contents = &manifest[db_name.len() + 1..];
+    let tmp = temp_file_name(db_name, descriptor_number);
+    let mut s =
+        write_string_to_file_sync(env, &Slice::new_from_string(format!("{contents}\n")), &tmp);
+    if s.is_ok() {
+        s = env.rename_file(&tmp, &current_file_name(db_name));
+    }
+    if !s.is_ok() {
+        env.remove_file(&tmp);
+    }
+    s
+}
+
+#[cfg(test)]
 mod test {
+    use super::*;
     use crate::db::file_name::{make_file_name, table_file_name};
+    use crate::util::env::StdEnv;
     use std::env;
     #[test]
     fn test_table_file_name() {
@@ -23,4 +134,96 @@ mod test {
         let result = table_file_name(&db_name.to_string(), number);
         assert_eq!(result, "test/001001.ldb");
     }
+
+    #[test]
+    fn test_parse_file_name() {
+        let cases = [
+            ("100.log", 100, FileType::LogFile),
+            ("0.log", 0, FileType::LogFile),
+            ("0.sst", 0, FileType::TableFile),
+            ("0.ldb", 0, FileType::TableFile),
+            ("CURRENT", 0, FileType::CurrentFile),
+            ("LOCK", 0, FileType::DBLockFile),
+            ("MANIFEST-2", 2, FileType::DescriptorFile),
+            ("MANIFEST-7", 7, FileType::DescriptorFile),
+            ("LOG", 0, FileType::InfoLogFile),
+            ("LOG.old", 0, FileType::InfoLogFile),
+            ("18446744073709551615.log", u64::MAX, FileType::LogFile),
+            ("000123.dbtmp", 123, FileType::TempFile),
+        ];
+        for (name, number, file_type) in cases {
+            assert_eq!(Some((number, file_type)), parse_file_name(name), "{}", name);
+        }
+
+        let errors = [
+            "",
+            "foo",
+            "foo-dx-100.log",
+            ".log",
+            "manifest",
+            "CURREN",
+            "CURRENTX",
+            "MANIFES",
+            "MANIFEST",
+            "MANIFEST-",
+            "XMANIFEST-3",
+            "MANIFEST-3x",
+            "LOC",
+            "LOCKx",
+            "LO",
+            "LOGx",
+            "18446744073709551616.log",
+            "184467440737095516150.log",
+            "100",
+            "100.",
+            "100.lop",
+            "+1.log",
+        ];
+        for name in errors {
+            assert_eq!(None, parse_file_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_construction() {
+        let db_name = "foo".to_string();
+        let names = [
+            (current_file_name(&db_name), 0, FileType::CurrentFile),
+            (lock_file_name(&db_name), 0, FileType::DBLockFile),
+            (log_file_name(&db_name, 192), 192, FileType::LogFile),
+            (table_file_name(&db_name, 200), 200, FileType::TableFile),
+            (sst_table_file_name(&db_name, 201), 201, FileType::TableFile),
+            (
+                descriptor_file_name(&db_name, 100),
+                100,
+                FileType::DescriptorFile,
+            ),
+            (temp_file_name(&db_name, 999), 999, FileType::TempFile),
+            (info_log_file_name(&db_name), 0, FileType::InfoLogFile),
+            (old_info_log_file_name(&db_name), 0, FileType::InfoLogFile),
+        ];
+        for (name, number, file_type) in names {
+            let base = name.strip_prefix("foo/").unwrap();
+            assert_eq!(Some((number, file_type)), parse_file_name(base), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_set_current_file() {
+        let env = StdEnv::new();
+        let db_name = format!(
+            "{}/file_name_set_current",
+            env.get_test_directory().unwrap()
+        );
+        let _ = std::fs::remove_dir_all(&db_name);
+        env.create_dir(&db_name);
+        assert!(set_current_file(&env, &db_name, 5).is_ok());
+        assert_eq!(
+            "MANIFEST-000005\n",
+            std::fs::read_to_string(current_file_name(&db_name)).unwrap()
+        );
+        // 临时文件已经被 rename
+        assert!(!env.file_exists(temp_file_name(&db_name, 5)));
+        let _ = std::fs::remove_dir_all(&db_name);
+    }
 }
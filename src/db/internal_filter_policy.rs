@@ -3,12 +3,23 @@ use crate::util::filter_policy::FilterPolicy;
 use bytes::BytesMut;
 use std::sync::Arc;
 
+/// 把用户的 filter policy 用在 internal key 上：去掉 internal key 末尾的序列号和类型，
+/// 只对 user key 生成和查询 filter
 pub(crate) struct InternalFilterPolicy {
     pub(crate) user_policy_: Arc<dyn FilterPolicy>,
 }
 
+impl InternalFilterPolicy {
+    pub(crate) fn new(user_policy: Arc<dyn FilterPolicy>) -> Self {
+        InternalFilterPolicy {
+            user_policy_: user_policy,
+        }
+    }
+}
+
 fn extract_user_key(internal_key: &Slice) -> Slice {
-    assert!(internal_key.len() > 8);
+    // user key 可以为空
+    assert!(internal_key.len() >= 8);
     Slice::new_from_ptr(&internal_key.data()[0..internal_key.len() - 8])
 }
 
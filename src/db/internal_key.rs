@@ -1,8 +1,10 @@
 use crate::db::internal_key_comparator::{
-    append_internal_key, extract_user_key, InternalKeyComparator, ParsedInternalKey, ValueType,
+    append_internal_key, extract_user_key, parse_internal_key, InternalKeyComparator,
+    ParsedInternalKey, ValueType, K_VALUE_TYPE_FOR_SEEK,
 };
 use crate::obj::slice::Slice;
 use crate::util::bytewise_comparator_impl;
+use crate::util::coding::put_varint32;
 use crate::util::comparator::Comparator;
 use bytes::BytesMut;
 use std::cmp::Ordering;
@@ -11,6 +13,7 @@ static INTERNAL_KEY_CMP: InternalKeyComparator = InternalKeyComparator {
     user_comparator_: bytewise_comparator_impl::BytewiseComparatorImpl {},
 };
 
+#[derive(Clone, Debug, Default)]
 pub(crate) struct InternalKey {
     pub rep_: BytesMut,
 }
@@ -30,15 +33,15 @@ impl InternalKey {
         );
         result
     }
-    fn decode_from(&mut self, s: &Slice) -> bool {
+    pub(crate) fn decode_from(&mut self, s: &Slice) -> bool {
         self.rep_ = BytesMut::from(s.data());
         !self.rep_.is_empty()
     }
-    fn encode(&self) -> Slice {
+    pub(crate) fn encode(&self) -> Slice {
         debug_assert!(!self.rep_.is_empty());
         Slice::new_from_mut(&self.rep_)
     }
-    fn user_key(&self) -> Slice {
+    pub(crate) fn user_key(&self) -> Slice {
         extract_user_key(&Slice::new_from_mut(&self.rep_))
     }
 
@@ -50,6 +53,26 @@ impl InternalKey {
     fn clear(&mut self) {
         self.rep_.clear();
     }
+
+    /// 形如 `'user_key' @ 100 : 1` 的可读格式，无法解析时以 `(bad)` 开头
+    pub(crate) fn debug_string(&self) -> String {
+        let mut parsed = ParsedInternalKey {
+            user_key: Slice::new_empty(),
+            sequence: 0,
+            value_type: ValueType::KTypeValue,
+        };
+        let rep = Slice::new_from_ptr(self.rep_.as_ref());
+        if parse_internal_key(&rep, &mut parsed) {
+            format!(
+                "'{}' @ {} : {}",
+                parsed.user_key.to_string(),
+                parsed.sequence,
+                parsed.value_type as u8
+            )
+        } else {
+            format!("(bad){}", rep.to_string())
+        }
+    }
 }
 
 impl Eq for InternalKey {}
@@ -81,6 +104,45 @@ impl Ord for InternalKey {
     }
 }
 
+/// 查找 memtable 和 table 时使用的 key，由 user key 和读取时的序列号组成。
+// rep_ 的格式：varint32(internal_key_size) | user_key | tag(sequence, K_VALUE_TYPE_FOR_SEEK)
+pub(crate) struct LookupKey {
+    rep_: BytesMut,
+    // internal key 在 rep_ 中的起始位置
+    kstart: usize,
+}
+
+impl LookupKey {
+    pub(crate) fn new(user_key: &Slice, sequence: u64) -> LookupKey {
+        let key_size = user_key.len();
+        let mut rep_ = BytesMut::with_capacity(key_size + 13);
+        put_varint32(&mut rep_, (key_size + 8) as u32);
+        let kstart = rep_.len();
+        append_internal_key(
+            &mut rep_,
+            &ParsedInternalKey {
+                user_key: user_key.clone(),
+                sequence,
+                value_type: K_VALUE_TYPE_FOR_SEEK,
+            },
+        );
+        LookupKey { rep_, kstart }
+    }
+
+    /// 带长度前缀的 internal key，与 memtable 条目的开头格式相同
+    pub(crate) fn memtable_key(&self) -> Slice {
+        Slice::new_from_ptr(&self.rep_)
+    }
+
+    pub(crate) fn internal_key(&self) -> Slice {
+        Slice::new_from_ptr(&self.rep_[self.kstart..])
+    }
+
+    pub(crate) fn user_key(&self) -> Slice {
+        Slice::new_from_ptr(&self.rep_[self.kstart..self.rep_.len() - 8])
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::db::internal_key::InternalKey;
@@ -31,8 +31,19 @@ pub struct ParsedInternalKey {
     pub(crate) value_type: ValueType,
 }
 
-const K_MAX_SEQUENCE_NUMBER: u64 = (0x1u64 << 56) - 1;
-const K_VALUE_TYPE_FOR_SEEK: ValueType = ValueType::KTypeValue;
+// LSM 的层数
+pub(crate) const K_NUM_LEVELS: usize = 7;
+// level-0 的文件数达到该值时开始 compaction
+pub(crate) const K_L0_COMPACTION_TRIGGER: usize = 4;
+// level-0 的文件数达到该值时每次写入延迟 1ms
+pub(crate) const K_L0_SLOWDOWN_WRITES_TRIGGER: usize = 8;
+// level-0 的文件数达到该值时停止写入，等待 compaction
+pub(crate) const K_L0_STOP_WRITES_TRIGGER: usize = 12;
+// 新 dump 出的 memtable 最多推到这一层，避免昂贵的 level-0 到 level-1 compaction
+pub(crate) const K_MAX_MEM_COMPACT_LEVEL: usize = 2;
+
+pub(crate) const K_MAX_SEQUENCE_NUMBER: u64 = (0x1u64 << 56) - 1;
+pub(crate) const K_VALUE_TYPE_FOR_SEEK: ValueType = ValueType::KTypeValue;
 #[inline]
 pub fn extract_user_key(internal_key: &Slice) -> Slice {
     debug_assert!(internal_key.len() >= 8);
@@ -52,22 +63,39 @@ pub fn append_internal_key(result: &mut BytesMut, key: &ParsedInternalKey) {
 }
 
 #[inline]
-fn parse_internal_key(internal_key: &Slice, result: &mut ParsedInternalKey) -> bool {
+pub(crate) fn parse_internal_key(internal_key: &Slice, result: &mut ParsedInternalKey) -> bool {
     let n = internal_key.len();
     if n < 8 {
         return false;
     }
     let num = decode_fixed64(&internal_key.data()[n - 8..]);
     let c = (num as u8) & 0xff;
-    result.sequence = num >> 8;
-    result.value_type = ValueType::try_from(c).unwrap();
-    result.user_key = internal_key.slice(n - 8);
-    c <= ValueType::KTypeValue as u8
+    match ValueType::try_from(c) {
+        Ok(value_type) => {
+            result.sequence = num >> 8;
+            result.value_type = value_type;
+            result.user_key = internal_key.slice(n - 8);
+            true
+        }
+        Err(_) => false,
+    }
 }
 
 pub(crate) struct InternalKeyComparator {
     pub(crate) user_comparator_: BytewiseComparatorImpl,
 }
+
+impl InternalKeyComparator {
+    pub(crate) fn new() -> Self {
+        InternalKeyComparator {
+            user_comparator_: BytewiseComparatorImpl {},
+        }
+    }
+
+    pub(crate) fn user_comparator(&self) -> &dyn Comparator {
+        &self.user_comparator_
+    }
+}
 impl Comparator for InternalKeyComparator {
     fn compare(&self, akey: &Slice, bkey: &Slice) -> Ordering {
         let mut r = self
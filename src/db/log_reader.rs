@@ -1,5 +1,6 @@
 use crate::db::log_format::{RecordType, K_BLOCK_SIZE, K_HEADER_SIZE, K_MAX_RECORD_TYPE};
 use crate::obj::byte_buffer::ByteBuffer;
+use crate::obj::options::WALRecoveryMode;
 use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
 use crate::util;
@@ -15,11 +16,11 @@ enum ReadStatus {
     KBadRecord = K_MAX_RECORD_TYPE + 2,
 }
 
-trait Reporter {
+pub(crate) trait Reporter {
     fn corruption(&mut self, bytes: usize, status: &Status);
 }
 
-struct Reader {
+pub(crate) struct Reader {
     file_: Arc<Mutex<dyn SequentialFile>>,
     reporter_: Option<Box<dyn Reporter>>,
     checksum_: bool,
@@ -30,14 +31,24 @@ struct Reader {
     end_of_buffer_offset_: u64,
     initial_offset_: usize,
     resyncing_: bool,
+    recovery_mode_: WALRecoveryMode,
+    // 按 recovery_mode_ 应当让恢复失败的第一个错误
+    status_: Status,
+    // PointInTimeRecovery 遇到的第一个错误，之后的内容全部丢弃
+    stop_reason_: Option<Status>,
+    stopped_: bool,
+    // 最后一条返回的记录之后的位置
+    last_record_end_offset_: u64,
+    dropped_bytes_: u64,
 }
 
 impl Reader {
-    fn new(
+    pub(crate) fn new(
         file: Arc<Mutex<dyn SequentialFile>>,
         reporter: Option<Box<dyn Reporter>>,
         checksum: bool,
         initial_offset: usize,
+        recovery_mode: WALRecoveryMode,
     ) -> Self {
         Reader {
             file_: file,
@@ -50,22 +61,86 @@ impl Reader {
             end_of_buffer_offset_: 0,
             initial_offset_: initial_offset,
             resyncing_: initial_offset > 0,
+            recovery_mode_: recovery_mode,
+            status_: Status::ok(),
+            stop_reason_: None,
+            stopped_: false,
+            last_record_end_offset_: initial_offset as u64,
+            dropped_bytes_: 0,
         }
     }
+
+    // 文件中间的损坏，后面可能还有完好的记录
     fn report_corruption(&mut self, bytes: u64, reason: &str) {
-        self.report_drop(bytes, Status::corruption(reason, None))
+        self.report_error(bytes, Status::corruption(reason, None), false)
+    }
+
+    // 写入方在写完最后一条记录之前崩溃，文件末尾留下了不完整的记录
+    fn report_truncated_tail(&mut self, bytes: u64, reason: &str) {
+        self.report_error(bytes, Status::corruption(reason, None), true)
+    }
+
+    fn report_error(&mut self, bytes: u64, reason: Status, tail: bool) {
+        match self.recovery_mode_ {
+            WALRecoveryMode::PointInTimeRecovery => {
+                // 由 drop_rest_of_log 统一报告丢弃的字节数
+                if self.stop_reason_.is_none() {
+                    self.stop_reason_ = Some(reason);
+                }
+                return;
+            }
+            WALRecoveryMode::AbsoluteConsistency => self.record_error(&reason),
+            WALRecoveryMode::TolerateCorruptedTailRecords if !tail => self.record_error(&reason),
+            _ => {}
+        }
+        self.report_drop(bytes, reason);
+    }
+
+    fn record_error(&mut self, reason: &Status) {
+        if self.status_.is_ok() {
+            self.status_ = reason.clone();
+        }
     }
 
     fn report_drop(&mut self, bytes: u64, reason: Status) {
-        if let Some(report) = self.reporter_.as_mut() {
-            if (self.end_of_buffer_offset_ as i64 - self.buffer_.size() as i64 - bytes as i64)
-                >= self.initial_offset_ as i64
-            {
+        if (self.end_of_buffer_offset_ as i64 - self.buffer_.size() as i64 - bytes as i64)
+            >= self.initial_offset_ as i64
+        {
+            self.dropped_bytes_ += bytes;
+            if let Some(report) = self.reporter_.as_mut() {
                 report.corruption(bytes as usize, &reason);
             }
         }
     }
 
+    // PointInTimeRecovery 遇到错误后，丢弃最后一条完整记录之后的所有内容
+    fn drop_rest_of_log(&mut self) {
+        let reason = match self.stop_reason_.take() {
+            Some(reason) => reason,
+            None => return,
+        };
+        self.stopped_ = true;
+        self.buffer_.clear();
+        while !self.eof_ {
+            let res = self.file_.lock().unwrap().read(K_BLOCK_SIZE);
+            match res {
+                Ok(buffer) => {
+                    self.end_of_buffer_offset_ += buffer.size() as u64;
+                    self.eof_ = buffer.size() < K_BLOCK_SIZE;
+                }
+                // 读不下去了，只能报告已知的部分
+                Err(_) => self.eof_ = true,
+            }
+        }
+        let bytes = self
+            .end_of_buffer_offset_
+            .saturating_sub(self.last_record_end_offset_);
+        self.dropped_bytes_ += bytes;
+        if let Some(report) = self.reporter_.as_mut() {
+            report.corruption(bytes as usize, &reason);
+        }
+    }
+
     fn skip_to_initial_block(&mut self) -> bool {
         let offset_in_block = self.initial_offset_ % (K_BLOCK_SIZE);
         let mut block_start_location = self.initial_offset_ - offset_in_block;
@@ -82,7 +157,7 @@ impl Reader {
         }
         true
     }
-    fn read_physical_record(&mut self) -> Result<(Slice, RecordType), ReadStatus> {
+    fn read_physical_record(&mut self) -> Result<(Slice, u8), ReadStatus> {
         loop {
             if self.buffer_.size() < K_HEADER_SIZE {
                 if !self.eof_ {
@@ -98,14 +173,19 @@ impl Reader {
                         }
                         Err(e) => {
                             self.buffer_.clear();
-                            self.report_drop(K_BLOCK_SIZE as u64, e);
+                            self.report_error(K_BLOCK_SIZE as u64, e, false);
                             self.eof_ = true;
                             return Err(ReadStatus::KEof);
                         }
                     }
                     continue;
                 } else {
+                    // 文件末尾不完整的 header
+                    let drop_size = self.buffer_.size();
                     self.buffer_.clear();
+                    if drop_size > 0 {
+                        self.report_truncated_tail(drop_size as u64, "truncated record header");
+                    }
                     return Err(ReadStatus::KEof);
                 }
             }
@@ -121,6 +201,8 @@ impl Reader {
                     self.report_corruption(drop_size as u64, "bad record length");
                     return Err(ReadStatus::KBadRecord);
                 }
+                // 记录还没写完时写入方就崩溃了
+                self.report_truncated_tail(drop_size as u64, "truncated record");
                 return Err(ReadStatus::KEof);
             }
 
@@ -139,31 +221,414 @@ impl Reader {
                     return Err(ReadStatus::KBadRecord);
                 }
             }
-            let buffer_size = self.buffer_.size();
             let res = Slice::new_from_array(&header[K_HEADER_SIZE..K_HEADER_SIZE + length]);
             self.buffer_.remove_prefix(K_HEADER_SIZE + length);
-            if (self.end_of_buffer_offset_ as usize)
-                < (self.initial_offset_ + buffer_size - K_HEADER_SIZE + length)
+            // 跳过起始位置在 initial_offset_ 之前的记录
+            if self.end_of_buffer_offset_
+                < self.initial_offset_ as u64
+                    + self.buffer_.size() as u64
+                    + (K_HEADER_SIZE + length) as u64
             {
                 return Err(ReadStatus::KBadRecord);
             }
+            return Ok((res, data_type));
+        }
+    }
+
+    /// 读取下一条完整的记录，到达文件末尾时返回 false。
+    /// 分片的记录会先拼接到 scratch 中，再拷贝到 record。
+    pub(crate) fn read_record(&mut self, record: &mut Slice, scratch: &mut BytesMut) -> bool {
+        if self.stopped_ {
+            return false;
+        }
+        if self.last_record_offset_ < self.initial_offset_ as u64 && !self.skip_to_initial_block() {
+            return false;
+        }
+        scratch.clear();
+        *record = Slice::new_empty();
+        let mut in_fragmented_record = false;
+        // 正在拼接的记录的起始偏移
+        let mut prospective_record_offset = 0u64;
+        // 正在拼接的记录已经读到的分片在文件中占用的字节数（包括 header），丢弃时按它报告
+        let mut fragmented_bytes = 0u64;
+        loop {
+            if self.stop_reason_.is_some() {
+                scratch.clear();
+                self.drop_rest_of_log();
+                return false;
+            }
+            let physical_record = self.read_physical_record();
+            if self.stop_reason_.is_some() {
+                continue;
+            }
+            let fragment_size = match &physical_record {
+                Ok((fragment, _)) => fragment.size(),
+                Err(_) => 0,
+            };
+            // 读到文件末尾时该值没有意义，可能回绕
+            let physical_record_offset = self
+                .end_of_buffer_offset_
+                .wrapping_sub(self.buffer_.size() as u64)
+                .wrapping_sub(K_HEADER_SIZE as u64)
+                .wrapping_sub(fragment_size as u64);
+
+            if self.resyncing_ {
+                match &physical_record {
+                    Ok((_, t)) if *t == RecordType::KMiddleType as u8 => continue,
+                    Ok((_, t)) if *t == RecordType::KLastType as u8 => {
+                        self.resyncing_ = false;
+                        continue;
+                    }
+                    _ => self.resyncing_ = false,
+                }
+            }
 
-            let enum_value: RecordType = unsafe { std::mem::transmute(data_type) };
-            return Ok((res, enum_value));
+            match physical_record {
+                Ok((fragment, t)) if t == RecordType::KFullType as u8 => {
+                    if in_fragmented_record {
+                        self.report_corruption(fragmented_bytes, "partial record without end(1)");
+                        if self.stop_reason_.is_some() {
+                            continue;
+                        }
+                    }
+                    prospective_record_offset = physical_record_offset;
+                    scratch.clear();
+                    *record = fragment;
+                    self.last_record_offset_ = prospective_record_offset;
+                    self.last_record_end_offset_ =
+                        self.end_of_buffer_offset_ - self.buffer_.size() as u64;
+                    return true;
+                }
+                Ok((fragment, t)) if t == RecordType::KFirstType as u8 => {
+                    if in_fragmented_record {
+                        self.report_corruption(fragmented_bytes, "partial record without end(2)");
+                    }
+                    prospective_record_offset = physical_record_offset;
+                    scratch.clear();
+                    scratch.put_slice(fragment.data());
+                    fragmented_bytes = (K_HEADER_SIZE + fragment.size()) as u64;
+                    in_fragmented_record = true;
+                }
+                Ok((fragment, t)) if t == RecordType::KMiddleType as u8 => {
+                    if !in_fragmented_record {
+                        self.report_corruption(
+                            (K_HEADER_SIZE + fragment.size()) as u64,
+                            "missing start of fragmented record(1)",
+                        );
+                    } else {
+                        scratch.put_slice(fragment.data());
+                        fragmented_bytes += (K_HEADER_SIZE + fragment.size()) as u64;
+                    }
+                }
+                Ok((fragment, t)) if t == RecordType::KLastType as u8 => {
+                    if !in_fragmented_record {
+                        self.report_corruption(
+                            (K_HEADER_SIZE + fragment.size()) as u64,
+                            "missing start of fragmented record(2)",
+                        );
+                    } else {
+                        scratch.put_slice(fragment.data());
+                        *record = Slice::new_from_array(scratch);
+                        self.last_record_offset_ = prospective_record_offset;
+                        self.last_record_end_offset_ =
+                            self.end_of_buffer_offset_ - self.buffer_.size() as u64;
+                        return true;
+                    }
+                }
+                Ok((fragment, t)) => {
+                    let dropped = (K_HEADER_SIZE + fragment.size()) as u64
+                        + if in_fragmented_record {
+                            fragmented_bytes
+                        } else {
+                            0
+                        };
+                    self.report_corruption(dropped, &format!("unknown record type {}", t));
+                    in_fragmented_record = false;
+                    scratch.clear();
+                }
+                Err(ReadStatus::KEof) => {
+                    // 写入方可能在写完记录的最后一个分片之前崩溃
+                    if in_fragmented_record {
+                        self.report_truncated_tail(fragmented_bytes, "truncated fragmented record");
+                    }
+                    scratch.clear();
+                    self.drop_rest_of_log();
+                    return false;
+                }
+                Err(ReadStatus::KBadRecord) => {
+                    if in_fragmented_record {
+                        self.report_corruption(fragmented_bytes, "error in middle of record");
+                        in_fragmented_record = false;
+                        scratch.clear();
+                    }
+                }
+            }
         }
     }
 
-    fn read_record(&mut self, record: &mut Slice, scratch: &mut BytesMut) {
-        if self.last_record_offset_ < self.initial_offset_ as u64 {
-            if !self.skip_to_initial_block() {
-                return;
+    /// 上一条 read_record 返回的记录在文件中的物理偏移。
+    pub(crate) fn last_record_offset(&self) -> u64 {
+        self.last_record_offset_
+    }
+
+    /// 按 recovery mode 应当让恢复失败的第一个错误
+    pub(crate) fn status(&self) -> Status {
+        self.status_.clone()
+    }
+
+    /// 到目前为止丢弃的字节数
+    pub(crate) fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes_
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::log_writer::LogWriter;
+    use crate::util::writable_file::WritableFile;
+    use std::path::Path;
+
+    struct StringDest {
+        contents: BytesMut,
+    }
+
+    impl WritableFile for StringDest {
+        fn new<P: AsRef<Path>>(_filename: P, _truncate: bool) -> std::io::Result<Self> {
+            Ok(StringDest {
+                contents: BytesMut::new(),
+            })
+        }
+
+        fn append(&mut self, data: &Slice) -> Status {
+            self.contents.put_slice(data.data());
+            Status::ok()
+        }
+
+        fn flush(&mut self) -> Status {
+            Status::ok()
+        }
+
+        fn sync(&mut self) -> Status {
+            Status::ok()
+        }
+    }
+
+    struct StringSource {
+        contents: BytesMut,
+    }
+
+    impl SequentialFile for StringSource {
+        fn new<P: AsRef<Path>>(_filename: P) -> std::io::Result<Self> {
+            Ok(StringSource {
+                contents: BytesMut::new(),
+            })
+        }
+
+        fn read(&mut self, n: usize) -> Result<Slice, Status> {
+            let n = n.min(self.contents.len());
+            Ok(Slice::new_bytes_mut(self.contents.split_to(n)))
+        }
+
+        fn skip(&mut self, n: i64) -> Status {
+            if n as usize > self.contents.len() {
+                self.contents.clear();
+                return Status::not_found("in-memory file skipped past end", None);
             }
+            let _ = self.contents.split_to(n as usize);
+            Status::ok()
         }
-        scratch.clear();
-        record.clear();
-        let in_fragmented_record = false;
-        let prospective_record_offset = 0;
-        let fragment = Slice::new_from_empty();
-        loop { /* let record_type = self*/ }
+    }
+
+    #[derive(Clone, Default)]
+    struct ReportCollector {
+        dropped_bytes: Arc<Mutex<usize>>,
+    }
+
+    impl Reporter for ReportCollector {
+        fn corruption(&mut self, bytes: usize, _status: &Status) {
+            *self.dropped_bytes.lock().unwrap() += bytes;
+        }
+    }
+
+    fn big_string(partial: &str, n: usize) -> String {
+        partial.repeat(n / partial.len() + 1)[..n].to_string()
+    }
+
+    fn write_records(records: &[Slice]) -> BytesMut {
+        let dest = Arc::new(Mutex::new(StringDest {
+            contents: BytesMut::new(),
+        }));
+        let mut writer = LogWriter::new(dest.clone());
+        for record in records {
+            assert!(writer.add_record(record).is_ok());
+        }
+        let contents = dest.lock().unwrap().contents.clone();
+        contents
+    }
+
+    fn new_reader(contents: BytesMut, reporter: ReportCollector) -> Reader {
+        new_reader_with_mode(contents, reporter, WALRecoveryMode::SkipAnyCorruptedRecords)
+    }
+
+    fn new_reader_with_mode(
+        contents: BytesMut,
+        reporter: ReportCollector,
+        mode: WALRecoveryMode,
+    ) -> Reader {
+        let source: Arc<Mutex<dyn SequentialFile>> =
+            Arc::new(Mutex::new(StringSource { contents }));
+        Reader::new(source, Some(Box::new(reporter)), true, 0, mode)
+    }
+
+    // 读出所有记录，返回记录的长度、reader 的状态和报告的丢弃字节数
+    fn read_all(contents: BytesMut, mode: WALRecoveryMode) -> (Vec<usize>, Status, usize) {
+        let reporter = ReportCollector::default();
+        let mut reader = new_reader_with_mode(contents, reporter.clone(), mode);
+        let mut record = Slice::new_empty();
+        let mut scratch = BytesMut::new();
+        let mut records = Vec::new();
+        while reader.read_record(&mut record, &mut scratch) {
+            records.push(record.size());
+        }
+        // 读完之后不会再报告
+        assert!(!reader.read_record(&mut record, &mut scratch));
+        let dropped = *reporter.dropped_bytes.lock().unwrap();
+        assert_eq!(dropped as u64, reader.dropped_bytes());
+        (records, reader.status(), dropped)
+    }
+
+    const ALL_MODES: [WALRecoveryMode; 4] = [
+        WALRecoveryMode::TolerateCorruptedTailRecords,
+        WALRecoveryMode::AbsoluteConsistency,
+        WALRecoveryMode::PointInTimeRecovery,
+        WALRecoveryMode::SkipAnyCorruptedRecords,
+    ];
+
+    #[test]
+    fn test_read_write_fragmented() {
+        let records = vec![
+            Slice::from("small"),
+            Slice::new_from_string(big_string("medium", 50000)),
+            Slice::from(""),
+            Slice::new_from_string(big_string("large", 100000)),
+            Slice::from("tail"),
+        ];
+        let reporter = ReportCollector::default();
+        let mut reader = new_reader(write_records(&records), reporter.clone());
+        let mut record = Slice::new_empty();
+        let mut scratch = BytesMut::new();
+        for expected in records.iter() {
+            assert!(reader.read_record(&mut record, &mut scratch));
+            assert_eq!(expected.data(), record.data());
+        }
+        assert!(!reader.read_record(&mut record, &mut scratch));
+        assert_eq!(0, *reporter.dropped_bytes.lock().unwrap());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut contents = write_records(&[Slice::from("foo"), Slice::from("bar")]);
+        // 破坏第一条记录的数据
+        contents[K_HEADER_SIZE] ^= 0xff;
+        let reporter = ReportCollector::default();
+        let mut reader = new_reader(contents, reporter.clone());
+        let mut record = Slice::new_empty();
+        let mut scratch = BytesMut::new();
+        assert!(!reader.read_record(&mut record, &mut scratch));
+        assert!(*reporter.dropped_bytes.lock().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_recovery_mode_truncated_tail() {
+        // 最后一条记录跨越两个 block，写到一半时崩溃
+        let mut contents = write_records(&[
+            Slice::from("foo"),
+            Slice::new_from_string(big_string("large", 50000)),
+        ]);
+        let truncated = contents.len() - 100;
+        contents.truncate(truncated);
+        for mode in ALL_MODES {
+            let (records, status, dropped) = read_all(contents.clone(), mode);
+            assert_eq!(vec![3], records, "{:?}", mode);
+            assert_eq!(truncated - (K_HEADER_SIZE + 3), dropped, "{:?}", mode);
+            assert_eq!(
+                mode == WALRecoveryMode::AbsoluteConsistency,
+                status.is_corruption(),
+                "{:?}",
+                mode
+            );
+        }
+
+        // 文件末尾只有半个 header
+        let mut contents = write_records(&[Slice::from("foo"), Slice::from("bar")]);
+        contents.truncate(K_HEADER_SIZE + 3 + 4);
+        for mode in ALL_MODES {
+            let (records, status, dropped) = read_all(contents.clone(), mode);
+            assert_eq!(vec![3], records, "{:?}", mode);
+            assert_eq!(4, dropped, "{:?}", mode);
+            assert_eq!(
+                mode == WALRecoveryMode::AbsoluteConsistency,
+                status.is_corruption(),
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_recovery_mode_middle_corruption() {
+        // 第二条记录正好填满第一个 block，之后的记录在第二个 block 中
+        let first_block_filler = K_BLOCK_SIZE - 2 * K_HEADER_SIZE - 3;
+        let mut contents = write_records(&[
+            Slice::from("foo"),
+            Slice::new_from_string(big_string("x", first_block_filler)),
+            Slice::from("bar"),
+            Slice::from("baz"),
+        ]);
+        contents[2 * K_HEADER_SIZE + 3] ^= 0xff;
+        let total = contents.len();
+        for mode in ALL_MODES {
+            let (records, status, dropped) = read_all(contents.clone(), mode);
+            match mode {
+                WALRecoveryMode::PointInTimeRecovery => {
+                    // 损坏之后的记录都不要了
+                    assert_eq!(vec![3], records);
+                    assert_eq!(total - (K_HEADER_SIZE + 3), dropped);
+                    assert!(status.is_ok());
+                }
+                _ => {
+                    // 丢弃第一个 block 中损坏的部分，继续读第二个 block
+                    assert_eq!(vec![3, 3, 3], records, "{:?}", mode);
+                    assert_eq!(K_BLOCK_SIZE - K_HEADER_SIZE - 3, dropped, "{:?}", mode);
+                    assert_eq!(
+                        mode != WALRecoveryMode::SkipAnyCorruptedRecords,
+                        status.is_corruption(),
+                        "{:?}",
+                        mode
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_replay_version_edits() {
+        use crate::db::version_edit::VersionEdit;
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("leveldb.BytewiseComparator".to_string());
+        edit.set_log_number_(3);
+        edit.set_next_file_number_(4);
+        let mut encoded = BytesMut::new();
+        edit.encode_to(&mut encoded);
+
+        let record = Slice::new_bytes_mut(encoded);
+        let mut reader = new_reader(write_records(&[record]), ReportCollector::default());
+        let mut slice = Slice::new_empty();
+        let mut scratch = BytesMut::new();
+        assert!(reader.read_record(&mut slice, &mut scratch));
+        let mut decoded = VersionEdit::new();
+        assert!(decoded.decode_from(&slice).is_ok());
+        assert_eq!(edit.debug_string(), decoded.debug_string());
     }
 }
@@ -6,7 +6,7 @@ use crate::util::crc32c;
 use crate::util::writable_file::WritableFile;
 use std::sync::{Arc, Mutex};
 
-struct LogWriter {
+pub(crate) struct LogWriter {
     dest_: Arc<Mutex<dyn WritableFile>>,
     block_offset_: usize,
     type_crc_: [u32; K_MAX_RECORD_TYPE + 1],
@@ -63,10 +63,10 @@ impl LogWriter {
         s
     }
 
-    fn add_record(&mut self, slice: &Slice) -> Status {
+    pub(crate) fn add_record(&mut self, slice: &Slice) -> Status {
         let mut ptr = slice.data();
         let mut left = slice.size();
-        let s = Status::ok();
+        let mut s;
         let mut begin = true;
         loop {
             let leftover = K_BLOCK_SIZE as i64 - self.block_offset_ as i64;
@@ -94,12 +94,12 @@ impl LogWriter {
             } else {
                 RecordType::KMiddleType
             };
-            let s = self.emit_physical_record(record_type, &ptr[..fragment_length]);
+            s = self.emit_physical_record(record_type, &ptr[..fragment_length]);
             ptr = &ptr[fragment_length..];
             left -= fragment_length;
             begin = false;
 
-            if (!s.is_ok() || left <= 0) {
+            if !s.is_ok() || left == 0 {
                 break;
             }
         }
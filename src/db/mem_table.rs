@@ -1,17 +1,56 @@
-use crate::db::internal_key_comparator::{InternalKeyComparator, ValueType};
+use crate::db::internal_key::LookupKey;
+use crate::db::internal_key_comparator::{
+    extract_user_key, InternalKeyComparator, ValueType, K_MAX_SEQUENCE_NUMBER,
+};
 use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
+use crate::table::iterator::Iter;
 use crate::util::arena::Arena;
-use crate::util::coding::{encode_fixed64, encode_varint32, get_varint32ptr, varint_length};
+use crate::util::bytewise_comparator_impl::BytewiseComparatorImpl;
+use crate::util::coding::{
+    decode_fixed64, encode_fixed64, encode_varint32, get_varint32ptr, varint_length,
+};
+use crate::util::comparator::Comparator;
 use crossbeam_skiplist::SkipMap;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
 
-struct KeyComparator {
-    comparator: InternalKeyComparator,
+static KEY_CMP: InternalKeyComparator = InternalKeyComparator {
+    user_comparator_: BytewiseComparatorImpl {},
+};
+
+// SkipMap 的 key，指向 arena 中条目的 internal key 部分，
+// 按 user key 升序、序列号降序排列
+#[derive(Clone, Debug)]
+struct MemTableKey(Slice);
+
+impl PartialEq for MemTableKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MemTableKey {}
+
+impl PartialOrd for MemTableKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MemTableKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        KEY_CMP.compare(&self.0, &other.0)
+    }
 }
 
+/// 按 internal key 排序的多版本 memtable，同一个 user key 的每次写入都会保留。
 pub struct MemTable {
-    table: SkipMap<Slice, (u64, ValueType, Slice)>,
-    arena: Arena,
+    // value 是条目在 arena 中的完整编码
+    table: SkipMap<MemTableKey, Slice>,
+    // Bump 不是 Sync，分配时加锁，读者只访问已经插入 SkipMap 的条目
+    arena: Mutex<Arena>,
 }
 
 fn get_length_prefixed_slice(data: &[u8]) -> Slice {
@@ -20,54 +59,309 @@ fn get_length_prefixed_slice(data: &[u8]) -> Slice {
     Slice::new_from_ptr(&p[..(len as usize)])
 }
 
+// 条目编码中 internal key 之后的 value
+fn entry_value(entry: &Slice) -> Slice {
+    let data = entry.data();
+    let mut key_len = 0;
+    let key_ptr = get_varint32ptr(data, &mut key_len).unwrap();
+    get_length_prefixed_slice(&key_ptr[key_len as usize..])
+}
+
 impl MemTable {
-    fn add(&self, seq: u64, value_type: ValueType, key: &Slice, value: Option<&Slice>) {
-        match value_type {
-            ValueType::KTypeDeletion => {
-                self.table
-                    .insert(key.clone(), (seq, value_type, Slice::new_from_empty()));
-            }
+    pub(crate) fn new() -> Self {
+        MemTable {
+            table: SkipMap::new(),
+            arena: Mutex::new(Arena::new()),
+        }
+    }
+
+    // 条目格式：varint32(internal_key_size) | user_key | tag(seq << 8 | type) | varint32(value_size) | value
+    // key 和 value 都拷贝到 arena 中，调用方的 Slice 在返回后即可释放
+    pub(crate) fn add(&self, seq: u64, value_type: ValueType, key: &Slice, value: Option<&Slice>) {
+        let empty = Slice::new_from_empty();
+        let value = match value_type {
+            ValueType::KTypeDeletion => &empty,
             ValueType::KTypeValue => match value {
-                None => {}
-                Some(value) => {
-                    let key_size = key.len();
-                    let val_size = value.len();
-                    let internal_key_size = key_size + 8;
-                    let encode_len = varint_length(internal_key_size as u64) as usize
-                        + internal_key_size
-                        + varint_length(val_size as u64) as usize
-                        + val_size;
-                    let buf = self.arena.alloc_array::<u8>(encode_len);
-                    let mut p = encode_varint32(buf, internal_key_size as u32);
-                    p.copy_from_slice(key.data());
-                    p = &mut p[key_size..];
-                    encode_fixed64(p, seq << 8 | value_type as u64);
-                    p = &mut p[8..];
-                    p = encode_varint32(p, val_size as u32);
-                    p.copy_from_slice(value.data());
-                    self.table
-                        .insert(key.clone(), (seq, value_type, Slice::new_from_ptr(buf)));
-                }
+                None => return,
+                Some(value) => value,
             },
+        };
+        let key_size = key.len();
+        let val_size = value.len();
+        let internal_key_size = key_size + 8;
+        let encode_len = varint_length(internal_key_size as u64) as usize
+            + internal_key_size
+            + varint_length(val_size as u64) as usize
+            + val_size;
+        let arena = self.arena.lock().unwrap();
+        let buf = arena.alloc_array::<u8>(encode_len);
+        let key_offset = encode_len - encode_varint32(buf, internal_key_size as u32).len();
+        buf[key_offset..key_offset + key_size].copy_from_slice(key.data());
+        let mut p = &mut buf[key_offset + key_size..];
+        encode_fixed64(p, seq << 8 | value_type as u64);
+        p = encode_varint32(&mut p[8..], val_size as u32);
+        p[..val_size].copy_from_slice(value.data());
+        let internal_key = Slice::new_from_ptr(&buf[key_offset..key_offset + internal_key_size]);
+        self.table
+            .insert(MemTableKey(internal_key), Slice::new_from_ptr(buf));
+    }
+
+    /// memtable 占用的内存估计，用于判断是否需要把 memtable 写成 level-0 文件
+    pub(crate) fn approximate_memory_usage(&self) -> usize {
+        self.arena.lock().unwrap().memory_usage()
+    }
+
+    /// 条目个数，包括同一个 user key 的多个版本和删除标记
+    pub(crate) fn num_entries(&self) -> usize {
+        self.table.len()
+    }
+
+    /// user key 在 [start, limit) 中的条目编码的总字节数
+    pub(crate) fn approximate_range_size(&self, start: &Slice, limit: &Slice) -> u64 {
+        let start = LookupKey::new(start, K_MAX_SEQUENCE_NUMBER);
+        self.table
+            .range((
+                Bound::Included(MemTableKey(start.internal_key())),
+                Bound::Unbounded,
+            ))
+            .take_while(|e| extract_user_key(&e.key().0).compare(limit) == Ordering::Less)
+            .map(|e| e.value().len() as u64)
+            .sum()
+    }
+
+    pub(crate) fn new_iterator(self: &Arc<Self>) -> MemTableIterator {
+        MemTableIterator {
+            mem: self.clone(),
+            current: None,
         }
     }
 
-    fn get(&self, key: &Slice) -> Result<Slice, Status> {
-        match self.table.get(key) {
-            Some(v) => {
-                let (_seq, value_type, value) = v.value();
-                match value_type {
-                    ValueType::KTypeDeletion => Err(Status::not_found("not found", None)),
-                    _ => {
-                        let data = value.data();
-                        let mut key_len = 0;
-                        let key_ptr = get_varint32ptr(data, &mut key_len).unwrap();
-                        let v = get_length_prefixed_slice(&key_ptr[..key_len as usize]);
-                        Ok(v)
+    /// 查找 key 的序列号之前最新的一次写入。memtable 中没有该 user key 时返回 None，
+    /// 最新的写入是删除时返回 NotFound。
+    pub(crate) fn get(&self, key: &LookupKey) -> Option<Result<Slice, Status>> {
+        let entry = self
+            .table
+            .lower_bound(Bound::Included(&MemTableKey(key.internal_key())))?;
+        let internal_key = &entry.key().0;
+        if extract_user_key(internal_key).compare(&key.user_key()) != Ordering::Equal {
+            return None;
+        }
+        let tag = decode_fixed64(&internal_key.data()[internal_key.len() - 8..]);
+        match ValueType::try_from((tag & 0xff) as u8) {
+            Ok(ValueType::KTypeValue) => {
+                Some(Ok(Slice::new_from_array(entry_value(entry.value()).data())))
+            }
+            Ok(ValueType::KTypeDeletion) => Some(Err(Status::not_found("", None))),
+            Err(_) => None,
+        }
+    }
+}
+
+/// 按 key 顺序遍历 memtable，key() 返回 internal key。
+/// 返回的 Slice 指向 memtable 的 arena，只在 memtable 存活期间有效。
+pub(crate) struct MemTableIterator {
+    mem: Arc<MemTable>,
+    // 当前位置的 (internal key, 条目编码)
+    current: Option<(MemTableKey, Slice)>,
+}
+
+type MemTableEntry<'a> = crossbeam_skiplist::map::Entry<'a, MemTableKey, Slice>;
+
+fn entry_position(entry: Option<MemTableEntry>) -> Option<(MemTableKey, Slice)> {
+    entry.map(|e| (e.key().clone(), e.value().clone()))
+}
+
+impl Iter for MemTableIterator {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.current = entry_position(self.mem.table.front());
+    }
+
+    fn seek_to_last(&mut self) {
+        self.current = entry_position(self.mem.table.back());
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        let target = MemTableKey(target.clone());
+        self.current = entry_position(self.mem.table.lower_bound(Bound::Included(&target)));
+    }
+
+    fn next(&mut self) {
+        let (key, _) = self.current.take().unwrap();
+        self.current = entry_position(self.mem.table.lower_bound(Bound::Excluded(&key)));
+    }
+
+    fn prev(&mut self) {
+        let (key, _) = self.current.take().unwrap();
+        self.current = entry_position(self.mem.table.upper_bound(Bound::Excluded(&key)));
+    }
+
+    fn key(&self) -> Slice {
+        let (key, _) = self.current.as_ref().unwrap();
+        key.0.clone()
+    }
+
+    fn value(&self) -> Slice {
+        let (_, entry) = self.current.as_ref().unwrap();
+        entry_value(entry)
+    }
+
+    fn status(&self) -> Status {
+        Status::ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::internal_key::InternalKey;
+    use crate::db::internal_key_comparator::{parse_internal_key, ParsedInternalKey};
+
+    fn get(mem: &MemTable, key: &'static str, seq: u64) -> Option<Result<String, Status>> {
+        mem.get(&LookupKey::new(&Slice::from(key), seq))
+            .map(|r| r.map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn test_get_multi_version() {
+        let mem = MemTable::new();
+        mem.add(
+            1,
+            ValueType::KTypeValue,
+            &Slice::from("k"),
+            Some(&Slice::from("v1")),
+        );
+        mem.add(
+            2,
+            ValueType::KTypeValue,
+            &Slice::from("k"),
+            Some(&Slice::from("v2")),
+        );
+        mem.add(3, ValueType::KTypeDeletion, &Slice::from("k"), None);
+        mem.add(
+            4,
+            ValueType::KTypeValue,
+            &Slice::from("k"),
+            Some(&Slice::from("v4")),
+        );
+
+        assert!(get(&mem, "k", 0).is_none());
+        assert_eq!("v1", get(&mem, "k", 1).unwrap().unwrap());
+        assert_eq!("v2", get(&mem, "k", 2).unwrap().unwrap());
+        assert!(get(&mem, "k", 3).unwrap().unwrap_err().is_not_found());
+        assert_eq!("v4", get(&mem, "k", 100).unwrap().unwrap());
+        assert!(get(&mem, "j", 100).is_none());
+        assert!(get(&mem, "kk", 100).is_none());
+        assert!(mem.approximate_memory_usage() > 0);
+    }
+
+    #[test]
+    fn test_iterator_order() {
+        let mem = Arc::new(MemTable::new());
+        mem.add(
+            5,
+            ValueType::KTypeValue,
+            &Slice::from("b"),
+            Some(&Slice::from("b5")),
+        );
+        mem.add(
+            1,
+            ValueType::KTypeValue,
+            &Slice::from("a"),
+            Some(&Slice::from("a1")),
+        );
+        mem.add(7, ValueType::KTypeDeletion, &Slice::from("b"), None);
+        mem.add(
+            3,
+            ValueType::KTypeValue,
+            &Slice::from("c"),
+            Some(&Slice::from("c3")),
+        );
+        mem.add(
+            2,
+            ValueType::KTypeValue,
+            &Slice::from("a"),
+            Some(&Slice::from("a2")),
+        );
+
+        let describe = |iter: &MemTableIterator| {
+            let mut parsed = ParsedInternalKey {
+                user_key: Slice::new_empty(),
+                sequence: 0,
+                value_type: ValueType::KTypeValue,
+            };
+            assert!(parse_internal_key(&iter.key(), &mut parsed));
+            format!(
+                "{}@{}:{}",
+                parsed.user_key.to_string(),
+                parsed.sequence,
+                iter.value().to_string()
+            )
+        };
+
+        let mut iter = mem.new_iterator();
+        let mut forward = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(describe(&iter));
+            iter.next();
+        }
+        assert_eq!(
+            vec!["a@2:a2", "a@1:a1", "b@7:", "b@5:b5", "c@3:c3"],
+            forward
+        );
+
+        let mut backward = vec![];
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(describe(&iter));
+            iter.prev();
+        }
+        forward.reverse();
+        assert_eq!(forward, backward);
+
+        // 定位到序列号不大于 6 的第一个 b
+        let target = InternalKey::new(Slice::from("b"), 6, ValueType::KTypeValue);
+        iter.seek(&target.encode());
+        assert!(iter.valid());
+        assert_eq!("b@5:b5", describe(&iter));
+        let target = InternalKey::new(Slice::from("d"), 100, ValueType::KTypeValue);
+        iter.seek(&target.encode());
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_concurrent_add() {
+        let mem = Arc::new(MemTable::new());
+        let threads: Vec<_> = (0..4u64)
+            .map(|t| {
+                let mem = mem.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000u64 {
+                        let key = format!("{:02}{:06}", t, i);
+                        mem.add(
+                            t * 1000 + i + 1,
+                            ValueType::KTypeValue,
+                            &Slice::new_from_str(&key),
+                            Some(&Slice::new_from_str(&key)),
+                        );
                     }
-                }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(4000, mem.num_entries());
+        for t in 0..4u64 {
+            for i in (0..1000u64).step_by(97) {
+                let key = format!("{:02}{:06}", t, i);
+                let value = mem.get(&LookupKey::new(&Slice::new_from_str(&key), 10000));
+                assert_eq!(key, value.unwrap().unwrap().to_string());
             }
-            None => Err(Status::not_found("not found", None)),
         }
     }
 }
@@ -3,12 +3,20 @@ pub mod internal_key;
 mod internal_key_comparator;
 mod table_cache;
 
+mod builder;
+mod db_iter;
 pub mod db;
+#[cfg(test)]
+mod db_test_util;
 mod file_name;
 pub mod log_format;
 pub mod log_reader;
 pub mod log_writer;
 pub mod mem_table;
 mod read_options;
-mod write_options;
+pub mod repair;
+pub mod snapshot;
+pub mod write_batch;
+pub mod write_options;
 mod version_edit;
+mod version_set;
@@ -0,0 +1,565 @@
+use crate::db::builder::build_table;
+use crate::db::db::{check_comparator, sanitize_options, LogReporter};
+use crate::db::file_name::{
+    descriptor_file_name, log_file_name, parse_file_name, set_current_file, sst_table_file_name,
+    table_file_name, temp_file_name, FileType,
+};
+use crate::db::internal_key_comparator::{
+    parse_internal_key, InternalKeyComparator, ParsedInternalKey, ValueType,
+};
+use crate::db::log_reader::{Reader, Reporter};
+use crate::db::log_writer::LogWriter;
+use crate::db::mem_table::MemTable;
+use crate::db::table_cache::TableCache;
+use crate::db::version_edit::{FileMetaData, VersionEdit};
+use crate::db::write_batch::WriteBatch;
+use crate::obj::options::{Options, ReadOptions, WALRecoveryMode};
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::table_builder::TableBuilder;
+use crate::util::env::Env;
+use crate::util::sequential_file::{SequentialFile, StdSequentialFile};
+use crate::util::writable_file::{StdWritableFile, WritableFile};
+use bytes::BytesMut;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+// 修复时只会顺序打开少量 table
+const K_REPAIR_TABLE_CACHE_SIZE: usize = 10;
+
+struct TableInfo {
+    meta: FileMetaData,
+    max_sequence: u64,
+}
+
+// 在 MANIFEST 丢失或损坏时，根据目录中的日志和 table 重建数据库：
+// (1) 把所有日志转换成 table
+// (2) 扫描每个 table 得到 key 范围和最大序列号，无法读取的文件移到 lost/ 目录
+// (3) 写一个新的 MANIFEST，把所有 table 放在 level-0
+struct Repairer<E>
+where
+    E: Env,
+{
+    dbname: String,
+    env: Arc<E>,
+    icmp: InternalKeyComparator,
+    options: Arc<Options<E>>,
+    table_cache: Arc<TableCache<E>>,
+    edit: VersionEdit,
+
+    manifests: Vec<String>,
+    table_numbers: Vec<u64>,
+    logs: Vec<u64>,
+    tables: Vec<TableInfo>,
+    next_file_number: u64,
+}
+
+impl<E> Repairer<E>
+where
+    E: Env + 'static,
+{
+    fn new(dbname: String, options: Arc<Options<E>>) -> Self {
+        let options = Arc::new(sanitize_options(&options));
+        let table_cache = Arc::new(TableCache::new(
+            dbname.clone(),
+            options.clone(),
+            NonZeroUsize::new(K_REPAIR_TABLE_CACHE_SIZE).unwrap(),
+        ));
+        Repairer {
+            dbname,
+            env: options.env.clone(),
+            icmp: InternalKeyComparator::new(),
+            options,
+            table_cache,
+            edit: VersionEdit::new(),
+            manifests: Vec::new(),
+            table_numbers: Vec::new(),
+            logs: Vec::new(),
+            tables: Vec::new(),
+            next_file_number: 1,
+        }
+    }
+
+    fn run(&mut self) -> Status {
+        let mut status = self.find_files();
+        if status.is_ok() {
+            self.convert_log_files_to_tables();
+            self.extract_meta_data();
+            status = self.write_descriptor();
+        }
+        if status.is_ok() {
+            let bytes: u64 = self.tables.iter().map(|t| t.meta.file_size).sum();
+            warn!(
+                "**** Repaired leveldb {}; recovered {} files; {} bytes. \
+                 Some data may have been lost. ****",
+                self.dbname,
+                self.tables.len(),
+                bytes
+            );
+        }
+        status
+    }
+
+    fn find_files(&mut self) -> Status {
+        let filenames = match self.env.get_children(&self.dbname) {
+            Ok(filenames) => filenames,
+            Err(e) => return e,
+        };
+        if filenames.is_empty() {
+            return Status::io_error(&self.dbname, Some("repair found no files"));
+        }
+
+        for filename in filenames {
+            if let Some((number, file_type)) = parse_file_name(&filename) {
+                if file_type == FileType::DescriptorFile {
+                    self.manifests.push(filename);
+                } else {
+                    if number + 1 > self.next_file_number {
+                        self.next_file_number = number + 1;
+                    }
+                    match file_type {
+                        FileType::LogFile => self.logs.push(number),
+                        FileType::TableFile => self.table_numbers.push(number),
+                        // 其它文件不需要处理
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Status::ok()
+    }
+
+    fn convert_log_files_to_tables(&mut self) {
+        for log in self.logs.clone() {
+            let logname = log_file_name(&self.dbname, log);
+            let status = self.convert_log_to_table(log);
+            if !status.is_ok() {
+                warn!("Log #{}: ignoring conversion error: {}", log, status);
+            }
+            self.archive_file(&logname);
+        }
+    }
+
+    fn convert_log_to_table(&mut self, log: u64) -> Status {
+        let logname = log_file_name(&self.dbname, log);
+        let file: Arc<Mutex<dyn SequentialFile>> = match self
+            .env
+            .new_sequential_file::<StdSequentialFile, _>(&logname)
+        {
+            Ok(file) => Arc::new(Mutex::new(file)),
+            Err(e) => return e,
+        };
+
+        // 校验 checksum，让损坏的记录整个被跳过，而不是把错误的数据（比如过大的序列号）带进数据库
+        let mut reporter = LogReporter {
+            fname: logname.clone(),
+        };
+        let mut reader = Reader::new(
+            file,
+            Some(Box::new(LogReporter { fname: logname })),
+            true,
+            0,
+            WALRecoveryMode::SkipAnyCorruptedRecords,
+        );
+
+        let mut scratch = BytesMut::new();
+        let mut record = Slice::new_empty();
+        let mut batch = WriteBatch::new();
+        let mem = Arc::new(MemTable::new());
+        let mut counter = 0;
+        while reader.read_record(&mut record, &mut scratch) {
+            if record.size() < 12 {
+                reporter.corruption(
+                    record.size(),
+                    &Status::corruption("log record too small", None),
+                );
+                continue;
+            }
+            batch.set_contents(&record);
+            let status = batch.insert_into(&mem);
+            if status.is_ok() {
+                counter += batch.count();
+            } else {
+                warn!("Log #{}: ignoring {}", log, status);
+            }
+        }
+        drop(reader);
+
+        // 这里不记录 edit，extract_meta_data 会扫描生成的 table
+        let mut meta = FileMetaData::new();
+        meta.number = self.next_file_number;
+        self.next_file_number += 1;
+        let mut iter = mem.new_iterator();
+        let status = build_table(
+            &self.dbname,
+            self.env.as_ref(),
+            self.options.clone(),
+            &self.table_cache,
+            &mut iter,
+            &mut meta,
+        );
+        if status.is_ok() && meta.file_size > 0 {
+            self.table_numbers.push(meta.number);
+        }
+        info!(
+            "Log #{}: {} ops saved to Table #{} {}",
+            log, counter, meta.number, status
+        );
+        status
+    }
+
+    fn extract_meta_data(&mut self) {
+        for number in self.table_numbers.clone() {
+            self.scan_table(number);
+        }
+    }
+
+    fn scan_table(&mut self, number: u64) {
+        let mut t = TableInfo {
+            meta: FileMetaData::new(),
+            max_sequence: 0,
+        };
+        t.meta.number = number;
+        let fname = table_file_name(&self.dbname, number);
+        let file_size = match self.env.get_file_size(&fname) {
+            Ok(size) => size,
+            // 兼容旧版本的 .sst 后缀
+            Err(e) => match self
+                .env
+                .get_file_size(sst_table_file_name(&self.dbname, number))
+            {
+                Ok(size) => size,
+                Err(_) => {
+                    self.archive_file(&fname);
+                    info!("Table #{}: dropped: {}", number, e);
+                    return;
+                }
+            },
+        };
+        t.meta.file_size = file_size;
+
+        // 校验每个 block，跳过损坏的 block
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let mut iter = self.table_cache.new_iterator(&options, number, file_size);
+        let mut empty = true;
+        let mut counter = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            let mut parsed = ParsedInternalKey {
+                user_key: Slice::new_empty(),
+                sequence: 0,
+                value_type: ValueType::KTypeValue,
+            };
+            if !parse_internal_key(&key, &mut parsed) {
+                info!("Table #{}: unparsable key {}", number, key.to_string());
+                iter.next();
+                continue;
+            }
+
+            counter += 1;
+            if empty {
+                empty = false;
+                t.meta.smallest.decode_from(&key);
+            }
+            t.meta.largest.decode_from(&key);
+            if parsed.sequence > t.max_sequence {
+                t.max_sequence = parsed.sequence;
+            }
+            iter.next();
+        }
+        let status = iter.status();
+        drop(iter);
+        info!("Table #{}: {} entries {}", number, counter, status);
+
+        if status.is_ok() {
+            self.tables.push(t);
+        } else {
+            // 只保留能读出来的数据
+            self.repair_table(&fname, t);
+        }
+    }
+
+    fn repair_table(&mut self, src: &String, mut t: TableInfo) {
+        // 把能读出来的 entry 拷贝到新文件中
+        let copy = table_file_name(&self.dbname, self.next_file_number);
+        self.next_file_number += 1;
+        let file: Arc<Mutex<dyn WritableFile>> =
+            match self.env.new_writable_file::<StdWritableFile, _>(&copy) {
+                Ok(file) => Arc::new(Mutex::new(file)),
+                Err(_) => return,
+            };
+        let mut builder = TableBuilder::new(self.options.clone(), file.clone());
+
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let mut iter = self
+            .table_cache
+            .new_iterator(&options, t.meta.number, t.meta.file_size);
+        let mut counter = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            builder.add(&iter.key(), &iter.value());
+            counter += 1;
+            iter.next();
+        }
+        drop(iter);
+        // 原文件不能再被打开
+        self.table_cache.evict(t.meta.number);
+
+        self.archive_file(src);
+        let mut s = Status::ok();
+        if counter == 0 {
+            // 什么也没有恢复出来
+            builder.abandon();
+        } else {
+            s = builder.finish();
+            if s.is_ok() {
+                t.meta.file_size = builder.file_size();
+            }
+        }
+        drop(builder);
+        if s.is_ok() {
+            s = file.lock().unwrap().sync();
+        }
+        drop(file);
+
+        if counter > 0 && s.is_ok() {
+            let orig = table_file_name(&self.dbname, t.meta.number);
+            s = self.env.rename_file(&copy, &orig);
+            if s.is_ok() {
+                info!("Table #{}: {} entries repaired", t.meta.number, counter);
+                self.tables.push(t);
+            }
+        }
+        if counter == 0 || !s.is_ok() {
+            self.env.remove_file(&copy);
+        }
+    }
+
+    fn write_descriptor(&mut self) -> Status {
+        let tmp = temp_file_name(&self.dbname, 1);
+        let file: Arc<Mutex<dyn WritableFile>> =
+            match self.env.new_writable_file::<StdWritableFile, _>(&tmp) {
+                Ok(file) => Arc::new(Mutex::new(file)),
+                Err(e) => return e,
+            };
+
+        let max_sequence = self
+            .tables
+            .iter()
+            .map(|t| t.max_sequence)
+            .max()
+            .unwrap_or(0);
+        self.edit
+            .set_comparator_name(self.icmp.user_comparator().name().to_string());
+        self.edit.set_log_number_(0);
+        self.edit.set_next_file_number_(self.next_file_number);
+        self.edit.set_last_sequence_(max_sequence);
+        for t in self.tables.iter() {
+            // 所有 table 都放在 level-0，交给之后的 compaction 整理
+            self.edit.add_file(
+                0,
+                t.meta.number,
+                t.meta.file_size,
+                t.meta.smallest.clone(),
+                t.meta.largest.clone(),
+            );
+        }
+
+        let mut status = {
+            let mut log = LogWriter::new(file.clone());
+            let mut record = BytesMut::new();
+            self.edit.encode_to(&mut record);
+            log.add_record(&Slice::new_from_ptr(&record))
+        };
+        if status.is_ok() {
+            status = file.lock().unwrap().sync();
+        }
+        drop(file);
+
+        if !status.is_ok() {
+            self.env.remove_file(&tmp);
+        } else {
+            // 丢弃旧的 MANIFEST
+            for manifest in self.manifests.clone() {
+                self.archive_file(&format!("{}/{}", self.dbname, manifest));
+            }
+
+            // 使用新的 MANIFEST
+            status = self
+                .env
+                .rename_file(&tmp, &descriptor_file_name(&self.dbname, 1));
+            if status.is_ok() {
+                status = set_current_file(self.env.as_ref(), &self.dbname, 1);
+            } else {
+                self.env.remove_file(&tmp);
+            }
+        }
+        status
+    }
+
+    // 把文件移到同目录下的 lost/ 中，例如 dir/foo 移到 dir/lost/foo
+    fn archive_file(&self, fname: &String) {
+        let (dir, base) = match fname.rfind('/') {
+            Some(slash) => (&fname[..slash + 1], &fname[slash + 1..]),
+            None => ("", fname.as_str()),
+        };
+        let new_dir = format!("{}lost", dir);
+        // 目录可能已经存在，忽略错误
+        self.env.create_dir(&new_dir);
+        let new_file = format!("{}/{}", new_dir, base);
+        let s = self.env.rename_file(fname, &new_file);
+        info!("Archiving {}: {}", fname, s);
+    }
+}
+
+/// 尽可能恢复 MANIFEST 丢失或损坏的数据库：日志转换成 table，所有 table 放在 level-0，
+/// 无法读取的文件移到 lost/ 目录。修复后可能会丢失部分数据，也可能重新出现已经删除的数据
+pub fn repair_db<E: Env + 'static>(dbname: &str, options: Arc<Options<E>>) -> Status {
+    let s = check_comparator(&options);
+    if !s.is_ok() {
+        return s;
+    }
+    let mut repairer = Repairer::new(dbname.to_string(), options);
+    repairer.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::{DBImpl, DB};
+    use crate::db::db_test_util::{test_db_name, test_options};
+    use crate::db::write_options::WriteOptions;
+    use crate::obj::options::CompressionType;
+    use crate::util::env::StdEnv;
+
+    fn key(i: usize) -> Slice {
+        Slice::new_from_string(format!("key{:06}", i))
+    }
+
+    fn files_of_type(env: &StdEnv, dir: &str, file_type: FileType) -> Vec<u64> {
+        env.get_children(dir)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|f| parse_file_name(f).filter(|(_, t)| *t == file_type))
+            .map(|(number, _)| number)
+            .collect()
+    }
+
+    // 返回 (key, value) 的个数，并检查 value 是否与 check 的结果一致
+    fn count_entries(db: &DBImpl<StdEnv>, check: impl Fn(&Slice, &Slice)) -> usize {
+        let mut iter = db.new_iterator(&ReadOptions::new());
+        let mut count = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            check(&iter.key(), &iter.value());
+            count += 1;
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        count
+    }
+
+    #[test]
+    fn test_repair_lost_manifest() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "repair_lost_manifest");
+        let options = Arc::new(test_options(env.clone()));
+        let write_options = WriteOptions::default();
+        let value = |i: usize| Slice::new_from_string(format!("v{}", i));
+        {
+            let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+            for i in 0..100 {
+                assert!(db.put(&write_options, &key(i), &value(i)).is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+            // 这些写入只在日志中
+            for i in 100..150 {
+                assert!(db.put(&write_options, &key(i), &value(i)).is_ok());
+            }
+            assert!(db.delete(&write_options, &key(0)).is_ok());
+        }
+        let logs = files_of_type(&env, &dbname, FileType::LogFile);
+        let manifests = files_of_type(&env, &dbname, FileType::DescriptorFile);
+        assert_eq!(1, logs.len());
+        env.remove_file(format!("{}/CURRENT", dbname));
+        for number in manifests.iter() {
+            env.remove_file(descriptor_file_name(&dbname, *number));
+        }
+        let mut no_create = test_options(env.clone());
+        no_create.create_if_missing = false;
+        assert!(DBImpl::open(Arc::new(no_create), dbname.clone()).is_err());
+
+        assert!(repair_db(&dbname, options.clone()).is_ok());
+        // 日志转换成 table 后被移到 lost/
+        let lost = format!("{}/lost", dbname);
+        assert_eq!(logs, files_of_type(&env, &lost, FileType::LogFile));
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let mut num_files = String::new();
+        assert!(db.get_property(&Slice::from("leveldb.num-files-at-level0"), &mut num_files));
+        assert_eq!("2", num_files);
+        let count = count_entries(&db, |k, v| {
+            let i: usize = k.to_string()[3..].parse().unwrap();
+            assert_eq!(value(i).to_string(), v.to_string());
+        });
+        assert_eq!(149, count);
+        assert!(db
+            .get(&ReadOptions::new(), &key(0))
+            .unwrap_err()
+            .is_not_found());
+
+        // 恢复出的序列号保证新的写入覆盖旧的数据
+        assert!(db.put(&write_options, &key(1), &Slice::from("new")).is_ok());
+        assert_eq!(
+            "new",
+            db.get(&ReadOptions::new(), &key(1)).unwrap().to_string()
+        );
+        drop(db);
+    }
+
+    #[test]
+    fn test_repair_corrupted_table() {
+        let env = Arc::new(StdEnv::new());
+        let dbname = test_db_name(&env, "repair_corrupted_table");
+        let mut options = test_options(env.clone());
+        options.compression = CompressionType::None;
+        let options = Arc::new(options);
+        let write_options = WriteOptions::default();
+        let value = |i: usize| Slice::new_from_vec(vec![b'a' + (i % 26) as u8; 1000]);
+        {
+            let db = DBImpl::open(options.clone(), dbname.clone()).unwrap();
+            for i in 0..100 {
+                assert!(db.put(&write_options, &key(i), &value(i)).is_ok());
+            }
+            assert!(db.test_compact_mem_table().is_ok());
+        }
+        let tables = files_of_type(&env, &dbname, FileType::TableFile);
+        assert_eq!(1, tables.len());
+
+        // 破坏中间的一个 data block
+        let fname = table_file_name(&dbname, tables[0]);
+        let mut contents = std::fs::read(&fname).unwrap();
+        for b in contents[10000..10010].iter_mut() {
+            *b ^= 0xff;
+        }
+        std::fs::write(&fname, contents).unwrap();
+
+        assert!(repair_db(&dbname, options.clone()).is_ok());
+        // 原文件移到 lost/，能读出来的数据写到同名的新文件中
+        let lost = format!("{}/lost", dbname);
+        assert_eq!(tables, files_of_type(&env, &lost, FileType::TableFile));
+        assert_eq!(tables, files_of_type(&env, &dbname, FileType::TableFile));
+
+        let db = DBImpl::open(options, dbname.clone()).unwrap();
+        let count = count_entries(&db, |k, v| {
+            let i: usize = k.to_string()[3..].parse().unwrap();
+            assert_eq!(value(i).data(), v.data());
+        });
+        assert!(count > 0 && count < 100, "{}", count);
+        drop(db);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// 数据库在某一时刻的只读视图，通过 `DB::get_snapshot` 获得，用完后交给 `DB::release_snapshot`
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence_number: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+}
+
+/// 所有存活的快照，按序列号从旧到新排列
+pub(crate) struct SnapshotList {
+    list: VecDeque<Arc<Snapshot>>,
+}
+
+impl SnapshotList {
+    pub(crate) fn new() -> Self {
+        SnapshotList {
+            list: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub(crate) fn oldest(&self) -> Option<&Arc<Snapshot>> {
+        self.list.front()
+    }
+
+    pub(crate) fn newest(&self) -> Option<&Arc<Snapshot>> {
+        self.list.back()
+    }
+
+    /// 新快照的序列号不能小于已有的快照
+    pub(crate) fn new_snapshot(&mut self, sequence_number: u64) -> Arc<Snapshot> {
+        debug_assert!(self
+            .newest()
+            .is_none_or(|s| s.sequence_number <= sequence_number));
+        let snapshot = Arc::new(Snapshot { sequence_number });
+        self.list.push_back(snapshot.clone());
+        snapshot
+    }
+
+    pub(crate) fn delete(&mut self, snapshot: &Arc<Snapshot>) {
+        if let Some(pos) = self.list.iter().position(|s| Arc::ptr_eq(s, snapshot)) {
+            self.list.remove(pos);
+        } else {
+            debug_assert!(false, "snapshot not in list");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let mut list = SnapshotList::new();
+        assert!(list.is_empty());
+        let s1 = list.new_snapshot(5);
+        let s2 = list.new_snapshot(5);
+        let s3 = list.new_snapshot(9);
+        assert_eq!(5, list.oldest().unwrap().sequence_number());
+        assert_eq!(9, list.newest().unwrap().sequence_number());
+
+        // 相同序列号的快照互不影响
+        list.delete(&s1);
+        assert!(Arc::ptr_eq(&s2, list.oldest().unwrap()));
+        list.delete(&s3);
+        assert_eq!(5, list.newest().unwrap().sequence_number());
+        list.delete(&s2);
+        assert!(list.is_empty());
+    }
+}
@@ -2,8 +2,9 @@ use crate::db::file_name::{sst_table_file_name, table_file_name};
 use crate::obj::options::{Options, ReadOptions};
 use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
+use crate::table::iterator::{new_error_iterator, Iter};
 use crate::table::table::{HandleResult, Table};
-use crate::util::cache::ShardedLRUCache;
+use crate::util::cache::{new_cache, Cache};
 use crate::util::coding::encode_fixed64;
 use crate::util::env::Env;
 use crate::util::random_access_file::RandomAccessFile;
@@ -38,7 +39,7 @@ where
     env_: Arc<E>,
     db_name: String,
     options: Arc<Options<E>>,
-    cache_: ShardedLRUCache<Slice, TableAndFile<E>>,
+    cache_: Arc<dyn Cache<Slice, TableAndFile<E>>>,
 }
 
 impl<'a, E> TableCache<E>
@@ -49,61 +50,101 @@ where
         TableCache {
             env_: options.env.clone(),
             db_name,
+            cache_: new_cache(options.cache_type, entries),
             options,
-            cache_: ShardedLRUCache::<Slice, TableAndFile<E>>::new(entries),
         }
     }
 
-    fn find_table(
-        &mut self,
+    pub(crate) fn find_table(
+        &self,
         file_number: u64,
-        file_size: usize,
-    ) -> Result<TableAndFile<E>, Status> {
+        file_size: u64,
+    ) -> Result<Arc<Table<E>>, Status> {
         let mut buf = [0; size_of::<u64>()];
         encode_fixed64(&mut buf, file_number);
         let key = Slice::new_from_ptr(buf.as_ref());
-        match self.cache_.get(&key) {
-            Some(table_and_file) => {
-                let res = table_and_file.value().clone();
-                Ok(res)
-            }
-            None => {
-                let file_name = table_file_name(&self.db_name, file_number);
-                let mut file = self.env_.new_random_access_file(file_name);
-                if file.is_err() {
-                    let old_filename = sst_table_file_name(&self.db_name, file_number);
-                    file = self.env_.new_random_access_file(old_filename);
-                    if file.is_err() {
-                        return Err(file.err().unwrap());
-                    }
+        if let Some(table_and_file) = self.cache_.get(&key) {
+            return Ok(table_and_file.value().table.clone());
+        }
+        let file_name = table_file_name(&self.db_name, file_number);
+        let file = match self.env_.new_random_access_file(file_name) {
+            Ok(file) => file,
+            Err(e) => {
+                // 兼容旧版本的 .sst 后缀
+                let old_filename = sst_table_file_name(&self.db_name, file_number);
+                match self.env_.new_random_access_file(old_filename) {
+                    Ok(file) => file,
+                    Err(_) => return Err(e),
                 }
-                let s = Table::open(self.options.clone(), file.clone()?, file_size as u64)?;
-                let tf = TableAndFile {
-                    random_access_file: file?,
-                    table: s,
-                };
-                self.cache_.insert(&key, tf.clone());
-                Ok(tf)
             }
+        };
+        // 打开失败不缓存，这样修复文件后可以自动恢复
+        let table = Table::open(self.options.clone(), file.clone(), file_size)?;
+        let tf = TableAndFile {
+            random_access_file: file,
+            table: table.clone(),
+        };
+        // 缓存中的 key 需要拥有自己的数据
+        // 容量按打开的文件个数计算，每个 table 占用 1
+        self.cache_.insert(&Slice::new_from_array(&buf), tf, 1);
+        Ok(table)
+    }
+
+    /// 返回指定 table 的迭代器，打开失败时返回带错误状态的空迭代器
+    pub(crate) fn new_iterator(
+        &self,
+        options: &ReadOptions,
+        file_number: u64,
+        file_size: u64,
+    ) -> Box<dyn Iter> {
+        match self.find_table(file_number, file_size) {
+            Ok(table) => table.new_iterator(options.clone()),
+            Err(s) => Box::new(new_error_iterator(s)),
         }
     }
 
-    fn get(
-        &mut self,
+    /// 在指定的 table 中查找 k，找到的 entry 交给 handle_result 处理
+    pub(crate) fn get(
+        &self,
         options: &ReadOptions,
         file_number: u64,
         file_size: u64,
-        K: &Slice,
+        k: &Slice,
         arg: Box<dyn Any>,
         handle_result: HandleResult,
-    ) -> Result<(), Status> {
-        let table_file = self.find_table(file_number, file_size as usize)?;
-        let table = table_file.table;
-        let s = table.internal_get(options, K, arg, handle_result);
-        Ok(())
+    ) -> Status {
+        match self.find_table(file_number, file_size) {
+            Ok(table) => table.internal_get(options, k, arg, handle_result),
+            Err(s) => s,
+        }
+    }
+
+    /// 估计指定 table 中的 entry 个数，打开失败时返回 0
+    pub(crate) fn approximate_num_entries(&self, file_number: u64, file_size: u64) -> u64 {
+        self.find_table(file_number, file_size)
+            .map_or(0, |table| table.approximate_num_entries())
+    }
+
+    /// key 在指定 table 中的大致文件偏移，打开失败时返回 0
+    pub(crate) fn approximate_offset_of(
+        &self,
+        file_number: u64,
+        file_size: u64,
+        key: &Slice,
+    ) -> u64 {
+        self.find_table(file_number, file_size)
+            .map_or(0, |table| table.approximate_offset_of(key))
+    }
+
+    /// 指定 table 的 filter 统计：(省去读取的次数, 误判的次数)，打开失败时返回 (0, 0)
+    pub(crate) fn filter_stats(&self, file_number: u64, file_size: u64) -> (u64, u64) {
+        self.find_table(file_number, file_size)
+            .map_or((0, 0), |table| {
+                (table.filter_useful(), table.filter_false_positive())
+            })
     }
 
-    fn evict(&mut self, file_number: u64) {
+    pub(crate) fn evict(&self, file_number: u64) {
         let mut buf = [0; size_of::<u64>()];
         encode_fixed64(&mut buf, file_number);
         let key = Slice::new_from_ptr(buf.as_ref());
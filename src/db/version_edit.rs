@@ -1,35 +1,103 @@
 use crate::db::internal_key::InternalKey;
-use ahash::HashSet;
-
-struct FileMetaData {
-    refs: i32,
-    allowed_seeks: i32,
-    number: u64,
-    file_size: u64,
-    smallest: InternalKey,
-    largest: InternalKey,
+use crate::db::internal_key_comparator::K_NUM_LEVELS;
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::util::coding::{
+    get_length_prefixed_slice, get_varint32, get_varint64, put_length_prefixed_slice, put_varint32,
+    put_varint64,
+};
+use bytes::BytesMut;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// manifest 中每个字段的 tag，数值与 LevelDB 保持一致以兼容其 manifest 格式
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+enum Tag {
+    KComparator = 1,
+    KLogNumber = 2,
+    KNextFileNumber = 3,
+    KLastSequence = 4,
+    KCompactPointer = 5,
+    KDeletedFile = 6,
+    KNewFile = 7,
+    // 8 曾经用于 large value refs
+    KPrevLogNumber = 9,
+}
+
+#[derive(Debug)]
+pub(crate) struct FileMetaData {
+    pub(crate) refs: i32,
+    // 读路径只持有 Arc<FileMetaData>，所以用原子变量扣减
+    pub(crate) allowed_seeks: AtomicI32,
+    pub(crate) number: u64,
+    pub(crate) file_size: u64,
+    pub(crate) smallest: InternalKey,
+    pub(crate) largest: InternalKey,
+}
+
+impl FileMetaData {
+    pub(crate) fn new() -> Self {
+        FileMetaData {
+            refs: 0,
+            // 每次查找未命中都会减少，耗尽时该文件需要 compaction
+            allowed_seeks: AtomicI32::new(1 << 30),
+            number: 0,
+            file_size: 0,
+            smallest: InternalKey::default(),
+            largest: InternalKey::default(),
+        }
+    }
 }
 
-struct VersionEdit {
-    comparator_: String,
-    log_number_: u64,
-    prev_log_number_: u64,
-    next_file_number_: u64,
-    last_sequence_: u64,
-    has_comparator_: bool,
-    has_log_number_: bool,
-    has_prev_log_number_: bool,
-    has_next_file_number_: bool,
-    has_last_sequence_: bool,
-    compact_pointers_: Vec<(i32, InternalKey)>,
-    deleted_files: HashSet<(i32, u64)>,
-    new_file: Vec<(i32, FileMetaData)>,
+impl Clone for FileMetaData {
+    fn clone(&self) -> Self {
+        FileMetaData {
+            refs: self.refs,
+            allowed_seeks: AtomicI32::new(self.allowed_seeks.load(Ordering::Relaxed)),
+            number: self.number,
+            file_size: self.file_size,
+            smallest: self.smallest.clone(),
+            largest: self.largest.clone(),
+        }
+    }
+}
+
+pub(crate) struct VersionEdit {
+    pub(crate) comparator_: String,
+    pub(crate) log_number_: u64,
+    pub(crate) prev_log_number_: u64,
+    pub(crate) next_file_number_: u64,
+    pub(crate) last_sequence_: u64,
+    pub(crate) has_comparator_: bool,
+    pub(crate) has_log_number_: bool,
+    pub(crate) has_prev_log_number_: bool,
+    pub(crate) has_next_file_number_: bool,
+    pub(crate) has_last_sequence_: bool,
+    pub(crate) compact_pointers_: Vec<(i32, InternalKey)>,
+    pub(crate) deleted_files: BTreeSet<(i32, u64)>,
+    pub(crate) new_file: Vec<(i32, FileMetaData)>,
 }
 
+fn get_internal_key(input: &mut Slice, dst: &mut InternalKey) -> bool {
+    let mut s = Slice::new_empty();
+    get_length_prefixed_slice(input, &mut s) && dst.decode_from(&s)
+}
+
+fn get_level(input: &mut Slice, level: &mut i32) -> bool {
+    let mut v = 0u32;
+    if get_varint32(input, &mut v) && (v as usize) < K_NUM_LEVELS {
+        *level = v as i32;
+        true
+    } else {
+        false
+    }
+}
 
 impl VersionEdit {
     pub fn new() -> Self {
-      VersionEdit{
+        VersionEdit {
             comparator_: "".to_string(),
             log_number_: 0,
             prev_log_number_: 0,
@@ -41,7 +109,7 @@ impl VersionEdit {
             has_next_file_number_: false,
             has_last_sequence_: false,
             compact_pointers_: vec![],
-            deleted_files: HashSet::default(),
+            deleted_files: BTreeSet::new(),
             new_file: vec![],
         }
     }
@@ -62,9 +130,8 @@ impl VersionEdit {
         self.new_file.clear();
     }
 
-
-    pub fn set_comparator_name(&mut self, name:String) {
-       self.has_comparator_ = true;
+    pub fn set_comparator_name(&mut self, name: String) {
+        self.has_comparator_ = true;
         self.comparator_ = name;
     }
 
@@ -74,7 +141,7 @@ impl VersionEdit {
     }
 
     pub fn set_prev_log_number_(&mut self, prev_log_number_: u64) {
-        self.has_prev_log_number_= true;
+        self.has_prev_log_number_ = true;
         self.prev_log_number_ = prev_log_number_;
     }
 
@@ -88,14 +155,21 @@ impl VersionEdit {
         self.last_sequence_ = last_sequence_;
     }
 
-    pub fn set_compact_pointers_(&mut self,level:i32,  key:InternalKey) {
+    pub fn set_compact_pointers_(&mut self, level: i32, key: InternalKey) {
         self.compact_pointers_.push((level, key))
     }
 
-    pub fn add_file(&mut self, level:i32, file:u64, file_size:u64, smallest:InternalKey, largest:InternalKey) {
-        let f = FileMetaData{
+    pub fn add_file(
+        &mut self,
+        level: i32,
+        file: u64,
+        file_size: u64,
+        smallest: InternalKey,
+        largest: InternalKey,
+    ) {
+        let f = FileMetaData {
             refs: 0,
-            allowed_seeks: 0,
+            allowed_seeks: AtomicI32::new(0),
             number: file,
             file_size,
             smallest,
@@ -104,7 +178,289 @@ impl VersionEdit {
         self.new_file.push((level, f))
     }
 
-    pub fn remove_file(&mut self, level:i32, file:u64) {
+    pub fn remove_file(&mut self, level: i32, file: u64) {
         self.deleted_files.insert((level, file));
     }
-}
\ No newline at end of file
+
+    /// 序列化为一条 manifest 记录，追加到 dst 末尾
+    pub(crate) fn encode_to(&self, dst: &mut BytesMut) {
+        if self.has_comparator_ {
+            put_varint32(dst, Tag::KComparator as u32);
+            put_length_prefixed_slice(dst, Slice::new_from_str(&self.comparator_));
+        }
+        if self.has_log_number_ {
+            put_varint32(dst, Tag::KLogNumber as u32);
+            put_varint64(dst, self.log_number_);
+        }
+        if self.has_prev_log_number_ {
+            put_varint32(dst, Tag::KPrevLogNumber as u32);
+            put_varint64(dst, self.prev_log_number_);
+        }
+        if self.has_next_file_number_ {
+            put_varint32(dst, Tag::KNextFileNumber as u32);
+            put_varint64(dst, self.next_file_number_);
+        }
+        if self.has_last_sequence_ {
+            put_varint32(dst, Tag::KLastSequence as u32);
+            put_varint64(dst, self.last_sequence_);
+        }
+        for (level, key) in self.compact_pointers_.iter() {
+            put_varint32(dst, Tag::KCompactPointer as u32);
+            put_varint32(dst, *level as u32);
+            put_length_prefixed_slice(dst, key.encode());
+        }
+        for (level, number) in self.deleted_files.iter() {
+            put_varint32(dst, Tag::KDeletedFile as u32);
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, *number);
+        }
+        for (level, f) in self.new_file.iter() {
+            put_varint32(dst, Tag::KNewFile as u32);
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, f.number);
+            put_varint64(dst, f.file_size);
+            put_length_prefixed_slice(dst, f.smallest.encode());
+            put_length_prefixed_slice(dst, f.largest.encode());
+        }
+    }
+
+    /// 从一条 manifest 记录解析，格式错误时返回 Corruption
+    pub(crate) fn decode_from(&mut self, src: &Slice) -> Status {
+        self.clear();
+        let mut input = src.clone();
+        let mut msg: Option<&str> = None;
+        let mut tag = 0u32;
+
+        let mut level = 0i32;
+        let mut number = 0u64;
+        let mut str = Slice::new_empty();
+
+        while msg.is_none() && get_varint32(&mut input, &mut tag) {
+            match Tag::from_u32(tag) {
+                Some(Tag::KComparator) => {
+                    if get_length_prefixed_slice(&mut input, &mut str) {
+                        self.set_comparator_name(str.to_string());
+                    } else {
+                        msg = Some("comparator name");
+                    }
+                }
+                Some(Tag::KLogNumber) => {
+                    if get_varint64(&mut input, &mut number) {
+                        self.set_log_number_(number);
+                    } else {
+                        msg = Some("log number");
+                    }
+                }
+                Some(Tag::KPrevLogNumber) => {
+                    if get_varint64(&mut input, &mut number) {
+                        self.set_prev_log_number_(number);
+                    } else {
+                        msg = Some("previous log number");
+                    }
+                }
+                Some(Tag::KNextFileNumber) => {
+                    if get_varint64(&mut input, &mut number) {
+                        self.set_next_file_number_(number);
+                    } else {
+                        msg = Some("next file number");
+                    }
+                }
+                Some(Tag::KLastSequence) => {
+                    if get_varint64(&mut input, &mut number) {
+                        self.set_last_sequence_(number);
+                    } else {
+                        msg = Some("last sequence number");
+                    }
+                }
+                Some(Tag::KCompactPointer) => {
+                    let mut key = InternalKey::default();
+                    if get_level(&mut input, &mut level) && get_internal_key(&mut input, &mut key) {
+                        self.set_compact_pointers_(level, key);
+                    } else {
+                        msg = Some("compaction pointer");
+                    }
+                }
+                Some(Tag::KDeletedFile) => {
+                    if get_level(&mut input, &mut level) && get_varint64(&mut input, &mut number) {
+                        self.remove_file(level, number);
+                    } else {
+                        msg = Some("deleted file");
+                    }
+                }
+                Some(Tag::KNewFile) => {
+                    let mut file_size = 0u64;
+                    let mut smallest = InternalKey::default();
+                    let mut largest = InternalKey::default();
+                    if get_level(&mut input, &mut level)
+                        && get_varint64(&mut input, &mut number)
+                        && get_varint64(&mut input, &mut file_size)
+                        && get_internal_key(&mut input, &mut smallest)
+                        && get_internal_key(&mut input, &mut largest)
+                    {
+                        self.add_file(level, number, file_size, smallest, largest);
+                    } else {
+                        msg = Some("new-file entry");
+                    }
+                }
+                None => {
+                    msg = Some("unknown tag");
+                }
+            }
+        }
+
+        if msg.is_none() && !input.is_empty() {
+            msg = Some("invalid tag");
+        }
+
+        match msg {
+            Some(msg) => Status::corruption("VersionEdit", Some(msg)),
+            None => Status::ok(),
+        }
+    }
+
+    pub(crate) fn debug_string(&self) -> String {
+        let mut r = String::from("VersionEdit {");
+        if self.has_comparator_ {
+            r.push_str(&format!("\n  Comparator: {}", self.comparator_));
+        }
+        if self.has_log_number_ {
+            r.push_str(&format!("\n  LogNumber: {}", self.log_number_));
+        }
+        if self.has_prev_log_number_ {
+            r.push_str(&format!("\n  PrevLogNumber: {}", self.prev_log_number_));
+        }
+        if self.has_next_file_number_ {
+            r.push_str(&format!("\n  NextFile: {}", self.next_file_number_));
+        }
+        if self.has_last_sequence_ {
+            r.push_str(&format!("\n  LastSeq: {}", self.last_sequence_));
+        }
+        for (level, key) in self.compact_pointers_.iter() {
+            r.push_str(&format!(
+                "\n  CompactPointer: {} {}",
+                level,
+                key.debug_string()
+            ));
+        }
+        for (level, number) in self.deleted_files.iter() {
+            r.push_str(&format!("\n  RemoveFile: {} {}", level, number));
+        }
+        for (level, f) in self.new_file.iter() {
+            r.push_str(&format!(
+                "\n  AddFile: {} {} {} {} .. {}",
+                level,
+                f.number,
+                f.file_size,
+                f.smallest.debug_string(),
+                f.largest.debug_string()
+            ));
+        }
+        r.push_str("\n}\n");
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::internal_key_comparator::ValueType;
+
+    fn test_encode_decode(edit: &VersionEdit) {
+        let mut encoded = BytesMut::new();
+        let mut encoded2 = BytesMut::new();
+        edit.encode_to(&mut encoded);
+        let mut parsed = VersionEdit::new();
+        let s = parsed.decode_from(&Slice::new_from_array(&encoded));
+        assert!(s.is_ok());
+        parsed.encode_to(&mut encoded2);
+        assert_eq!(encoded, encoded2);
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        const K_BIG: u64 = 1u64 << 50;
+
+        let mut edit = VersionEdit::new();
+        for i in 0..4u64 {
+            test_encode_decode(&edit);
+            edit.add_file(
+                3,
+                K_BIG + 300 + i,
+                K_BIG + 400 + i,
+                InternalKey::new(Slice::from("foo"), K_BIG + 500 + i, ValueType::KTypeValue),
+                InternalKey::new(
+                    Slice::from("zoo"),
+                    K_BIG + 600 + i,
+                    ValueType::KTypeDeletion,
+                ),
+            );
+            edit.remove_file(4, K_BIG + 700 + i);
+            edit.set_compact_pointers_(
+                i as i32,
+                InternalKey::new(Slice::from("x"), K_BIG + 900 + i, ValueType::KTypeValue),
+            );
+        }
+
+        edit.set_comparator_name("foo".to_string());
+        edit.set_log_number_(K_BIG + 100);
+        edit.set_next_file_number_(K_BIG + 200);
+        edit.set_last_sequence_(K_BIG + 1000);
+        test_encode_decode(&edit);
+    }
+
+    #[test]
+    fn test_decode_corruption() {
+        let mut edit = VersionEdit::new();
+        edit.add_file(
+            1,
+            7,
+            100,
+            InternalKey::new(Slice::from("a"), 1, ValueType::KTypeValue),
+            InternalKey::new(Slice::from("b"), 2, ValueType::KTypeValue),
+        );
+        let mut encoded = BytesMut::new();
+        edit.encode_to(&mut encoded);
+
+        // 截断的记录
+        let mut parsed = VersionEdit::new();
+        let truncated = Slice::new_from_array(&encoded[..encoded.len() - 1]);
+        assert!(parsed.decode_from(&truncated).is_corruption());
+
+        // 未知的 tag
+        let mut unknown = encoded.clone();
+        unknown[0] = 8;
+        assert!(parsed
+            .decode_from(&Slice::new_from_array(&unknown))
+            .is_corruption());
+
+        // 层数越界
+        let mut bad_level = encoded.clone();
+        bad_level[1] = K_NUM_LEVELS as u8;
+        assert!(parsed
+            .decode_from(&Slice::new_from_array(&bad_level))
+            .is_corruption());
+    }
+
+    #[test]
+    fn test_debug_string() {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("leveldb.BytewiseComparator".to_string());
+        edit.set_log_number_(5);
+        edit.set_next_file_number_(8);
+        edit.set_last_sequence_(20);
+        edit.remove_file(2, 6);
+        edit.add_file(
+            1,
+            7,
+            100,
+            InternalKey::new(Slice::from("a"), 1, ValueType::KTypeValue),
+            InternalKey::new(Slice::from("b"), 2, ValueType::KTypeDeletion),
+        );
+        assert_eq!(
+            "VersionEdit {\n  Comparator: leveldb.BytewiseComparator\n  LogNumber: 5\n  \
+             NextFile: 8\n  LastSeq: 20\n  RemoveFile: 2 6\n  \
+             AddFile: 1 7 100 'a' @ 1 : 1 .. 'b' @ 2 : 0\n}\n",
+            edit.debug_string()
+        );
+    }
+}
@@ -0,0 +1,1902 @@
+use crate::db::file_name::{current_file_name, descriptor_file_name, set_current_file};
+use crate::db::internal_key::InternalKey;
+use crate::db::internal_key_comparator::{
+    parse_internal_key, InternalKeyComparator, ParsedInternalKey, ValueType,
+    K_L0_COMPACTION_TRIGGER, K_MAX_MEM_COMPACT_LEVEL, K_MAX_SEQUENCE_NUMBER, K_NUM_LEVELS,
+    K_VALUE_TYPE_FOR_SEEK,
+};
+use crate::db::log_reader::Reader;
+use crate::db::log_writer::LogWriter;
+use crate::db::table_cache::TableCache;
+use crate::db::version_edit::{FileMetaData, VersionEdit};
+use crate::obj::options::{Options, ReadOptions, WALRecoveryMode};
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::iterator::{new_error_iterator, Iter};
+use crate::table::merger::new_merging_iterator;
+use crate::table::two_level_iterator::{BlockFunction, TwoLevelIterator};
+use crate::util::coding::{decode_fixed64, encode_fixed64};
+use crate::util::comparator::Comparator;
+use crate::util::env::{read_file_to_string, Env};
+use crate::util::sequential_file::{SequentialFile, StdSequentialFile};
+use crate::util::writable_file::{StdWritableFile, WritableFile};
+use ahash::HashSet;
+use bytes::BytesMut;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI32, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::info;
+
+fn target_file_size<E: Env>(options: &Options<E>) -> u64 {
+    options.max_file_size as u64
+}
+
+// level 层的新文件与 level+2 层重叠的最大字节数，超过后不再把 memtable 推到更深的层
+fn max_grand_parent_overlap_bytes<E: Env>(options: &Options<E>) -> u64 {
+    10 * target_file_size(options)
+}
+
+// 扩大 compaction 的输入时，所有输入文件的总大小上限
+fn expanded_compaction_byte_size_limit<E: Env>(options: &Options<E>) -> u64 {
+    25 * target_file_size(options)
+}
+
+fn max_bytes_for_level(mut level: usize) -> f64 {
+    // level-0 按文件个数计算，这里的结果对它无意义
+    let mut result = 10. * 1048576.0;
+    while level > 1 {
+        result *= 10.;
+        level -= 1;
+    }
+    result
+}
+
+pub(crate) fn total_file_size(files: &[Arc<FileMetaData>]) -> u64 {
+    files.iter().map(|f| f.file_size).sum()
+}
+
+/// 返回第一个 largest >= key 的文件下标，不存在时返回 files.len()。
+/// files 必须是按 key 排好序且互不重叠的文件列表。
+pub(crate) fn find_file(
+    icmp: &InternalKeyComparator,
+    files: &[Arc<FileMetaData>],
+    key: &Slice,
+) -> usize {
+    let mut left = 0;
+    let mut right = files.len();
+    while left < right {
+        let mid = (left + right) / 2;
+        let f = &files[mid];
+        if icmp.compare(&Slice::new_from_ptr(&f.largest.rep_), key) == Ordering::Less {
+            // mid 及之前的文件都小于 key
+            left = mid + 1;
+        } else {
+            right = mid;
+        }
+    }
+    right
+}
+
+fn after_file(ucmp: &dyn Comparator, user_key: Option<&Slice>, f: &FileMetaData) -> bool {
+    // None 表示在所有 key 之前
+    user_key.is_some_and(|k| ucmp.compare(k, &f.largest.user_key()) == Ordering::Greater)
+}
+
+fn before_file(ucmp: &dyn Comparator, user_key: Option<&Slice>, f: &FileMetaData) -> bool {
+    // None 表示在所有 key 之后
+    user_key.is_some_and(|k| ucmp.compare(k, &f.smallest.user_key()) == Ordering::Less)
+}
+
+/// 判断 [smallest_user_key, largest_user_key] 是否与 files 中的某个文件重叠，
+/// None 分别表示比所有 key 小/大。disjoint_sorted_files 为 true 时使用二分查找。
+pub(crate) fn some_file_overlaps_range(
+    icmp: &InternalKeyComparator,
+    disjoint_sorted_files: bool,
+    files: &[Arc<FileMetaData>],
+    smallest_user_key: Option<&Slice>,
+    largest_user_key: Option<&Slice>,
+) -> bool {
+    let ucmp = icmp.user_comparator();
+    if !disjoint_sorted_files {
+        return files.iter().any(|f| {
+            !after_file(ucmp, smallest_user_key, f) && !before_file(ucmp, largest_user_key, f)
+        });
+    }
+
+    let mut index = 0;
+    if let Some(smallest_user_key) = smallest_user_key {
+        // 找到第一个可能包含 smallest_user_key 的文件
+        let small_key = InternalKey::new(
+            smallest_user_key.clone(),
+            K_MAX_SEQUENCE_NUMBER,
+            K_VALUE_TYPE_FOR_SEEK,
+        );
+        index = find_file(icmp, files, &Slice::new_from_ptr(&small_key.rep_));
+    }
+    if index >= files.len() {
+        // 起点在所有文件之后
+        return false;
+    }
+    !before_file(ucmp, largest_user_key, &files[index])
+}
+
+/// 遍历某一层（level > 0）的文件列表，key 是文件的 largest，
+/// value 是 16 字节的文件编号和文件大小
+struct LevelFileNumIterator {
+    icmp: InternalKeyComparator,
+    flist: Vec<Arc<FileMetaData>>,
+    // 等于 flist.len() 时无效
+    index: usize,
+}
+
+impl LevelFileNumIterator {
+    fn new(flist: Vec<Arc<FileMetaData>>) -> Self {
+        let index = flist.len();
+        LevelFileNumIterator {
+            icmp: InternalKeyComparator::new(),
+            flist,
+            index,
+        }
+    }
+}
+
+impl Iter for LevelFileNumIterator {
+    fn valid(&self) -> bool {
+        self.index < self.flist.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index = if self.flist.is_empty() {
+            0
+        } else {
+            self.flist.len() - 1
+        };
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.index = find_file(&self.icmp, &self.flist, target);
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.index += 1;
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        if self.index == 0 {
+            self.index = self.flist.len();
+        } else {
+            self.index -= 1;
+        }
+    }
+
+    fn key(&self) -> Slice {
+        assert!(self.valid());
+        Slice::new_from_array(&self.flist[self.index].largest.rep_)
+    }
+
+    fn value(&self) -> Slice {
+        assert!(self.valid());
+        let f = &self.flist[self.index];
+        let mut buf = [0u8; 16];
+        encode_fixed64(&mut buf, f.number);
+        encode_fixed64(&mut buf[8..], f.file_size);
+        Slice::new_from_array(&buf)
+    }
+
+    fn status(&self) -> Status {
+        Status::ok()
+    }
+}
+
+// 把一层中互不重叠的文件按顺序拼接成一个迭代器，只在需要时打开文件
+fn new_concatenating_iterator<E: Env + 'static>(
+    table_cache: Arc<TableCache<E>>,
+    files: Vec<Arc<FileMetaData>>,
+    options: &ReadOptions,
+) -> Box<dyn Iter> {
+    let block_function: BlockFunction = Box::new(move |options, file_value| {
+        if file_value.len() != 16 {
+            return Box::new(new_error_iterator(Status::corruption(
+                "FileReader invoked with unexpected value",
+                None,
+            )));
+        }
+        table_cache.new_iterator(
+            options,
+            decode_fixed64(file_value.data()),
+            decode_fixed64(&file_value.data()[8..]),
+        )
+    });
+    Box::new(TwoLevelIterator::new(
+        Box::new(LevelFileNumIterator::new(files)),
+        block_function,
+        options.clone(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SaverState {
+    NotFound,
+    Found,
+    Deleted,
+    Corrupt,
+}
+
+struct Saver {
+    state: SaverState,
+    user_key: Slice,
+    value: Slice,
+}
+
+fn save_value(arg: Box<dyn Any>, ikey: &Slice, v: &Slice) -> bool {
+    let saver = arg.downcast::<Rc<RefCell<Saver>>>().unwrap();
+    let mut saver = saver.borrow_mut();
+    let mut parsed_key = ParsedInternalKey {
+        user_key: Slice::new_empty(),
+        sequence: 0,
+        value_type: ValueType::KTypeValue,
+    };
+    if !parse_internal_key(ikey, &mut parsed_key) {
+        saver.state = SaverState::Corrupt;
+    } else if parsed_key.user_key.compare(&saver.user_key) == Ordering::Equal {
+        if parsed_key.value_type == ValueType::KTypeValue {
+            saver.state = SaverState::Found;
+            saver.value = Slice::new_from_array(v.data());
+        } else {
+            saver.state = SaverState::Deleted;
+        }
+    }
+    saver.state != SaverState::NotFound
+}
+
+/// `Version::get` 的查找统计，第一个被读过但没有命中的文件记录在 seek_file 中
+pub(crate) struct GetStats {
+    pub(crate) seek_file: Option<Arc<FileMetaData>>,
+    pub(crate) seek_file_level: i32,
+}
+
+impl GetStats {
+    pub(crate) fn new() -> Self {
+        GetStats {
+            seek_file: None,
+            seek_file_level: -1,
+        }
+    }
+}
+
+/// 某一时刻数据库中每一层的文件集合，创建之后不再修改。
+pub(crate) struct Version<E>
+where
+    E: Env,
+{
+    table_cache: Arc<TableCache<E>>,
+    options: Arc<Options<E>>,
+    icmp: InternalKeyComparator,
+    // 每一层的文件，level > 0 的文件按 key 排序且互不重叠
+    pub(crate) files: Vec<Vec<Arc<FileMetaData>>>,
+    // 下一个需要 compaction 的层及其分数，分数 >= 1 表示需要 compaction
+    pub(crate) compaction_score: f64,
+    pub(crate) compaction_level: i32,
+    // 查找时 allowed_seeks 耗尽的文件及其所在层，由读路径设置
+    file_to_compact: Mutex<Option<(Arc<FileMetaData>, i32)>>,
+}
+
+impl<E> Version<E>
+where
+    E: Env + 'static,
+{
+    fn new(table_cache: Arc<TableCache<E>>, options: Arc<Options<E>>) -> Self {
+        Version {
+            table_cache,
+            options,
+            icmp: InternalKeyComparator::new(),
+            files: (0..K_NUM_LEVELS).map(|_| Vec::new()).collect(),
+            compaction_score: -1.0,
+            compaction_level: -1,
+            file_to_compact: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn num_files(&self, level: usize) -> usize {
+        self.files[level].len()
+    }
+
+    /// 按 level 从小到大查找 key，ikey 是由 user key、快照序列号和 K_VALUE_TYPE_FOR_SEEK 组成的
+    pub(crate) fn get(
+        &self,
+        options: &ReadOptions,
+        ikey: &InternalKey,
+        stats: &mut GetStats,
+    ) -> Result<Slice, Status> {
+        let ucmp = self.icmp.user_comparator();
+        let user_key = ikey.user_key();
+        let internal_key = Slice::new_from_ptr(&ikey.rep_);
+        stats.seek_file = None;
+        stats.seek_file_level = -1;
+        let mut last_file_read: Option<(Arc<FileMetaData>, i32)> = None;
+
+        for level in 0..K_NUM_LEVELS {
+            let files = &self.files[level];
+            if files.is_empty() {
+                continue;
+            }
+            let candidates: Vec<Arc<FileMetaData>> = if level == 0 {
+                // level-0 的文件可能互相重叠，按从新到旧的顺序查找
+                let mut tmp: Vec<Arc<FileMetaData>> = files
+                    .iter()
+                    .filter(|f| {
+                        ucmp.compare(&user_key, &f.smallest.user_key()) != Ordering::Less
+                            && ucmp.compare(&user_key, &f.largest.user_key()) != Ordering::Greater
+                    })
+                    .cloned()
+                    .collect();
+                tmp.sort_by(|a, b| b.number.cmp(&a.number));
+                tmp
+            } else {
+                let index = find_file(&self.icmp, files, &internal_key);
+                if index < files.len()
+                    && ucmp.compare(&user_key, &files[index].smallest.user_key()) != Ordering::Less
+                {
+                    vec![files[index].clone()]
+                } else {
+                    vec![]
+                }
+            };
+
+            for f in candidates {
+                if stats.seek_file.is_none() {
+                    if let Some((file, file_level)) = last_file_read.take() {
+                        // 这次查找读了不止一个文件，记下第一个
+                        stats.seek_file = Some(file);
+                        stats.seek_file_level = file_level;
+                    }
+                }
+                last_file_read = Some((f.clone(), level as i32));
+
+                let saver = Rc::new(RefCell::new(Saver {
+                    state: SaverState::NotFound,
+                    user_key: user_key.clone(),
+                    value: Slice::new_empty(),
+                }));
+                let s = self.table_cache.get(
+                    options,
+                    f.number,
+                    f.file_size,
+                    &internal_key,
+                    Box::new(saver.clone()),
+                    Box::new(save_value),
+                );
+                if !s.is_ok() {
+                    return Err(s);
+                }
+                let saver = saver.borrow();
+                match saver.state {
+                    SaverState::NotFound => {}
+                    SaverState::Found => return Ok(saver.value.clone()),
+                    SaverState::Deleted => return Err(Status::not_found("", None)),
+                    SaverState::Corrupt => {
+                        return Err(Status::corruption(
+                            "corrupted key for ",
+                            Some(&user_key.to_string()),
+                        ))
+                    }
+                }
+            }
+        }
+        Err(Status::not_found("", None))
+    }
+
+    /// 把 get 的统计记到对应文件上，返回是否有文件因此需要 compaction
+    pub(crate) fn update_stats(&self, stats: &GetStats) -> bool {
+        if let Some(f) = &stats.seek_file {
+            if f.allowed_seeks.fetch_sub(1, AtomicOrdering::Relaxed) <= 1 {
+                let mut file_to_compact = self.file_to_compact.lock().unwrap();
+                if file_to_compact.is_none() {
+                    *file_to_compact = Some((f.clone(), stats.seek_file_level));
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// 因 seek 次数过多而需要 compaction 的文件及其所在层
+    pub(crate) fn file_to_compact(&self) -> Option<(Arc<FileMetaData>, i32)> {
+        self.file_to_compact.lock().unwrap().clone()
+    }
+
+    /// 按从新到旧的顺序返回与 user_key 重叠的文件及其所在层
+    fn overlapping_files(&self, user_key: &Slice) -> Vec<(Arc<FileMetaData>, i32)> {
+        let ucmp = self.icmp.user_comparator();
+        let mut result: Vec<(Arc<FileMetaData>, i32)> = self.files[0]
+            .iter()
+            .filter(|f| {
+                ucmp.compare(user_key, &f.smallest.user_key()) != Ordering::Less
+                    && ucmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater
+            })
+            .map(|f| (f.clone(), 0))
+            .collect();
+        result.sort_by(|a, b| b.0.number.cmp(&a.0.number));
+
+        let ikey = InternalKey::new(
+            user_key.clone(),
+            K_MAX_SEQUENCE_NUMBER,
+            K_VALUE_TYPE_FOR_SEEK,
+        );
+        for level in 1..K_NUM_LEVELS {
+            let files = &self.files[level];
+            let index = find_file(&self.icmp, files, &ikey.encode());
+            if index < files.len()
+                && ucmp.compare(user_key, &files[index].smallest.user_key()) != Ordering::Less
+            {
+                result.push((files[index].clone(), level as i32));
+            }
+        }
+        result
+    }
+
+    /// 记录迭代器读到的一个 internal key，该 key 落在多个文件中时给第一个文件扣一次 seek。
+    /// 返回是否有文件因此需要 compaction
+    pub(crate) fn record_read_sample(&self, internal_key: &Slice) -> bool {
+        let mut ikey = ParsedInternalKey {
+            user_key: Slice::new_empty(),
+            sequence: 0,
+            value_type: ValueType::KTypeValue,
+        };
+        if !parse_internal_key(internal_key, &mut ikey) {
+            return false;
+        }
+        let mut matches = self.overlapping_files(&ikey.user_key);
+        if matches.len() >= 2 {
+            let (file, level) = matches.swap_remove(0);
+            let stats = GetStats {
+                seek_file: Some(file),
+                seek_file_level: level,
+            };
+            return self.update_stats(&stats);
+        }
+        false
+    }
+
+    /// 把这个版本中所有文件的迭代器加入 iters，合并后即为整个版本的内容。
+    /// level-0 的文件可能重叠，每个文件一个迭代器；其它层每层一个拼接迭代器
+    pub(crate) fn add_iterators(&self, options: &ReadOptions, iters: &mut Vec<Box<dyn Iter>>) {
+        for f in self.files[0].iter() {
+            iters.push(
+                self.table_cache
+                    .new_iterator(options, f.number, f.file_size),
+            );
+        }
+        for level in 1..K_NUM_LEVELS {
+            if !self.files[level].is_empty() {
+                iters.push(new_concatenating_iterator(
+                    self.table_cache.clone(),
+                    self.files[level].clone(),
+                    options,
+                ));
+            }
+        }
+    }
+
+    /// 估计所有文件中的 entry 个数，包括旧版本和删除标记
+    pub(crate) fn approximate_num_entries(&self) -> u64 {
+        self.files
+            .iter()
+            .flatten()
+            .map(|f| {
+                self.table_cache
+                    .approximate_num_entries(f.number, f.file_size)
+            })
+            .sum()
+    }
+
+    /// 数据库中 ikey 之前的数据的大致字节数
+    pub(crate) fn approximate_offset_of(&self, ikey: &InternalKey) -> u64 {
+        let key = Slice::new_from_ptr(&ikey.rep_);
+        let mut result = 0;
+        for (level, files) in self.files.iter().enumerate() {
+            for f in files.iter() {
+                if self
+                    .icmp
+                    .compare(&Slice::new_from_ptr(&f.largest.rep_), &key)
+                    != Ordering::Greater
+                {
+                    // 整个文件都在 ikey 之前
+                    result += f.file_size;
+                } else if self
+                    .icmp
+                    .compare(&Slice::new_from_ptr(&f.smallest.rep_), &key)
+                    == Ordering::Greater
+                {
+                    // 整个文件都在 ikey 之后。level > 0 的文件有序，后面的文件也在 ikey 之后
+                    if level > 0 {
+                        break;
+                    }
+                } else {
+                    // ikey 落在文件的范围内
+                    result += self
+                        .table_cache
+                        .approximate_offset_of(f.number, f.file_size, &key);
+                }
+            }
+        }
+        result
+    }
+
+    /// level 层是否有文件与 [smallest_user_key, largest_user_key] 重叠
+    pub(crate) fn overlap_in_level(
+        &self,
+        level: usize,
+        smallest_user_key: Option<&Slice>,
+        largest_user_key: Option<&Slice>,
+    ) -> bool {
+        some_file_overlaps_range(
+            &self.icmp,
+            level > 0,
+            &self.files[level],
+            smallest_user_key,
+            largest_user_key,
+        )
+    }
+
+    /// 为覆盖 [smallest_user_key, largest_user_key] 的新 memtable 文件选择输出层
+    pub(crate) fn pick_level_for_memtable_output(
+        &self,
+        smallest_user_key: &Slice,
+        largest_user_key: &Slice,
+    ) -> usize {
+        let mut level = 0;
+        if !self.overlap_in_level(0, Some(smallest_user_key), Some(largest_user_key)) {
+            // 下一层没有重叠，且与再下一层的重叠不多时，把文件推到下一层
+            let start = InternalKey::new(
+                smallest_user_key.clone(),
+                K_MAX_SEQUENCE_NUMBER,
+                K_VALUE_TYPE_FOR_SEEK,
+            );
+            let limit = InternalKey::new(largest_user_key.clone(), 0, ValueType::KTypeDeletion);
+            while level < K_MAX_MEM_COMPACT_LEVEL {
+                if self.overlap_in_level(level + 1, Some(smallest_user_key), Some(largest_user_key))
+                {
+                    break;
+                }
+                if level + 2 < K_NUM_LEVELS {
+                    let overlaps =
+                        self.get_overlapping_inputs(level + 2, Some(&start), Some(&limit));
+                    if total_file_size(&overlaps) > max_grand_parent_overlap_bytes(&self.options) {
+                        break;
+                    }
+                }
+                level += 1;
+            }
+        }
+        level
+    }
+
+    /// level 层中与 [begin, end] 重叠的文件，None 表示无边界。
+    /// level-0 的文件互相重叠，范围会扩大到把所有相关文件都包含进来。
+    pub(crate) fn get_overlapping_inputs(
+        &self,
+        level: usize,
+        begin: Option<&InternalKey>,
+        end: Option<&InternalKey>,
+    ) -> Vec<Arc<FileMetaData>> {
+        let ucmp = self.icmp.user_comparator();
+        let mut user_begin = begin.map(|k| k.user_key());
+        let mut user_end = end.map(|k| k.user_key());
+        let mut inputs = Vec::new();
+        let mut i = 0;
+        while i < self.files[level].len() {
+            let f = self.files[level][i].clone();
+            i += 1;
+            let file_start = f.smallest.user_key();
+            let file_limit = f.largest.user_key();
+            if user_begin
+                .as_ref()
+                .is_some_and(|b| ucmp.compare(&file_limit, b) == Ordering::Less)
+            {
+                // f 完全在范围之前
+            } else if user_end
+                .as_ref()
+                .is_some_and(|e| ucmp.compare(&file_start, e) == Ordering::Greater)
+            {
+                // f 完全在范围之后
+            } else {
+                inputs.push(f);
+                if level == 0 {
+                    if user_begin
+                        .as_ref()
+                        .is_some_and(|b| ucmp.compare(&file_start, b) == Ordering::Less)
+                    {
+                        user_begin = Some(file_start);
+                        inputs.clear();
+                        i = 0;
+                    } else if user_end
+                        .as_ref()
+                        .is_some_and(|e| ucmp.compare(&file_limit, e) == Ordering::Greater)
+                    {
+                        user_end = Some(file_limit);
+                        inputs.clear();
+                        i = 0;
+                    }
+                }
+            }
+        }
+        inputs
+    }
+
+    pub(crate) fn debug_string(&self) -> String {
+        let mut r = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            r.push_str(&format!("--- level {} ---\n", level));
+            for f in files {
+                r.push_str(&format!(
+                    " {}:{}[{} .. {}]\n",
+                    f.number,
+                    f.file_size,
+                    f.smallest.debug_string(),
+                    f.largest.debug_string()
+                ));
+            }
+        }
+        r
+    }
+}
+
+// 在 files 中找到 largest key 最大的文件的 largest key
+fn find_largest_key(
+    icmp: &InternalKeyComparator,
+    files: &[Arc<FileMetaData>],
+) -> Option<InternalKey> {
+    let mut largest: Option<&InternalKey> = None;
+    for f in files {
+        if largest.is_none_or(|l| {
+            icmp.compare(
+                &Slice::new_from_ptr(&f.largest.rep_),
+                &Slice::new_from_ptr(&l.rep_),
+            ) == Ordering::Greater
+        }) {
+            largest = Some(&f.largest);
+        }
+    }
+    largest.cloned()
+}
+
+// 在 level_files 中找到 smallest 与 largest_key 的 user key 相同且 smallest > largest_key 的文件中最小的一个
+fn find_smallest_boundary_file(
+    icmp: &InternalKeyComparator,
+    level_files: &[Arc<FileMetaData>],
+    largest_key: &InternalKey,
+) -> Option<Arc<FileMetaData>> {
+    let ucmp = icmp.user_comparator();
+    let largest = Slice::new_from_ptr(&largest_key.rep_);
+    let mut smallest_boundary_file: Option<&Arc<FileMetaData>> = None;
+    for f in level_files {
+        let f_smallest = Slice::new_from_ptr(&f.smallest.rep_);
+        if icmp.compare(&f_smallest, &largest) == Ordering::Greater
+            && ucmp.compare(&f.smallest.user_key(), &largest_key.user_key()) == Ordering::Equal
+            && smallest_boundary_file.is_none_or(|b| {
+                icmp.compare(&f_smallest, &Slice::new_from_ptr(&b.smallest.rep_)) == Ordering::Less
+            })
+        {
+            smallest_boundary_file = Some(f);
+        }
+    }
+    smallest_boundary_file.cloned()
+}
+
+// 同一个 user key 的不同版本可能被分到相邻的两个文件中，只 compaction 前一个文件会让
+// 旧版本被推到下一层，而新版本还留在这一层，之后的读会读到旧版本。
+// 这里把这类边界文件也加入 compaction_files
+fn add_boundary_inputs(
+    icmp: &InternalKeyComparator,
+    level_files: &[Arc<FileMetaData>],
+    compaction_files: &mut Vec<Arc<FileMetaData>>,
+) {
+    let mut largest_key = match find_largest_key(icmp, compaction_files) {
+        Some(key) => key,
+        None => return,
+    };
+    while let Some(f) = find_smallest_boundary_file(icmp, level_files, &largest_key) {
+        largest_key = f.largest.clone();
+        compaction_files.push(f);
+    }
+}
+
+/// 一次 compaction 的输入和相关信息
+pub(crate) struct Compaction<E>
+where
+    E: Env,
+{
+    level: usize,
+    max_output_file_size: u64,
+    input_version: Arc<Version<E>>,
+    edit: VersionEdit,
+    icmp: InternalKeyComparator,
+    // inputs[0] 是 level 层的输入，inputs[1] 是 level+1 层的输入
+    pub(crate) inputs: [Vec<Arc<FileMetaData>>; 2],
+    // 与输出范围重叠的 level+2 层文件
+    grandparents: Vec<Arc<FileMetaData>>,
+    grandparent_index: usize,
+    // 是否已经输出过 key
+    seen_key: bool,
+    // 当前输出文件与 grandparents 重叠的字节数
+    overlapped_bytes: u64,
+    // is_base_level_for_key 在每一层上的位置，输入 key 有序所以只需向后移动
+    level_ptrs: [usize; K_NUM_LEVELS],
+}
+
+impl<E> Compaction<E>
+where
+    E: Env + 'static,
+{
+    fn new(options: &Options<E>, level: usize, input_version: Arc<Version<E>>) -> Self {
+        Compaction {
+            level,
+            max_output_file_size: target_file_size(options),
+            input_version,
+            edit: VersionEdit::new(),
+            icmp: InternalKeyComparator::new(),
+            inputs: [Vec::new(), Vec::new()],
+            grandparents: Vec::new(),
+            grandparent_index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            level_ptrs: [0; K_NUM_LEVELS],
+        }
+    }
+
+    /// 输入文件所在的层，输出写到 level + 1 层
+    pub(crate) fn level(&self) -> usize {
+        self.level
+    }
+
+    /// 保存 compaction 结果的 edit
+    pub(crate) fn edit(&mut self) -> &mut VersionEdit {
+        &mut self.edit
+    }
+
+    pub(crate) fn num_input_files(&self, which: usize) -> usize {
+        self.inputs[which].len()
+    }
+
+    pub(crate) fn input(&self, which: usize, i: usize) -> &Arc<FileMetaData> {
+        &self.inputs[which][i]
+    }
+
+    pub(crate) fn max_output_file_size(&self) -> u64 {
+        self.max_output_file_size
+    }
+
+    /// 是否可以直接把唯一的输入文件移动到下一层，不需要合并或拆分
+    pub(crate) fn is_trivial_move(&self) -> bool {
+        // 与 grandparents 重叠太多时直接移动会让之后的 compaction 代价过高
+        self.num_input_files(0) == 1
+            && self.num_input_files(1) == 0
+            && total_file_size(&self.grandparents)
+                <= max_grand_parent_overlap_bytes(&self.input_version.options)
+    }
+
+    /// 在 edit 中删除所有输入文件
+    pub(crate) fn add_input_deletions(&mut self) {
+        for which in 0..2 {
+            for f in self.inputs[which].iter() {
+                self.edit.remove_file((self.level + which) as i32, f.number);
+            }
+        }
+    }
+
+    /// 输出层之下的层中是否一定不存在 user_key，是则可以丢弃它的删除标记
+    pub(crate) fn is_base_level_for_key(&mut self, user_key: &Slice) -> bool {
+        let ucmp = self.icmp.user_comparator();
+        for level in self.level + 2..K_NUM_LEVELS {
+            let files = &self.input_version.files[level];
+            while self.level_ptrs[level] < files.len() {
+                let f = &files[self.level_ptrs[level]];
+                if ucmp.compare(user_key, &f.largest.user_key()) != Ordering::Greater {
+                    // user_key 在这个文件之前或之中
+                    if ucmp.compare(user_key, &f.smallest.user_key()) != Ordering::Less {
+                        return false;
+                    }
+                    break;
+                }
+                self.level_ptrs[level] += 1;
+            }
+        }
+        true
+    }
+
+    /// 在输出 internal_key 之前是否应该结束当前的输出文件，避免与 grandparents 重叠过多
+    pub(crate) fn should_stop_before(&mut self, internal_key: &Slice) -> bool {
+        while self.grandparent_index < self.grandparents.len()
+            && self.icmp.compare(
+                internal_key,
+                &Slice::new_from_ptr(&self.grandparents[self.grandparent_index].largest.rep_),
+            ) == Ordering::Greater
+        {
+            if self.seen_key {
+                self.overlapped_bytes += self.grandparents[self.grandparent_index].file_size;
+            }
+            self.grandparent_index += 1;
+        }
+        self.seen_key = true;
+
+        if self.overlapped_bytes > max_grand_parent_overlap_bytes(&self.input_version.options) {
+            // 重叠太多，开始新的输出文件
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct LevelState {
+    deleted_files: HashSet<u64>,
+    added_files: Vec<Arc<FileMetaData>>,
+}
+
+/// 把一系列 VersionEdit 叠加到 base 上，生成新的 Version，避免为每个 edit 都创建中间版本
+pub(crate) struct Builder<E>
+where
+    E: Env,
+{
+    icmp: InternalKeyComparator,
+    base: Arc<Version<E>>,
+    levels: Vec<LevelState>,
+}
+
+impl<E> Builder<E>
+where
+    E: Env + 'static,
+{
+    pub(crate) fn new(base: Arc<Version<E>>) -> Self {
+        Builder {
+            icmp: InternalKeyComparator::new(),
+            base,
+            levels: (0..K_NUM_LEVELS)
+                .map(|_| LevelState {
+                    deleted_files: HashSet::default(),
+                    added_files: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    // 先按 smallest key 排序，相同时按文件编号排序
+    fn by_smallest_key(&self, f1: &FileMetaData, f2: &FileMetaData) -> Ordering {
+        match self.icmp.compare(
+            &Slice::new_from_ptr(&f1.smallest.rep_),
+            &Slice::new_from_ptr(&f2.smallest.rep_),
+        ) {
+            Ordering::Equal => f1.number.cmp(&f2.number),
+            r => r,
+        }
+    }
+
+    /// 叠加一个 edit，compact_pointer 是 VersionSet 中每层下一次 compaction 的起点
+    pub(crate) fn apply(&mut self, edit: &VersionEdit, compact_pointer: &mut [BytesMut]) {
+        for (level, key) in edit.compact_pointers_.iter() {
+            compact_pointer[*level as usize] = key.rep_.clone();
+        }
+
+        for (level, number) in edit.deleted_files.iter() {
+            self.levels[*level as usize].deleted_files.insert(*number);
+        }
+
+        for (level, f) in edit.new_file.iter() {
+            let mut f = f.clone();
+            f.refs = 1;
+            // 每 16KB 数据允许一次无效的 seek，之后触发 compaction：
+            // 一次 seek 的代价约等于 compaction 40KB 数据，这个值比较保守
+            f.allowed_seeks = AtomicI32::new((f.file_size / 16384).max(100) as i32);
+            let state = &mut self.levels[*level as usize];
+            state.deleted_files.remove(&f.number);
+            state.added_files.push(Arc::new(f));
+        }
+    }
+
+    /// 把 base 与叠加的修改合并写入 v
+    pub(crate) fn save_to(&mut self, v: &mut Version<E>) {
+        for level in 0..K_NUM_LEVELS {
+            let mut added = std::mem::take(&mut self.levels[level].added_files);
+            added.sort_by(|a, b| self.by_smallest_key(a, b));
+            let base_files = &self.base.files[level];
+            v.files[level].reserve(base_files.len() + added.len());
+
+            let mut base_iter = base_files.iter().peekable();
+            for added_file in added.iter() {
+                while let Some(base_file) =
+                    base_iter.next_if(|f| self.by_smallest_key(f, added_file) == Ordering::Less)
+                {
+                    self.maybe_add_file(v, level, base_file.clone());
+                }
+                self.maybe_add_file(v, level, added_file.clone());
+            }
+            for base_file in base_iter {
+                self.maybe_add_file(v, level, base_file.clone());
+            }
+        }
+    }
+
+    fn maybe_add_file(&self, v: &mut Version<E>, level: usize, f: Arc<FileMetaData>) {
+        if self.levels[level].deleted_files.contains(&f.number) {
+            return;
+        }
+        let files = &mut v.files[level];
+        if level > 0 {
+            if let Some(last) = files.last() {
+                // level > 0 的文件不能重叠
+                debug_assert_eq!(
+                    self.icmp.compare(
+                        &Slice::new_from_ptr(&last.largest.rep_),
+                        &Slice::new_from_ptr(&f.smallest.rep_)
+                    ),
+                    Ordering::Less
+                );
+            }
+        }
+        files.push(f);
+    }
+}
+
+/// 管理数据库的所有 Version，负责 MANIFEST 的写入与恢复，以及文件编号和序列号的分配
+pub(crate) struct VersionSet<E>
+where
+    E: Env,
+{
+    env: Arc<E>,
+    dbname: String,
+    options: Arc<Options<E>>,
+    table_cache: Arc<TableCache<E>>,
+    icmp: InternalKeyComparator,
+    next_file_number: u64,
+    manifest_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+    // 0 或正在 compaction 的 memtable 对应的日志编号
+    prev_log_number: u64,
+
+    descriptor_file: Option<Arc<Mutex<dyn WritableFile>>>,
+    descriptor_log: Option<LogWriter>,
+    current: Arc<Version<E>>,
+    // 所有仍被引用的 Version，用于统计仍在使用的文件
+    versions: Vec<Weak<Version<E>>>,
+
+    // 每层下一次 compaction 的起始 key，空表示从头开始
+    compact_pointer: Vec<BytesMut>,
+}
+
+impl<E> VersionSet<E>
+where
+    E: Env + 'static,
+{
+    pub(crate) fn new(
+        dbname: String,
+        options: Arc<Options<E>>,
+        table_cache: Arc<TableCache<E>>,
+    ) -> Self {
+        let current = Arc::new(Version::new(table_cache.clone(), options.clone()));
+        VersionSet {
+            env: options.env.clone(),
+            dbname,
+            options,
+            table_cache,
+            icmp: InternalKeyComparator::new(),
+            next_file_number: 2,
+            manifest_file_number: 0,
+            last_sequence: 0,
+            log_number: 0,
+            prev_log_number: 0,
+            descriptor_file: None,
+            descriptor_log: None,
+            versions: vec![Arc::downgrade(&current)],
+            current,
+            compact_pointer: (0..K_NUM_LEVELS).map(|_| BytesMut::new()).collect(),
+        }
+    }
+
+    pub(crate) fn current(&self) -> Arc<Version<E>> {
+        self.current.clone()
+    }
+
+    pub(crate) fn manifest_file_number(&self) -> u64 {
+        self.manifest_file_number
+    }
+
+    pub(crate) fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    /// 归还 new_file_number 分配的编号，只有它是最后一个分配的编号时才生效
+    pub(crate) fn reuse_file_number(&mut self, file_number: u64) {
+        if self.next_file_number == file_number + 1 {
+            self.next_file_number = file_number;
+        }
+    }
+
+    pub(crate) fn mark_file_number_used(&mut self, number: u64) {
+        if self.next_file_number <= number {
+            self.next_file_number = number + 1;
+        }
+    }
+
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub(crate) fn set_last_sequence(&mut self, s: u64) {
+        assert!(s >= self.last_sequence);
+        self.last_sequence = s;
+    }
+
+    pub(crate) fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    pub(crate) fn prev_log_number(&self) -> u64 {
+        self.prev_log_number
+    }
+
+    pub(crate) fn num_level_files(&self, level: usize) -> usize {
+        assert!(level < K_NUM_LEVELS);
+        self.current.files[level].len()
+    }
+
+    pub(crate) fn num_level_bytes(&self, level: usize) -> u64 {
+        assert!(level < K_NUM_LEVELS);
+        total_file_size(&self.current.files[level])
+    }
+
+    /// 把所有仍被引用的版本中的文件编号加入 live
+    pub(crate) fn add_live_files(&self, live: &mut HashSet<u64>) {
+        for v in self.versions.iter().filter_map(|w| w.upgrade()) {
+            for files in v.files.iter() {
+                live.extend(files.iter().map(|f| f.number));
+            }
+        }
+    }
+
+    /// 当前版本是否需要 compaction
+    pub(crate) fn needs_compaction(&self) -> bool {
+        self.current.compaction_score >= 1.0 || self.current.file_to_compact().is_some()
+    }
+
+    fn append_version(&mut self, v: Version<E>) {
+        let v = Arc::new(v);
+        self.versions.retain(|w| w.strong_count() > 0);
+        self.versions.push(Arc::downgrade(&v));
+        self.current = v;
+    }
+
+    /// 把 edit 叠加到当前版本上得到新版本，写入 MANIFEST 后切换为当前版本。
+    /// 调用方需要持有 DB 的锁。
+    pub(crate) fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Status {
+        if edit.has_log_number_ {
+            assert!(edit.log_number_ >= self.log_number);
+            assert!(edit.log_number_ < self.next_file_number);
+        } else {
+            edit.set_log_number_(self.log_number);
+        }
+        if !edit.has_prev_log_number_ {
+            edit.set_prev_log_number_(self.prev_log_number);
+        }
+        edit.set_next_file_number_(self.next_file_number);
+        edit.set_last_sequence_(self.last_sequence);
+
+        let mut v = Version::new(self.table_cache.clone(), self.options.clone());
+        {
+            let mut builder = Builder::new(self.current.clone());
+            builder.apply(edit, &mut self.compact_pointer);
+            builder.save_to(&mut v);
+        }
+        self.finalize(&mut v);
+
+        // 第一次写入时创建新的 MANIFEST，并写入当前版本的快照
+        let mut new_manifest_file = None;
+        let mut s = Status::ok();
+        if self.descriptor_log.is_none() {
+            let file_name = descriptor_file_name(&self.dbname, self.manifest_file_number);
+            match self.env.new_writable_file::<StdWritableFile, _>(&file_name) {
+                Ok(file) => {
+                    let file: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(file));
+                    let mut log = LogWriter::new(file.clone());
+                    s = self.write_snapshot(&mut log);
+                    self.descriptor_file = Some(file);
+                    self.descriptor_log = Some(log);
+                }
+                Err(e) => s = e,
+            }
+            new_manifest_file = Some(file_name);
+        }
+
+        if s.is_ok() {
+            let mut record = BytesMut::new();
+            edit.encode_to(&mut record);
+            s = self
+                .descriptor_log
+                .as_mut()
+                .unwrap()
+                .add_record(&Slice::new_from_ptr(&record));
+            if s.is_ok() {
+                s = self
+                    .descriptor_file
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .sync();
+            }
+        }
+
+        // 新建了 MANIFEST 时，让 CURRENT 指向它
+        if s.is_ok() && new_manifest_file.is_some() {
+            s = set_current_file(self.env.as_ref(), &self.dbname, self.manifest_file_number);
+        }
+
+        if s.is_ok() {
+            self.append_version(v);
+            self.log_number = edit.log_number_;
+            self.prev_log_number = edit.prev_log_number_;
+        } else if let Some(file_name) = new_manifest_file {
+            self.descriptor_log = None;
+            self.descriptor_file = None;
+            self.env.remove_file(&file_name);
+        }
+        s
+    }
+
+    /// 从 CURRENT 指向的 MANIFEST 恢复最后一次保存的状态。
+    /// save_manifest 为 true 表示调用方需要通过 log_and_apply 写一个新的 MANIFEST。
+    pub(crate) fn recover(&mut self, save_manifest: &mut bool) -> Status {
+        let mut current = BytesMut::new();
+        let s = read_file_to_string(
+            self.env.as_ref(),
+            current_file_name(&self.dbname),
+            &mut current,
+        );
+        if !s.is_ok() {
+            return s;
+        }
+        if current.is_empty() || current[current.len() - 1] != b'\n' {
+            return Status::corruption("CURRENT file does not end with newline", None);
+        }
+        let current = String::from_utf8_lossy(&current[..current.len() - 1]).to_string();
+        let dscname = format!("{}/{}", self.dbname, current);
+
+        let file: Arc<Mutex<dyn SequentialFile>> = match self
+            .env
+            .new_sequential_file::<StdSequentialFile, _>(&dscname)
+        {
+            Ok(file) => Arc::new(Mutex::new(file)),
+            Err(e) => {
+                if !self.env.file_exists(&dscname) {
+                    return Status::corruption(
+                        "CURRENT points to a non-existent file",
+                        Some(&current),
+                    );
+                }
+                return e;
+            }
+        };
+
+        let mut have_log_number = false;
+        let mut have_prev_log_number = false;
+        let mut have_next_file = false;
+        let mut have_last_sequence = false;
+        let mut next_file = 0;
+        let mut last_sequence = 0;
+        let mut log_number = 0;
+        let mut prev_log_number = 0;
+        let mut builder = Builder::new(self.current.clone());
+        let mut read_records = 0;
+
+        let mut s = Status::ok();
+        {
+            // 写 MANIFEST 时崩溃留下的不完整记录可以忽略，其它损坏都让恢复失败
+            let mut reader = Reader::new(
+                file,
+                None,
+                true,
+                0,
+                WALRecoveryMode::TolerateCorruptedTailRecords,
+            );
+            let mut record = Slice::new_empty();
+            let mut scratch = BytesMut::new();
+            while s.is_ok() && reader.read_record(&mut record, &mut scratch) {
+                read_records += 1;
+                let mut edit = VersionEdit::new();
+                s = edit.decode_from(&record);
+                if s.is_ok()
+                    && edit.has_comparator_
+                    && edit.comparator_ != self.icmp.user_comparator().name()
+                {
+                    s = Status::invalid_argument(
+                        &format!("{} does not match existing comparator ", edit.comparator_),
+                        Some(self.icmp.user_comparator().name()),
+                    );
+                }
+
+                if s.is_ok() {
+                    builder.apply(&edit, &mut self.compact_pointer);
+                }
+                if edit.has_log_number_ {
+                    log_number = edit.log_number_;
+                    have_log_number = true;
+                }
+                if edit.has_prev_log_number_ {
+                    prev_log_number = edit.prev_log_number_;
+                    have_prev_log_number = true;
+                }
+                if edit.has_next_file_number_ {
+                    next_file = edit.next_file_number_;
+                    have_next_file = true;
+                }
+                if edit.has_last_sequence_ {
+                    last_sequence = edit.last_sequence_;
+                    have_last_sequence = true;
+                }
+                if s.is_ok() {
+                    s = reader.status();
+                }
+            }
+            if s.is_ok() {
+                s = reader.status();
+            }
+        }
+
+        if s.is_ok() {
+            if !have_next_file {
+                s = Status::corruption("no meta-nextfile entry in descriptor", None);
+            } else if !have_log_number {
+                s = Status::corruption("no meta-lognumber entry in descriptor", None);
+            } else if !have_last_sequence {
+                s = Status::corruption("no last-sequence-number entry in descriptor", None);
+            }
+            if !have_prev_log_number {
+                prev_log_number = 0;
+            }
+            self.mark_file_number_used(prev_log_number);
+            self.mark_file_number_used(log_number);
+        }
+
+        if s.is_ok() {
+            let mut v = Version::new(self.table_cache.clone(), self.options.clone());
+            builder.save_to(&mut v);
+            self.finalize(&mut v);
+            self.append_version(v);
+            self.manifest_file_number = next_file;
+            self.next_file_number = next_file + 1;
+            self.last_sequence = last_sequence;
+            self.log_number = log_number;
+            self.prev_log_number = prev_log_number;
+            // 总是写一个新的 MANIFEST，避免旧文件无限增长
+            *save_manifest = true;
+        } else {
+            tracing::error!(
+                "error recovering version set with {} records: {:?}",
+                read_records,
+                s
+            );
+        }
+        s
+    }
+
+    /// 选择下一次 compaction 的层和输入，不需要 compaction 时返回 None。
+    /// 文件过多或过大引起的 compaction 优先于 seek 引起的
+    pub(crate) fn pick_compaction(&mut self) -> Option<Compaction<E>> {
+        let current = self.current.clone();
+        let size_compaction = current.compaction_score >= 1.0;
+        let seek_compaction = current.file_to_compact();
+        let mut c;
+        if size_compaction {
+            let level = current.compaction_level as usize;
+            debug_assert!(level + 1 < K_NUM_LEVELS);
+            c = Compaction::new(&self.options, level, current.clone());
+            // 从上次 compaction 结束的位置开始
+            for f in current.files[level].iter() {
+                if self.compact_pointer[level].is_empty()
+                    || self.icmp.compare(
+                        &Slice::new_from_ptr(&f.largest.rep_),
+                        &Slice::new_from_ptr(&self.compact_pointer[level]),
+                    ) == Ordering::Greater
+                {
+                    c.inputs[0].push(f.clone());
+                    break;
+                }
+            }
+            if c.inputs[0].is_empty() {
+                // 回到开头
+                c.inputs[0].push(current.files[level][0].clone());
+            }
+        } else if let Some((f, level)) = seek_compaction {
+            c = Compaction::new(&self.options, level as usize, current.clone());
+            c.inputs[0].push(f);
+        } else {
+            return None;
+        }
+
+        // level-0 的文件互相重叠，需要把所有重叠的文件都加进来
+        if c.level == 0 {
+            let (smallest, largest) = self.get_range(&c.inputs[0]);
+            c.inputs[0] = current.get_overlapping_inputs(0, Some(&smallest), Some(&largest));
+            debug_assert!(!c.inputs[0].is_empty());
+        }
+
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    /// 手动 compaction level 层中与 [begin, end] 重叠的文件，None 表示无边界，没有重叠的文件时返回 None
+    pub(crate) fn compact_range(
+        &mut self,
+        level: usize,
+        begin: Option<&InternalKey>,
+        end: Option<&InternalKey>,
+    ) -> Option<Compaction<E>> {
+        let current = self.current.clone();
+        let mut inputs = current.get_overlapping_inputs(level, begin, end);
+        if inputs.is_empty() {
+            return None;
+        }
+
+        // 范围很大时一次只 compaction 一部分，避免单次工作量过大。
+        // level-0 的文件互相重叠，不能只选其中一部分
+        if level > 0 {
+            let limit = target_file_size(&self.options);
+            let mut total = 0;
+            if let Some(i) = inputs.iter().position(|f| {
+                total += f.file_size;
+                total >= limit
+            }) {
+                inputs.truncate(i + 1);
+            }
+        }
+
+        let mut c = Compaction::new(&self.options, level, current);
+        c.inputs[0] = inputs;
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    // 返回 inputs 覆盖的最小和最大 internal key，inputs 不能为空
+    fn get_range(&self, inputs: &[Arc<FileMetaData>]) -> (InternalKey, InternalKey) {
+        debug_assert!(!inputs.is_empty());
+        let mut smallest = &inputs[0].smallest;
+        let mut largest = &inputs[0].largest;
+        for f in inputs.iter().skip(1) {
+            if self.icmp.compare(
+                &Slice::new_from_ptr(&f.smallest.rep_),
+                &Slice::new_from_ptr(&smallest.rep_),
+            ) == Ordering::Less
+            {
+                smallest = &f.smallest;
+            }
+            if self.icmp.compare(
+                &Slice::new_from_ptr(&f.largest.rep_),
+                &Slice::new_from_ptr(&largest.rep_),
+            ) == Ordering::Greater
+            {
+                largest = &f.largest;
+            }
+        }
+        (smallest.clone(), largest.clone())
+    }
+
+    fn get_range2(
+        &self,
+        inputs1: &[Arc<FileMetaData>],
+        inputs2: &[Arc<FileMetaData>],
+    ) -> (InternalKey, InternalKey) {
+        let all: Vec<Arc<FileMetaData>> = inputs1.iter().chain(inputs2.iter()).cloned().collect();
+        self.get_range(&all)
+    }
+
+    // 根据 inputs[0] 确定 inputs[1]，并在不增加 level+1 层输入的前提下尽量扩大 inputs[0]
+    fn setup_other_inputs(&mut self, c: &mut Compaction<E>) {
+        let level = c.level;
+        let current = c.input_version.clone();
+
+        add_boundary_inputs(&self.icmp, &current.files[level], &mut c.inputs[0]);
+        let (smallest, largest) = self.get_range(&c.inputs[0]);
+        c.inputs[1] = current.get_overlapping_inputs(level + 1, Some(&smallest), Some(&largest));
+        add_boundary_inputs(&self.icmp, &current.files[level + 1], &mut c.inputs[1]);
+
+        // 所有输入覆盖的范围
+        let (mut all_start, mut all_limit) = self.get_range2(&c.inputs[0], &c.inputs[1]);
+
+        if !c.inputs[1].is_empty() {
+            let mut expanded0 =
+                current.get_overlapping_inputs(level, Some(&all_start), Some(&all_limit));
+            add_boundary_inputs(&self.icmp, &current.files[level], &mut expanded0);
+            let inputs0_size = total_file_size(&c.inputs[0]);
+            let inputs1_size = total_file_size(&c.inputs[1]);
+            let expanded0_size = total_file_size(&expanded0);
+            if expanded0.len() > c.inputs[0].len()
+                && inputs1_size + expanded0_size
+                    < expanded_compaction_byte_size_limit(&self.options)
+            {
+                let (new_start, new_limit) = self.get_range(&expanded0);
+                let mut expanded1 =
+                    current.get_overlapping_inputs(level + 1, Some(&new_start), Some(&new_limit));
+                add_boundary_inputs(&self.icmp, &current.files[level + 1], &mut expanded1);
+                if expanded1.len() == c.inputs[1].len() {
+                    info!(
+                        "Expanding@{} {}+{} ({}+{} bytes) to {}+{} ({}+{} bytes)",
+                        level,
+                        c.inputs[0].len(),
+                        c.inputs[1].len(),
+                        inputs0_size,
+                        inputs1_size,
+                        expanded0.len(),
+                        expanded1.len(),
+                        expanded0_size,
+                        inputs1_size
+                    );
+                    c.inputs[0] = expanded0;
+                    c.inputs[1] = expanded1;
+                    (all_start, all_limit) = self.get_range2(&c.inputs[0], &c.inputs[1]);
+                }
+            }
+        }
+
+        // 记录与输出范围重叠的 grandparent 文件
+        if level + 2 < K_NUM_LEVELS {
+            c.grandparents =
+                current.get_overlapping_inputs(level + 2, Some(&all_start), Some(&all_limit));
+        }
+
+        // 下一次这一层的 compaction 从这次的最大 key 之后开始。
+        // 这里立即更新而不是等 compaction 成功，失败时下次会换一个范围重试
+        let (_, largest) = self.get_range(&c.inputs[0]);
+        self.compact_pointer[level] = largest.rep_.clone();
+        c.edit.set_compact_pointers_(level as i32, largest);
+    }
+
+    /// 返回按 internal key 有序遍历所有输入文件的迭代器
+    pub(crate) fn make_input_iterator(&self, c: &Compaction<E>) -> Box<dyn Iter> {
+        let mut options = ReadOptions::new();
+        options.verify_checksums = self.options.paranoid_checks;
+        // compaction 读到的数据不会很快再被读，不放进缓存
+        options.fill_cache = false;
+
+        // level-0 的每个文件一个迭代器，其它层每层一个拼接迭代器
+        let mut list: Vec<Box<dyn Iter>> = Vec::new();
+        for which in 0..2 {
+            if c.inputs[which].is_empty() {
+                continue;
+            }
+            if c.level + which == 0 {
+                for f in c.inputs[which].iter() {
+                    list.push(
+                        self.table_cache
+                            .new_iterator(&options, f.number, f.file_size),
+                    );
+                }
+            } else {
+                list.push(new_concatenating_iterator(
+                    self.table_cache.clone(),
+                    c.inputs[which].clone(),
+                    &options,
+                ));
+            }
+        }
+        new_merging_iterator(Arc::new(InternalKeyComparator::new()), list)
+    }
+
+    /// level 层和 level+1 层所有文件中，重叠字节数最多的一个，用于观察 compaction 是否充分
+    pub(crate) fn max_next_level_overlapping_bytes(&self) -> u64 {
+        let mut result = 0;
+        for level in 1..K_NUM_LEVELS - 1 {
+            for f in self.current.files[level].iter() {
+                let overlaps = self.current.get_overlapping_inputs(
+                    level + 1,
+                    Some(&f.smallest),
+                    Some(&f.largest),
+                );
+                result = result.max(total_file_size(&overlaps));
+            }
+        }
+        result
+    }
+
+    // 计算最需要 compaction 的层
+    fn finalize(&self, v: &mut Version<E>) {
+        let mut best_level = -1;
+        let mut best_score = -1.0;
+        for level in 0..K_NUM_LEVELS - 1 {
+            let score = if level == 0 {
+                // level-0 按文件个数而不是字节数计算：写缓冲较大时 level-0 的 compaction 不宜过于频繁，
+                // 而且每次读都要合并所有 level-0 文件，文件数过多会拖慢读
+                v.files[level].len() as f64 / K_L0_COMPACTION_TRIGGER as f64
+            } else {
+                total_file_size(&v.files[level]) as f64 / max_bytes_for_level(level)
+            };
+            if score > best_score {
+                best_level = level as i32;
+                best_score = score;
+            }
+        }
+        v.compaction_level = best_level;
+        v.compaction_score = best_score;
+    }
+
+    // 把当前版本完整写入一条 MANIFEST 记录
+    fn write_snapshot(&self, log: &mut LogWriter) -> Status {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.icmp.user_comparator().name().to_string());
+
+        for (level, pointer) in self.compact_pointer.iter().enumerate() {
+            if !pointer.is_empty() {
+                let mut key = InternalKey::default();
+                key.decode_from(&Slice::new_from_ptr(pointer));
+                edit.set_compact_pointers_(level as i32, key);
+            }
+        }
+
+        for (level, files) in self.current.files.iter().enumerate() {
+            for f in files {
+                edit.add_file(
+                    level as i32,
+                    f.number,
+                    f.file_size,
+                    f.smallest.clone(),
+                    f.largest.clone(),
+                );
+            }
+        }
+
+        let mut record = BytesMut::new();
+        edit.encode_to(&mut record);
+        log.add_record(&Slice::new_from_ptr(&record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::env::StdEnv;
+    use std::num::NonZeroUsize;
+
+    struct FindFileTest {
+        disjoint_sorted_files: bool,
+        files: Vec<Arc<FileMetaData>>,
+    }
+
+    impl FindFileTest {
+        fn new() -> Self {
+            FindFileTest {
+                disjoint_sorted_files: true,
+                files: vec![],
+            }
+        }
+
+        fn add(&mut self, smallest: &'static str, largest: &'static str) {
+            self.add_with_seq(smallest, largest, 100, 100);
+        }
+
+        fn add_with_seq(
+            &mut self,
+            smallest: &'static str,
+            largest: &'static str,
+            smallest_seq: u64,
+            largest_seq: u64,
+        ) {
+            self.files.push(Arc::new(FileMetaData {
+                refs: 0,
+                allowed_seeks: AtomicI32::new(0),
+                number: self.files.len() as u64 + 1,
+                file_size: 0,
+                smallest: InternalKey::new(
+                    Slice::from(smallest),
+                    smallest_seq,
+                    ValueType::KTypeValue,
+                ),
+                largest: InternalKey::new(Slice::from(largest), largest_seq, ValueType::KTypeValue),
+            }));
+        }
+
+        fn find(&self, key: &'static str) -> usize {
+            let target = InternalKey::new(Slice::from(key), 100, ValueType::KTypeValue);
+            find_file(
+                &InternalKeyComparator::new(),
+                &self.files,
+                &Slice::new_from_ptr(&target.rep_),
+            )
+        }
+
+        fn overlaps(&self, smallest: Option<&'static str>, largest: Option<&'static str>) -> bool {
+            let s = smallest.map(Slice::from);
+            let l = largest.map(Slice::from);
+            some_file_overlaps_range(
+                &InternalKeyComparator::new(),
+                self.disjoint_sorted_files,
+                &self.files,
+                s.as_ref(),
+                l.as_ref(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_find_file_empty() {
+        let t = FindFileTest::new();
+        assert_eq!(0, t.find("foo"));
+        assert!(!t.overlaps(Some("a"), Some("z")));
+        assert!(!t.overlaps(None, Some("z")));
+        assert!(!t.overlaps(Some("a"), None));
+        assert!(!t.overlaps(None, None));
+    }
+
+    #[test]
+    fn test_find_file_single() {
+        let mut t = FindFileTest::new();
+        t.add("p", "q");
+        assert_eq!(0, t.find("a"));
+        assert_eq!(0, t.find("p"));
+        assert_eq!(0, t.find("p1"));
+        assert_eq!(0, t.find("q"));
+        assert_eq!(1, t.find("q1"));
+        assert_eq!(1, t.find("z"));
+
+        assert!(!t.overlaps(Some("a"), Some("b")));
+        assert!(!t.overlaps(Some("z1"), Some("z2")));
+        assert!(t.overlaps(Some("a"), Some("p")));
+        assert!(t.overlaps(Some("a"), Some("q")));
+        assert!(t.overlaps(Some("a"), Some("z")));
+        assert!(t.overlaps(Some("p"), Some("p1")));
+        assert!(t.overlaps(Some("p"), Some("q")));
+        assert!(t.overlaps(Some("p"), Some("z")));
+        assert!(t.overlaps(Some("p1"), Some("p2")));
+        assert!(t.overlaps(Some("p1"), Some("z")));
+        assert!(t.overlaps(Some("q"), Some("q")));
+        assert!(t.overlaps(Some("q"), Some("q1")));
+
+        assert!(!t.overlaps(None, Some("j")));
+        assert!(!t.overlaps(Some("r"), None));
+        assert!(t.overlaps(None, Some("p")));
+        assert!(t.overlaps(None, Some("p1")));
+        assert!(t.overlaps(Some("q"), None));
+        assert!(t.overlaps(None, None));
+    }
+
+    #[test]
+    fn test_find_file_multiple() {
+        let mut t = FindFileTest::new();
+        t.add("150", "200");
+        t.add("200", "250");
+        t.add("300", "350");
+        t.add("400", "450");
+        assert_eq!(0, t.find("100"));
+        assert_eq!(0, t.find("150"));
+        assert_eq!(0, t.find("151"));
+        assert_eq!(0, t.find("199"));
+        assert_eq!(0, t.find("200"));
+        assert_eq!(1, t.find("201"));
+        assert_eq!(1, t.find("249"));
+        assert_eq!(1, t.find("250"));
+        assert_eq!(2, t.find("251"));
+        assert_eq!(2, t.find("299"));
+        assert_eq!(2, t.find("300"));
+        assert_eq!(2, t.find("349"));
+        assert_eq!(2, t.find("350"));
+        assert_eq!(3, t.find("351"));
+        assert_eq!(3, t.find("400"));
+        assert_eq!(3, t.find("450"));
+        assert_eq!(4, t.find("451"));
+
+        assert!(!t.overlaps(Some("100"), Some("149")));
+        assert!(!t.overlaps(Some("251"), Some("299")));
+        assert!(!t.overlaps(Some("451"), Some("500")));
+        assert!(!t.overlaps(Some("351"), Some("399")));
+
+        assert!(t.overlaps(Some("100"), Some("150")));
+        assert!(t.overlaps(Some("100"), Some("200")));
+        assert!(t.overlaps(Some("100"), Some("300")));
+        assert!(t.overlaps(Some("100"), Some("400")));
+        assert!(t.overlaps(Some("100"), Some("500")));
+        assert!(t.overlaps(Some("375"), Some("400")));
+        assert!(t.overlaps(Some("450"), Some("450")));
+        assert!(t.overlaps(Some("450"), Some("500")));
+    }
+
+    #[test]
+    fn test_find_file_overlap_sequence_checks() {
+        let mut t = FindFileTest::new();
+        t.add_with_seq("200", "200", 5000, 3000);
+        assert!(!t.overlaps(Some("199"), Some("199")));
+        assert!(!t.overlaps(Some("201"), Some("300")));
+        assert!(t.overlaps(Some("200"), Some("200")));
+        assert!(t.overlaps(Some("190"), Some("200")));
+        assert!(t.overlaps(Some("200"), Some("210")));
+    }
+
+    #[test]
+    fn test_find_file_overlapping_files() {
+        let mut t = FindFileTest::new();
+        t.add("150", "600");
+        t.add("400", "500");
+        t.disjoint_sorted_files = false;
+        assert!(!t.overlaps(Some("100"), Some("149")));
+        assert!(!t.overlaps(Some("601"), Some("700")));
+        assert!(t.overlaps(Some("100"), Some("150")));
+        assert!(t.overlaps(Some("100"), Some("200")));
+        assert!(t.overlaps(Some("100"), Some("300")));
+        assert!(t.overlaps(Some("100"), Some("400")));
+        assert!(t.overlaps(Some("100"), Some("500")));
+        assert!(t.overlaps(Some("375"), Some("400")));
+        assert!(t.overlaps(Some("450"), Some("450")));
+        assert!(t.overlaps(Some("450"), Some("500")));
+        assert!(t.overlaps(Some("450"), Some("700")));
+        assert!(t.overlaps(Some("600"), Some("700")));
+    }
+
+    fn ikey(user_key: &'static str, seq: u64) -> InternalKey {
+        InternalKey::new(Slice::from(user_key), seq, ValueType::KTypeValue)
+    }
+
+    fn new_version_set(dbname: &String) -> VersionSet<StdEnv> {
+        let options = Arc::new(Options::new(Arc::new(StdEnv::new())));
+        let table_cache = Arc::new(TableCache::new(
+            dbname.clone(),
+            options.clone(),
+            NonZeroUsize::new(100).unwrap(),
+        ));
+        VersionSet::new(dbname.clone(), options, table_cache)
+    }
+
+    // 与新建数据库时一样，写一个只包含初始状态的 MANIFEST-000001
+    fn new_db(env: &StdEnv, dbname: &String) {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(
+            InternalKeyComparator::new()
+                .user_comparator()
+                .name()
+                .to_string(),
+        );
+        edit.set_log_number_(0);
+        edit.set_next_file_number_(2);
+        edit.set_last_sequence_(0);
+        let manifest = descriptor_file_name(dbname, 1);
+        let file: Arc<Mutex<dyn WritableFile>> = Arc::new(Mutex::new(
+            env.new_writable_file::<StdWritableFile, _>(&manifest)
+                .unwrap(),
+        ));
+        let mut log = LogWriter::new(file.clone());
+        let mut record = BytesMut::new();
+        edit.encode_to(&mut record);
+        assert!(log.add_record(&Slice::new_from_ptr(&record)).is_ok());
+        assert!(file.lock().unwrap().sync().is_ok());
+        assert!(set_current_file(env, dbname, 1).is_ok());
+    }
+
+    #[test]
+    fn test_log_and_apply_then_recover() {
+        let env = StdEnv::new();
+        let dbname = format!("{}/version_set_test", env.get_test_directory().unwrap());
+        let _ = std::fs::remove_dir_all(&dbname);
+        assert!(env.create_dir(&dbname).is_ok());
+        new_db(&env, &dbname);
+
+        let mut vset = new_version_set(&dbname);
+        let mut save_manifest = false;
+        assert!(vset.recover(&mut save_manifest).is_ok());
+        assert!(save_manifest);
+        // 恢复时为新的 MANIFEST 预留了 MANIFEST-000001 中记录的下一个文件编号
+        assert_eq!(2, vset.manifest_file_number());
+
+        let f1 = vset.new_file_number();
+        let f2 = vset.new_file_number();
+        let f3 = vset.new_file_number();
+        let mut edit = VersionEdit::new();
+        edit.add_file(0, f1, 100, ikey("a", 1), ikey("c", 2));
+        edit.add_file(1, f2, 200, ikey("d", 3), ikey("f", 4));
+        edit.add_file(1, f3, 300, ikey("g", 5), ikey("h", 6));
+        vset.set_last_sequence(6);
+        assert!(vset.log_and_apply(&mut edit).is_ok());
+
+        let mut edit = VersionEdit::new();
+        edit.remove_file(1, f2);
+        assert!(vset.log_and_apply(&mut edit).is_ok());
+        assert_eq!(1, vset.num_level_files(0));
+        assert_eq!(1, vset.num_level_files(1));
+        assert_eq!(300, vset.num_level_bytes(1));
+        let expected = vset.current().debug_string();
+        let next_file_number = vset.new_file_number();
+
+        let mut recovered = new_version_set(&dbname);
+        let mut save_manifest = false;
+        assert!(recovered.recover(&mut save_manifest).is_ok());
+        assert_eq!(expected, recovered.current().debug_string());
+        assert_eq!(6, recovered.last_sequence());
+        assert!(recovered.new_file_number() >= next_file_number - 1);
+
+        // 查询重叠的文件
+        let v = recovered.current();
+        assert!(v.overlap_in_level(0, Some(&Slice::from("b")), Some(&Slice::from("b"))));
+        assert!(!v.overlap_in_level(1, Some(&Slice::from("d")), Some(&Slice::from("f"))));
+        assert_eq!(
+            1,
+            v.get_overlapping_inputs(1, Some(&ikey("a", 10)), None)
+                .len()
+        );
+        assert_eq!(
+            2,
+            v.pick_level_for_memtable_output(&Slice::from("x"), &Slice::from("y"))
+        );
+        assert_eq!(
+            0,
+            v.pick_level_for_memtable_output(&Slice::from("b"), &Slice::from("y"))
+        );
+        let _ = std::fs::remove_dir_all(&dbname);
+    }
+
+    #[test]
+    fn test_recover_missing_current() {
+        let env = StdEnv::new();
+        let dbname = format!("{}/version_set_missing", env.get_test_directory().unwrap());
+        let _ = std::fs::remove_dir_all(&dbname);
+        assert!(env.create_dir(&dbname).is_ok());
+        assert!(set_current_file(&env, &dbname, 7).is_ok());
+
+        let mut vset = new_version_set(&dbname);
+        let mut save_manifest = false;
+        assert!(vset.recover(&mut save_manifest).is_corruption());
+        let _ = std::fs::remove_dir_all(&dbname);
+    }
+
+    fn boundary_file(
+        number: u64,
+        smallest: (&'static str, u64),
+        largest: (&'static str, u64),
+    ) -> Arc<FileMetaData> {
+        let mut f = FileMetaData::new();
+        f.number = number;
+        f.smallest = InternalKey::new(Slice::from(smallest.0), smallest.1, ValueType::KTypeValue);
+        f.largest = InternalKey::new(Slice::from(largest.0), largest.1, ValueType::KTypeValue);
+        Arc::new(f)
+    }
+
+    fn numbers(files: &[Arc<FileMetaData>]) -> Vec<u64> {
+        files.iter().map(|f| f.number).collect()
+    }
+
+    #[test]
+    fn test_add_boundary_inputs() {
+        let icmp = InternalKeyComparator::new();
+
+        // 没有输入或者没有边界文件时不变
+        let mut compaction_files = vec![];
+        add_boundary_inputs(&icmp, &[], &mut compaction_files);
+        assert!(compaction_files.is_empty());
+        let f1 = boundary_file(1, ("100", 2), ("100", 1));
+        let mut compaction_files = vec![f1.clone()];
+        add_boundary_inputs(&icmp, &[f1.clone()], &mut compaction_files);
+        assert_eq!(vec![1], numbers(&compaction_files));
+
+        // f2、f3 中的 "100" 比 f1 中的旧，必须一起 compaction，f4 不受影响
+        let f2 = boundary_file(2, ("100", 6), ("100", 5));
+        let f3 = boundary_file(3, ("100", 4), ("100", 3));
+        let f4 = boundary_file(4, ("200", 9), ("300", 8));
+        let level_files = vec![f3.clone(), f2.clone(), f4.clone()];
+        let mut compaction_files = vec![f2.clone()];
+        add_boundary_inputs(&icmp, &level_files, &mut compaction_files);
+        assert_eq!(vec![2, 3], numbers(&compaction_files));
+
+        let f5 = boundary_file(5, ("100", 1), ("200", 1));
+        let level_files = vec![f2.clone(), f3.clone(), f5.clone()];
+        let mut compaction_files = vec![f2.clone()];
+        add_boundary_inputs(&icmp, &level_files, &mut compaction_files);
+        assert_eq!(vec![2, 3, 5], numbers(&compaction_files));
+    }
+}
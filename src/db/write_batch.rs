@@ -0,0 +1,320 @@
+use crate::db::internal_key_comparator::ValueType;
+use crate::db::mem_table::MemTable;
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::util::coding::{
+    decode_fixed32, decode_fixed64, encode_fixed32, encode_fixed64, get_length_prefixed_slice,
+    put_length_prefixed_slice,
+};
+use bytes::{BufMut, BytesMut};
+
+// rep_ 的格式：
+//    sequence: fixed64
+//    count: fixed32
+//    data: record[count]
+// record :=
+//    KTypeValue varstring varstring |
+//    KTypeDeletion varstring
+// varstring :=
+//    len: varint32
+//    data: uint8[len]
+const K_HEADER: usize = 12;
+
+/// 一组原子写入的操作，按加入顺序应用到 DB 上。
+#[derive(Clone, Debug)]
+pub struct WriteBatch {
+    rep_: BytesMut,
+}
+
+/// 遍历 WriteBatch 中的记录时的回调。
+pub trait Handler {
+    fn put(&mut self, key: &Slice, value: &Slice);
+    fn delete(&mut self, key: &Slice);
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        let mut rep_ = BytesMut::with_capacity(K_HEADER);
+        rep_.resize(K_HEADER, 0);
+        WriteBatch { rep_ }
+    }
+
+    pub fn put(&mut self, key: &Slice, value: &Slice) {
+        self.set_count(self.count() + 1);
+        self.rep_.put_u8(ValueType::KTypeValue as u8);
+        put_length_prefixed_slice(&mut self.rep_, key.clone());
+        put_length_prefixed_slice(&mut self.rep_, value.clone());
+    }
+
+    pub fn delete(&mut self, key: &Slice) {
+        self.set_count(self.count() + 1);
+        self.rep_.put_u8(ValueType::KTypeDeletion as u8);
+        put_length_prefixed_slice(&mut self.rep_, key.clone());
+    }
+
+    pub fn clear(&mut self) {
+        self.rep_.clear();
+        self.rep_.resize(K_HEADER, 0);
+    }
+
+    /// 把 source 中的记录追加到当前 batch 末尾，sequence 保持不变。
+    pub fn append(&mut self, source: &WriteBatch) {
+        self.set_count(self.count() + source.count());
+        debug_assert!(source.rep_.len() >= K_HEADER);
+        self.rep_.put_slice(&source.rep_[K_HEADER..]);
+    }
+
+    /// batch 序列化后的字节数，即写入 WAL 的大小。
+    pub fn approximate_size(&self) -> usize {
+        self.rep_.len()
+    }
+
+    pub fn iterate(&self, handler: &mut dyn Handler) -> Status {
+        if self.rep_.len() < K_HEADER {
+            return Status::corruption("malformed WriteBatch (too small)", None);
+        }
+        let mut input = Slice::new_from_ptr(&self.rep_[K_HEADER..]);
+        let mut key = Slice::new_empty();
+        let mut value = Slice::new_empty();
+        let mut found = 0;
+        while !input.is_empty() {
+            found += 1;
+            let tag = input[0];
+            input.remove_prefix(1);
+            match ValueType::try_from(tag) {
+                Ok(ValueType::KTypeValue) => {
+                    if get_length_prefixed_slice(&mut input, &mut key)
+                        && get_length_prefixed_slice(&mut input, &mut value)
+                    {
+                        handler.put(&key, &value);
+                    } else {
+                        return Status::corruption("bad WriteBatch Put", None);
+                    }
+                }
+                Ok(ValueType::KTypeDeletion) => {
+                    if get_length_prefixed_slice(&mut input, &mut key) {
+                        handler.delete(&key);
+                    } else {
+                        return Status::corruption("bad WriteBatch Delete", None);
+                    }
+                }
+                Err(_) => return Status::corruption("unknown WriteBatch tag", None),
+            }
+        }
+        if found != self.count() {
+            Status::corruption("WriteBatch has wrong count", None)
+        } else {
+            Status::ok()
+        }
+    }
+
+    pub(crate) fn count(&self) -> u32 {
+        decode_fixed32(&self.rep_[8..])
+    }
+
+    pub(crate) fn set_count(&mut self, n: u32) {
+        encode_fixed32(&mut self.rep_[8..], n);
+    }
+
+    pub(crate) fn sequence(&self) -> u64 {
+        decode_fixed64(&self.rep_)
+    }
+
+    pub(crate) fn set_sequence(&mut self, seq: u64) {
+        encode_fixed64(&mut self.rep_, seq);
+    }
+
+    /// 序列化后的内容，可以直接作为一条 WAL 记录写入。
+    pub(crate) fn contents(&self) -> Slice {
+        Slice::new_from_ptr(&self.rep_)
+    }
+
+    /// 用 WAL 中读出的一条记录重建 batch。
+    pub(crate) fn set_contents(&mut self, contents: &Slice) {
+        assert!(contents.size() >= K_HEADER);
+        self.rep_.clear();
+        self.rep_.put_slice(contents.data());
+    }
+
+    /// 只检查格式和记录个数，不应用任何记录。恢复日志时先检查，以免损坏的 batch 只写入一部分
+    pub(crate) fn validate(&self) -> Status {
+        self.iterate(&mut Validator)
+    }
+
+    /// 从 `sequence()` 开始依次分配序列号，把所有记录写入 memtable。
+    pub(crate) fn insert_into(&self, memtable: &MemTable) -> Status {
+        let mut inserter = MemTableInserter {
+            sequence: self.sequence(),
+            mem: memtable,
+        };
+        self.iterate(&mut inserter)
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+struct Validator;
+
+impl Handler for Validator {
+    fn put(&mut self, _key: &Slice, _value: &Slice) {}
+
+    fn delete(&mut self, _key: &Slice) {}
+}
+
+struct MemTableInserter<'a> {
+    sequence: u64,
+    mem: &'a MemTable,
+}
+
+impl Handler for MemTableInserter<'_> {
+    fn put(&mut self, key: &Slice, value: &Slice) {
+        self.mem
+            .add(self.sequence, ValueType::KTypeValue, key, Some(value));
+        self.sequence += 1;
+    }
+
+    fn delete(&mut self, key: &Slice) {
+        self.mem
+            .add(self.sequence, ValueType::KTypeDeletion, key, None);
+        self.sequence += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::internal_key::LookupKey;
+
+    // 把 batch 的内容打印成 "Put(k, v)@seq" 的形式，便于断言
+    struct Printer {
+        sequence: u64,
+        state: String,
+    }
+
+    impl Handler for Printer {
+        fn put(&mut self, key: &Slice, value: &Slice) {
+            self.state.push_str(&format!(
+                "Put({}, {})@{}",
+                key.to_string(),
+                value.to_string(),
+                self.sequence
+            ));
+            self.sequence += 1;
+        }
+
+        fn delete(&mut self, key: &Slice) {
+            self.state
+                .push_str(&format!("Delete({})@{}", key.to_string(), self.sequence));
+            self.sequence += 1;
+        }
+    }
+
+    fn print_contents(batch: &WriteBatch) -> String {
+        let mut printer = Printer {
+            sequence: batch.sequence(),
+            state: String::new(),
+        };
+        let s = batch.iterate(&mut printer);
+        if !s.is_ok() {
+            printer.state.push_str("ParseError()");
+        }
+        printer.state
+    }
+
+    #[test]
+    fn test_empty() {
+        let batch = WriteBatch::new();
+        assert_eq!("", print_contents(&batch));
+        assert_eq!(0, batch.count());
+    }
+
+    #[test]
+    fn test_multiple() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("foo"), &Slice::from("bar"));
+        batch.delete(&Slice::from("box"));
+        batch.put(&Slice::from("baz"), &Slice::from("boo"));
+        batch.set_sequence(100);
+        assert_eq!(100, batch.sequence());
+        assert_eq!(3, batch.count());
+        assert_eq!(
+            "Put(foo, bar)@100Delete(box)@101Put(baz, boo)@102",
+            print_contents(&batch)
+        );
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("foo"), &Slice::from("bar"));
+        batch.delete(&Slice::from("box"));
+        batch.set_sequence(200);
+        let contents = batch.contents();
+        let truncated = Slice::new_from_array(&contents.data()[..contents.size() - 1]);
+        batch.set_contents(&truncated);
+        assert_eq!("Put(foo, bar)@200ParseError()", print_contents(&batch));
+    }
+
+    #[test]
+    fn test_append() {
+        let mut b1 = WriteBatch::new();
+        let mut b2 = WriteBatch::new();
+        b1.set_sequence(200);
+        b2.set_sequence(300);
+        b1.append(&b2);
+        assert_eq!("", print_contents(&b1));
+        b2.put(&Slice::from("a"), &Slice::from("va"));
+        b1.append(&b2);
+        assert_eq!("Put(a, va)@200", print_contents(&b1));
+        b2.clear();
+        b2.put(&Slice::from("b"), &Slice::from("vb"));
+        b1.append(&b2);
+        assert_eq!("Put(a, va)@200Put(b, vb)@201", print_contents(&b1));
+        b2.delete(&Slice::from("foo"));
+        b1.append(&b2);
+        assert_eq!(
+            "Put(a, va)@200Put(b, vb)@201Put(b, vb)@202Delete(foo)@203",
+            print_contents(&b1)
+        );
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        let mut batch = WriteBatch::new();
+        let empty_size = batch.approximate_size();
+
+        batch.put(&Slice::from("foo"), &Slice::from("bar"));
+        let one_key_size = batch.approximate_size();
+        assert!(empty_size < one_key_size);
+
+        batch.put(&Slice::from("baz"), &Slice::from("boo"));
+        let two_keys_size = batch.approximate_size();
+        assert!(one_key_size < two_keys_size);
+
+        batch.delete(&Slice::from("box"));
+        let post_delete_size = batch.approximate_size();
+        assert!(two_keys_size < post_delete_size);
+    }
+
+    #[test]
+    fn test_insert_into() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from("foo"), &Slice::from("bar"));
+        batch.put(&Slice::from("baz"), &Slice::from("boo"));
+        batch.delete(&Slice::from("baz"));
+        batch.set_sequence(7);
+        let mem = MemTable::new();
+        assert!(batch.insert_into(&mem).is_ok());
+        drop(batch);
+        let get = |k: &'static str| mem.get(&LookupKey::new(&Slice::from(k), 9)).unwrap();
+        assert_eq!("bar", get("foo").unwrap().to_string());
+        assert!(get("baz").unwrap_err().is_not_found());
+        // 删除之前的快照仍能看到旧值
+        let old = mem.get(&LookupKey::new(&Slice::from("baz"), 8)).unwrap();
+        assert_eq!("boo", old.unwrap().to_string());
+    }
+}
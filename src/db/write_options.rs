@@ -1,3 +1,4 @@
-pub(crate) struct WriteOptions {
+#[derive(Clone, Default)]
+pub struct WriteOptions {
     pub sync: bool,
 }
@@ -1,6 +1,8 @@
+use crate::db::snapshot::Snapshot;
 use crate::obj::slice::Slice;
 use crate::table::block::Block;
-use crate::util::cache::ShardedLRUCache;
+use crate::util::bytewise_comparator_impl::byte_wise_comparator;
+use crate::util::cache::Cache;
 use crate::util::comparator::Comparator;
 use crate::util::env::Env;
 use crate::util::filter_policy::FilterPolicy;
@@ -8,44 +10,141 @@ use crate::util::hash::LocalHash;
 use num_derive::{FromPrimitive, ToPrimitive};
 use std::sync::Arc;
 
-#[derive(FromPrimitive, ToPrimitive)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CompressionType {
     None = 0x0,
     Snappy = 0x1,
     Zstd = 0x2,
 }
 
+/// block cache 和 table cache 的淘汰策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// 淘汰最久没有使用的条目
+    Lru,
+    /// 用 CLOCK 近似 LRU，查找时不加锁，适合并发读多的场景
+    Clock,
+    /// 新条目先放在冷区，再次被访问才进入热区，大范围扫描不会把热点 block 挤出去
+    Midpoint,
+}
+
+/// 恢复时如何处理日志中的损坏，被丢弃的字节数都会通过 `Reporter::corruption` 报告
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WALRecoveryMode {
+    /// 只容忍日志末尾不完整的记录（写入时崩溃），其它损坏都让恢复失败
+    TolerateCorruptedTailRecords,
+    /// 任何损坏（包括末尾不完整的记录）都让恢复失败
+    AbsoluteConsistency,
+    /// 在第一个损坏处停止重放，丢弃之后的所有日志，恢复到损坏之前的一致状态
+    PointInTimeRecovery,
+    /// 跳过所有损坏的记录，继续重放后面的数据
+    SkipAnyCorruptedRecords,
+}
+
 pub struct Options<E>
 where
     E: Env,
 {
-    pub(crate) comparator: Arc<dyn Comparator>,
-    create_if_missing: bool,
-    error_if_exists: bool,
-    pub(crate) paranoid_checks: bool,
-    pub(crate) env: Arc<E>,
-    write_buffer_size: usize,
-    max_open_files: u64,
-    pub(crate) block_cache: Option<ShardedLRUCache<Slice, Block>>,
-    block_size: usize,
-    pub(crate) block_restart_interval: u32,
-    pub(crate) max_file_size: usize,
-    compression: CompressionType,
-    zstd_compression_level: u32,
-    reuse_logs: bool,
-    pub(crate) filter_policy: Option<Arc<dyn FilterPolicy>>,
+    pub comparator: Arc<dyn Comparator>,
+    pub create_if_missing: bool,
+    pub error_if_exists: bool,
+    pub paranoid_checks: bool,
+    pub env: Arc<E>,
+    pub write_buffer_size: usize,
+    pub max_open_files: u64,
+    pub(crate) block_cache: Option<Arc<dyn Cache<Slice, Block>>>,
+    // 没有指定 block_cache 时，按这个字节数创建 block cache
+    pub block_cache_capacity: usize,
+    pub cache_type: CacheType,
+    pub block_size: usize,
+    pub block_restart_interval: u32,
+    pub max_file_size: usize,
+    pub compression: CompressionType,
+    pub zstd_compression_level: i32,
+    pub reuse_logs: bool,
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    // paranoid_checks 为 true 时 SkipAnyCorruptedRecords 按 AbsoluteConsistency 处理
+    pub wal_recovery_mode: WALRecoveryMode,
+}
+
+impl<E> Options<E>
+where
+    E: Env,
+{
+    pub fn new(env: Arc<E>) -> Options<E> {
+        Options {
+            comparator: byte_wise_comparator(),
+            create_if_missing: false,
+            error_if_exists: false,
+            paranoid_checks: false,
+            env,
+            write_buffer_size: 4 * 1024 * 1024,
+            max_open_files: 1000,
+            block_cache: None,
+            block_cache_capacity: 8 * 1024 * 1024,
+            cache_type: CacheType::Lru,
+            block_size: 4 * 1024,
+            block_restart_interval: 16,
+            max_file_size: 2 * 1024 * 1024,
+            compression: CompressionType::Snappy,
+            zstd_compression_level: 1,
+            reuse_logs: false,
+            filter_policy: None,
+            wal_recovery_mode: WALRecoveryMode::SkipAnyCorruptedRecords,
+        }
+    }
+}
+
+impl<E> Default for Options<E>
+where
+    E: Env,
+{
+    fn default() -> Self {
+        Options::new(Arc::new(E::new()))
+    }
+}
+
+impl<E> Clone for Options<E>
+where
+    E: Env,
+{
+    fn clone(&self) -> Self {
+        Options {
+            comparator: self.comparator.clone(),
+            create_if_missing: self.create_if_missing,
+            error_if_exists: self.error_if_exists,
+            paranoid_checks: self.paranoid_checks,
+            env: self.env.clone(),
+            write_buffer_size: self.write_buffer_size,
+            max_open_files: self.max_open_files,
+            block_cache: self.block_cache.clone(),
+            block_cache_capacity: self.block_cache_capacity,
+            cache_type: self.cache_type,
+            block_size: self.block_size,
+            block_restart_interval: self.block_restart_interval,
+            max_file_size: self.max_file_size,
+            compression: self.compression,
+            zstd_compression_level: self.zstd_compression_level,
+            reuse_logs: self.reuse_logs,
+            filter_policy: self.filter_policy.clone(),
+            wal_recovery_mode: self.wal_recovery_mode,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ReadOptions {
     pub(crate) verify_checksums: bool,
     pub(crate) fill_cache: bool,
+    // 不为空时读这个快照时刻的数据，否则读最新的数据
+    pub snapshot: Option<Arc<Snapshot>>,
 }
 impl ReadOptions {
     pub fn new() -> ReadOptions {
         ReadOptions {
             verify_checksums: false,
             fill_cache: true,
+            snapshot: None,
         }
     }
 }
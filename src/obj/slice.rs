@@ -84,8 +84,8 @@ impl SliceData {
                 })
             }
             SliceData::PtrBuffer(p) => {
-                let new_len = min(end - start, p.len());
-                SliceData::PtrBuffer(ByteBuffer::from_slice(&p.as_slice()[start..new_len]))
+                let new_end = min(end, p.len());
+                SliceData::PtrBuffer(ByteBuffer::from_slice(&p.as_slice()[start..new_end]))
             }
         }
     }
@@ -348,10 +348,17 @@ impl Slice {
     pub fn size(&self) -> usize {
         self.len()
     }
+    // 数据是否直接引用 mmap 的文件内容
+    pub(crate) fn is_mmap(&self) -> bool {
+        matches!(self.data_bytes, SliceData::MMap(_))
+    }
     // 获取引用的长度
     pub fn len(&self) -> usize {
         self.len
     }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
     pub(crate) fn remove_prefix(&mut self, n: usize) {
         if n > self.len() {
             panic!("remove_prefix: n is out of range")
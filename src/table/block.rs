@@ -5,6 +5,7 @@ use crate::table::format::BlockContents;
 use crate::table::iterator::{new_empty_iterator, new_error_iterator, Iter};
 use crate::util::coding::{decode_fixed32, get_varint32ptr};
 use crate::util::comparator::Comparator;
+use bytes::{BufMut, BytesMut};
 use std::cmp::Ordering;
 use std::sync::Arc;
 
@@ -141,8 +142,9 @@ struct BlockIterator {
     num_restarts_: u32,
     current_: u32,
     restart_index_: u32,
-    key_: String,
-    value_: Slice,
+    key_: BytesMut,
+    value_offset_: u32,
+    value_len_: u32,
     status: Status,
 }
 
@@ -155,8 +157,9 @@ impl BlockIterator {
             num_restarts_: num_restarts,
             current_: restarts,
             restart_index_: num_restarts,
-            key_: String::new(),
-            value_: Slice::new_from_str(""),
+            key_: BytesMut::new(),
+            value_offset_: restarts,
+            value_len_: 0,
             status: Status::ok(),
         }
     }
@@ -165,8 +168,7 @@ impl BlockIterator {
     }
 
     fn next_entry_offset(&self) -> u32 {
-        (self.value_.data().as_ptr() as u64 + self.value_.size() as u64
-            - self.data_.data().as_ptr() as u64) as u32
+        self.value_offset_ + self.value_len_
     }
 
     fn get_restart_point(&self, index: u32) -> u32 {
@@ -180,10 +182,8 @@ impl BlockIterator {
         self.key_.clear();
         self.restart_index_ = index;
         let offset = self.get_restart_point(index);
-        let mut new_value = self.data_.clone();
-        new_value.advance(offset as usize);
-        self.value_ = new_value;
-        self.value_.resize(0);
+        self.value_offset_ = offset;
+        self.value_len_ = 0;
     }
 
     fn corruption_error(&mut self) {
@@ -191,7 +191,8 @@ impl BlockIterator {
         self.restart_index_ = self.num_restarts_;
         self.status = Status::corruption("block has corrupted restart points", None);
         self.key_.clear();
-        self.value_.clear();
+        self.value_offset_ = self.restarts_;
+        self.value_len_ = 0;
     }
 
     fn parse_next_key(&mut self) -> bool {
@@ -217,15 +218,13 @@ impl BlockIterator {
             false
         } else {
             let kv_ptr = kv_ptr.unwrap();
+            let entry_offset = limit - kv_ptr.len();
             self.key_.reserve((shared + non_shared) as usize);
             // 调整到 shared 长度（截断或清空）
             self.key_.truncate(shared as usize);
-            self.key_.push_str(
-                std::str::from_utf8(&kv_ptr[..non_shared as usize]).expect("Invalid UTF-8"),
-            );
-            self.value_ = Slice::new_from_ptr(
-                &kv_ptr[non_shared as usize..(non_shared + value_length) as usize],
-            );
+            self.key_.put_slice(&kv_ptr[..non_shared as usize]);
+            self.value_offset_ = (entry_offset + non_shared as usize) as u32;
+            self.value_len_ = value_length;
             while self.restart_index_ + 1 < self.num_restarts_
                 && self.get_restart_point(self.restart_index_ + 1) < self.current_
             {
@@ -247,7 +246,7 @@ impl Iter for BlockIterator {
 
     fn seek_to_last(&mut self) {
         self.seek_to_restart_point(self.num_restarts_ - 1);
-        self.parse_next_key();
+        while self.parse_next_key() && self.next_entry_offset() < self.restarts_ {}
     }
 
     fn seek(&mut self, target: &Slice) {
@@ -255,7 +254,7 @@ impl Iter for BlockIterator {
         let mut right = self.num_restarts_ - 1;
         let mut key_compare = Ordering::Equal;
         if self.valid() {
-            key_compare = self.compare(&Slice::new_from_string_buffer(&self.key_), target);
+            key_compare = self.compare(&Slice::new_from_ptr(&self.key_), target);
             if key_compare == Ordering::Less {
                 left = self.restart_index_;
             } else if key_compare == Ordering::Greater {
@@ -300,7 +299,7 @@ impl Iter for BlockIterator {
             if !self.parse_next_key() {
                 return;
             }
-            if self.compare(&Slice::new_from_string_buffer(&self.key_), target) >= Ordering::Equal {
+            if self.compare(&Slice::new_from_ptr(&self.key_), target) >= Ordering::Equal {
                 return;
             }
         }
@@ -328,12 +327,13 @@ impl Iter for BlockIterator {
 
     fn key(&self) -> Slice {
         assert!(self.valid());
-        Slice::new_from_string_buffer(&self.key_)
+        Slice::new_from_array(&self.key_)
     }
 
     fn value(&self) -> Slice {
         assert!(self.valid());
-        self.value_.clone()
+        let start = self.value_offset_ as usize;
+        Slice::new_from_slice(&self.data_, start..start + self.value_len_ as usize)
     }
 
     fn status(&self) -> Status {
@@ -8,12 +8,13 @@ use crate::util::hash::LocalHash;
 use bytes::{BufMut, BytesMut};
 use std::cmp::{min, Ordering};
 use std::hash::Hash;
+use std::sync::Arc;
 
-struct BlockBuilder<E>
+pub(crate) struct BlockBuilder<E>
 where
     E: Env,
 {
-    option: Options<E>,
+    option: Arc<Options<E>>,
     buffer_: BytesMut,
     restarts_: Vec<u32>,
     counter_: i32, //上一个restart index之后，存储了多少个kv
@@ -25,7 +26,7 @@ impl<E> BlockBuilder<E>
 where
     E: Env,
 {
-    fn new(option: Options<E>) -> BlockBuilder<E> {
+    pub(crate) fn new(option: Arc<Options<E>>) -> BlockBuilder<E> {
         assert!(option.block_restart_interval >= 1);
         BlockBuilder {
             option,
@@ -37,7 +38,7 @@ where
         }
     }
 
-    fn reset(&mut self) {
+    pub(crate) fn reset(&mut self) {
         self.buffer_.clear();
         self.restarts_.clear();
         self.restarts_.push(0);
@@ -46,7 +47,7 @@ where
         self.last_key.clear();
     }
 
-    fn add(&mut self, key: &Slice, value: &Slice) {
+    pub(crate) fn add(&mut self, key: &Slice, value: &Slice) {
         let last_key_piece = Slice::new_from_ptr(&self.last_key);
         assert!(!self.finished);
         assert!(self.counter_ <= self.option.block_restart_interval as i32);
@@ -76,11 +77,11 @@ where
         self.counter_ += 1;
     }
 
-    fn current_size_estimate(&self) -> usize {
+    pub(crate) fn current_size_estimate(&self) -> usize {
         self.buffer_.len() + self.restarts_.len() * size_of::<u32>() + size_of::<u32>()
     }
 
-    fn finish(&mut self) -> Slice {
+    pub(crate) fn finish(&mut self) -> Slice {
         // Append restart array
         for i in 0..self.restarts_.len() {
             put_fixed32(&mut self.buffer_, self.restarts_[i]);
@@ -90,7 +91,7 @@ where
         Slice::new_from_ptr(&self.buffer_)
     }
 
-    fn empty(&self) -> bool {
+    pub(crate) fn empty(&self) -> bool {
         self.buffer_.is_empty()
     }
 }
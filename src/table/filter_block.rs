@@ -6,7 +6,7 @@ use std::sync::Arc;
 
 const K_FILTER_BASE_LG: u8 = 11;
 const K_FILTER_BASE: u64 = 1 << K_FILTER_BASE_LG;
-struct FilterBlockBuilder {
+pub(crate) struct FilterBlockBuilder {
     policy_: Arc<dyn FilterPolicy>,
     keys_: BytesMut,
     start_: Vec<usize>,
@@ -55,7 +55,7 @@ impl FilterBlockBuilder {
         self.start_.clear();
     }
 
-    fn start_block(&mut self, block_offset: u64) {
+    pub(crate) fn start_block(&mut self, block_offset: u64) {
         let filter_index = block_offset / K_FILTER_BASE;
         assert!(filter_index >= self.filter_offsets_.len() as u64);
         while filter_index > self.filter_offsets_.len() as u64 {
@@ -63,12 +63,12 @@ impl FilterBlockBuilder {
         }
     }
 
-    fn add_key(&mut self, key: &Slice) {
+    pub(crate) fn add_key(&mut self, key: &Slice) {
         self.start_.push(self.keys_.len());
         self.keys_.put_slice(key.data());
     }
 
-    fn finish(&mut self) -> Slice {
+    pub(crate) fn finish(&mut self) -> Slice {
         if !self.start_.is_empty() {
             self.generate_filter();
         }
@@ -1,4 +1,3 @@
-use crate::obj::byte_buffer::ByteBuffer;
 use crate::obj::options::{CompressionType, ReadOptions};
 use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
@@ -32,7 +31,6 @@ impl BlockHandle {
         self.size
     }
     pub fn encode_to(&self, dst: &mut BytesMut) {
-        debug_assert!(self.offset != 0);
         debug_assert!(self.size != 0);
         put_varint64(dst, self.offset as u64);
         put_varint64(dst, self.size as u64);
@@ -55,7 +53,7 @@ pub struct Footer {
 const K_TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
 
 // 1-byte type + 32-bit crc
-const K_BLOCK_TRAILER_SIZE: u64 = 5;
+pub(crate) const K_BLOCK_TRAILER_SIZE: u64 = 5;
 
 impl Footer {
     pub fn new() -> Footer {
@@ -80,11 +78,11 @@ impl Footer {
         self.index_handle = index_handle.clone();
     }
 
-    fn encode_to(&self, dst: &mut BytesMut) {
+    pub(crate) fn encode_to(&self, dst: &mut BytesMut) {
         let original_size = dst.len();
         self.meta_index_handle.encode_to(dst);
         self.index_handle.encode_to(dst);
-        dst.resize((2 * K_MAX_ENCODED_LENGTH) as usize, 0);
+        dst.resize(original_size + (2 * K_MAX_ENCODED_LENGTH) as usize, 0);
         put_fixed32(dst, (K_TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
         put_fixed32(dst, (K_TABLE_MAGIC_NUMBER >> 32) as u32);
         debug_assert!(dst.len() == original_size + K_ENCODED_LENGTH as usize)
@@ -101,12 +99,15 @@ impl Footer {
         if magic != K_TABLE_MAGIC_NUMBER {
             return Status::corruption("not an sstable (bad magic number)", None);
         }
+        let original_size = input.size();
         let mut result = self.meta_index_handle.decode_from(input);
         if result.is_ok() {
             result = self.index_handle.decode_from(input);
         }
         if result.is_ok() {
-            input.advance(K_ENCODED_LENGTH as usize);
+            // 跳过 handle 之后的填充和 magic number
+            let consumed = original_size - input.size();
+            input.advance(K_ENCODED_LENGTH as usize - consumed);
         }
         result
     }
@@ -133,26 +134,22 @@ pub(crate) fn read_block(
     options: &ReadOptions,
     handle: &BlockHandle,
 ) -> Result<BlockContents, Status> {
-    let n = handle.size();
-    let mut buf = ByteBuffer::new((n + K_BLOCK_TRAILER_SIZE) as usize);
+    let n = handle.size() as usize;
     let mut file = file.lock().unwrap();
-    let contents = file.read(
-        handle.offset(),
-        (n + K_BLOCK_TRAILER_SIZE) as usize,
-        Some(buf.as_mut_slice()),
-    )?;
-    if contents.size() != (n + K_BLOCK_TRAILER_SIZE) as usize {
+    let contents = file.read(handle.offset(), n + K_BLOCK_TRAILER_SIZE as usize, None)?;
+    drop(file);
+    if contents.size() != n + K_BLOCK_TRAILER_SIZE as usize {
         return Err(Status::corruption("truncated block read", None));
     }
     let read_data = contents.data();
     if options.verify_checksums {
-        let crc = crate::util::crc32c::unmask(decode_fixed32(&read_data[(n + 1) as usize..]));
-        let actual = crate::util::crc32c::value(&read_data[..(n + 1) as usize]);
+        let crc = crate::util::crc32c::unmask(decode_fixed32(&read_data[n + 1..]));
+        let actual = crate::util::crc32c::value(&read_data[..n + 1]);
         if crc != actual {
             return Err(Status::corruption("block checksum mismatch", None));
         }
     }
-    let compression = CompressionType::from_u8(read_data[n as usize]);
+    let compression = CompressionType::from_u8(read_data[n]);
     if compression.is_none() {
         return Err(Status::corruption(
             "bad block type or unsupported block compression type",
@@ -160,44 +157,40 @@ pub(crate) fn read_block(
         ));
     }
     let compression = compression.unwrap();
+    let block_data = &read_data[..n];
     match compression {
         CompressionType::None => {
-            if read_data.as_ptr() != buf.as_slice().as_ptr() {
-                let result = BlockContents {
-                    data: contents,
-                    cachable: false,
-                };
-                Ok(result)
-            } else {
-                buf.resize(read_data.len());
-                let result = BlockContents {
-                    data: Slice::new_from_buff(buf),
-                    cachable: true,
-                };
-                Ok(result)
-            }
+            // The returned slice shares ownership of the read buffer (or the mmap),
+            // so it stays valid after the file lock is released. Mmapped blocks are
+            // already in memory and are not worth a second copy in the block cache.
+            let cachable = !contents.is_mmap();
+            let result = BlockContents {
+                data: Slice::new_from_slice(&contents, 0..n),
+                cachable,
+            };
+            Ok(result)
         }
         CompressionType::Snappy => {
-            let u_length = decompress_len(read_data);
+            let u_length = decompress_len(block_data);
             if u_length.is_err() {
                 return Err(Status::corruption(
                     "corrupted snappy compressed block length",
                     None,
                 ));
             }
-            let mut uncompressed = ByteBuffer::new(u_length.unwrap());
-            let success = Decoder::new().decompress(read_data, uncompressed.as_mut_slice());
+            let mut uncompressed = vec![0u8; u_length.unwrap()];
+            let success = Decoder::new().decompress(block_data, &mut uncompressed);
             if success.is_err() {
-                return Err(Status::corruption("corrupted zstd compressed block", None));
+                return Err(Status::corruption("corrupted snappy compressed block", None));
             }
             let result = BlockContents {
-                data: Slice::new_from_buff(uncompressed),
+                data: Slice::new_from_vec(uncompressed),
                 cachable: true,
             };
             Ok(result)
         }
         CompressionType::Zstd => {
-            let u_length = zstd_safe::get_frame_content_size(read_data);
+            let u_length = zstd_safe::get_frame_content_size(block_data);
             if u_length.is_err() {
                 return Err(Status::corruption(
                     "corrupted zstd compressed block length",
@@ -211,14 +204,14 @@ pub(crate) fn read_block(
                     None,
                 ));
             }
-            let mut uncompressed = ByteBuffer::new(u_length.unwrap() as usize);
+            let mut uncompressed = vec![0u8; u_length.unwrap() as usize];
             let mut ctx = DCtx::create();
-            let res = ctx.decompress(uncompressed.as_mut_slice(), read_data);
+            let res = ctx.decompress(&mut uncompressed[..], block_data);
             if res.is_err() {
                 return Err(Status::corruption("corrupted zstd compressed block", None));
             }
             let result = BlockContents {
-                data: Slice::new_from_buff(uncompressed),
+                data: Slice::new_from_vec(uncompressed),
                 cachable: true,
             };
             Ok(result)
@@ -57,10 +57,12 @@ impl IteratorWrapper {
     pub fn next(&mut self) {
         assert!(self.iter.is_some());
         self.iter.as_mut().map(|iter| iter.next());
+        self.update();
     }
     pub fn prev(&mut self) {
         assert!(self.iter.is_some());
         self.iter.as_mut().map(|iter| iter.prev());
+        self.update();
     }
 
     pub fn seek(&mut self, target: &Slice) {
@@ -0,0 +1,306 @@
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::iterator::{new_empty_iterator, Iter};
+use crate::table::iterator_wrapper::IteratorWrapper;
+use crate::util::comparator::Comparator;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// 把多个有序的 child 合并成一个有序的迭代器，相同的 key 不去重
+pub(crate) struct MergingIterator {
+    comparator: Arc<dyn Comparator>,
+    children: Vec<IteratorWrapper>,
+    // 当前指向的 child 下标
+    current: Option<usize>,
+    direction: Direction,
+}
+
+impl MergingIterator {
+    fn new(comparator: Arc<dyn Comparator>, children: Vec<Box<dyn Iter>>) -> Self {
+        MergingIterator {
+            comparator,
+            children: children
+                .into_iter()
+                .map(|child| IteratorWrapper::new(Some(child)))
+                .collect(),
+            current: None,
+            direction: Direction::Forward,
+        }
+    }
+
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            match smallest {
+                Some(s)
+                    if self
+                        .comparator
+                        .compare(&child.key(), &self.children[s].key())
+                        != Ordering::Less => {}
+                _ => smallest = Some(i),
+            }
+        }
+        self.current = smallest;
+    }
+
+    fn find_largest(&mut self) {
+        let mut largest: Option<usize> = None;
+        // 从后往前找，key 相同时保持与正向相反的顺序
+        for (i, child) in self.children.iter().enumerate().rev() {
+            if !child.valid() {
+                continue;
+            }
+            match largest {
+                Some(l)
+                    if self
+                        .comparator
+                        .compare(&child.key(), &self.children[l].key())
+                        != Ordering::Greater => {}
+                _ => largest = Some(i),
+            }
+        }
+        self.current = largest;
+    }
+}
+
+impl Iter for MergingIterator {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        for child in self.children.iter_mut() {
+            child.seek(target);
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn next(&mut self) {
+        let current = self.current.expect("next on invalid MergingIterator");
+        // 反向时其它 child 位于 key() 之前，需要先把它们移到 key() 之后。
+        // 正向时 current 之外的 child 已经都在 key() 之后
+        if self.direction != Direction::Forward {
+            let key = self.key();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() && self.comparator.compare(&key, &child.key()) == Ordering::Equal {
+                    child.next();
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+        self.children[current].next();
+        self.find_smallest();
+    }
+
+    fn prev(&mut self) {
+        let current = self.current.expect("prev on invalid MergingIterator");
+        // 正向时其它 child 位于 key() 之后，需要先把它们移到 key() 之前
+        if self.direction != Direction::Reverse {
+            let key = self.key();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() {
+                    // child 的第一个 >= key 的位置，退一步即可
+                    child.prev();
+                } else {
+                    // child 中没有 >= key 的数据，最后一个就是 < key 的
+                    child.seek_to_last();
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.children[current].prev();
+        self.find_largest();
+    }
+
+    fn key(&self) -> Slice {
+        self.children[self.current.unwrap()].key()
+    }
+
+    fn value(&self) -> Slice {
+        self.children[self.current.unwrap()].value()
+    }
+
+    fn status(&self) -> Status {
+        self.children
+            .iter()
+            .map(|child| child.status())
+            .find(|s| !s.is_ok())
+            .unwrap_or_else(Status::ok)
+    }
+}
+
+/// 合并 children，children 为空时返回空迭代器，只有一个时直接返回它
+pub(crate) fn new_merging_iterator(
+    comparator: Arc<dyn Comparator>,
+    mut children: Vec<Box<dyn Iter>>,
+) -> Box<dyn Iter> {
+    match children.len() {
+        0 => Box::new(new_empty_iterator()),
+        1 => children.pop().unwrap(),
+        _ => Box::new(MergingIterator::new(comparator, children)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::iterator::new_error_iterator;
+    use crate::util::bytewise_comparator_impl::BytewiseComparatorImpl;
+
+    // 按顺序保存 (key, value) 的测试迭代器
+    struct VecIter {
+        data: Vec<(&'static str, &'static str)>,
+        pos: Option<usize>,
+    }
+
+    impl VecIter {
+        fn new(data: Vec<(&'static str, &'static str)>) -> Box<dyn Iter> {
+            Box::new(VecIter { data, pos: None })
+        }
+    }
+
+    impl Iter for VecIter {
+        fn valid(&self) -> bool {
+            self.pos.is_some()
+        }
+        fn seek_to_first(&mut self) {
+            self.pos = if self.data.is_empty() { None } else { Some(0) };
+        }
+        fn seek_to_last(&mut self) {
+            self.pos = self.data.len().checked_sub(1);
+        }
+        fn seek(&mut self, target: &Slice) {
+            self.pos = self
+                .data
+                .iter()
+                .position(|(k, _)| Slice::from(*k).compare(target) != Ordering::Less);
+        }
+        fn next(&mut self) {
+            self.pos = self.pos.map(|p| p + 1).filter(|p| *p < self.data.len());
+        }
+        fn prev(&mut self) {
+            self.pos = self.pos.and_then(|p| p.checked_sub(1));
+        }
+        fn key(&self) -> Slice {
+            Slice::from(self.data[self.pos.unwrap()].0)
+        }
+        fn value(&self) -> Slice {
+            Slice::from(self.data[self.pos.unwrap()].1)
+        }
+        fn status(&self) -> Status {
+            Status::ok()
+        }
+    }
+
+    fn new_test_iterator() -> Box<dyn Iter> {
+        new_merging_iterator(
+            Arc::new(BytewiseComparatorImpl {}),
+            vec![
+                VecIter::new(vec![("a", "1"), ("d", "1"), ("g", "1")]),
+                VecIter::new(vec![]),
+                VecIter::new(vec![("b", "2"), ("d", "2"), ("h", "2")]),
+                VecIter::new(vec![("c", "3")]),
+            ],
+        )
+    }
+
+    fn entry(iter: &dyn Iter) -> String {
+        format!("{}{}", iter.key().to_string(), iter.value().to_string())
+    }
+
+    #[test]
+    fn test_forward_and_backward() {
+        let mut iter = new_test_iterator();
+        let mut forward = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(entry(iter.as_ref()));
+            iter.next();
+        }
+        assert_eq!(vec!["a1", "b2", "c3", "d1", "d2", "g1", "h2"], forward);
+
+        let mut backward = vec![];
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(entry(iter.as_ref()));
+            iter.prev();
+        }
+        assert_eq!(vec!["h2", "g1", "d2", "d1", "c3", "b2", "a1"], backward);
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_seek_and_change_direction() {
+        let mut iter = new_test_iterator();
+        iter.seek(&Slice::from("c"));
+        assert_eq!("c3", entry(iter.as_ref()));
+        iter.next();
+        assert_eq!("d1", entry(iter.as_ref()));
+        iter.prev();
+        assert_eq!("c3", entry(iter.as_ref()));
+        iter.prev();
+        assert_eq!("b2", entry(iter.as_ref()));
+        iter.next();
+        assert_eq!("c3", entry(iter.as_ref()));
+
+        iter.seek(&Slice::from("e"));
+        assert_eq!("g1", entry(iter.as_ref()));
+        iter.prev();
+        assert_eq!("d2", entry(iter.as_ref()));
+        iter.seek(&Slice::from("z"));
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_empty_and_error_children() {
+        let cmp: Arc<dyn Comparator> = Arc::new(BytewiseComparatorImpl {});
+        let mut iter = new_merging_iterator(cmp.clone(), vec![]);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+
+        let mut iter = new_merging_iterator(
+            cmp,
+            vec![
+                VecIter::new(vec![("a", "1")]),
+                Box::new(new_error_iterator(Status::corruption("bad block", None))),
+            ],
+        );
+        iter.seek_to_first();
+        assert_eq!("a1", entry(iter.as_ref()));
+        assert!(iter.status().is_corruption());
+    }
+}
@@ -4,6 +4,8 @@ mod filter_block;
 mod format;
 pub mod iterator;
 mod iterator_wrapper;
+pub(crate) mod merger;
 pub(crate) mod table;
+pub(crate) mod table_builder;
 mod table_test;
-mod two_level_iterator;
+pub(crate) mod two_level_iterator;
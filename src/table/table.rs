@@ -6,15 +6,17 @@ use crate::table::block::Block;
 use crate::table::filter_block::FilterBlockReader;
 use crate::table::format::{read_block, BlockHandle, Footer, K_ENCODED_LENGTH};
 use crate::table::iterator::{new_error_iterator, Iter};
-use crate::table::two_level_iterator::TwoLevelIterator;
+use crate::table::two_level_iterator::{BlockFunction, TwoLevelIterator};
 use crate::util::bytewise_comparator_impl::byte_wise_comparator;
 use crate::util::coding::encode_fixed64;
 use crate::util::env::Env;
 use crate::util::filter_policy::FilterPolicy;
 use crate::util::random_access_file::RandomAccessFile;
 use std::any::Any;
-use std::sync::{Arc, Mutex};
-pub type HandleResult = Box<dyn Fn(Box<dyn Any>, &Slice, &Slice)>;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+/// 处理 internal_get 找到的 entry，返回它是否就是要查找的 key
+pub type HandleResult = Box<dyn Fn(Box<dyn Any>, &Slice, &Slice) -> bool>;
 struct Rep<E>
 where
     E: Env,
@@ -50,6 +52,12 @@ where
     E: Env,
 {
     rep: Arc<Mutex<Rep<E>>>,
+    // filter 判断 key 不存在，省去了一次 data block 的读取
+    filter_useful: AtomicU64,
+    // filter 判断 key 可能存在，但 data block 中并没有
+    filter_false_positive: AtomicU64,
+    // approximate_num_entries 第一次成功读取后的结果，table 不会改变
+    num_entries: OnceLock<u64>,
 }
 
 impl<'a, E> Table<E>
@@ -57,7 +65,12 @@ where
     E: Env + 'static,
 {
     fn new(rep: Arc<Mutex<Rep<E>>>) -> Table<E> {
-        Table { rep }
+        Table {
+            rep,
+            filter_useful: AtomicU64::new(0),
+            filter_false_positive: AtomicU64::new(0),
+            num_entries: OnceLock::new(),
+        }
     }
     pub fn open(
         options: Arc<Options<E>>,
@@ -94,7 +107,7 @@ where
             Some(ref cache) => cache.new_id(),
             None => 0,
         };
-        let rep = Rep {
+        let mut rep = Rep {
             options: options.clone(),
             status: Status::ok(),
             file,
@@ -103,18 +116,19 @@ where
             meta_index_handle: footer.meta_index_handle().clone(),
             index_block: Arc::new(index_block),
         };
+        Self::read_meta(&mut rep, &footer);
         let table = Arc::new(Table::new(Arc::new(Mutex::new(rep))));
         Ok(table)
     }
 
-    fn read_filter(&mut self, filter_handle_value: &mut Slice) {
+    // 读取失败时不使用 filter，不影响读取的正确性
+    fn read_filter(rep: &mut Rep<E>, filter_handle_value: &mut Slice) {
         let mut filter_handle = BlockHandle::new();
         if !filter_handle.decode_from(filter_handle_value).is_ok() {
             return;
         }
 
         let mut opt = ReadOptions::new();
-        let mut rep = self.rep.lock().unwrap();
         if rep.options.paranoid_checks {
             opt.verify_checksums = true;
         }
@@ -128,31 +142,28 @@ where
         rep.filter = Some(Arc::new(filter_reader))
     }
 
-    fn read_meta(&mut self, footer: &Footer) {
-        let rep = self.rep.lock().unwrap();
-        if rep.options.filter_policy.is_none() {
-            return;
-        }
+    // 在 meta index block 中查找与当前 filter policy 同名的 filter block，
+    // 用其它 policy 生成的 filter 无法使用
+    fn read_meta(rep: &mut Rep<E>, footer: &Footer) {
+        let name = match rep.options.filter_policy {
+            Some(ref filter_policy) => filter_policy.name(),
+            None => return,
+        };
         let mut opt = ReadOptions::new();
         if rep.options.paranoid_checks {
             opt.verify_checksums = true;
         }
-        let contents = read_block(rep.file.clone(), &opt, &footer.meta_index_handle());
+        let contents = read_block(rep.file.clone(), &opt, footer.meta_index_handle());
         if contents.is_err() {
             return;
         }
         let contents = contents.unwrap();
         let meta_block = Block::new(contents);
         let mut iter = meta_block.new_iterator(byte_wise_comparator());
-        let name = match rep.options.filter_policy {
-            Some(ref filter_policy) => filter_policy.name(),
-            None => "",
-        };
-        drop(rep);
         let key = Slice::new_from_string(format!("filter.{}", name));
         iter.seek(&key);
         if iter.valid() && iter.key() == key {
-            self.read_filter(&mut iter.value());
+            Self::read_filter(rep, &mut iter.value());
         }
     }
 
@@ -187,7 +198,10 @@ where
                             let cache_block = Block::new(contents);
                             block = Some(cache_block.clone());
                             if need_cache {
-                                let _ = cache.insert(&key, cache_block);
+                                let key = Slice::new_from_array(&cache_key_buffer);
+                                // 按解压后的大小计算占用
+                                let charge = cache_block.data.size();
+                                let _ = cache.insert(&key, cache_block, charge);
                             };
                         } else {
                             status = s.err().unwrap();
@@ -216,18 +230,19 @@ where
         }
     }
 
-    fn new_iterator(&'a self, options: ReadOptions) -> Box<dyn Iter + 'a> {
+    pub(crate) fn new_iterator(self: &Arc<Self>, options: ReadOptions) -> Box<dyn Iter> {
         let rep = self.rep.lock().unwrap();
         let index_block_iter = rep.index_block.new_iterator(rep.options.comparator.clone());
-        let block_function = Box::new(Table::<E>::block_reader);
-
-        let res: Box<dyn Iter> = Box::new(TwoLevelIterator::<'a, E>::new(
+        drop(rep);
+        let table = self.clone();
+        let block_function: BlockFunction = Box::new(move |read_options, index_value| {
+            Table::<E>::block_reader(&table, read_options, index_value)
+        });
+        Box::new(TwoLevelIterator::new(
             index_block_iter,
             block_function,
-            self,
             options,
-        ));
-        res
+        ))
     }
 
     pub fn internal_get(
@@ -238,26 +253,32 @@ where
         handle_result: HandleResult,
     ) -> Status {
         let mut s = Status::ok();
+        // block_reader 会再次获取 rep 的锁，这里只在创建 index 迭代器时持有
         let rep = self.rep.lock().unwrap();
         let mut iiter = rep.index_block.new_iterator(rep.options.comparator.clone());
+        let filter = rep.filter.clone();
+        drop(rep);
         iiter.seek(key);
         if iiter.valid() {
             let mut handle_value = iiter.value();
-            let filter = rep.filter.clone();
             let mut handle = BlockHandle::new();
-            if filter.is_some()
-                && handle.decode_from(&mut handle_value).is_ok()
-                && !filter
-                    .map(|filter| filter.key_may_match(handle.offset(), key))
-                    .unwrap_or(true)
+            // index 中的 handle 损坏时不使用 filter，由 block_reader 报告错误
+            let filter = filter.filter(|_| handle.decode_from(&mut handle_value).is_ok());
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.key_may_match(handle.offset(), key))
             {
+                // 一定不存在，不需要读 data block
+                self.filter_useful.fetch_add(1, Ordering::Relaxed);
             } else {
                 let mut block_iter = Self::block_reader(self, options, &iiter.value());
                 block_iter.seek(key);
-                if block_iter.valid() {
-                    handle_result(arg, &block_iter.key(), &block_iter.value());
-                }
+                let found = block_iter.valid()
+                    && handle_result(arg, &block_iter.key(), &block_iter.value());
                 s = block_iter.status();
+                if filter.is_some() && !found && s.is_ok() {
+                    self.filter_false_positive.fetch_add(1, Ordering::Relaxed);
+                }
             }
         }
         if s.is_ok() {
@@ -266,7 +287,61 @@ where
         s
     }
 
-    fn approximate_offset_of(&self, key: &Slice) -> u64 {
+    /// filter 判断 key 不存在、省去读取 data block 的次数
+    pub(crate) fn filter_useful(&self) -> u64 {
+        self.filter_useful.load(Ordering::Relaxed)
+    }
+
+    /// filter 判断 key 可能存在、读取 data block 后却没有找到的次数
+    pub(crate) fn filter_false_positive(&self) -> u64 {
+        self.filter_false_positive.load(Ordering::Relaxed)
+    }
+
+    /// 估计 table 中的 entry 个数：data block 的个数乘以第一个 data block 中的 entry 个数。
+    /// 只在第一次调用时读取 data block，之后返回缓存的结果
+    pub(crate) fn approximate_num_entries(&self) -> u64 {
+        if let Some(num_entries) = self.num_entries.get() {
+            return *num_entries;
+        }
+        // 读取失败时不缓存，下次调用再试
+        match self.count_num_entries() {
+            Some(num_entries) => *self.num_entries.get_or_init(|| num_entries),
+            None => 0,
+        }
+    }
+
+    fn count_num_entries(&self) -> Option<u64> {
+        let rep = self.rep.lock().unwrap();
+        let mut index_iter = rep.index_block.new_iterator(rep.options.comparator.clone());
+        drop(rep);
+        index_iter.seek_to_first();
+        if !index_iter.valid() {
+            return Some(0);
+        }
+
+        let mut options = ReadOptions::new();
+        // 只为统计读取，不放进缓存
+        options.fill_cache = false;
+        let mut block_iter = Self::block_reader(self, &options, &index_iter.value());
+        let mut entries_per_block = 0u64;
+        block_iter.seek_to_first();
+        while block_iter.valid() {
+            entries_per_block += 1;
+            block_iter.next();
+        }
+        if !block_iter.status().is_ok() {
+            return None;
+        }
+
+        let mut num_blocks = 0u64;
+        while index_iter.valid() {
+            num_blocks += 1;
+            index_iter.next();
+        }
+        Some(num_blocks * entries_per_block)
+    }
+
+    pub(crate) fn approximate_offset_of(&self, key: &Slice) -> u64 {
         let rep = self.rep.lock().unwrap();
         let mut index_iter = rep.index_block.new_iterator(rep.options.comparator.clone());
         index_iter.seek(key);
@@ -0,0 +1,294 @@
+use crate::obj::options::{CompressionType, Options};
+use crate::obj::slice::Slice;
+use crate::obj::status_rs::Status;
+use crate::table::block_builder::BlockBuilder;
+use crate::table::filter_block::FilterBlockBuilder;
+use crate::table::format::{BlockHandle, Footer, K_BLOCK_TRAILER_SIZE};
+use crate::util::coding::encode_fixed32;
+use crate::util::crc32c;
+use crate::util::env::Env;
+use crate::util::writable_file::WritableFile;
+use bytes::{BufMut, BytesMut};
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+
+/// 按顺序接收 key/value，生成一个完整的 sstable，格式与 `Table::open` 读取的一致。
+///
+/// 调用方必须保证 key 严格递增（按 `Options::comparator`），最后调用 `finish` 或 `abandon`。
+pub(crate) struct TableBuilder<E>
+where
+    E: Env,
+{
+    options: Arc<Options<E>>,
+    file: Arc<Mutex<dyn WritableFile>>,
+    offset: u64,
+    status: Status,
+    data_block: BlockBuilder<E>,
+    index_block: BlockBuilder<E>,
+    last_key: BytesMut,
+    num_entries: u64,
+    closed: bool,
+    filter_block: Option<FilterBlockBuilder>,
+    // 在看到下一个 data block 的第一个 key 之前，不写入上一个 block 的索引项，
+    // 这样可以用更短的分隔 key 作为索引（见 Comparator::find_shortest_separator）。
+    pending_index_entry: bool,
+    pending_handle: BlockHandle,
+}
+
+impl<E> TableBuilder<E>
+where
+    E: Env,
+{
+    pub(crate) fn new(options: Arc<Options<E>>, file: Arc<Mutex<dyn WritableFile>>) -> Self {
+        // index block 的每一项都是重启点，便于二分查找
+        let mut index_block_options = options.as_ref().clone();
+        index_block_options.block_restart_interval = 1;
+        let filter_block = options
+            .filter_policy
+            .clone()
+            .map(|policy| FilterBlockBuilder::new(policy));
+        let mut builder = TableBuilder {
+            options: options.clone(),
+            file,
+            offset: 0,
+            status: Status::ok(),
+            data_block: BlockBuilder::new(options),
+            index_block: BlockBuilder::new(Arc::new(index_block_options)),
+            last_key: BytesMut::new(),
+            num_entries: 0,
+            closed: false,
+            filter_block,
+            pending_index_entry: false,
+            pending_handle: BlockHandle::new(),
+        };
+        if let Some(filter_block) = builder.filter_block.as_mut() {
+            filter_block.start_block(0);
+        }
+        builder
+    }
+
+    pub(crate) fn add(&mut self, key: &Slice, value: &Slice) {
+        assert!(!self.closed);
+        if !self.ok() {
+            return;
+        }
+        if self.num_entries > 0 {
+            assert_eq!(
+                self.options
+                    .comparator
+                    .compare(key, &Slice::new_from_ptr(&self.last_key)),
+                Ordering::Greater
+            );
+        }
+        if self.pending_index_entry {
+            assert!(self.data_block.empty());
+            self.options
+                .comparator
+                .find_shortest_separator(&mut self.last_key, key);
+            let mut handle_encoding = BytesMut::new();
+            self.pending_handle.encode_to(&mut handle_encoding);
+            self.index_block.add(
+                &Slice::new_from_ptr(&self.last_key),
+                &Slice::new_from_ptr(&handle_encoding),
+            );
+            self.pending_index_entry = false;
+        }
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block.add_key(key);
+        }
+        self.last_key.clear();
+        self.last_key.put_slice(key.data());
+        self.num_entries += 1;
+        self.data_block.add(key, value);
+
+        if self.data_block.current_size_estimate() >= self.options.block_size {
+            self.flush();
+        }
+    }
+
+    /// 把缓冲的 data block 写入文件。主要给 `add` 内部使用，调用方也可以用它强制切块。
+    pub(crate) fn flush(&mut self) {
+        assert!(!self.closed);
+        if !self.ok() || self.data_block.empty() {
+            return;
+        }
+        assert!(!self.pending_index_entry);
+        let raw = self.data_block.finish();
+        let handle = self.write_block(&raw);
+        self.data_block.reset();
+        if let Ok(handle) = handle {
+            self.pending_handle = handle;
+            self.pending_index_entry = true;
+            self.status = self.file.lock().unwrap().flush();
+        }
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block.start_block(self.offset);
+        }
+    }
+
+    /// 按 `Options::compression` 压缩后写入一个 block；压缩收益不足 12.5% 时写原始数据。
+    fn write_block(&mut self, raw: &Slice) -> Result<BlockHandle, Status> {
+        let raw_data = raw.data();
+        let compressed = match self.options.compression {
+            CompressionType::None => None,
+            CompressionType::Snappy => snap::raw::Encoder::new().compress_vec(raw_data).ok(),
+            CompressionType::Zstd => {
+                let mut output = vec![0u8; zstd_safe::compress_bound(raw_data.len())];
+                zstd_safe::compress(
+                    &mut output[..],
+                    raw_data,
+                    self.options.zstd_compression_level,
+                )
+                .ok()
+                .map(|len| {
+                    output.truncate(len);
+                    output
+                })
+            }
+        };
+        match compressed {
+            Some(ref output) if output.len() < raw_data.len() - (raw_data.len() / 8) => {
+                let compression = self.options.compression;
+                self.write_raw_block(&Slice::new_from_ptr(output), compression)
+            }
+            _ => self.write_raw_block(raw, CompressionType::None),
+        }
+    }
+
+    fn write_raw_block(
+        &mut self,
+        block_contents: &Slice,
+        compression: CompressionType,
+    ) -> Result<BlockHandle, Status> {
+        let mut handle = BlockHandle::new();
+        handle.set_offset(self.offset);
+        handle.set_size(block_contents.size() as u64);
+        let mut file = self.file.lock().unwrap();
+        self.status = file.append(block_contents);
+        if self.status.is_ok() {
+            let mut trailer = [0u8; K_BLOCK_TRAILER_SIZE as usize];
+            trailer[0] = compression as u8;
+            let crc = crc32c::extend(crc32c::value(block_contents.data()), &trailer[..1]);
+            encode_fixed32(&mut trailer[1..], crc32c::mask(crc));
+            self.status = file.append(&Slice::new_from_ptr(&trailer));
+            if self.status.is_ok() {
+                self.offset += block_contents.size() as u64 + K_BLOCK_TRAILER_SIZE;
+            }
+        }
+        if self.status.is_ok() {
+            Ok(handle)
+        } else {
+            Err(self.status.clone())
+        }
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        self.status.clone()
+    }
+
+    fn ok(&self) -> bool {
+        self.status.is_ok()
+    }
+
+    /// 写入剩余的 data block、filter block、meta index block、index block 和 footer。
+    pub(crate) fn finish(&mut self) -> Status {
+        self.flush();
+        assert!(!self.closed);
+        self.closed = true;
+
+        let mut filter_block_handle = BlockHandle::new();
+        let mut meta_index_block_handle = BlockHandle::new();
+        let mut index_block_handle = BlockHandle::new();
+
+        // Write filter block
+        if self.ok() {
+            if let Some(mut filter_block) = self.filter_block.take() {
+                let contents = filter_block.finish();
+                if let Ok(handle) = self.write_raw_block(&contents, CompressionType::None) {
+                    filter_block_handle = handle;
+                }
+            }
+        }
+
+        // Write meta index block
+        if self.ok() {
+            let mut meta_index_block = BlockBuilder::new(self.options.clone());
+            if let Some(policy) = self.options.filter_policy.as_ref() {
+                let key = format!("filter.{}", policy.name());
+                let mut handle_encoding = BytesMut::new();
+                filter_block_handle.encode_to(&mut handle_encoding);
+                meta_index_block.add(
+                    &Slice::new_from_string(key),
+                    &Slice::new_from_ptr(&handle_encoding),
+                );
+            }
+            let raw = meta_index_block.finish();
+            if let Ok(handle) = self.write_block(&raw) {
+                meta_index_block_handle = handle;
+            }
+        }
+
+        // Write index block
+        if self.ok() {
+            if self.pending_index_entry {
+                self.options
+                    .comparator
+                    .find_short_successor(&mut self.last_key);
+                let mut handle_encoding = BytesMut::new();
+                self.pending_handle.encode_to(&mut handle_encoding);
+                self.index_block.add(
+                    &Slice::new_from_ptr(&self.last_key),
+                    &Slice::new_from_ptr(&handle_encoding),
+                );
+                self.pending_index_entry = false;
+            }
+            let raw = self.index_block.finish();
+            if let Ok(handle) = self.write_block(&raw) {
+                index_block_handle = handle;
+            }
+        }
+
+        // Write footer
+        if self.ok() {
+            let mut footer = Footer::new();
+            footer.set_meta_index_handle(&meta_index_block_handle);
+            footer.set_index_handle(&index_block_handle);
+            let mut footer_encoding = BytesMut::new();
+            footer.encode_to(&mut footer_encoding);
+            self.status = self
+                .file
+                .lock()
+                .unwrap()
+                .append(&Slice::new_from_ptr(&footer_encoding));
+            if self.ok() {
+                self.offset += footer_encoding.len() as u64;
+            }
+        }
+        self.status.clone()
+    }
+
+    /// 放弃构建，已经写入文件的内容由调用方负责删除。
+    pub(crate) fn abandon(&mut self) {
+        assert!(!self.closed);
+        self.closed = true;
+    }
+
+    pub(crate) fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// 目前已经生成的文件大小；`finish` 成功后即为最终文件大小。
+    pub(crate) fn file_size(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<E> Drop for TableBuilder<E>
+where
+    E: Env,
+{
+    fn drop(&mut self) {
+        // 调用方必须先调用 finish 或 abandon
+        debug_assert!(self.closed || std::thread::panicking());
+    }
+}
@@ -167,3 +167,239 @@ trait Constructor {
     fn new_iterator(&self) -> Box<dyn Iter>;
     /*    fn db() -> D*/
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{KVMap, StringSink, StringSource};
+    use crate::obj::options::{CompressionType, Options, ReadOptions};
+    use crate::obj::slice::Slice;
+    use crate::table::iterator::Iter;
+    use crate::table::table::Table;
+    use crate::table::table_builder::TableBuilder;
+    use crate::util::env::{Env, StdEnv};
+    use crate::util::new_bloom_filter_policy;
+    use crate::util::random::Random;
+    use crate::util::random_access_file::RandomAccessFile;
+    use crate::util::test_util::{compressible_string, random_key, random_string};
+    use bytes::BytesMut;
+    use std::sync::{Arc, Mutex};
+
+    fn new_options() -> Options<StdEnv> {
+        let mut options = Options::new(Arc::new(StdEnv::new()));
+        options.compression = CompressionType::None;
+        options
+    }
+
+    fn build_table(options: &Arc<Options<StdEnv>>, data: &KVMap) -> BytesMut {
+        let sink = Arc::new(Mutex::new(StringSink {
+            contents_: BytesMut::new(),
+        }));
+        let mut builder = TableBuilder::new(options.clone(), sink.clone());
+        for (key, value) in data.iter() {
+            builder.add(key, value);
+            assert!(builder.status().is_ok());
+        }
+        assert!(builder.finish().is_ok());
+        assert_eq!(data.len() as u64, builder.num_entries());
+        let contents = sink.lock().unwrap().contents_.clone();
+        assert_eq!(contents.len() as u64, builder.file_size());
+        contents
+    }
+
+    fn open_table(options: &Arc<Options<StdEnv>>, contents: BytesMut) -> Arc<Table<StdEnv>> {
+        let size = contents.len() as u64;
+        let source: Arc<Mutex<dyn RandomAccessFile>> =
+            Arc::new(Mutex::new(StringSource::new_contents(contents)));
+        Table::open(options.clone(), source, size).unwrap()
+    }
+
+    fn check_table(table: &Arc<Table<StdEnv>>, data: &KVMap) {
+        let mut read_options = ReadOptions::new();
+        read_options.verify_checksums = true;
+        let mut iter = table.new_iterator(read_options);
+
+        iter.seek_to_first();
+        for (key, value) in data.iter() {
+            assert!(iter.valid());
+            assert_eq!(key.data(), iter.key().data());
+            assert_eq!(value.data(), iter.value().data());
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        for (key, value) in data.iter().rev() {
+            assert!(iter.valid());
+            assert_eq!(key.data(), iter.key().data());
+            assert_eq!(value.data(), iter.value().data());
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        for (key, value) in data.iter() {
+            iter.seek(key);
+            assert!(iter.valid());
+            assert_eq!(key.data(), iter.key().data());
+            assert_eq!(value.data(), iter.value().data());
+        }
+        assert!(iter.status().is_ok());
+    }
+
+    fn random_data(rnd: &mut Random, num_entries: usize) -> KVMap {
+        let mut data = KVMap::new();
+        for _ in 0..num_entries {
+            let key_len = rnd.skewed(4) as usize + 1;
+            let key = random_key(rnd, key_len);
+            let value_len = rnd.skewed(5) as usize;
+            let mut value = BytesMut::new();
+            random_string(rnd, value_len, &mut value);
+            data.insert(Slice::new_bytes_mut(key), Slice::new_bytes_mut(value));
+        }
+        data
+    }
+
+    #[test]
+    fn test_empty_table() {
+        let options = Arc::new(new_options());
+        let contents = build_table(&options, &KVMap::new());
+        let table = open_table(&options, contents);
+        let mut iter = table.new_iterator(ReadOptions::new());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rnd = Random::new(301);
+        for restart_interval in [1, 2, 16, 1024] {
+            for num_entries in [1, 3, 100, 2000] {
+                let mut options = new_options();
+                options.block_restart_interval = restart_interval;
+                options.block_size = 256;
+                let options = Arc::new(options);
+                let data = random_data(&mut rnd, num_entries);
+                let table = open_table(&options, build_table(&options, &data));
+                check_table(&table, &data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        for compression in [CompressionType::Snappy, CompressionType::Zstd] {
+            let mut rnd = Random::new(301);
+            let mut options = new_options();
+            options.compression = compression;
+            let options = Arc::new(options);
+            let mut data = KVMap::new();
+            for i in 0..200 {
+                let mut value = BytesMut::new();
+                compressible_string(&mut rnd, 0.25, 1000, &mut value);
+                data.insert(
+                    Slice::new_from_string(format!("k{:04}", i)),
+                    Slice::new_bytes_mut(value),
+                );
+            }
+            let contents = build_table(&options, &data);
+            // 四分之一可压缩率的数据压缩后应该明显小于原始数据
+            assert!(contents.len() < 200 * 1000 / 2);
+            let table = open_table(&options, contents);
+            check_table(&table, &data);
+        }
+    }
+
+    // 用 internal_get 查找 key，返回是否找到
+    fn table_get(table: &Arc<Table<StdEnv>>, key: &Slice) -> bool {
+        let found = Arc::new(Mutex::new(false));
+        let target = key.clone();
+        let s = table.internal_get(
+            &ReadOptions::new(),
+            key,
+            Box::new(found.clone()),
+            Box::new(move |arg, k, _v| {
+                let matched = k.data() == target.data();
+                let found = arg.downcast::<Arc<Mutex<bool>>>().unwrap();
+                *found.lock().unwrap() = matched;
+                matched
+            }),
+        );
+        assert!(s.is_ok());
+        let found = *found.lock().unwrap();
+        found
+    }
+
+    #[test]
+    fn test_filter_block() {
+        let mut options = new_options();
+        options.block_size = 256;
+        options.filter_policy = Some(new_bloom_filter_policy(10));
+        let options = Arc::new(options);
+        let mut data = KVMap::new();
+        for i in 0..1000 {
+            data.insert(
+                Slice::new_from_string(format!("key{:06}", i * 2)),
+                Slice::new_from_string(format!("value{}", i)),
+            );
+        }
+        let contents = build_table(&options, &data);
+
+        let table = open_table(&options, contents.clone());
+        for key in data.keys() {
+            assert!(table_get(&table, key));
+        }
+        assert_eq!(0, table.filter_useful());
+        assert_eq!(0, table.filter_false_positive());
+        // 不存在的 key 绝大部分被 filter 排除，不需要读 data block
+        for i in 0..1000 {
+            assert!(!table_get(&table, &Slice::new_from_string(format!("key{:06}", i * 2 + 1))));
+        }
+        assert_eq!(1000, table.filter_useful() + table.filter_false_positive());
+        assert!(table.filter_false_positive() < 50, "{}", table.filter_false_positive());
+
+        // 没有配置 filter policy 时不读取 filter block
+        let table = open_table(&Arc::new(new_options()), contents);
+        check_table(&table, &data);
+        assert!(!table_get(&table, &Slice::from("key000001")));
+        assert!(table_get(&table, &Slice::from("key000002")));
+        assert_eq!(0, table.filter_useful());
+        assert_eq!(0, table.filter_false_positive());
+    }
+
+    fn between(val: u64, low: u64, high: u64) -> bool {
+        val >= low && val <= high
+    }
+
+    #[test]
+    fn test_approximate_offset_of_plain() {
+        let mut options = new_options();
+        options.block_size = 1024;
+        let options = Arc::new(options);
+        let mut data = KVMap::new();
+        let mut add = |key: &'static str, len: usize| {
+            data.insert(Slice::new_from_str(key), Slice::new_from_vec(vec![b'x'; len]));
+        };
+        add("k01", 5);
+        add("k02", 6);
+        add("k03", 10000);
+        add("k04", 200000);
+        add("k05", 300000);
+        add("k06", 6);
+        add("k07", 100000);
+        let table = open_table(&options, build_table(&options, &data));
+        let offset = |key: &'static str| table.approximate_offset_of(&Slice::new_from_str(key));
+        assert!(between(offset("abc"), 0, 0));
+        assert!(between(offset("k01"), 0, 0));
+        assert!(between(offset("k01a"), 0, 0));
+        assert!(between(offset("k02"), 0, 0));
+        assert!(between(offset("k03"), 0, 0));
+        assert!(between(offset("k04"), 10000, 11000));
+        assert!(between(offset("k04a"), 210000, 211000));
+        assert!(between(offset("k05"), 210000, 211000));
+        assert!(between(offset("k06"), 510000, 511000));
+        assert!(between(offset("k07"), 510000, 511000));
+        assert!(between(offset("xyz"), 610000, 612000));
+    }
+}
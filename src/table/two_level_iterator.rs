@@ -3,18 +3,13 @@ use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
 use crate::table::iterator::Iter;
 use crate::table::iterator_wrapper::IteratorWrapper;
-use crate::table::table::Table;
-use crate::util::env::Env;
 use bytes::BufMut;
 use std::cmp::Ordering;
 
-type BlockFunction<E> = Box<dyn Fn(&Table<E>, &ReadOptions, &Slice) -> Box<dyn Iter>>;
-pub struct TwoLevelIterator<'a, E>
-where
-    E: Env,
-{
-    block_function: BlockFunction<E>,
-    arg: &'a Table<E>,
+// 根据 index 迭代器的 value 打开对应的二级迭代器，需要的上下文由闭包自己持有
+pub(crate) type BlockFunction = Box<dyn Fn(&ReadOptions, &Slice) -> Box<dyn Iter>>;
+pub struct TwoLevelIterator {
+    block_function: BlockFunction,
     read_options: ReadOptions,
     status: Status,
     index_iter_: IteratorWrapper,
@@ -22,19 +17,14 @@ where
     data_block_handle_: Vec<u8>,
 }
 
-impl<'a, E> TwoLevelIterator<'a, E>
-where
-    E: Env,
-{
+impl TwoLevelIterator {
     pub fn new(
         index_iter: Box<dyn Iter>,
-        block_function: BlockFunction<E>,
-        table: &Table<E>,
+        block_function: BlockFunction,
         read_options: ReadOptions,
-    ) -> TwoLevelIterator<E> {
+    ) -> TwoLevelIterator {
         TwoLevelIterator {
             block_function,
-            arg: table,
             read_options,
             status: Status::ok(),
             index_iter_: IteratorWrapper::new(Some(index_iter)),
@@ -68,7 +58,7 @@ where
                 && handle.compare(&data_block_handle_) == Ordering::Equal
             {
             } else {
-                let iter = (self.block_function)(&mut self.arg, &self.read_options, &handle);
+                let iter = (self.block_function)(&self.read_options, &handle);
                 self.data_block_handle_.clear();
                 self.data_block_handle_.put_slice(handle.data());
                 self.set_data_iterator(Some(iter))
@@ -105,10 +95,7 @@ where
         }
     }
 }
-impl<'a, E> Iter for TwoLevelIterator<'a, E>
-where
-    E: Env,
-{
+impl Iter for TwoLevelIterator {
     fn valid(&self) -> bool {
         self.data_iter_.valid()
     }
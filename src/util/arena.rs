@@ -1,13 +1,26 @@
 use bumpalo::Bump;
+use std::sync::atomic::{AtomicUsize, Ordering};
 pub(crate) struct Arena {
     bump: Bump,
+    // 分配时更新，其他线程可以在分配的同时读取
+    memory_usage: AtomicUsize,
 }
 impl Arena {
-    fn new() -> Self {
-        Self { bump: Bump::new() }
+    pub(crate) fn new() -> Self {
+        Self {
+            bump: Bump::new(),
+            memory_usage: AtomicUsize::new(0),
+        }
     }
     pub(crate) fn alloc<T>(&self, value: T) -> &mut T {
-        self.bump.alloc(value)
+        let result = self.bump.alloc(value);
+        self.memory_usage
+            .store(self.bump.allocated_bytes(), Ordering::Relaxed);
+        result
+    }
+    /// arena 已经向系统申请的总字节数
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
     }
     // 专门为数组优化的版本
     pub(crate) fn alloc_array<T>(&self, len: usize) -> &mut [T] {
@@ -16,6 +29,8 @@ impl Arena {
             let ptr = self.bump.alloc_layout(layout).as_ptr() as *mut T;
             unsafe { std::slice::from_raw_parts_mut(ptr, len) }
         };
+        self.memory_usage
+            .store(self.bump.allocated_bytes(), Ordering::Relaxed);
         slice
     }
 }
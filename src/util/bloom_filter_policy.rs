@@ -3,6 +3,7 @@ use crate::util::filter_policy::FilterPolicy;
 use crate::util::hash;
 use bytes::{BufMut, Bytes, BytesMut};
 use std::num::Wrapping;
+use std::sync::Arc;
 
 struct BloomFilterPolicy {
     bits_per_key: usize,
@@ -27,6 +28,11 @@ impl BloomFilterPolicy {
     }
 }
 
+/// 每个 key 使用大约 bits_per_key 位的布隆过滤器，10 位时误判率约为 1%
+pub fn new_bloom_filter_policy(bits_per_key: usize) -> Arc<dyn FilterPolicy> {
+    Arc::new(BloomFilterPolicy::new(bits_per_key))
+}
+
 impl FilterPolicy for BloomFilterPolicy {
     fn name(&self) -> &'static str {
         "leveldb.BuiltinBloomFilter2"
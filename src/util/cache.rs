@@ -1,167 +1,193 @@
+use crate::obj::options::CacheType;
 use crate::obj::slice::Slice;
+use crate::util::clock_cache::ClockCache;
 use crate::util::hash::LocalHash;
+use crate::util::midpoint_cache::MidpointCache;
 use ahash::AHashMap;
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter};
 use std::hash::Hash;
 use std::num::NonZeroUsize;
 use std::ops::Deref;
-use std::ptr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Mutex;
-
-/*trait Cache<T, S> {
-    fn new(capacity: NonZeroUsize) -> Self;
-    fn get(&mut self, key: &T) -> Option<&T>;
-    fn put(&mut self, key: T, value: T);
-    fn erase(&mut self, key: &T);
-    fn release(&mut self, key: &T);
-}*/
-// Node in either in-use or LRU doubly linked list
-struct Node<K, V>
-where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
-{
-    key: K,
-    value: Option<V>,
-    prev: *mut Node<K, V>,
-    next: *mut Node<K, V>,
-    ref_count: u64,
+use std::sync::{Arc, Mutex};
+
+/// 缓存条目的引用，持有期间条目不会被淘汰
+pub(crate) struct CacheHandle<V> {
+    inner: Box<dyn Deref<Target = V>>,
 }
 
-impl<K, V> Node<K, V>
-where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
-{
-    fn new(key: K, value: V) -> *mut Node<K, V> {
-        let node = Box::new(Node {
-            key,
-            value: Some(value),
-            prev: ptr::null_mut(),
-            next: ptr::null_mut(),
-            ref_count: 0,
-        });
-        Box::into_raw(node)
+impl<V> CacheHandle<V> {
+    pub(crate) fn new(inner: impl Deref<Target = V> + 'static) -> Self {
+        CacheHandle {
+            inner: Box::new(inner),
+        }
     }
 
-    fn new_empty(key: K) -> *mut Node<K, V> {
-        let node = Box::new(Node {
-            key,
-            value: None,
-            prev: ptr::null_mut(),
-            next: ptr::null_mut(),
-            ref_count: 0,
-        });
-        Box::into_raw(node)
+    pub(crate) fn value(&self) -> &V {
+        &self.inner
     }
 }
-struct LRUCacheInner<K, V>
+
+impl<V> Deref for CacheHandle<V> {
+    type Target = V;
+    fn deref(&self) -> &Self::Target {
+        self.value()
+    }
+}
+
+/// block cache 和 table cache 共用的接口，charge 的单位由使用者决定，
+/// 所有条目的 charge 之和超过容量时淘汰没有被引用的条目。
+/// 条目被删除或淘汰后，已经发出的句柄仍然可以使用，最后一个句柄释放时才销毁
+pub(crate) trait Cache<K, V>: Send + Sync {
+    /// 插入一个占用 charge 的条目，已有的同一个 key 会被替换
+    fn insert(&self, key: &K, value: V, charge: usize) -> Option<CacheHandle<V>>;
+    fn get(&self, key: &K) -> Option<CacheHandle<V>>;
+    fn erase(&self, key: &K);
+    /// 共享同一个 cache 的使用者用不同的 id 作为 key 的前缀
+    fn new_id(&self) -> u64;
+    /// 所有条目的 charge 之和
+    fn usage(&self) -> usize;
+    /// 正在被引用、不能被淘汰的条目的 charge 之和
+    fn pinned_usage(&self) -> usize;
+}
+
+/// 按 cache_type 创建 cache
+pub(crate) fn new_cache<K, V>(cache_type: CacheType, capacity: NonZeroUsize) -> Arc<dyn Cache<K, V>>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Ord + Clone + LocalHash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
 {
+    match cache_type {
+        CacheType::Lru => Arc::new(ShardedLRUCache::new(capacity)),
+        CacheType::Clock => Arc::new(ClockCache::new(capacity)),
+        CacheType::Midpoint => Arc::new(MidpointCache::new(capacity)),
+    }
+}
+
+pub(crate) const K_NUM_SHARD_BITS: usize = 4;
+pub(crate) const K_NUM_SHARDS: usize = 1 << K_NUM_SHARD_BITS;
+
+/// 每个 shard 的容量，总容量平均分给每个 shard
+pub(crate) fn shard_capacity(capacity: NonZeroUsize) -> usize {
+    usize::from(capacity).div_ceil(K_NUM_SHARDS)
+}
+
+/// key 所在的 shard
+pub(crate) fn shard<K: LocalHash>(key: &K) -> usize {
+    (key.local_hash() >> (32 - K_NUM_SHARD_BITS)) as usize
+}
+
+// 条目的数据由 cache 和所有句柄共享，被删除或淘汰后直到最后一个句柄释放才销毁
+struct LRUEntry<K, V> {
+    key: K,
+    value: V,
+}
+
+struct LRUSlot<K, V> {
+    entry: Arc<LRUEntry<K, V>>,
+    // 占用的容量，通常是 value 的字节数
+    charge: usize,
+    // 外部持有的句柄个数，大于 0 时不在 lru 中，不会被淘汰
+    refs: usize,
+    // 最近一次被释放的时间，也是在 lru 中的 key
+    tick: u64,
+}
+
+struct LRUCacheInner<K, V> {
+    // 所有条目的 charge 之和超过 capacity 时淘汰没有被引用的条目
     capacity: usize,
-    map: AHashMap<K, *mut Node<K, V>>,
-    in_use_head: *mut Node<K, V>, // Dummy head for an in-use list
-    in_use_tail: *mut Node<K, V>, // Dummy tail for an in-use list
-    lru_head: *mut Node<K, V>,    // Dummy head for LRU list
-    lru_tail: *mut Node<K, V>,    // Dummy tail for LRU list
+    usage: usize,
+    map: AHashMap<K, LRUSlot<K, V>>,
+    // 没有被引用的条目，tick 越小越久没有使用
+    lru: BTreeMap<u64, K>,
+    tick: u64,
 }
 
 impl<K, V> LRUCacheInner<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
-    // Unlink node from its current list
-    unsafe fn unlink_node(&self, node: *mut Node<K, V>) {
-        (*(*node).prev).next = (*node).next;
-        (*(*node).next).prev = (*node).prev;
-        (*node).prev = ptr::null_mut();
-        (*node).next = ptr::null_mut();
-    }
-
-    // Add node to an in-use list
-    unsafe fn add_to_in_use(&mut self, node: *mut Node<K, V>) {
-        (*node).next = (*self.in_use_head).next;
-        (*node).prev = self.in_use_head;
-        (*(*self.in_use_head).next).prev = node;
-        (*self.in_use_head).next = node;
-    }
-
-    // Add node to LRU list
-    unsafe fn add_to_lru(&mut self, node: *mut Node<K, V>) {
-        (*node).next = (*self.lru_head).next;
-        (*node).prev = self.lru_head;
-        (*(*self.lru_head).next).prev = node;
-        (*self.lru_head).next = node;
-    }
-
-    // Move node between lists or within the same list
-    unsafe fn move_node(&mut self, node: *mut Node<K, V>, to_in_use: bool) {
-        self.unlink_node(node);
-        if to_in_use {
-            self.add_to_in_use(node);
-        } else {
-            self.add_to_lru(node);
+    // 从 cache 中删除，已经发出的句柄仍然持有条目的数据
+    fn remove<Q>(&mut self, key: &Q)
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        if let Some(slot) = self.map.remove(key) {
+            if slot.refs == 0 {
+                self.lru.remove(&slot.tick);
+            }
+            self.usage -= slot.charge;
         }
     }
 
-    // Remove and deallocate node (only from an LRU list)
-    unsafe fn remove_node(&mut self, node: *mut Node<K, V>) {
-        if (*node).ref_count > 0 {
-            panic!("Cannot remove node from in-use list");
+    // 从最久没有使用的条目开始淘汰，直到总 charge 不超过容量；被引用的条目不会被淘汰
+    fn evict(&mut self) {
+        while self.usage > self.capacity {
+            let key = match self.lru.pop_first() {
+                Some((_, key)) => key,
+                None => break,
+            };
+            let slot = self.map.remove(&key).unwrap();
+            self.usage -= slot.charge;
         }
-        self.unlink_node(node);
-        let _ = Box::from_raw(node); // Deallocate
+    }
+
+    // 句柄释放时调用，条目已经被删除或被新的值替换时什么也不做
+    fn release(&mut self, entry: &Arc<LRUEntry<K, V>>) {
+        let slot = match self.map.get_mut(&entry.key) {
+            Some(slot) if Arc::ptr_eq(&slot.entry, entry) => slot,
+            _ => return,
+        };
+        slot.refs -= 1;
+        if slot.refs == 0 {
+            self.tick += 1;
+            slot.tick = self.tick;
+            self.lru.insert(self.tick, entry.key.clone());
+            // 引用期间超出的容量在释放后补上淘汰
+            self.evict();
+        }
+    }
+
+    // 被引用的条目的 charge 之和
+    fn pinned_usage(&self) -> usize {
+        self.map
+            .values()
+            .filter(|slot| slot.refs > 0)
+            .map(|slot| slot.charge)
+            .sum()
     }
 }
 
-struct LRUCache<K, V>
-where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
-{
-    inner: Mutex<LRUCacheInner<K, V>>,
+struct LRUCache<K, V> {
+    // 句柄也持有 inner，释放时不依赖 cache 本身还存在
+    inner: Arc<Mutex<LRUCacheInner<K, V>>>,
 }
 
-#[derive(PartialEq, Debug)]
+/// LRUCache 的句柄，持有期间条目不会被淘汰；条目被删除后句柄仍然可以使用
 pub struct LruRes<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
-    node: *mut Node<K, V>,
-    lru: *const LRUCache<K, V>,
+    entry: Arc<LRUEntry<K, V>>,
+    cache: Arc<Mutex<LRUCacheInner<K, V>>>,
 }
 
 impl<K, V> LRUCache<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
     pub fn new(capacity: NonZeroUsize) -> Self {
-        let in_use_head = Node::new_empty(K::default());
-        let in_use_tail = Node::new_empty(K::default());
-        let lru_head = Node::new_empty(K::default());
-        let lru_tail = Node::new_empty(K::default());
-
-        unsafe {
-            (*in_use_head).next = in_use_tail;
-            (*in_use_tail).prev = in_use_head;
-            (*lru_head).next = lru_tail;
-            (*lru_tail).prev = lru_head;
-        }
         LRUCache {
-            inner: Mutex::new(LRUCacheInner {
+            inner: Arc::new(Mutex::new(LRUCacheInner {
                 capacity: usize::from(capacity),
+                usage: 0,
                 map: AHashMap::new(),
-                in_use_head,
-                in_use_tail,
-                lru_head,
-                lru_tail,
-            }),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
         }
     }
     pub fn get<Q>(&self, key: &Q) -> Option<LruRes<K, V>>
@@ -170,148 +196,70 @@ where
         K: Borrow<Q>,
     {
         let mut cache = self.inner.lock().unwrap();
-        let node = { cache.map.get(key) }; // lock_guard 在此离开作用域，锁释放
-        if let Some(&node) = node {
-            unsafe {
-                if (*node).ref_count == 0 {
-                    cache.move_node(node, true);
-                }
-                (*node).ref_count += 1;
-                drop(cache);
-                Some(LruRes {
-                    node,
-                    lru: self as *const Self,
-                })
-            }
-        } else {
-            None
-        }
-    }
-    pub fn put(&self, key: K, value: V) -> Option<LruRes<K, V>> {
-        let mut cache = self.inner.lock().unwrap();
-        // Check if key exists
-        if let Some(&node) = cache.map.get(&key) {
-            unsafe {
-                (*node).value = Some(value);
-                (*node).ref_count += 1;
-                cache.move_node(node, true);
-            }
-            drop(cache);
-            return Some(LruRes {
-                node,
-                lru: self as *const Self,
-            });
+        let slot = cache.map.get_mut(key)?;
+        let tick = slot.tick;
+        slot.refs += 1;
+        let entry = slot.entry.clone();
+        if slot.refs == 1 {
+            cache.lru.remove(&tick);
         }
-        // Create a new node
-        let node = Node::new(key.clone(), value);
-        cache.map.insert(key, node);
-        unsafe {
-            (*node).ref_count += 1;
-            cache.add_to_in_use(node);
-            // Evict from LRU if over capacity
-            if cache.map.len() > cache.capacity {
-                // Get the least recently used node (before lru_tail)
-                let lru = (*cache.lru_tail).prev;
-                if lru != cache.lru_head {
-                    let lru_key = (*lru).key.clone();
-                    cache.remove_node(lru);
-                    cache.map.remove(&lru_key);
-                }
-            }
-        }
-        drop(cache);
         Some(LruRes {
-            node,
-            lru: self as *const Self,
+            entry,
+            cache: self.inner.clone(),
         })
     }
-
-    // Move node from an in-use to LRU list (simulating release of reference)
-    pub fn release(&self, key: &K) {
+    pub fn put(&self, key: K, value: V, charge: usize) -> Option<LruRes<K, V>> {
         let mut cache = self.inner.lock().unwrap();
-        if let Some(&node) = cache.map.get(key) {
-            unsafe {
-                (*node).ref_count -= 1;
-                if (*node).ref_count == 0 {
-                    cache.move_node(node, false);
-                }
-            }
-        }
+        // 替换已有的条目，旧值的句柄仍然指向旧值
+        cache.remove(&key);
+        let entry = Arc::new(LRUEntry {
+            key: key.clone(),
+            value,
+        });
+        cache.map.insert(
+            key,
+            LRUSlot {
+                entry: entry.clone(),
+                charge,
+                refs: 1,
+                tick: 0,
+            },
+        );
+        cache.usage += charge;
+        cache.evict();
+        Some(LruRes {
+            entry,
+            cache: self.inner.clone(),
+        })
     }
 
-    /// 删除时，对应value被持有的引用会变成空指针
+    /// 删除后已经发出的句柄仍然可以使用，最后一个句柄释放时才销毁
     pub fn erase(&self, key: &K) {
-        let mut cache = self.inner.lock().unwrap();
-        if let Some(&node) = cache.map.get(&key) {
-            unsafe { cache.remove_node(node) }
-            cache.map.remove(key);
-        }
-    }
-}
-
-impl<K, V> Drop for LRUCache<K, V>
-where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
-{
-    fn drop(&mut self) {
-        let cache = self.inner.lock().unwrap();
-
-        unsafe {
-            // Clean up an in-use list (nodes are not deallocated per requirement)
-            let mut current = (*cache.in_use_head).next;
-            while current != cache.in_use_tail {
-                let next = (*current).next;
-                // Do not deallocate in-use nodes
-                current = next;
-            }
-            let _ = Box::from_raw(cache.in_use_head);
-            let _ = Box::from_raw(cache.in_use_tail);
-
-            // Clean up LRU list
-            let mut current = (*cache.lru_head).next;
-            while current != cache.lru_tail {
-                let next = (*current).next;
-                let _ = Box::from_raw(current);
-                current = next;
-            }
-            let _ = Box::from_raw(cache.lru_head);
-            let _ = Box::from_raw(cache.lru_tail);
-        }
+        self.inner.lock().unwrap().remove(key);
     }
 }
 
 impl<K, V> Drop for LruRes<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
     fn drop(&mut self) {
-        let key = unsafe { &(*self.node).key };
-        unsafe {
-            (*(self.lru)).release(key);
-        }
+        self.cache.lock().unwrap().release(&self.entry);
     }
 }
 
 impl<K, V> LruRes<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
     pub fn value(&self) -> &V {
-        let data = unsafe { &((*(self.node)).value) };
-        match data {
-            Some(v) => v,
-            None => panic!("value is None"),
-        }
+        &self.entry.value
     }
 }
 
 impl<K, V> Deref for LruRes<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
     type Target = V;
     fn deref(&self) -> &Self::Target {
@@ -319,24 +267,36 @@ where
     }
 }
 
-const K_NUM_SHARD_BITS: usize = 4;
-const K_NUM_SHARDS: usize = 1 << K_NUM_SHARD_BITS;
-pub(crate) struct ShardedLRUCache<K, V>
+impl<K, V> PartialEq for LruRes<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.entry, &other.entry)
+    }
+}
+
+impl<K, V> Debug for LruRes<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone + LocalHash,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LruRes").finish_non_exhaustive()
+    }
+}
+
+pub(crate) struct ShardedLRUCache<K, V> {
     shared: [LRUCache<K, V>; K_NUM_SHARDS],
     last_id_: AtomicU64,
 }
 
 impl<K, V> ShardedLRUCache<K, V>
 where
-    K: Hash + Eq + PartialEq + Default + Clone + LocalHash,
-    V: Clone,
+    K: Hash + Eq + Clone,
 {
+    /// capacity 是所有条目的 charge 之和的上限，平均分给每个 shard
     pub(crate) fn new(capacity: NonZeroUsize) -> Self {
-        let per_shard = (usize::from(capacity) + (K_NUM_SHARDS - 1)) / K_NUM_SHARDS;
+        let per_shard = shard_capacity(capacity);
         ShardedLRUCache {
             shared: std::array::from_fn(|_| {
                 LRUCache::new(NonZeroUsize::try_from(per_shard).unwrap())
@@ -344,41 +304,62 @@ where
             last_id_: AtomicU64::new(0),
         }
     }
-    fn shard(hash: u32) -> usize {
-        (hash >> (32 - K_NUM_SHARD_BITS)) as usize
+}
+
+impl<K, V> Cache<K, V> for ShardedLRUCache<K, V>
+where
+    K: Hash + Eq + Clone + LocalHash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// 超出容量时淘汰最久没有使用且没有被引用的条目
+    fn insert(&self, key: &K, value: V, charge: usize) -> Option<CacheHandle<V>> {
+        self.shared[shard(key)]
+            .put(key.clone(), value, charge)
+            .map(CacheHandle::new)
+    }
+
+    fn get(&self, key: &K) -> Option<CacheHandle<V>> {
+        self.shared[shard(key)].get(key).map(CacheHandle::new)
     }
-    pub fn insert(&self, key: &K, value: V) -> Option<LruRes<K, V>> {
-        let hash = key.local_hash();
-        self.shared[Self::shard(hash)].put(key.clone(), value)
+
+    fn erase(&self, key: &K) {
+        self.shared[shard(key)].erase(key)
     }
-    pub(crate) fn new_id(&self) -> u64 {
+
+    fn new_id(&self) -> u64 {
         self.last_id_.fetch_add(1, Ordering::Relaxed);
         self.last_id_.load(Ordering::Relaxed)
     }
 
-    pub fn get(&self, key: &K) -> Option<LruRes<K, V>> {
-        let hash = key.local_hash();
-        self.shared[Self::shard(hash)].get(key)
+    fn usage(&self) -> usize {
+        self.shared
+            .iter()
+            .map(|shard| shard.inner.lock().unwrap().usage)
+            .sum()
     }
-    /// 删除时，对应value被持有的引用会变成空指针
-    pub fn erase(&self, key: &K) {
-        let hash = key.local_hash();
-        self.shared[Self::shard(hash)].erase(key)
+
+    fn pinned_usage(&self) -> usize {
+        self.shared
+            .iter()
+            .map(|shard| shard.inner.lock().unwrap().pinned_usage())
+            .sum()
     }
 }
 
 #[cfg(test)]
 const K_CACHE_SIZE: usize = 1000;
 #[cfg(test)]
+const ALL_CACHE_TYPES: [CacheType; 3] = [CacheType::Lru, CacheType::Clock, CacheType::Midpoint];
+#[cfg(test)]
 struct CacheTest<T: Clone + Default> {
-    cache: ShardedLRUCache<Slice, T>,
+    cache: Arc<dyn Cache<Slice, T>>,
 }
 
 #[cfg(test)]
 impl CacheTest<i32> {
-    fn new() -> Self {
+    fn new(cache_type: CacheType) -> Self {
         CacheTest {
-            cache: ShardedLRUCache::new(NonZeroUsize::new(K_CACHE_SIZE).unwrap()),
+            cache: new_cache(cache_type, NonZeroUsize::new(K_CACHE_SIZE).unwrap()),
         }
     }
     fn encode_key(i: i32) -> Slice {
@@ -408,7 +389,7 @@ impl CacheTest<i32> {
         }
     }
     fn insert(&mut self, key: i32, value: i32) {
-        let _ = self.cache.insert(&CacheTest::encode_key(key), value);
+        let _ = self.cache.insert(&CacheTest::encode_key(key), value, 1);
     }
     fn erase(&mut self, key: i32) {
         self.cache.erase(&CacheTest::encode_key(key))
@@ -418,74 +399,224 @@ impl CacheTest<i32> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::random::Random;
 
     #[test]
     fn test_lru_cache() {
         let mut cache = LRUCache::new(NonZeroUsize::new(2).unwrap());
 
         // Add to in-use
-        cache.put("key1".to_string(), "value1".to_string());
-        cache.put("key2".to_string(), "value2".to_string());
+        cache.put("key1".to_string(), "value1".to_string(), 1);
+        cache.put("key2".to_string(), "value2".to_string(), 1);
         assert_eq!(cache.get("key1").unwrap().value(), "value1");
         assert_eq!(cache.get("key2").unwrap().value(), "value2");
         assert_eq!(cache.get("key1").unwrap().value(), "value1");
         assert_eq!(cache.get("key2").unwrap().value(), "value2"); // Moves back to in-use
-        cache.put("key3".to_string(), "value3".to_string());
+        cache.put("key3".to_string(), "value3".to_string(), 1);
         assert_eq!(cache.get("key1"), None);
         assert_eq!(*cache.get("key2").unwrap(), "value2");
         assert_eq!(*cache.get("key3").unwrap(), "value3");
         // Update key2
-        cache.put("key2".to_string(), "value2_updated".to_string());
+        cache.put("key2".to_string(), "value2_updated".to_string(), 1);
         assert_eq!(cache.get("key2").unwrap().value(), "value2_updated");
         assert_eq!(cache.get("key3").unwrap().value(), "value3");
-        cache.put("key4".to_string(), "value4".to_string());
+        cache.put("key4".to_string(), "value4".to_string(), 1);
         assert_eq!(cache.get("key2"), None);
         assert_eq!(cache.get("key3").unwrap().value(), "value3");
         assert_eq!(cache.get("key4").unwrap().value(), "value4");
     }
     #[test]
     fn test_hit_and_miss() {
-        let mut test = CacheTest::<i32>::new();
-        assert_eq!(-1, *test.lookup(100));
-        test.insert(100, 101);
-        assert_eq!(101, *test.lookup(100));
-        assert_eq!(-1, *test.lookup(200));
-        assert_eq!(-1, *test.lookup(300));
-        test.insert(200, 201);
-        assert_eq!(101, *test.lookup(100));
-        assert_eq!(201, *test.lookup(200));
-        assert_eq!(-1, *test.lookup(300));
-        test.insert(100, 102);
-        assert_eq!(102, *test.lookup(100));
-        assert_eq!(201, *test.lookup(200));
-        assert_eq!(-1, *test.lookup(300));
+        for cache_type in ALL_CACHE_TYPES {
+            let mut test = CacheTest::<i32>::new(cache_type);
+            assert_eq!(-1, *test.lookup(100));
+            test.insert(100, 101);
+            assert_eq!(101, *test.lookup(100));
+            assert_eq!(-1, *test.lookup(200));
+            assert_eq!(-1, *test.lookup(300));
+            test.insert(200, 201);
+            assert_eq!(101, *test.lookup(100));
+            assert_eq!(201, *test.lookup(200));
+            assert_eq!(-1, *test.lookup(300));
+            test.insert(100, 102);
+            assert_eq!(102, *test.lookup(100));
+            assert_eq!(201, *test.lookup(200));
+            assert_eq!(-1, *test.lookup(300));
+        }
     }
     #[test]
     fn test_erase() {
-        let mut test = CacheTest::<i32>::new();
-        test.erase(200);
-        test.insert(100, 101);
-        test.insert(200, 201);
-        test.erase(100);
-        assert_eq!(-1, *test.lookup(100));
-        assert_eq!(201, *test.lookup(200));
-        test.erase(100);
-        assert_eq!(-1, *test.lookup(100));
-        assert_eq!(201, *test.lookup(200));
+        for cache_type in ALL_CACHE_TYPES {
+            let mut test = CacheTest::<i32>::new(cache_type);
+            test.erase(200);
+            test.insert(100, 101);
+            test.insert(200, 201);
+            test.erase(100);
+            assert_eq!(-1, *test.lookup(100));
+            assert_eq!(201, *test.lookup(200));
+            test.erase(100);
+            assert_eq!(-1, *test.lookup(100));
+            assert_eq!(201, *test.lookup(200));
+            assert_eq!(1, test.cache.usage(), "{:?}", cache_type);
+        }
     }
     #[test]
     fn test_eviction_policy() {
-        let mut test = CacheTest::<i32>::new();
-        test.insert(100, 101);
-        test.insert(200, 201);
-        test.insert(300, 301);
-        for i in 0..K_CACHE_SIZE + 100 {
-            test.insert((1000 + i) as i32, (i + 2000) as i32);
-            assert_eq!((i + 2000) as i32, *test.lookup((1000 + i) as i32));
+        for cache_type in ALL_CACHE_TYPES {
+            let mut test = CacheTest::<i32>::new(cache_type);
+            test.insert(100, 101);
+            test.insert(200, 201);
+            test.insert(300, 301);
+            for i in 0..K_CACHE_SIZE + 100 {
+                test.insert((1000 + i) as i32, (i + 2000) as i32);
+                assert_eq!((i + 2000) as i32, *test.lookup((1000 + i) as i32));
+                assert_eq!(101, *test.lookup(100), "{:?}", cache_type);
+            }
             assert_eq!(101, *test.lookup(100));
+            assert_eq!(-1, *test.lookup(200), "{:?}", cache_type);
+            assert_eq!(-1, *test.lookup(300), "{:?}", cache_type);
+            let capacity = shard_capacity(NonZeroUsize::new(K_CACHE_SIZE).unwrap()) * K_NUM_SHARDS;
+            assert!(test.cache.usage() <= capacity, "{:?}", cache_type);
+        }
+    }
+    #[test]
+    fn test_evict_by_charge() {
+        let cache = LRUCache::new(NonZeroUsize::new(100).unwrap());
+        drop(cache.put(1, 1, 40));
+        drop(cache.put(2, 2, 40));
+        assert_eq!(80, cache.inner.lock().unwrap().usage);
+        // 超出容量，淘汰最久没有使用的 1
+        drop(cache.put(3, 3, 40));
+        assert!(cache.get(&1).is_none());
+        assert_eq!(2, *cache.get(&2).unwrap());
+        assert_eq!(80, cache.inner.lock().unwrap().usage);
+        // 单个条目超过剩余容量时淘汰多个条目
+        drop(cache.put(4, 4, 90));
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&3).is_none());
+        assert_eq!(90, cache.inner.lock().unwrap().usage);
+        // 更新已有条目时按新的 charge 计算
+        drop(cache.put(4, 5, 10));
+        assert_eq!(5, *cache.get(&4).unwrap());
+        assert_eq!(10, cache.inner.lock().unwrap().usage);
+    }
+    #[test]
+    fn test_pinned_usage() {
+        let cache = ShardedLRUCache::<Slice, i32>::new(NonZeroUsize::new(16 * 100).unwrap());
+        let key = |i: i32| CacheTest::<i32>::encode_key(i);
+        let pinned = cache.insert(&key(1), 1, 60);
+        drop(cache.insert(&key(2), 2, 30));
+        assert_eq!(90, cache.usage());
+        assert_eq!(60, cache.pinned_usage());
+        cache.erase(&key(2));
+        assert_eq!(60, cache.usage());
+        drop(pinned);
+        assert_eq!(60, cache.usage());
+        assert_eq!(0, cache.pinned_usage());
+        // 被引用的条目不会被淘汰，释放后才按容量淘汰
+        let big = cache.insert(&key(3), 3, 1000);
+        assert!(cache.usage() >= 1000);
+        assert_eq!(1000, cache.pinned_usage());
+        assert_eq!(3, *cache.get(&key(3)).unwrap());
+        drop(big);
+        assert!(cache.get(&key(3)).is_none());
+        assert!(cache.usage() <= 16 * 100);
+    }
+    #[test]
+    fn test_erase_with_outstanding_handles() {
+        for cache_type in ALL_CACHE_TYPES {
+            let cache = new_cache::<Slice, Vec<u8>>(cache_type, NonZeroUsize::new(64).unwrap());
+            let key = |i: i32| CacheTest::<i32>::encode_key(i);
+            drop(cache.insert(&key(1), vec![1; 100], 1));
+            let old = cache.get(&key(1)).unwrap();
+            cache.erase(&key(1));
+            assert!(cache.get(&key(1)).is_none());
+            // 同一个 key 插入新的值，并让其它条目把 cache 填满、互相淘汰
+            drop(cache.insert(&key(1), vec![2; 100], 1));
+            for i in 2..1000 {
+                drop(cache.insert(&key(i), vec![i as u8; 100], 1));
+            }
+            assert!(old.iter().all(|b| *b == 1), "{:?}", cache_type);
+            if let Some(new) = cache.get(&key(1)) {
+                assert!(new.iter().all(|b| *b == 2), "{:?}", cache_type);
+            }
+            assert_eq!(0, cache.pinned_usage(), "{:?}", cache_type);
+            drop(old);
+            for i in 1..1000 {
+                cache.erase(&key(i));
+            }
+            assert_eq!(0, cache.usage(), "{:?}", cache_type);
+        }
+    }
+    #[test]
+    fn test_concurrent_erase_with_outstanding_handles() {
+        const K_NUM_THREADS: u32 = 4;
+        const K_NUM_KEYS: u32 = 200;
+        const K_ROUNDS: usize = 5;
+        // 值记录 key 和写入的线程，句柄读到的内容必须和 key 一致
+        type Value = (u32, u32, Vec<u8>);
+        let check = |key: u32, value: &Value| {
+            assert_eq!(key, value.0);
+            assert!(value.2.iter().all(|b| *b == key as u8));
+        };
+        for cache_type in ALL_CACHE_TYPES {
+            let cache: Arc<dyn Cache<Slice, Value>> =
+                new_cache(cache_type, NonZeroUsize::new(K_NUM_SHARDS * 4).unwrap());
+            let barrier = Arc::new(std::sync::Barrier::new(K_NUM_THREADS as usize));
+            let threads: Vec<_> = (0..K_NUM_THREADS)
+                .map(|id| {
+                    let cache = cache.clone();
+                    let barrier = barrier.clone();
+                    std::thread::spawn(move || {
+                        let key = |i: u32| CacheTest::<i32>::encode_key(i as i32);
+                        // 每个线程的操作序列由固定的种子决定
+                        let mut rnd = Random::new(301 + id);
+                        for _ in 0..K_ROUNDS {
+                            let mut held: Vec<(u32, CacheHandle<Value>)> = Vec::new();
+                            for _ in 0..2000 {
+                                let k = rnd.uniform(K_NUM_KEYS);
+                                match rnd.uniform(10) {
+                                    0..=3 => {
+                                        let value = (k, id, vec![k as u8; 64]);
+                                        if let Some(handle) = cache.insert(&key(k), value, 1) {
+                                            held.push((k, handle));
+                                        }
+                                    }
+                                    4 | 5 => cache.erase(&key(k)),
+                                    _ => {
+                                        if let Some(handle) = cache.get(&key(k)) {
+                                            held.push((k, handle));
+                                        }
+                                    }
+                                }
+                                if held.len() > 16 {
+                                    let (k, handle) =
+                                        held.swap_remove(rnd.uniform(held.len() as u32) as usize);
+                                    check(k, &handle);
+                                }
+                            }
+                            // 其它线程持有句柄时删除所有条目
+                            barrier.wait();
+                            if id == 0 {
+                                for k in 0..K_NUM_KEYS {
+                                    cache.erase(&key(k));
+                                }
+                            }
+                            barrier.wait();
+                            for (k, handle) in held.iter() {
+                                check(*k, handle);
+                            }
+                            drop(held);
+                            barrier.wait();
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(0, cache.usage(), "{:?}", cache_type);
+            assert_eq!(0, cache.pinned_usage(), "{:?}", cache_type);
         }
-        assert_eq!(101, *test.lookup(100));
-        assert_eq!(-1, *test.lookup(200));
-        assert_eq!(-1, *test.lookup(300));
     }
 }
@@ -0,0 +1,321 @@
+use crate::util::cache::{shard, shard_capacity, Cache, CacheHandle, K_NUM_SHARDS};
+use crate::util::hash::LocalHash;
+use crossbeam_skiplist::SkipMap;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// 访问计数的上限，经常被访问的条目要被指针经过这么多次才会被淘汰
+const K_MAX_CLOCK_COUNT: u32 = 3;
+// 被淘汰的条目在 refs 上设置的标记，之后查找不能再引用它
+const K_EVICTED: usize = 1 << (usize::BITS - 1);
+
+struct ClockEntry<K, V> {
+    key: K,
+    value: V,
+    charge: usize,
+    // 在 ring 中的位置，创建后不再改变
+    slot: usize,
+    // 每次访问加一（不超过 K_MAX_CLOCK_COUNT），指针经过时减一，减到 0 时被淘汰
+    count: AtomicU32,
+    // 外部持有的句柄个数，大于 0 时不会被淘汰
+    refs: AtomicUsize,
+}
+
+struct ClockHandle<K, V> {
+    entry: Arc<ClockEntry<K, V>>,
+}
+
+impl<K, V> ClockHandle<K, V> {
+    fn new(entry: Arc<ClockEntry<K, V>>) -> Self {
+        entry.refs.fetch_add(1, Ordering::AcqRel);
+        ClockHandle { entry }
+    }
+
+    // 查找时引用，与淘汰时的 compare_exchange 互斥，已经被淘汰的条目返回 None
+    fn pin(entry: Arc<ClockEntry<K, V>>) -> Option<Self> {
+        entry
+            .refs
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |refs| {
+                (refs & K_EVICTED == 0).then_some(refs + 1)
+            })
+            .ok()?;
+        Some(ClockHandle { entry })
+    }
+}
+
+impl<K, V> Deref for ClockHandle<K, V> {
+    type Target = V;
+    fn deref(&self) -> &Self::Target {
+        &self.entry.value
+    }
+}
+
+impl<K, V> Drop for ClockHandle<K, V> {
+    fn drop(&mut self) {
+        self.entry.refs.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// 插入、删除和淘汰时才需要修改，由 ClockShard::ring 的锁保护
+struct ClockRing<K, V> {
+    slots: Vec<Option<Arc<ClockEntry<K, V>>>>,
+    free: Vec<usize>,
+    hand: usize,
+}
+
+struct ClockShard<K, V>
+where
+    K: Ord + Send + 'static,
+    V: Send + 'static,
+{
+    // 查找只访问 map，不加锁
+    map: SkipMap<K, Arc<ClockEntry<K, V>>>,
+    ring: Mutex<ClockRing<K, V>>,
+    capacity: usize,
+    usage: AtomicUsize,
+}
+
+impl<K, V> ClockShard<K, V>
+where
+    K: Ord + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn new(capacity: usize) -> Self {
+        ClockShard {
+            map: SkipMap::new(),
+            ring: Mutex::new(ClockRing {
+                slots: Vec::new(),
+                free: Vec::new(),
+                hand: 0,
+            }),
+            capacity,
+            usage: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<ClockHandle<K, V>> {
+        let entry = self.map.get(key)?.value().clone();
+        let _ = entry
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < K_MAX_CLOCK_COUNT).then_some(count + 1)
+            });
+        ClockHandle::pin(entry)
+    }
+
+    fn insert(&self, key: &K, value: V, charge: usize) -> ClockHandle<K, V> {
+        let mut ring = self.ring.lock().unwrap();
+        self.remove(&mut ring, key);
+        let slot = match ring.free.pop() {
+            Some(slot) => slot,
+            None => {
+                ring.slots.push(None);
+                ring.slots.len() - 1
+            }
+        };
+        let entry = Arc::new(ClockEntry {
+            key: key.clone(),
+            value,
+            charge,
+            slot,
+            count: AtomicU32::new(0),
+            refs: AtomicUsize::new(0),
+        });
+        // 先持有句柄，避免刚插入的条目被淘汰
+        let handle = ClockHandle::new(entry.clone());
+        ring.slots[slot] = Some(entry.clone());
+        self.map.insert(key.clone(), entry);
+        self.usage.fetch_add(charge, Ordering::Relaxed);
+        self.evict(&mut ring);
+        handle
+    }
+
+    fn erase(&self, key: &K) {
+        let mut ring = self.ring.lock().unwrap();
+        self.remove(&mut ring, key);
+    }
+
+    // 从 map 和 ring 中删除，已经发出的句柄仍然可以使用
+    fn remove(&self, ring: &mut ClockRing<K, V>, key: &K) {
+        if let Some(entry) = self.map.remove(key) {
+            let entry = entry.value();
+            ring.slots[entry.slot] = None;
+            ring.free.push(entry.slot);
+            self.usage.fetch_sub(entry.charge, Ordering::Relaxed);
+        }
+    }
+
+    // 指针转过的圈数有上限，所有条目都被引用时不再淘汰
+    fn evict(&self, ring: &mut ClockRing<K, V>) {
+        let mut steps = (K_MAX_CLOCK_COUNT as usize + 1) * ring.slots.len();
+        while self.usage.load(Ordering::Relaxed) > self.capacity && steps > 0 {
+            steps -= 1;
+            let hand = ring.hand;
+            ring.hand = (hand + 1) % ring.slots.len();
+            let key = match ring.slots[hand] {
+                Some(ref entry) if entry.refs.load(Ordering::Acquire) > 0 => continue,
+                Some(ref entry) if entry.count.load(Ordering::Relaxed) > 0 => {
+                    entry.count.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                }
+                // 没有被引用时才能淘汰，与查找的 pin 竞争
+                Some(ref entry)
+                    if entry
+                        .refs
+                        .compare_exchange(0, K_EVICTED, Ordering::AcqRel, Ordering::Acquire)
+                        .is_err() =>
+                {
+                    continue
+                }
+                Some(ref entry) => entry.key.clone(),
+                None => continue,
+            };
+            self.remove(ring, &key);
+        }
+    }
+
+    fn pinned_usage(&self) -> usize {
+        let ring = self.ring.lock().unwrap();
+        ring.slots
+            .iter()
+            .flatten()
+            .filter(|entry| entry.refs.load(Ordering::Acquire) > 0)
+            .map(|entry| entry.charge)
+            .sum()
+    }
+}
+
+/// CLOCK 淘汰策略的 cache：查找只读 lock-free 的 skiplist 并增加访问计数，
+/// 插入和淘汰时才需要获取 shard 的锁
+pub(crate) struct ClockCache<K, V>
+where
+    K: Ord + Send + 'static,
+    V: Send + 'static,
+{
+    shards: [ClockShard<K, V>; K_NUM_SHARDS],
+    last_id_: AtomicU64,
+}
+
+impl<K, V> ClockCache<K, V>
+where
+    K: Ord + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    pub(crate) fn new(capacity: NonZeroUsize) -> Self {
+        let per_shard = shard_capacity(capacity);
+        ClockCache {
+            shards: std::array::from_fn(|_| ClockShard::new(per_shard)),
+            last_id_: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K, V> Cache<K, V> for ClockCache<K, V>
+where
+    K: Ord + Clone + LocalHash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn insert(&self, key: &K, value: V, charge: usize) -> Option<CacheHandle<V>> {
+        let handle = self.shards[shard(key)].insert(key, value, charge);
+        Some(CacheHandle::new(handle))
+    }
+
+    fn get(&self, key: &K) -> Option<CacheHandle<V>> {
+        self.shards[shard(key)].get(key).map(CacheHandle::new)
+    }
+
+    fn erase(&self, key: &K) {
+        self.shards[shard(key)].erase(key)
+    }
+
+    fn new_id(&self) -> u64 {
+        self.last_id_.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.usage.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    fn pinned_usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.pinned_usage()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accessed_entries_survive() {
+        let shard = ClockShard::<u32, u32>::new(4);
+        for i in 0..4 {
+            drop(shard.insert(&i, i, 1));
+        }
+        // 0 和 1 被访问过，指针经过时只减少计数
+        drop(shard.get(&0));
+        drop(shard.get(&1));
+        drop(shard.insert(&4, 4, 1));
+        assert!(shard.get(&0).is_some());
+        assert!(shard.get(&1).is_some());
+        assert!(shard.get(&2).is_none());
+        assert!(shard.get(&3).is_some());
+        assert_eq!(4, shard.usage.load(Ordering::Relaxed));
+
+        // 被引用的条目不会被淘汰，删除后句柄仍然可以使用
+        let pinned = shard.get(&3).unwrap();
+        for i in 10..20 {
+            drop(shard.insert(&i, i, 1));
+        }
+        assert!(shard.get(&3).is_some());
+        assert_eq!(1, shard.pinned_usage());
+        shard.erase(&3);
+        assert!(shard.get(&3).is_none());
+        assert_eq!(3, *pinned);
+        assert_eq!(0, shard.pinned_usage());
+        assert!(shard.usage.load(Ordering::Relaxed) <= 4);
+    }
+
+    #[test]
+    fn test_concurrent_get_under_pressure() {
+        let shard = Arc::new(ClockShard::<u64, u64>::new(16));
+        let inserted = Arc::new(AtomicU64::new(0));
+        let mut threads = vec![];
+        for t in 0..2u64 {
+            let shard = shard.clone();
+            let inserted = inserted.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in 0..20000u64 {
+                    let key = i * 2 + t;
+                    drop(shard.insert(&key, key, 1));
+                    inserted.fetch_max(key, Ordering::Relaxed);
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let shard = shard.clone();
+            let inserted = inserted.clone();
+            threads.push(std::thread::spawn(move || {
+                for _ in 0..20000 {
+                    let newest = inserted.load(Ordering::Relaxed);
+                    for key in newest.saturating_sub(16)..=newest {
+                        // 每个 key 只插入一次，持有句柄期间条目不会被淘汰
+                        if let Some(handle) = shard.get(&key) {
+                            assert_eq!(key, *handle);
+                            assert!(shard.get(&key).is_some());
+                        }
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(0, shard.pinned_usage());
+        assert!(shard.usage.load(Ordering::Relaxed) <= 16);
+    }
+}
@@ -106,7 +106,7 @@ pub(crate) fn put_varint64(dst: &mut BytesMut, v: u64) {
     dst.put_slice(&buf[..len]);
 }
 
-fn put_length_prefixed_slice(dst: &mut BytesMut, value: Slice) {
+pub(crate) fn put_length_prefixed_slice(dst: &mut BytesMut, value: Slice) {
     put_varint32(dst, value.len() as u32);
     dst.put_slice(value.data());
 }
@@ -152,7 +152,7 @@ pub fn get_varint32ptr<'a>(ptr: &'a [u8], value: &mut u32) -> Option<&'a [u8]> {
     get_varint32ptr_fallback(ptr, value)
 }
 
-fn get_varint32(input: &mut Slice, value: &mut u32) -> bool {
+pub(crate) fn get_varint32(input: &mut Slice, value: &mut u32) -> bool {
     let ptr = input.data();
     let limit = input.size();
     if let Some(q) = get_varint32ptr(ptr, value) {
@@ -196,7 +196,7 @@ pub(crate) fn get_varint64(input: &mut Slice, value: &mut u64) -> bool {
     }
 }
 
-fn get_length_prefixed_slice(input: &mut Slice, result: &mut Slice) -> bool {
+pub(crate) fn get_length_prefixed_slice(input: &mut Slice, result: &mut Slice) -> bool {
     let mut len = 0u32;
     if get_varint32(input, &mut len) && input.size() >= len as usize {
         *result = input.slice(len as usize);
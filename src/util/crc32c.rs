@@ -8,12 +8,12 @@ pub fn value(data: &[u8]) -> u32 {
 }
 #[inline]
 pub fn mask(crc: u32) -> u32 {
-    ((crc >> 15) | (crc << 17)) + K_MASK_DELTA
+    ((crc >> 15) | (crc << 17)).wrapping_add(K_MASK_DELTA)
 }
 
 #[inline]
 pub fn unmask(masked_crc: u32) -> u32 {
-    let crc = masked_crc - K_MASK_DELTA;
+    let crc = masked_crc.wrapping_sub(K_MASK_DELTA);
     (crc >> 17) | (crc << 15)
 }
 
@@ -1,11 +1,13 @@
+use crate::obj::slice::Slice;
 use crate::obj::status_rs::Status;
 use crate::util::random_access_file::{
     Limiter, PosixMmapReadableFile, RandomAccessFile, StdRandomAccessFile,
 };
 use crate::util::sequential_file::{SequentialFile, StdSequentialFile};
 use crate::util::thread_pool::ThreadPool;
-use crate::util::writable_file::WritableFile;
+use crate::util::writable_file::{StdWritableFile, WritableFile};
 use crate::util::K_OPEN_BASE_FLAGS;
+use ahash::HashSet;
 use bytes::{BufMut, BytesMut};
 use libc::{gettimeofday, timeval};
 use rustix::fs::FlockOperation;
@@ -120,7 +122,7 @@ impl FileLock {
     }
 }
 
-pub trait Env {
+pub trait Env: Send + Sync {
     fn new() -> Self;
     fn new_sequential_file<T: SequentialFile, P: AsRef<Path>>(
         &self,
@@ -167,7 +169,28 @@ pub fn get_env<T: Env>() -> T {
     T::new()
 }
 
-fn read_file_to_string<T: Env, P: AsRef<Path>>(
+/// 写入 data 并 sync，失败时删除写了一半的文件
+pub(crate) fn write_string_to_file_sync<T: Env, P: AsRef<Path>>(
+    env: &T,
+    data: &Slice,
+    filename: P,
+) -> Status {
+    let mut file = match env.new_writable_file::<StdWritableFile, _>(filename.as_ref()) {
+        Ok(file) => file,
+        Err(e) => return e,
+    };
+    let mut s = file.append(data);
+    if s.is_ok() {
+        s = file.sync();
+    }
+    drop(file);
+    if !s.is_ok() {
+        env.remove_file(filename);
+    }
+    s
+}
+
+pub(crate) fn read_file_to_string<T: Env, P: AsRef<Path>>(
     env: &T,
     filename: P,
     data: &mut BytesMut,
@@ -197,10 +220,12 @@ fn read_file_to_string<T: Env, P: AsRef<Path>>(
     }
 }
 
-struct StdEnv {
+pub(crate) struct StdEnv {
     mmap_limiter_: Arc<Limiter>,
     fd_limiter_: Arc<Limiter>,
     thread_pool: ThreadPool,
+    // fcntl 锁对同一进程无效，这里记录本进程已经锁住的文件
+    locks_: Mutex<HashSet<String>>,
 }
 
 impl Env for StdEnv {
@@ -209,6 +234,7 @@ impl Env for StdEnv {
             mmap_limiter_: Arc::new(Limiter::new(DEFAULT_MMAP_LIMIT)),
             fd_limiter_: Arc::new(Limiter::new(max_open_files())),
             thread_pool: ThreadPool::new(1),
+            locks_: Mutex::new(HashSet::default()),
         }
     }
 
@@ -369,11 +395,19 @@ impl Env for StdEnv {
             option.mode(0o644);
             option.custom_flags(K_OPEN_BASE_FLAGS); // 对应 O_CLOEXEC
         }
+        let name = filename.as_ref().to_string_lossy().into_owned();
+        if !self.locks_.lock().unwrap().insert(name.clone()) {
+            return Err(Status::io_error(
+                &format!("lock {}", name),
+                Some("already held by process"),
+            ));
+        }
         match option.open(filename.as_ref()) {
             Ok(file) => {
                 if let Ok(_) = lock_file_exclusive(&file) {
-                    Ok(FileLock::new(file, &filename.as_ref().to_string_lossy()))
+                    Ok(FileLock::new(file, &name))
                 } else {
+                    self.locks_.lock().unwrap().remove(&name);
                     Err(Status::io_error(
                         "lock file error",
                         Some(&filename.as_ref().to_string_lossy().into_owned()),
@@ -381,6 +415,7 @@ impl Env for StdEnv {
                 }
             }
             Err(err) => {
+                self.locks_.lock().unwrap().remove(&name);
                 error!(
                     "lock file {} error: {}",
                     filename.as_ref().to_string_lossy(),
@@ -395,6 +430,7 @@ impl Env for StdEnv {
     }
 
     fn unlock_file(&self, file_lock: &FileLock) -> Status {
+        self.locks_.lock().unwrap().remove(&file_lock.file_name);
         match unlock_file(&file_lock.file) {
             Ok(_) => Status::ok(),
             Err(err) => {
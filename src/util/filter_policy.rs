@@ -1,6 +1,6 @@
 use crate::obj::slice::Slice;
 use bytes::BytesMut;
-pub trait FilterPolicy {
+pub trait FilterPolicy: Send + Sync {
     fn name(&self) -> &'static str;
     fn create_filter(&self, keys: &[Slice], dst: &mut BytesMut);
     fn key_may_match(&self, key: &Slice, filter: &Slice) -> bool;
@@ -0,0 +1,431 @@
+use crate::util::cache::{shard, shard_capacity, Cache, CacheHandle, K_NUM_SHARDS};
+use crate::util::hash::LocalHash;
+use ahash::AHashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// 链表中表示没有前驱或后继的下标
+const NIL: usize = usize::MAX;
+
+// 条目的数据由 cache 和所有句柄共享，被删除或淘汰后直到最后一个句柄释放才销毁
+struct MidpointEntry<K, V> {
+    // 插入时的 key，查找用的 key 可能引用调用者的临时缓冲区，不能保存
+    key: K,
+    value: V,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Hot,
+    Cold,
+}
+
+struct Node<K, V> {
+    entry: Arc<MidpointEntry<K, V>>,
+    charge: usize,
+    // 外部持有的句柄个数，大于 0 时不在链表中，不会被淘汰
+    refs: usize,
+    // 所在的区，被引用时是释放后回到的区
+    region: Region,
+    prev: usize,
+    next: usize,
+}
+
+// 以 nodes 的下标链接的双向链表，head 是最近访问的条目，tail 是最久没有访问的
+struct List {
+    head: usize,
+    tail: usize,
+}
+
+impl List {
+    fn new() -> Self {
+        List {
+            head: NIL,
+            tail: NIL,
+        }
+    }
+}
+
+struct MidpointShard<K, V> {
+    map: AHashMap<K, usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    // 只包含没有被引用的条目，淘汰时直接取 tail
+    hot: List,
+    cold: List,
+    capacity: usize,
+    hot_capacity: usize,
+    usage: usize,
+    // 热区条目的 charge 之和，包括正在被引用的
+    hot_usage: usize,
+}
+
+impl<K, V> MidpointShard<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn new(capacity: usize) -> Self {
+        MidpointShard {
+            map: AHashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            hot: List::new(),
+            cold: List::new(),
+            capacity,
+            // 冷区占 3/8，与 InnoDB buffer pool 的默认值相同
+            hot_capacity: capacity - capacity * 3 / 8,
+            usage: 0,
+            hot_usage: 0,
+        }
+    }
+
+    fn node(&self, index: usize) -> &Node<K, V> {
+        self.nodes[index].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node<K, V> {
+        self.nodes[index].as_mut().unwrap()
+    }
+
+    fn list_mut(&mut self, region: Region) -> &mut List {
+        match region {
+            Region::Hot => &mut self.hot,
+            Region::Cold => &mut self.cold,
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (region, prev, next) = {
+            let node = self.node(index);
+            (node.region, node.prev, node.next)
+        };
+        match prev {
+            NIL => self.list_mut(region).head = next,
+            prev => self.node_mut(prev).next = next,
+        }
+        match next {
+            NIL => self.list_mut(region).tail = prev,
+            next => self.node_mut(next).prev = prev,
+        }
+    }
+
+    // 放到 node.region 所在链表的头部
+    fn push_front(&mut self, index: usize) {
+        let region = self.node(index).region;
+        let head = self.list_mut(region).head;
+        let node = self.node_mut(index);
+        node.prev = NIL;
+        node.next = head;
+        match head {
+            NIL => self.list_mut(region).tail = index,
+            head => self.node_mut(head).prev = index,
+        }
+        self.list_mut(region).head = index;
+    }
+
+    fn set_region(&mut self, index: usize, region: Region) {
+        let node = self.node_mut(index);
+        let charge = node.charge;
+        match (node.region, region) {
+            (Region::Cold, Region::Hot) => self.hot_usage += charge,
+            (Region::Hot, Region::Cold) => self.hot_usage -= charge,
+            _ => {}
+        }
+        self.node_mut(index).region = region;
+    }
+
+    // 热区放不下时把最久没有访问的条目放回冷区的头部
+    fn demote(&mut self) {
+        while self.hot_usage > self.hot_capacity && self.hot.tail != NIL {
+            let oldest = self.hot.tail;
+            self.unlink(oldest);
+            self.set_region(oldest, Region::Cold);
+            self.push_front(oldest);
+        }
+    }
+
+    // 第二次被访问时进入热区，释放后放在热区的头部
+    fn get(&mut self, key: &K) -> Option<Arc<MidpointEntry<K, V>>> {
+        let index = *self.map.get(key)?;
+        let node = self.node_mut(index);
+        node.refs += 1;
+        let entry = node.entry.clone();
+        if node.refs == 1 {
+            self.unlink(index);
+        }
+        self.set_region(index, Region::Hot);
+        self.demote();
+        Some(entry)
+    }
+
+    // 新条目释放后放在冷区的头部，只被访问一次的条目会先于热区的条目被淘汰
+    fn insert(&mut self, key: &K, value: V, charge: usize) -> Arc<MidpointEntry<K, V>> {
+        self.erase(key);
+        let entry = Arc::new(MidpointEntry {
+            key: key.clone(),
+            value,
+        });
+        let node = Node {
+            entry: entry.clone(),
+            charge,
+            refs: 1,
+            region: Region::Cold,
+            prev: NIL,
+            next: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.map.insert(key.clone(), index);
+        self.usage += charge;
+        self.evict();
+        entry
+    }
+
+    // 从 cache 中删除，已经发出的句柄仍然持有条目的数据
+    fn erase(&mut self, key: &K) {
+        if let Some(index) = self.map.remove(key) {
+            if self.node(index).refs == 0 {
+                self.unlink(index);
+            }
+            let node = self.nodes[index].take().unwrap();
+            self.free.push(index);
+            if node.region == Region::Hot {
+                self.hot_usage -= node.charge;
+            }
+            self.usage -= node.charge;
+        }
+    }
+
+    // 先淘汰冷区中最久没有访问的条目，冷区为空时再淘汰热区的；被引用的条目不在链表中
+    fn evict(&mut self) {
+        while self.usage > self.capacity {
+            let victim = match (self.cold.tail, self.hot.tail) {
+                (NIL, NIL) => break,
+                (NIL, hot) => hot,
+                (cold, _) => cold,
+            };
+            let key = self.node(victim).entry.key.clone();
+            self.erase(&key);
+        }
+    }
+
+    // 句柄释放时调用，条目已经被删除或被新的值替换时什么也不做
+    fn release(&mut self, entry: &Arc<MidpointEntry<K, V>>) {
+        let index = match self.map.get(&entry.key) {
+            Some(&index) if Arc::ptr_eq(&self.node(index).entry, entry) => index,
+            _ => return,
+        };
+        let node = self.node_mut(index);
+        node.refs -= 1;
+        if node.refs == 0 {
+            self.push_front(index);
+            // 引用期间超出的容量在释放后补上淘汰
+            self.demote();
+            self.evict();
+        }
+    }
+
+    fn pinned_usage(&self) -> usize {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter(|node| node.refs > 0)
+            .map(|node| node.charge)
+            .sum()
+    }
+}
+
+struct MidpointHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    entry: Arc<MidpointEntry<K, V>>,
+    // 释放时不依赖 cache 本身还存在
+    shard: Arc<Mutex<MidpointShard<K, V>>>,
+}
+
+impl<K, V> Deref for MidpointHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    type Target = V;
+    fn deref(&self) -> &Self::Target {
+        &self.entry.value
+    }
+}
+
+impl<K, V> Drop for MidpointHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn drop(&mut self) {
+        self.shard.lock().unwrap().release(&self.entry);
+    }
+}
+
+type ShardRef<K, V> = Arc<Mutex<MidpointShard<K, V>>>;
+
+fn insert<K, V>(shard: &ShardRef<K, V>, key: &K, value: V, charge: usize) -> MidpointHandle<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    let entry = shard.lock().unwrap().insert(key, value, charge);
+    MidpointHandle {
+        entry,
+        shard: shard.clone(),
+    }
+}
+
+fn lookup<K, V>(shard: &ShardRef<K, V>, key: &K) -> Option<MidpointHandle<K, V>>
+where
+    K: Hash + Eq + Clone,
+{
+    let entry = shard.lock().unwrap().get(key)?;
+    Some(MidpointHandle {
+        entry,
+        shard: shard.clone(),
+    })
+}
+
+/// 中点插入的 LRU：新条目先进入冷区，在冷区中再次被访问才进入热区。
+/// 大范围扫描读到的 block 只会在冷区中互相淘汰，不会把点查询的热点 block 挤出去
+pub(crate) struct MidpointCache<K, V> {
+    shards: [ShardRef<K, V>; K_NUM_SHARDS],
+    last_id_: AtomicU64,
+}
+
+impl<K, V> MidpointCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub(crate) fn new(capacity: NonZeroUsize) -> Self {
+        let per_shard = shard_capacity(capacity);
+        MidpointCache {
+            shards: std::array::from_fn(|_| Arc::new(Mutex::new(MidpointShard::new(per_shard)))),
+            last_id_: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K, V> Cache<K, V> for MidpointCache<K, V>
+where
+    K: Hash + Eq + Clone + LocalHash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn insert(&self, key: &K, value: V, charge: usize) -> Option<CacheHandle<V>> {
+        let handle = insert(&self.shards[shard(key)], key, value, charge);
+        Some(CacheHandle::new(handle))
+    }
+
+    fn get(&self, key: &K) -> Option<CacheHandle<V>> {
+        lookup(&self.shards[shard(key)], key).map(CacheHandle::new)
+    }
+
+    fn erase(&self, key: &K) {
+        self.shards[shard(key)].lock().unwrap().erase(key)
+    }
+
+    fn new_id(&self) -> u64 {
+        self.last_id_.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().usage)
+            .sum()
+    }
+
+    fn pinned_usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().pinned_usage())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_shard(capacity: usize) -> ShardRef<u32, u32> {
+        Arc::new(Mutex::new(MidpointShard::new(capacity)))
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        let shard = new_shard(8);
+        let hot_usage = || shard.lock().unwrap().hot_usage;
+        let usage = || shard.lock().unwrap().usage;
+        // 点查询的热点：插入后再次访问，进入热区
+        for i in 0..4 {
+            drop(insert(&shard, &i, i, 1));
+            drop(lookup(&shard, &i));
+        }
+        assert_eq!(4, hot_usage());
+        // 扫描读到的条目只访问一次，只在冷区中互相淘汰
+        for i in 100..1000 {
+            drop(insert(&shard, &i, i, 1));
+        }
+        for i in 0..4 {
+            assert_eq!(i, *lookup(&shard, &i).unwrap());
+        }
+        assert!(lookup(&shard, &100).is_none());
+        assert_eq!(8, usage());
+
+        // 热区放不下时最久没有访问的条目回到冷区，随后被淘汰
+        for i in 10..20 {
+            drop(insert(&shard, &i, i, 1));
+            drop(lookup(&shard, &i));
+        }
+        assert!(hot_usage() <= 5);
+        assert!(lookup(&shard, &0).is_none());
+        assert!(lookup(&shard, &19).is_some());
+        assert_eq!(8, usage());
+    }
+
+    #[test]
+    fn test_pinned_entries_skipped() {
+        let shard = new_shard(4);
+        let usage = || shard.lock().unwrap().usage;
+        // 冷区中最久没有访问的条目被引用，淘汰时跳过
+        let pinned: Vec<_> = (0..3).map(|i| insert(&shard, &i, i, 1)).collect();
+        for i in 10..20 {
+            drop(insert(&shard, &i, i, 1));
+        }
+        for i in 0..3 {
+            assert_eq!(i, *lookup(&shard, &i).unwrap());
+        }
+        assert!(lookup(&shard, &18).is_none());
+        assert!(lookup(&shard, &19).is_some());
+        assert_eq!(3, shard.lock().unwrap().pinned_usage());
+
+        // 全部被引用时超出容量，释放后补上淘汰
+        let more: Vec<_> = (20..23).map(|i| insert(&shard, &i, i, 1)).collect();
+        assert_eq!(6, usage());
+        drop(pinned);
+        drop(more);
+        assert_eq!(4, usage());
+        assert_eq!(0, shard.lock().unwrap().pinned_usage());
+        assert!(lookup(&shard, &22).is_some());
+
+        // 被删除的条目的句柄仍然可以使用
+        let handle = lookup(&shard, &22).unwrap();
+        shard.lock().unwrap().erase(&22);
+        assert!(lookup(&shard, &22).is_none());
+        assert_eq!(22, *handle);
+        drop(handle);
+        assert_eq!(3, usage());
+    }
+}
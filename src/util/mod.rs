@@ -10,17 +10,20 @@ use libc::c_int;
 #[cfg(unix)]
 pub const K_OPEN_BASE_FLAGS: c_int = libc::O_CLOEXEC;
 pub(crate) mod cache;
+mod clock_cache;
 pub(crate) mod env;
 pub(crate) mod filter_policy;
 pub(crate) mod hash;
 mod histogram;
+mod midpoint_cache;
 mod options;
-mod random;
+pub(crate) mod random;
 pub(crate) mod random_access_file;
 pub(crate) mod sequential_file;
-mod test_util;
+pub(crate) mod test_util;
 mod thread_pool;
 pub mod writable_file;
 
+pub use bloom_filter_policy::new_bloom_filter_policy;
 pub use hash::hash;
 pub use hash::hash_string;
@@ -40,13 +40,18 @@ impl SequentialFile for StdSequentialFile {
         // 创建一个 BytesMut 来存储数据
         let mut buffer = BytesMut::with_capacity(n);
         buffer.resize(n, 0);
-        match self.file.read(&mut buffer) {
-            Ok(len) => {
-                let res = buffer.split_to(len).freeze();
-                Ok(Slice::new(res))
+        // 单次 read 可能只返回部分数据，循环读满 n 字节或到达文件末尾
+        let mut len = 0;
+        while len < n {
+            match self.file.read(&mut buffer[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(Status::from_io_error(err, &self.filename)),
             }
-            Err(err) => Err(Status::from_io_error(err, &self.filename)),
         }
+        let res = buffer.split_to(len).freeze();
+        Ok(Slice::new(res))
     }
 
     fn skip(&mut self, n: i64) -> Status {
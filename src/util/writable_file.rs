@@ -11,7 +11,7 @@ use std::path::Path;
 #[cfg(unix)]
 use {crate::util::K_OPEN_BASE_FLAGS, std::os::unix::fs::OpenOptionsExt};
 
-pub trait WritableFile {
+pub trait WritableFile: Send {
     fn new<P: AsRef<Path>>(filename: P, truncate: bool) -> io::Result<Self>
     where
         Self: Sized;